@@ -0,0 +1,346 @@
+use crate::dao::{create_indexes, edit_one_doc, find_one_doc, get_all_docs, insert_one_doc};
+use crate::jwt_auth::TokenClaims;
+use crate::permission::Permission;
+use crate::refresh_token::generate_token;
+use crate::AppState;
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use chrono::{SecondsFormat, Utc};
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::serde_helpers::{bson_datetime_as_rfc3339_string, hex_string_as_object_id};
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::IndexOptions;
+use mongodb::{Database, IndexModel};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cmp::Reverse;
+use std::str::FromStr;
+use std::time::Duration;
+use tracing::{error, info};
+
+pub const API_KEY_HEADER: &str = "x-api-key";
+const API_KEY_TAG: &str = "mg";
+/// Uses closer together than this don't move `last_used_at`, a busy bot would write on every
+/// request otherwise.
+const LAST_USED_RESOLUTION: Duration = Duration::from_secs(60);
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ApiKey {
+    #[serde(with = "hex_string_as_object_id")]
+    _id: String,
+    name: String,
+    /// The start of the key, e.g. `mg_1a2b3c4d`, so a key can be told apart without its secret.
+    prefix: String,
+    /// SHA-256 of the whole key. Keys are random, so unlike passwords they need no slow hash.
+    key_hash: String,
+    scopes: Vec<Permission>,
+    /// The admin who created the key.
+    created_by: String,
+    #[serde(with = "bson_datetime_as_rfc3339_string")]
+    created_at: DateTime,
+    #[serde(default)]
+    last_used_at: Option<String>,
+    /// Set once the key is revoked, it's kept so the list shows what happened to it.
+    #[serde(default)]
+    revoked_at: Option<String>,
+}
+
+impl ApiKey {
+    /// A new key for `scopes`, along with the key itself, which only its hash is stored of.
+    pub fn generate(name: &str, scopes: Vec<Permission>, created_by: &str) -> (Self, String) {
+        let mut prefix_bytes = [0u8; 4];
+        rand::thread_rng().fill_bytes(&mut prefix_bytes);
+        let prefix = format!("{}_{}", API_KEY_TAG, hex::encode(prefix_bytes));
+        let key = format!("{}_{}", prefix, generate_token());
+
+        let api_key = ApiKey {
+            _id: ObjectId::new().to_hex(),
+            name: name.trim().to_string(),
+            prefix,
+            key_hash: hash_api_key(&key),
+            scopes,
+            created_by: created_by.to_string(),
+            created_at: DateTime::now(),
+            last_used_at: None,
+            revoked_at: None,
+        };
+
+        (api_key, key)
+    }
+}
+
+/// What an API key may do, readable by handlers as an `Extension<ApiKeyClaims>` the way a
+/// token's are as `Extension<TokenClaims>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ApiKeyClaims {
+    pub id: String,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<Permission>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct NewApiKeyRequest {
+    pub name: String,
+    pub scopes: Vec<Permission>,
+}
+
+/// An API key as listed, without its hash.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ApiKeyResponse {
+    pub id: String,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<Permission>,
+    pub created_by: String,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+    pub revoked_at: Option<String>,
+}
+
+impl From<ApiKey> for ApiKeyResponse {
+    fn from(api_key: ApiKey) -> Self {
+        ApiKeyResponse {
+            id: api_key._id,
+            name: api_key.name,
+            prefix: api_key.prefix,
+            scopes: api_key.scopes,
+            created_by: api_key.created_by,
+            created_at: api_key
+                .created_at
+                .try_to_rfc3339_string()
+                .unwrap_or_default(),
+            last_used_at: api_key.last_used_at,
+            revoked_at: api_key.revoked_at,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct CreatedApiKeyResponse {
+    /// Only ever shown here, it can't be looked up again.
+    pub api_key: String,
+    #[serde(flatten)]
+    pub details: ApiKeyResponse,
+}
+
+fn hash_api_key(api_key: &str) -> String {
+    hex::encode(Sha256::digest(api_key.as_bytes()))
+}
+
+fn now_rfc3339() -> String {
+    Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+pub async fn create_api_key_indexes(mongo: Database) -> anyhow::Result<()> {
+    let unique_hash_index = IndexModel::builder()
+        .keys(doc! { "key_hash": 1 })
+        .options(
+            IndexOptions::builder()
+                .name("api_key_unique_hash".to_string())
+                .unique(true)
+                .build(),
+        )
+        .build();
+
+    let index_names = create_indexes::<ApiKey>(mongo, vec![unique_hash_index]).await?;
+    info!("ApiKey indexes ready {:?}", index_names);
+
+    Ok(())
+}
+
+/// Whether `last_used_at` is old enough to be moved to now.
+fn is_last_used_stale(last_used_at: Option<&str>) -> bool {
+    let last_used_at = match last_used_at.map(chrono::DateTime::parse_from_rfc3339) {
+        Some(Ok(last_used_at)) => last_used_at,
+        _ => return true,
+    };
+
+    Utc::now().signed_duration_since(last_used_at)
+        >= chrono::Duration::from_std(LAST_USED_RESOLUTION).unwrap_or_default()
+}
+
+/// Looks the key in `headers` up on every request, so a revoked key stops working at once.
+pub async fn authenticate_api_key(
+    state: &AppState,
+    headers: &HeaderMap,
+) -> Result<ApiKeyClaims, StatusCode> {
+    let key = headers
+        .get(API_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|key| key.starts_with(API_KEY_TAG))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    let filter = doc! { "key_hash": hash_api_key(key), "revoked_at": null };
+    let api_key = match find_one_doc::<ApiKey>(state.mongo.clone(), filter).await {
+        Ok(Some(api_key)) => api_key,
+        Ok(None) => return Err(StatusCode::UNAUTHORIZED),
+        Err(err) => {
+            error!("fail to find api key {}", err);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    if is_last_used_stale(api_key.last_used_at.as_deref()) {
+        let mongo = state.mongo.clone();
+        let id = api_key._id.clone();
+        // the request doesn't wait on the bookkeeping
+        tokio::spawn(async move {
+            let result = match ObjectId::from_str(&id) {
+                Ok(object_id) => edit_one_doc::<ApiKey>(
+                    mongo,
+                    doc! { "_id": object_id },
+                    doc! { "$set": { "last_used_at": now_rfc3339() } },
+                )
+                .await
+                .map(|_| ()),
+                Err(err) => Err(err.into()),
+            };
+            if let Err(err) = result {
+                error!("fail to record api key use {}", err);
+            }
+        });
+    }
+
+    Ok(ApiKeyClaims {
+        id: api_key._id,
+        name: api_key.name,
+        prefix: api_key.prefix,
+        scopes: api_key.scopes,
+    })
+}
+
+pub async fn create_api_key(
+    State(state): State<AppState>,
+    Extension(claims): Extension<TokenClaims>,
+    Json(req): Json<NewApiKeyRequest>,
+) -> Result<Json<CreatedApiKeyResponse>, impl IntoResponse> {
+    let error_message = if req.name.trim().is_empty() {
+        Some("An API key needs a name!")
+    } else if req.scopes.is_empty() {
+        Some("An API key needs at least one scope!")
+    } else if req.scopes.contains(&Permission::ApiKeysManage) {
+        // a leaked key must not be able to mint more keys
+        Some("An API key can't manage API keys!")
+    } else {
+        None
+    };
+    if let Some(error_message) = error_message {
+        error!(error_message);
+        return Err((StatusCode::BAD_REQUEST, error_message.to_string()).into_response());
+    }
+
+    let (api_key, key) = ApiKey::generate(&req.name, req.scopes, &claims.name);
+
+    match insert_one_doc::<ApiKey>(state.mongo.clone(), api_key.clone()).await {
+        Ok(_) => {
+            info!(
+                "API key {} {} created by {}",
+                api_key.prefix, api_key.name, claims.name
+            );
+            Ok(Json(CreatedApiKeyResponse {
+                api_key: key,
+                details: api_key.into(),
+            }))
+        }
+        Err(err) => {
+            let error_message = err.to_string();
+            error!("{}", error_message.clone());
+            Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response())
+        }
+    }
+}
+
+/// Every key, revoked ones included, newest first.
+pub async fn get_api_keys(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ApiKeyResponse>>, impl IntoResponse> {
+    match get_all_docs::<ApiKey>(state.mongo).await {
+        Ok(mut api_keys) => {
+            api_keys.sort_by_key(|api_key| Reverse(api_key.created_at));
+            Ok(Json(
+                api_keys.into_iter().map(ApiKeyResponse::from).collect(),
+            ))
+        }
+        Err(err) => {
+            let error_message = err.to_string();
+            error!("{}", error_message.clone());
+            Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response())
+        }
+    }
+}
+
+pub async fn revoke_api_key(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<ApiKeyResponse>, impl IntoResponse> {
+    let object_id = match ObjectId::from_str(&id) {
+        Ok(object_id) => object_id,
+        Err(err) => {
+            let error_message = err.to_string();
+            error!(error_message);
+            return Err((StatusCode::BAD_REQUEST, error_message).into_response());
+        }
+    };
+
+    let result = edit_one_doc::<ApiKey>(
+        state.mongo.clone(),
+        doc! { "_id": object_id, "revoked_at": null },
+        doc! { "$set": { "revoked_at": now_rfc3339() } },
+    )
+    .await;
+
+    match result {
+        Ok(Some(api_key)) => {
+            info!("API key {} {} revoked", api_key.prefix, api_key.name);
+            Ok(Json(api_key.into()))
+        }
+        Ok(None) => {
+            error!("The active API key with id: {} not found!", id);
+            Err((
+                StatusCode::NOT_FOUND,
+                format!("The active API key with id: {} not found!", id),
+            )
+                .into_response())
+        }
+        Err(err) => {
+            let error_message = err.to_string();
+            error!("{}", error_message.clone());
+            Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_api_key() {
+        let (api_key, key) = ApiKey::generate(" Discord bot ", vec![Permission::SyncRead], "admin");
+
+        assert_eq!(api_key.name, "Discord bot");
+        assert!(api_key.prefix.starts_with("mg_"));
+        assert_eq!(api_key.prefix.len(), 11);
+        assert!(key.starts_with(&format!("{}_", api_key.prefix)));
+        assert_eq!(api_key.key_hash, hash_api_key(&key));
+        assert!(!api_key.key_hash.contains(&key));
+
+        let response = ApiKeyResponse::from(api_key.clone());
+        assert_eq!(response.prefix, api_key.prefix);
+        assert!(!serde_json::to_string(&response)
+            .unwrap()
+            .contains(&api_key.key_hash));
+    }
+
+    #[test]
+    fn test_is_last_used_stale() {
+        assert!(is_last_used_stale(None));
+        assert!(is_last_used_stale(Some("garbage")));
+        assert!(is_last_used_stale(Some("2024-03-15T10:00:00Z")));
+        assert!(!is_last_used_stale(Some(&now_rfc3339())));
+    }
+}
@@ -0,0 +1,535 @@
+use crate::dao::{
+    count_docs, create_indexes, find_one_doc, insert_one_doc, is_duplicate_key_error,
+};
+use crate::jwt_auth::{deny_access_tokens, issue_access_token, TokenClaims, ACCESS_TOKEN_TTL};
+use crate::permission::ADMIN_ROLE;
+use crate::refresh_token::{
+    issue_refresh_token, revoke_token_family, rotate_refresh_token, use_refresh_token,
+    RefreshOutcome, RefreshTokenGrant,
+};
+use crate::AppState;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use axum::extract::{ConnectInfo, State};
+use axum::http::header::RETRY_AFTER;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Extension;
+use axum::Json;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::serde_helpers::{bson_datetime_as_rfc3339_string, hex_string_as_object_id};
+use mongodb::bson::{doc, DateTime};
+use mongodb::options::IndexOptions;
+use mongodb::{Database, IndexModel};
+use redis::Script;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tracing::{error, info};
+use uuid::Uuid;
+
+const FAILED_LOGIN_KEY_PREFIX: &str = "login:failures";
+/// Failed logins are counted over this window, which restarts with every attempt.
+const FAILED_LOGIN_WINDOW: Duration = Duration::from_secs(15 * 60);
+const MAX_FAILED_LOGINS_PER_ACCOUNT: u64 = 5;
+/// Higher than per account, so admins behind one address don't lock each other out.
+const MAX_FAILED_LOGINS_PER_IP: u64 = 20;
+
+// checks every limit and counts the attempt in one go, so parallel logins can't all slip
+// in before the first failure is counted. Returns the seconds to wait, 0 when let through
+const RESERVE_LOGIN_ATTEMPT_SCRIPT: &str = r#"
+local retry_after = 0
+for i, key in ipairs(KEYS) do
+    if tonumber(redis.call("GET", key) or "0") >= tonumber(ARGV[i]) then
+        retry_after = math.max(retry_after, redis.call("TTL", key), 1)
+    end
+end
+if retry_after > 0 then
+    return retry_after
+end
+for _, key in ipairs(KEYS) do
+    redis.call("INCR", key)
+    redis.call("EXPIRE", key, ARGV[#KEYS + 1])
+end
+return 0
+"#;
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct AdminUser {
+    #[serde(with = "hex_string_as_object_id")]
+    _id: String,
+    name: String,
+    /// Argon2 hash in the PHC string format, which carries its own salt and parameters.
+    password_hash: String,
+    role: String,
+    #[serde(with = "bson_datetime_as_rfc3339_string")]
+    created_at: DateTime,
+    #[serde(with = "bson_datetime_as_rfc3339_string")]
+    updated_at: DateTime,
+}
+
+impl AdminUser {
+    pub fn new(name: &str, password_hash: String, role: &str) -> Self {
+        AdminUser {
+            _id: ObjectId::new().to_hex(),
+            name: normalize_name(name),
+            password_hash,
+            role: role.to_string(),
+            created_at: DateTime::now(),
+            updated_at: DateTime::now(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct LoginRequest {
+    pub name: String,
+    pub password: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct LoginResponse {
+    pub access_token: String,
+    /// Single use, each refresh returns the one to use next.
+    pub refresh_token: String,
+    pub token_type: String,
+    /// Seconds until the access token expires.
+    pub expires_in: u64,
+}
+
+/// Names are matched case-insensitively, so `Admin` and `admin` are one account.
+fn normalize_name(name: &str) -> String {
+    name.trim().to_lowercase()
+}
+
+pub async fn create_admin_user_indexes(mongo: Database) -> anyhow::Result<()> {
+    let unique_name_index = IndexModel::builder()
+        .keys(doc! { "name": 1 })
+        .options(
+            IndexOptions::builder()
+                .name("admin_user_unique_name".to_string())
+                .unique(true)
+                .build(),
+        )
+        .build();
+
+    let index_names = create_indexes::<AdminUser>(mongo, vec![unique_name_index]).await?;
+    info!("AdminUser indexes ready {:?}", index_names);
+
+    Ok(())
+}
+
+/// Hashing is slow on purpose, so it runs off the async workers.
+pub async fn hash_password(password: String) -> anyhow::Result<String> {
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|err| anyhow::anyhow!("fail to hash password {}", err))
+    })
+    .await?
+}
+
+pub async fn verify_password(password: String, password_hash: String) -> bool {
+    let result = tokio::task::spawn_blocking(move || {
+        let parsed_hash = match PasswordHash::new(&password_hash) {
+            Ok(parsed_hash) => parsed_hash,
+            Err(err) => {
+                error!("stored password hash is invalid {}", err);
+                return false;
+            }
+        };
+
+        Argon2::default()
+            .verify_password(password.as_bytes(), &parsed_hash)
+            .is_ok()
+    })
+    .await;
+
+    result.unwrap_or(false)
+}
+
+/// Checked against when the account doesn't exist, so a missing account takes as long to
+/// turn down as a wrong password and names can't be probed by timing.
+async fn dummy_password_hash() -> String {
+    static DUMMY_PASSWORD_HASH: OnceLock<String> = OnceLock::new();
+
+    if let Some(password_hash) = DUMMY_PASSWORD_HASH.get() {
+        return password_hash.clone();
+    }
+
+    let password_hash = hash_password("not a password".to_string())
+        .await
+        .unwrap_or_default();
+    DUMMY_PASSWORD_HASH.get_or_init(|| password_hash).clone()
+}
+
+/// Creates the first admin from the `ADMIN_NAME` and `ADMIN_PASSWORD` secrets. Does nothing
+/// once any admin exists, so the secrets can stay set, though they're best removed after.
+pub async fn bootstrap_admin(mongo: Database, name: &str, password: &str) -> anyhow::Result<bool> {
+    if name.trim().is_empty() || password.is_empty() {
+        return Ok(false);
+    }
+
+    if count_docs::<AdminUser>(mongo.clone()).await? > 0 {
+        return Ok(false);
+    }
+
+    let password_hash = hash_password(password.to_string()).await?;
+    let admin_user = AdminUser::new(name, password_hash, ADMIN_ROLE);
+
+    match insert_one_doc::<AdminUser>(mongo, admin_user).await {
+        Ok(_) => {
+            info!("Admin {} created", normalize_name(name));
+            Ok(true)
+        }
+        // another instance bootstrapped the same admin first
+        Err(err) if is_duplicate_key_error(&err) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// The address the request came from. Behind `trusted_proxy_count` proxies that's the
+/// `X-Forwarded-For` entry the outermost one added; entries before it are up to the client.
+/// Without a trusted proxy the whole header is, so only the peer address counts.
+pub fn client_ip(
+    headers: &HeaderMap,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    trusted_proxy_count: usize,
+) -> String {
+    let forwarded_for = headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok());
+    let forwarded_for = match (trusted_proxy_count, forwarded_for) {
+        (0, _) | (_, None) => None,
+        (count, Some(value)) => value
+            .rsplit(',')
+            .map(str::trim)
+            .filter(|ip| !ip.is_empty())
+            .nth(count - 1),
+    };
+
+    match (forwarded_for, connect_info) {
+        (Some(ip), _) => ip.to_string(),
+        (None, Some(ConnectInfo(addr))) => addr.ip().to_string(),
+        (None, None) => "unknown".to_string(),
+    }
+}
+
+fn failed_login_keys(name: &str, ip: &str) -> [(String, u64); 2] {
+    [
+        (
+            format!("{}:account:{}", FAILED_LOGIN_KEY_PREFIX, name),
+            MAX_FAILED_LOGINS_PER_ACCOUNT,
+        ),
+        (
+            format!("{}:ip:{}", FAILED_LOGIN_KEY_PREFIX, ip),
+            MAX_FAILED_LOGINS_PER_IP,
+        ),
+    ]
+}
+
+/// Counts a login attempt as failed up front, before the password is checked. Returns the
+/// seconds until another login may be tried instead when either limit is reached.
+async fn reserve_login_attempt(
+    redis: &redis::Client,
+    name: &str,
+    ip: &str,
+) -> redis::RedisResult<Option<u64>> {
+    let mut con = redis.get_multiplexed_async_connection().await?;
+    let [(account_key, account_limit), (ip_key, ip_limit)] = failed_login_keys(name, ip);
+
+    let retry_after: u64 = Script::new(RESERVE_LOGIN_ATTEMPT_SCRIPT)
+        .key(account_key)
+        .key(ip_key)
+        .arg(account_limit)
+        .arg(ip_limit)
+        .arg(FAILED_LOGIN_WINDOW.as_secs())
+        .invoke_async(&mut con)
+        .await?;
+
+    Ok((retry_after > 0).then_some(retry_after))
+}
+
+/// A successful login clears the account's failures, and gives back the attempt it took
+/// from the address without clearing the address's other failures.
+async fn release_login_attempt(
+    redis: &redis::Client,
+    name: &str,
+    ip: &str,
+) -> redis::RedisResult<()> {
+    let mut con = redis.get_multiplexed_async_connection().await?;
+    let [(account_key, _), (ip_key, _)] = failed_login_keys(name, ip);
+
+    redis::pipe()
+        .atomic()
+        .del(account_key)
+        .ignore()
+        .decr(ip_key, 1)
+        .ignore()
+        .query_async(&mut con)
+        .await
+}
+
+fn invalid_credentials() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        "Invalid name or password!".to_string(),
+    )
+        .into_response()
+}
+
+pub async fn login(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    Json(req): Json<LoginRequest>,
+) -> Result<Json<LoginResponse>, impl IntoResponse> {
+    let name = normalize_name(&req.name);
+    let ip = client_ip(&headers, connect_info, state.trusted_proxy_count);
+
+    match reserve_login_attempt(&state.redis, &name, &ip).await {
+        Ok(None) => {}
+        Ok(Some(retry_after)) => {
+            info!("login of {} from {} is rate limited", name, ip);
+            return Err((
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, retry_after.to_string())],
+                "Too many failed logins, try again later!".to_string(),
+            )
+                .into_response());
+        }
+        Err(err) => {
+            let error_message = format!("fail to check failed logins {}", err);
+            error!(error_message);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response());
+        }
+    }
+
+    let admin_user =
+        match find_one_doc::<AdminUser>(state.mongo.clone(), doc! { "name": &name }).await {
+            Ok(admin_user) => admin_user,
+            Err(err) => {
+                let error_message = err.to_string();
+                error!("{}", error_message.clone());
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response());
+            }
+        };
+
+    let password_hash = match &admin_user {
+        Some(admin_user) => admin_user.password_hash.clone(),
+        None => dummy_password_hash().await,
+    };
+    let is_password_valid = verify_password(req.password, password_hash).await;
+
+    let admin_user = match admin_user {
+        Some(admin_user) if is_password_valid => admin_user,
+        _ => {
+            // the reserved attempt stays counted as the failure
+            info!("failed login of {} from {}", name, ip);
+            return Err(invalid_credentials());
+        }
+    };
+
+    if let Err(err) = release_login_attempt(&state.redis, &name, &ip).await {
+        error!("fail to clear failed logins {}", err);
+    }
+
+    let grant = RefreshTokenGrant {
+        family_id: Uuid::new_v4().to_string(),
+        name: admin_user.name,
+        role: admin_user.role,
+    };
+    let login_response = issue_tokens(&state, &grant, false).await?;
+    info!("{} logged in", grant.name);
+
+    Ok(Json(login_response))
+}
+
+/// Signs an access token and stores the refresh token that goes with it. A rotation stays in
+/// the family of the spent token, and fails once the family is revoked.
+async fn issue_tokens(
+    state: &AppState,
+    grant: &RefreshTokenGrant,
+    is_rotation: bool,
+) -> Result<LoginResponse, Response> {
+    let (access_token, claims) =
+        match issue_access_token(&state.jwt_key, &grant.name, &grant.role, &grant.family_id) {
+            Ok(result) => result,
+            Err(err) => {
+                let error_message = format!("fail to issue access token {}", err);
+                error!(error_message);
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response());
+            }
+        };
+
+    let refresh_token_result = if is_rotation {
+        rotate_refresh_token(&state.redis, grant, &claims.jti).await
+    } else {
+        issue_refresh_token(&state.redis, grant, &claims.jti)
+            .await
+            .map(Some)
+    };
+
+    match refresh_token_result {
+        Ok(Some(refresh_token)) => Ok(LoginResponse {
+            access_token,
+            refresh_token,
+            token_type: "Bearer".to_string(),
+            expires_in: ACCESS_TOKEN_TTL.as_secs(),
+        }),
+        Ok(None) => Err(invalid_refresh_token()),
+        Err(err) => {
+            let error_message = format!("fail to issue refresh token {}", err);
+            error!(error_message);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response())
+        }
+    }
+}
+
+fn invalid_refresh_token() -> Response {
+    (
+        StatusCode::UNAUTHORIZED,
+        "Invalid refresh token!".to_string(),
+    )
+        .into_response()
+}
+
+/// Trades a refresh token for a new access token and the refresh token to use next time.
+pub async fn refresh(
+    State(state): State<AppState>,
+    Json(req): Json<RefreshRequest>,
+) -> Result<Json<LoginResponse>, impl IntoResponse> {
+    let mut grant = match use_refresh_token(&state.redis, &req.refresh_token).await {
+        Ok(RefreshOutcome::Rotated(grant)) => grant,
+        Ok(RefreshOutcome::Invalid) | Ok(RefreshOutcome::Reused) => {
+            return Err(invalid_refresh_token());
+        }
+        Err(err) => {
+            let error_message = format!("fail to use refresh token {}", err);
+            error!(error_message);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response());
+        }
+    };
+
+    // the account may have been removed, or its role changed, since it logged in
+    match find_one_doc::<AdminUser>(state.mongo.clone(), doc! { "name": &grant.name }).await {
+        Ok(Some(admin_user)) => grant.role = admin_user.role,
+        Ok(None) => {
+            if let Err(err) = revoke_token_family(&state.redis, &grant.family_id).await {
+                error!("fail to revoke token family {}", err);
+            }
+            return Err(invalid_refresh_token());
+        }
+        Err(err) => {
+            let error_message = err.to_string();
+            error!("{}", error_message.clone());
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response());
+        }
+    }
+
+    issue_tokens(&state, &grant, true).await.map(Json)
+}
+
+/// Revokes the caller's access token and the refresh token family it was issued from.
+pub async fn logout(
+    State(state): State<AppState>,
+    claims: Option<Extension<TokenClaims>>,
+) -> Result<StatusCode, impl IntoResponse> {
+    // an API key has no session to end, it's revoked instead
+    let claims = match claims {
+        Some(Extension(claims)) => claims,
+        None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Only a login session can be logged out!".to_string(),
+            )
+                .into_response());
+        }
+    };
+
+    let result = match deny_access_tokens(&state.redis, &[claims.jti]).await {
+        Ok(()) => revoke_token_family(&state.redis, &claims.family_id).await,
+        Err(err) => Err(err),
+    };
+
+    match result {
+        Ok(()) => {
+            info!("{} logged out", claims.name);
+            Ok(StatusCode::OK)
+        }
+        Err(err) => {
+            let error_message = format!("fail to log out {}", err);
+            error!(error_message);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[tokio::test]
+    async fn test_hash_and_verify_password() {
+        let password_hash = hash_password("hunter2".to_string()).await.unwrap();
+
+        assert!(password_hash.starts_with("$argon2id$"));
+        assert!(verify_password("hunter2".to_string(), password_hash.clone()).await);
+        assert!(!verify_password("hunter3".to_string(), password_hash).await);
+        assert!(!verify_password("hunter2".to_string(), "not a hash".to_string()).await);
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let addr: SocketAddr = "10.0.0.1:4321".parse().unwrap();
+
+        assert_eq!(client_ip(&HeaderMap::new(), None, 0), "unknown");
+        assert_eq!(
+            client_ip(&HeaderMap::new(), Some(ConnectInfo(addr)), 1),
+            "10.0.0.1"
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("1.1.1.1, 203.0.113.7, 10.0.0.2"),
+        );
+        // without a proxy in front the header is the client's own
+        assert_eq!(client_ip(&headers, Some(ConnectInfo(addr)), 0), "10.0.0.1");
+        assert_eq!(client_ip(&headers, Some(ConnectInfo(addr)), 1), "10.0.0.2");
+        assert_eq!(
+            client_ip(&headers, Some(ConnectInfo(addr)), 2),
+            "203.0.113.7"
+        );
+        assert_eq!(client_ip(&headers, Some(ConnectInfo(addr)), 4), "10.0.0.1");
+    }
+
+    #[test]
+    fn test_failed_login_keys() {
+        assert_eq!(
+            failed_login_keys("admin", "203.0.113.7"),
+            [
+                (
+                    "login:failures:account:admin".to_string(),
+                    MAX_FAILED_LOGINS_PER_ACCOUNT
+                ),
+                (
+                    "login:failures:ip:203.0.113.7".to_string(),
+                    MAX_FAILED_LOGINS_PER_IP
+                ),
+            ]
+        );
+        assert_eq!(normalize_name("  Admin "), "admin");
+    }
+}
@@ -0,0 +1,59 @@
+use crate::patreon_api::RetryPolicy;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::error;
+
+/// A long running task told to stop through a watch channel. Dropping it stops
+/// the task too, so it never outlives the server.
+pub struct BackgroundTask {
+    name: &'static str,
+    shutdown: watch::Sender<bool>,
+    task: Option<JoinHandle<()>>,
+}
+
+impl BackgroundTask {
+    pub fn spawn<F, Fut>(name: &'static str, run: F) -> BackgroundTask
+    where
+        F: FnOnce(watch::Receiver<bool>) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (shutdown, shutdown_receiver) = watch::channel(false);
+
+        let task = tokio::spawn(run(shutdown_receiver));
+
+        BackgroundTask {
+            name,
+            shutdown,
+            task: Some(task),
+        }
+    }
+
+    /// Tells the task to stop and waits for it to wind down.
+    pub async fn shutdown(mut self) {
+        let _ = self.shutdown.send(true);
+
+        if let Some(task) = self.task.take() {
+            if let Err(err) = task.await {
+                error!("{} stopped with error {}", self.name, err);
+            }
+        }
+    }
+}
+
+impl Drop for BackgroundTask {
+    fn drop(&mut self) {
+        let _ = self.shutdown.send(true);
+    }
+}
+
+/// How long a task waits before reconnecting to redis.
+pub fn reconnect_policy() -> RetryPolicy {
+    RetryPolicy {
+        // background tasks never give up, only the delay is capped
+        max_retries: u32::MAX,
+        base_delay: Duration::from_millis(500),
+        max_delay: Duration::from_secs(30),
+    }
+}
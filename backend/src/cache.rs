@@ -0,0 +1,393 @@
+use crate::redis_pubsub::message::{CacheScope, Message, MessageKind};
+use crate::redis_pubsub::pubsub::{notify, MessageHandler};
+use crate::AppState;
+use async_trait::async_trait;
+use axum::extract::State;
+use axum::response::Response;
+use axum::Json;
+use redis::{AsyncCommands, Commands, RedisResult};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, error};
+
+const CACHE_KEY_PREFIX: &str = "cache";
+const CACHE_TTL: Duration = Duration::from_secs(5 * 60);
+/// How long an instance trusts its copy of a generation, in case it missed the message
+/// telling it to drop the copy.
+const LOCAL_GENERATION_TTL: Duration = Duration::from_secs(10);
+
+impl CacheScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheScope::Posts => "posts",
+            CacheScope::Tags => "tags",
+        }
+    }
+}
+
+fn generation_key(scope: CacheScope) -> String {
+    format!("{}:{}:generation", CACHE_KEY_PREFIX, scope.as_str())
+}
+
+/// Entries of an older generation are never read again and expire on their own.
+fn cache_key(scope: CacheScope, generation: u64, route: &str, key: &str) -> String {
+    format!(
+        "{}:{}:{}:{}:{}",
+        CACHE_KEY_PREFIX,
+        scope.as_str(),
+        generation,
+        route,
+        key
+    )
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct CacheStatsResponse {
+    pub hit_count: u64,
+    pub miss_count: u64,
+    /// Lookups that went straight to the database because redis failed.
+    pub error_count: u64,
+    pub hit_rate: f64,
+}
+
+/// Read-through cache of responses in redis, shared by every instance.
+#[derive(Debug, Default)]
+pub struct ResponseCache {
+    hit_count: AtomicU64,
+    miss_count: AtomicU64,
+    error_count: AtomicU64,
+    generations: Mutex<HashMap<CacheScope, (u64, Instant)>>,
+}
+
+impl ResponseCache {
+    pub fn snapshot(&self) -> CacheStatsResponse {
+        let hit_count = self.hit_count.load(Ordering::Relaxed);
+        let miss_count = self.miss_count.load(Ordering::Relaxed);
+        let lookup_count = hit_count + miss_count;
+
+        CacheStatsResponse {
+            hit_count,
+            miss_count,
+            error_count: self.error_count.load(Ordering::Relaxed),
+            hit_rate: if lookup_count == 0 {
+                0.0
+            } else {
+                hit_count as f64 / lookup_count as f64
+            },
+        }
+    }
+
+    /// Forgets this instance's copy of the generation, so the next lookup reads it from redis.
+    pub fn drop_local(&self, scope: CacheScope) {
+        self.generations.lock().unwrap().remove(&scope);
+    }
+
+    fn local_generation(&self, scope: CacheScope) -> Option<u64> {
+        match self.generations.lock().unwrap().get(&scope) {
+            Some((generation, read_at)) if read_at.elapsed() < LOCAL_GENERATION_TTL => {
+                Some(*generation)
+            }
+            _ => None,
+        }
+    }
+
+    async fn generation(&self, redis: &redis::Client, scope: CacheScope) -> RedisResult<u64> {
+        if let Some(generation) = self.local_generation(scope) {
+            return Ok(generation);
+        }
+
+        let mut con = redis.get_multiplexed_async_connection().await?;
+        let generation: Option<u64> = con.get(generation_key(scope)).await?;
+        let generation = generation.unwrap_or_default();
+
+        self.generations
+            .lock()
+            .unwrap()
+            .insert(scope, (generation, Instant::now()));
+
+        Ok(generation)
+    }
+}
+
+/// Returns the cached response for `route` and `key`, or loads and caches it.
+/// Errors are never cached, and a failing redis only costs the database a lookup.
+pub async fn cached<T, F, Fut>(
+    state: &AppState,
+    scope: CacheScope,
+    route: &str,
+    key: &str,
+    load: F,
+) -> Result<T, Response>
+where
+    T: Serialize + DeserializeOwned,
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<T, Response>>,
+{
+    let cache = &state.cache;
+
+    let generation = match cache.generation(&state.redis, scope).await {
+        Ok(generation) => generation,
+        Err(err) => {
+            cache.error_count.fetch_add(1, Ordering::Relaxed);
+            error!("fail to read cache generation {}", err);
+            return load().await;
+        }
+    };
+    let cache_key = cache_key(scope, generation, route, key);
+
+    match get_cached(&state.redis, &cache_key).await {
+        Ok(Some(value)) => {
+            cache.hit_count.fetch_add(1, Ordering::Relaxed);
+            debug!("cache hit {}", cache_key);
+            return Ok(value);
+        }
+        Ok(None) => {
+            cache.miss_count.fetch_add(1, Ordering::Relaxed);
+        }
+        Err(err) => {
+            cache.error_count.fetch_add(1, Ordering::Relaxed);
+            error!("fail to read cache {} {}", cache_key, err);
+            return load().await;
+        }
+    }
+
+    let value = load().await?;
+
+    if let Err(err) = set_cached(&state.redis, &cache_key, &value).await {
+        error!("fail to write cache {} {}", cache_key, err);
+    }
+
+    Ok(value)
+}
+
+async fn get_cached<T: DeserializeOwned>(
+    redis: &redis::Client,
+    cache_key: &str,
+) -> anyhow::Result<Option<T>> {
+    let mut con = redis.get_multiplexed_async_connection().await?;
+
+    let json: Option<String> = con.get(cache_key).await?;
+
+    match json {
+        Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+        None => Ok(None),
+    }
+}
+
+async fn set_cached<T: Serialize>(
+    redis: &redis::Client,
+    cache_key: &str,
+    value: &T,
+) -> anyhow::Result<()> {
+    let mut con = redis.get_multiplexed_async_connection().await?;
+
+    let json = serde_json::to_string(value)?;
+    let _: () = con.set_ex(cache_key, json, CACHE_TTL.as_secs()).await?;
+
+    Ok(())
+}
+
+/// Moves `scope` to a new generation, so no instance reads what was cached before.
+pub fn expire_cache(state: &AppState, scope: CacheScope) {
+    let result: RedisResult<u64> = state
+        .redis
+        .get_connection()
+        .and_then(|mut con| con.incr(generation_key(scope), 1));
+
+    if let Err(err) = result {
+        error!("fail to expire {} cache {}", scope.as_str(), err);
+    }
+    state.cache.drop_local(scope);
+}
+
+/// Expires `scope` and tells every other instance to drop its copy of the generation.
+pub fn invalidate_cache(state: &AppState, scope: CacheScope) {
+    expire_cache(state, scope);
+    notify(state.redis.clone(), MessageKind::CacheInvalidate { scope });
+}
+
+pub struct CacheInvalidateHandler;
+
+#[async_trait]
+impl MessageHandler for CacheInvalidateHandler {
+    async fn handle(&self, state: AppState, message: Message) {
+        match message.kind {
+            MessageKind::CacheInvalidate { scope } => state.cache.drop_local(scope),
+            MessageKind::PostChanged { .. } => state.cache.drop_local(CacheScope::Posts),
+            _ => {}
+        }
+    }
+}
+
+pub async fn get_cache_stats(State(state): State<AppState>) -> Json<CacheStatsResponse> {
+    Json(state.cache.snapshot())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_util::{
+        create_test_state, get_redis_connection_uri, get_redis_image,
+    };
+    use axum::http::StatusCode;
+    use axum::response::IntoResponse;
+    use mongodb::Client;
+    use std::sync::atomic::AtomicUsize;
+    use testcontainers_modules::redis::REDIS_PORT;
+    use testcontainers_modules::testcontainers::clients;
+
+    async fn create_state(redis_uri: &str) -> AppState {
+        let client = Client::with_uri_str("mongodb://127.0.0.1:1").await.unwrap();
+        let redis_client = redis::Client::open(redis_uri).unwrap();
+
+        create_test_state(client.database("test_db"), redis_client)
+    }
+
+    /// Loads `value`, counting how often the cache had to.
+    async fn load_counted(
+        state: &AppState,
+        load_count: &AtomicUsize,
+        value: &str,
+    ) -> Result<String, Response> {
+        cached(state, CacheScope::Posts, "test", "key", || async {
+            load_count.fetch_add(1, Ordering::Relaxed);
+            Ok(value.to_string())
+        })
+        .await
+    }
+
+    #[test]
+    fn test_cache_key() {
+        assert_eq!(generation_key(CacheScope::Posts), "cache:posts:generation");
+        assert_eq!(
+            cache_key(CacheScope::Posts, 3, "list", r#"{"limit":1}"#),
+            r#"cache:posts:3:list:{"limit":1}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cached_without_redis() {
+        let state = create_state("redis://127.0.0.1:1").await;
+        let load_count = AtomicUsize::new(0);
+
+        let value = load_counted(&state, &load_count, "asdf")
+            .await
+            .ok()
+            .unwrap();
+
+        assert_eq!(value, "asdf");
+        assert_eq!(load_count.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            state.cache.snapshot(),
+            CacheStatsResponse {
+                hit_count: 0,
+                miss_count: 0,
+                error_count: 1,
+                hit_rate: 0.0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cache_invalidate_handler() {
+        let state = create_state("redis://127.0.0.1:1").await;
+        state
+            .cache
+            .generations
+            .lock()
+            .unwrap()
+            .insert(CacheScope::Posts, (1, Instant::now()));
+        assert_eq!(state.cache.local_generation(CacheScope::Posts), Some(1));
+
+        CacheInvalidateHandler
+            .handle(
+                state.clone(),
+                Message::new(MessageKind::CacheInvalidate {
+                    scope: CacheScope::Tags,
+                }),
+            )
+            .await;
+        assert_eq!(state.cache.local_generation(CacheScope::Posts), Some(1));
+
+        CacheInvalidateHandler
+            .handle(
+                state.clone(),
+                Message::new(MessageKind::CacheInvalidate {
+                    scope: CacheScope::Posts,
+                }),
+            )
+            .await;
+        assert_eq!(state.cache.local_generation(CacheScope::Posts), None);
+    }
+
+    #[tokio::test]
+    async fn test_cached_reads_through() {
+        let docker = clients::Cli::default();
+        let redis_img = get_redis_image();
+        let redis_node = docker.run(redis_img);
+
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let state = create_state(&redis_uri).await;
+        let load_count = AtomicUsize::new(0);
+
+        assert_eq!(
+            load_counted(&state, &load_count, "asdf")
+                .await
+                .ok()
+                .unwrap(),
+            "asdf"
+        );
+        assert_eq!(
+            load_counted(&state, &load_count, "qwer")
+                .await
+                .ok()
+                .unwrap(),
+            "asdf"
+        );
+        assert_eq!(load_count.load(Ordering::Relaxed), 1);
+
+        // another instance changed the posts
+        let other_state = create_state(&redis_uri).await;
+        expire_cache(&other_state, CacheScope::Posts);
+        state.cache.drop_local(CacheScope::Posts);
+
+        assert_eq!(
+            load_counted(&state, &load_count, "qwer")
+                .await
+                .ok()
+                .unwrap(),
+            "qwer"
+        );
+        assert_eq!(load_count.load(Ordering::Relaxed), 2);
+
+        let stats = state.cache.snapshot();
+        assert_eq!(stats.hit_count, 1);
+        assert_eq!(stats.miss_count, 2);
+    }
+
+    #[tokio::test]
+    async fn test_cached_skips_errors() {
+        let docker = clients::Cli::default();
+        let redis_img = get_redis_image();
+        let redis_node = docker.run(redis_img);
+
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let state = create_state(&redis_uri).await;
+
+        for _ in 0..2 {
+            let result: Result<String, Response> =
+                cached(&state, CacheScope::Posts, "test", "missing", || async {
+                    Err((StatusCode::NOT_FOUND, "not found".to_string()).into_response())
+                })
+                .await;
+
+            assert_eq!(result.err().unwrap().status(), StatusCode::NOT_FOUND);
+        }
+        assert_eq!(state.cache.snapshot().miss_count, 2);
+    }
+}
@@ -0,0 +1,236 @@
+use axum::http::header::{
+    CACHE_CONTROL, ETAG, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED,
+};
+use axum::http::{HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use chrono::{DateTime, Utc};
+use md5::{Digest, Md5};
+use serde::Serialize;
+
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// What a resource's validators are derived from, one per document in the response.
+pub struct ResourceVersion<'a> {
+    pub id: &'a str,
+    pub updated_at: mongodb::bson::DateTime,
+    pub synced_at: mongodb::bson::DateTime,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Validators {
+    etag: String,
+    last_modified: Option<DateTime<Utc>>,
+}
+
+impl Validators {
+    /// `extra` covers whatever else shapes the response, e.g. the pagination of a list.
+    pub fn new<'a>(versions: impl IntoIterator<Item = ResourceVersion<'a>>, extra: &str) -> Self {
+        let mut hasher = Md5::new();
+        let mut last_modified: Option<DateTime<Utc>> = None;
+
+        for version in versions {
+            hasher.update(format!(
+                "{}:{}:{}\n",
+                version.id,
+                version.updated_at.timestamp_millis(),
+                version.synced_at.timestamp_millis()
+            ));
+
+            let modified_at = version.updated_at.max(version.synced_at).to_chrono();
+            last_modified = last_modified.max(Some(modified_at));
+        }
+        hasher.update(extra);
+
+        Validators {
+            etag: format!("\"{}\"", hex::encode(hasher.finalize())),
+            last_modified,
+        }
+    }
+
+    pub fn last_modified(&self) -> Option<String> {
+        self.last_modified
+            .map(|last_modified| last_modified.format(HTTP_DATE_FORMAT).to_string())
+    }
+
+    /// Whether the client's cached copy is still current. `If-None-Match` wins over
+    /// `If-Modified-Since` when both are sent.
+    pub fn is_not_modified(&self, headers: &HeaderMap) -> bool {
+        if let Some(if_none_match) = header_str(headers, IF_NONE_MATCH) {
+            // weak comparison, a `W/` prefix doesn't matter here
+            return if_none_match
+                .split(',')
+                .map(str::trim)
+                .any(|etag| etag == "*" || etag.trim_start_matches("W/") == self.etag);
+        }
+
+        let if_modified_since = header_str(headers, IF_MODIFIED_SINCE)
+            .and_then(|since| DateTime::parse_from_rfc2822(since).ok());
+
+        match (if_modified_since, self.last_modified) {
+            // the header only has whole seconds
+            (Some(since), Some(last_modified)) => last_modified.timestamp() <= since.timestamp(),
+            _ => false,
+        }
+    }
+
+    /// Whether a write may go ahead. Without `If-Match` it always may.
+    pub fn is_precondition_met(&self, headers: &HeaderMap) -> bool {
+        match header_str(headers, IF_MATCH) {
+            // strong comparison, a weak tag never matches
+            Some(if_match) => if_match
+                .split(',')
+                .map(str::trim)
+                .any(|etag| etag == "*" || etag == self.etag),
+            None => true,
+        }
+    }
+
+    fn apply(&self, response: &mut Response) {
+        let headers = response.headers_mut();
+
+        if let Ok(etag) = HeaderValue::from_str(&self.etag) {
+            headers.insert(ETAG, etag);
+        }
+        if let Some(Ok(last_modified)) = self.last_modified().map(|s| HeaderValue::from_str(&s)) {
+            headers.insert(LAST_MODIFIED, last_modified);
+        }
+        // cached copies are fine as long as they're revalidated first
+        headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: axum::http::HeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// A JSON response that turns into an empty 304 when the client already has it.
+#[derive(Debug)]
+pub struct ConditionalJson<T> {
+    pub body: T,
+    pub validators: Validators,
+    pub is_not_modified: bool,
+}
+
+impl<T> ConditionalJson<T> {
+    pub fn new(headers: &HeaderMap, body: T, validators: Validators) -> Self {
+        ConditionalJson {
+            body,
+            is_not_modified: validators.is_not_modified(headers),
+            validators,
+        }
+    }
+}
+
+impl<T: Serialize> IntoResponse for ConditionalJson<T> {
+    fn into_response(self) -> Response {
+        let mut response = if self.is_not_modified {
+            StatusCode::NOT_MODIFIED.into_response()
+        } else {
+            Json(self.body).into_response()
+        };
+        self.validators.apply(&mut response);
+
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validators() -> Validators {
+        Validators::new(
+            [ResourceVersion {
+                id: "659e79f831f22dc0395699b2",
+                updated_at: mongodb::bson::DateTime::parse_rfc3339_str("2024-01-23T13:48:06.761Z")
+                    .unwrap(),
+                synced_at: mongodb::bson::DateTime::parse_rfc3339_str("2024-01-20T00:00:00Z")
+                    .unwrap(),
+            }],
+            "",
+        )
+    }
+
+    fn headers(name: axum::http::HeaderName, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_validators() {
+        let validators = validators();
+
+        assert_eq!(validators.etag.len(), 34);
+        assert!(validators.etag.starts_with('"'));
+        assert_eq!(
+            validators.last_modified(),
+            Some("Tue, 23 Jan 2024 13:48:06 GMT".to_string())
+        );
+
+        // any change to a version or the pagination changes the etag
+        assert_ne!(Validators::new([], "").etag, validators.etag);
+        assert_ne!(Validators::new([], "2:").etag, Validators::new([], "").etag);
+        assert_eq!(Validators::new([], "").last_modified(), None);
+    }
+
+    #[test]
+    fn test_is_not_modified() {
+        let validators = validators();
+        let etag = validators.etag.clone();
+
+        assert!(!validators.is_not_modified(&HeaderMap::new()));
+        assert!(validators.is_not_modified(&headers(IF_NONE_MATCH, &etag)));
+        assert!(
+            validators.is_not_modified(&headers(IF_NONE_MATCH, &format!("\"asdf\", W/{}", etag)))
+        );
+        assert!(!validators.is_not_modified(&headers(IF_NONE_MATCH, "\"asdf\"")));
+
+        assert!(validators
+            .is_not_modified(&headers(IF_MODIFIED_SINCE, "Tue, 23 Jan 2024 13:48:06 GMT")));
+        assert!(!validators
+            .is_not_modified(&headers(IF_MODIFIED_SINCE, "Tue, 23 Jan 2024 13:48:05 GMT")));
+        assert!(!validators.is_not_modified(&headers(IF_MODIFIED_SINCE, "yesterday")));
+
+        // a changed etag wins over an unchanged date
+        let mut both = headers(IF_NONE_MATCH, "\"asdf\"");
+        both.insert(
+            IF_MODIFIED_SINCE,
+            HeaderValue::from_static("Tue, 23 Jan 2024 13:48:06 GMT"),
+        );
+        assert!(!validators.is_not_modified(&both));
+    }
+
+    #[test]
+    fn test_is_precondition_met() {
+        let validators = validators();
+        let etag = validators.etag.clone();
+
+        assert!(validators.is_precondition_met(&HeaderMap::new()));
+        assert!(validators.is_precondition_met(&headers(IF_MATCH, &etag)));
+        assert!(validators.is_precondition_met(&headers(IF_MATCH, "*")));
+        assert!(!validators.is_precondition_met(&headers(IF_MATCH, "\"asdf\"")));
+        assert!(!validators.is_precondition_met(&headers(IF_MATCH, &format!("W/{}", etag))));
+    }
+
+    #[test]
+    fn test_conditional_json_response() {
+        let validators = validators();
+        let etag = validators.etag.clone();
+
+        let response =
+            ConditionalJson::new(&HeaderMap::new(), "asdf", validators.clone()).into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(ETAG).unwrap(), etag.as_str());
+        assert_eq!(
+            response.headers().get(LAST_MODIFIED).unwrap(),
+            "Tue, 23 Jan 2024 13:48:06 GMT"
+        );
+
+        let response = ConditionalJson::new(&headers(IF_NONE_MATCH, &etag), "asdf", validators)
+            .into_response();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers().get(ETAG).unwrap(), etag.as_str());
+    }
+}
@@ -1,13 +1,149 @@
-use anyhow::{Error, Result};
+use anyhow::{anyhow, Error, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
 use futures::TryStreamExt;
-use mongodb::bson::{doc, Bson, Document};
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, to_document, Bson, Document};
+use mongodb::error::{ErrorKind, WriteFailure};
 use mongodb::options::FindOneAndUpdateOptions;
+use mongodb::options::FindOneOptions;
+use mongodb::options::FindOptions;
 use mongodb::options::ReturnDocument::After;
+use mongodb::options::UpdateModifications;
 use mongodb::Database;
-use serde::Serialize;
+use mongodb::IndexModel;
+use serde::{Deserialize, Serialize};
 use std::any::type_name;
 use tracing::error;
 
+pub const DEFAULT_PAGE_SIZE: i64 = 20;
+pub const MAX_PAGE_SIZE: i64 = 100;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// Position of the last document of a page: its sort key and its `_id` as a tie-breaker.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageCursor {
+    pub value: Bson,
+    pub id: ObjectId,
+}
+
+impl PageCursor {
+    pub fn encode(&self) -> String {
+        let cursor_doc = doc! {
+            "v": self.value.clone(),
+            "id": self.id,
+        };
+        let mut bytes = vec![];
+        // writing a document into a Vec cannot fail
+        cursor_doc.to_writer(&mut bytes).unwrap_or_default();
+
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self> {
+        let bytes = URL_SAFE_NO_PAD.decode(cursor)?;
+        let cursor_doc = Document::from_reader(bytes.as_slice())?;
+
+        let value = cursor_doc
+            .get("v")
+            .cloned()
+            .ok_or_else(|| anyhow!("cursor has no sort value"))?;
+        let id = cursor_doc.get_object_id("id")?;
+
+        Ok(PageCursor { value, id })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PageOptions {
+    pub sort_field: String,
+    pub order: SortOrder,
+    pub limit: i64,
+    pub cursor: Option<PageCursor>,
+}
+
+impl PageOptions {
+    pub fn new(
+        sort_field: &str,
+        order: SortOrder,
+        limit: Option<i64>,
+        cursor: Option<PageCursor>,
+    ) -> Self {
+        PageOptions {
+            sort_field: sort_field.to_string(),
+            order,
+            limit: limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE),
+            cursor,
+        }
+    }
+
+    fn direction(&self) -> i32 {
+        match self.order {
+            SortOrder::Asc => 1,
+            SortOrder::Desc => -1,
+        }
+    }
+
+    fn sort(&self) -> Document {
+        let mut sort = Document::new();
+        sort.insert(self.sort_field.clone(), self.direction());
+        sort.insert("_id", self.direction());
+        sort
+    }
+
+    /// Restricts `filter` to the documents that come after the cursor in sort order.
+    fn apply_cursor(&self, filter: Document) -> Document {
+        let cursor = match &self.cursor {
+            Some(cursor) => cursor,
+            None => return filter,
+        };
+
+        let operator = match self.order {
+            SortOrder::Asc => "$gt",
+            SortOrder::Desc => "$lt",
+        };
+
+        let mut after_value = Document::new();
+        after_value.insert(
+            self.sort_field.clone(),
+            doc! { operator: cursor.value.clone() },
+        );
+        let mut same_value = Document::new();
+        same_value.insert(self.sort_field.clone(), cursor.value.clone());
+        same_value.insert("_id", doc! { operator: cursor.id });
+
+        doc! {
+            "$and": [
+                filter,
+                doc! { "$or": [after_value, same_value] }
+            ]
+        }
+    }
+
+    fn cursor_of(&self, last_doc: &Document) -> Result<PageCursor> {
+        Ok(PageCursor {
+            value: last_doc
+                .get(&self.sort_field)
+                .cloned()
+                .unwrap_or(Bson::Null),
+            id: last_doc.get_object_id("_id")?,
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+    pub total_count: u64,
+}
+
 pub fn get_collection_name<T>() -> String {
     let type_name = type_name::<T>();
     let splitted_type_name: Vec<_> = type_name.split("::").collect();
@@ -19,7 +155,7 @@ pub async fn get_all_docs<T>(mongo: Database) -> Result<Vec<T>>
 where
     T: serde::de::DeserializeOwned,
 {
-    let typed_collection = mongo.collection::<T>(&*get_collection_name::<T>());
+    let typed_collection = mongo.collection::<T>(&get_collection_name::<T>());
 
     match typed_collection.find(None, None).await {
         Ok(cursor) => {
@@ -34,11 +170,121 @@ where
     }
 }
 
+pub async fn find_docs_paginated<T>(
+    mongo: Database,
+    filter: Document,
+    options: PageOptions,
+) -> Result<Page<T>>
+where
+    T: serde::de::DeserializeOwned + Serialize + Unpin + Send + Sync,
+{
+    let typed_collection = mongo.collection::<T>(&get_collection_name::<T>());
+
+    let total_count = typed_collection
+        .count_documents(filter.clone(), None)
+        .await?;
+
+    // fetch one extra document to know whether another page exists
+    let find_options = FindOptions::builder()
+        .sort(options.sort())
+        .limit(options.limit + 1)
+        .build();
+    let cursor = typed_collection
+        .find(options.apply_cursor(filter), find_options)
+        .await?;
+    let mut items: Vec<T> = cursor.try_collect().await?;
+
+    let mut next_cursor = None;
+    if items.len() as i64 > options.limit {
+        items.truncate(options.limit as usize);
+        if let Some(last_item) = items.last() {
+            let last_doc = to_document(last_item)?;
+            next_cursor = Some(options.cursor_of(&last_doc)?.encode());
+        }
+    }
+
+    Ok(Page {
+        items,
+        next_cursor,
+        total_count,
+    })
+}
+
+/// Same paging contract as `find_docs_paginated`, but runs `stages` (e.g. computed fields)
+/// between the initial `$match` and the sort, so the sort key may be a computed field.
+pub async fn aggregate_docs_paginated<T>(
+    mongo: Database,
+    filter: Document,
+    stages: Vec<Document>,
+    options: PageOptions,
+) -> Result<Page<Document>> {
+    let typed_collection = mongo.collection::<T>(&get_collection_name::<T>());
+
+    let total_count = typed_collection
+        .count_documents(filter.clone(), None)
+        .await?;
+
+    let mut pipeline = vec![doc! { "$match": filter }];
+    pipeline.extend(stages);
+    if options.cursor.is_some() {
+        pipeline.push(doc! { "$match": options.apply_cursor(doc! {}) });
+    }
+    pipeline.push(doc! { "$sort": options.sort() });
+    pipeline.push(doc! { "$limit": options.limit + 1 });
+
+    let cursor = typed_collection.aggregate(pipeline, None).await?;
+    let mut items: Vec<Document> = cursor.try_collect().await?;
+
+    let mut next_cursor = None;
+    if items.len() as i64 > options.limit {
+        items.truncate(options.limit as usize);
+        if let Some(last_doc) = items.last() {
+            next_cursor = Some(options.cursor_of(last_doc)?.encode());
+        }
+    }
+
+    Ok(Page {
+        items,
+        next_cursor,
+        total_count,
+    })
+}
+
+pub async fn create_indexes<T>(mongo: Database, indexes: Vec<IndexModel>) -> Result<Vec<String>> {
+    let typed_collection = mongo.collection::<T>(&get_collection_name::<T>());
+
+    match typed_collection.create_indexes(indexes, None).await {
+        Ok(result) => Ok(result.index_names),
+        Err(err) => Err(Error::from(err)),
+    }
+}
+
+pub async fn find_docs<T>(mongo: Database, filter: Document) -> Result<Vec<T>>
+where
+    T: serde::de::DeserializeOwned + Unpin + Send + Sync,
+{
+    let typed_collection = mongo.collection::<T>(&get_collection_name::<T>());
+
+    match typed_collection.find(filter, None).await {
+        Ok(cursor) => Ok(cursor.try_collect().await?),
+        Err(err) => Err(Error::from(err)),
+    }
+}
+
+pub async fn aggregate_docs<T>(mongo: Database, pipeline: Vec<Document>) -> Result<Vec<Document>> {
+    let typed_collection = mongo.collection::<T>(&get_collection_name::<T>());
+
+    match typed_collection.aggregate(pipeline, None).await {
+        Ok(cursor) => Ok(cursor.try_collect().await?),
+        Err(err) => Err(Error::from(err)),
+    }
+}
+
 pub async fn insert_one_doc<T>(mongo: Database, new_doc: T) -> Result<Bson>
 where
     T: Serialize,
 {
-    let typed_collection = mongo.collection::<T>(&*get_collection_name::<T>());
+    let typed_collection = mongo.collection::<T>(&get_collection_name::<T>());
 
     match typed_collection.insert_one(new_doc, None).await {
         Ok(result) => Ok(result.inserted_id),
@@ -50,7 +296,7 @@ pub async fn find_one_doc<T>(mongo: Database, filter: Document) -> Result<Option
 where
     T: serde::de::DeserializeOwned + Unpin + Send + Sync,
 {
-    let typed_collection = mongo.collection::<T>(&*get_collection_name::<T>());
+    let typed_collection = mongo.collection::<T>(&get_collection_name::<T>());
 
     match typed_collection.find_one(filter, None).await {
         Ok(result) => Ok(result),
@@ -58,15 +304,32 @@ where
     }
 }
 
+pub async fn find_one_doc_sorted<T>(
+    mongo: Database,
+    filter: Document,
+    sort: Document,
+) -> Result<Option<T>>
+where
+    T: serde::de::DeserializeOwned + Unpin + Send + Sync,
+{
+    let typed_collection = mongo.collection::<T>(&get_collection_name::<T>());
+    let options = FindOneOptions::builder().sort(sort).build();
+
+    match typed_collection.find_one(filter, options).await {
+        Ok(result) => Ok(result),
+        Err(err) => Err(Error::from(err)),
+    }
+}
+
 pub async fn edit_one_doc<T>(
     mongo: Database,
     filter: Document,
-    update: Document,
+    update: impl Into<UpdateModifications>,
 ) -> Result<Option<T>>
 where
     T: serde::de::DeserializeOwned,
 {
-    let typed_collection = mongo.collection::<T>(&*get_collection_name::<T>());
+    let typed_collection = mongo.collection::<T>(&get_collection_name::<T>());
 
     let options = FindOneAndUpdateOptions::builder()
         .return_document(After)
@@ -85,7 +348,7 @@ pub async fn delete_one_doc<T>(mongo: Database, filter: Document) -> Result<Opti
 where
     T: serde::de::DeserializeOwned,
 {
-    let typed_collection = mongo.collection::<T>(&*get_collection_name::<T>());
+    let typed_collection = mongo.collection::<T>(&get_collection_name::<T>());
 
     match typed_collection.find_one_and_delete(filter, None).await {
         Ok(result) => Ok(result),
@@ -93,8 +356,21 @@ where
     }
 }
 
+pub async fn update_many_docs<T>(
+    mongo: Database,
+    filter: Document,
+    update: impl Into<UpdateModifications>,
+) -> Result<u64> {
+    let typed_collection = mongo.collection::<T>(&get_collection_name::<T>());
+
+    match typed_collection.update_many(filter, update, None).await {
+        Ok(result) => Ok(result.modified_count),
+        Err(err) => Err(Error::from(err)),
+    }
+}
+
 pub async fn delete_all_docs<T>(mongo: Database) -> Result<u64> {
-    let typed_collection = mongo.collection::<T>(&*get_collection_name::<T>());
+    let typed_collection = mongo.collection::<T>(&get_collection_name::<T>());
 
     match typed_collection.delete_many(doc! {}, None).await {
         Ok(result) => Ok(result.deleted_count),
@@ -102,9 +378,18 @@ pub async fn delete_all_docs<T>(mongo: Database) -> Result<u64> {
     }
 }
 
-#[allow(dead_code)]
+pub fn is_duplicate_key_error(err: &Error) -> bool {
+    match err.downcast_ref::<mongodb::error::Error>() {
+        Some(mongo_err) => matches!(
+            mongo_err.kind.as_ref(),
+            ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == 11000
+        ),
+        None => false,
+    }
+}
+
 pub async fn count_docs<T>(mongo: Database) -> Result<u64> {
-    let typed_collection = mongo.collection::<T>(&*get_collection_name::<T>());
+    let typed_collection = mongo.collection::<T>(&get_collection_name::<T>());
 
     match typed_collection.count_documents(None, None).await {
         Ok(count) => Ok(count),
@@ -115,15 +400,16 @@ pub async fn count_docs<T>(mongo: Database) -> Result<u64> {
 #[cfg(test)]
 mod tests {
     use crate::dao::{
-        delete_all_docs, delete_one_doc, edit_one_doc, find_one_doc, get_all_docs,
-        get_collection_name, insert_one_doc,
+        delete_all_docs, delete_one_doc, edit_one_doc, find_docs_paginated, find_one_doc,
+        get_all_docs, get_collection_name, insert_one_doc, PageCursor, PageOptions, SortOrder,
     };
     use crate::posts::Post;
     use crate::sync_post::SyncResult;
     use crate::test_util::test_util::{
         generate_port_number, get_db_connection_uri, get_mongo_image, populate_test_data,
     };
-    use mongodb::bson::{doc, to_document};
+    use mongodb::bson::oid::ObjectId;
+    use mongodb::bson::{doc, to_document, Bson};
     use mongodb::Client;
     use testcontainers_modules::testcontainers::clients;
 
@@ -151,6 +437,84 @@ mod tests {
         assert_eq!(posts.len(), 2);
     }
 
+    #[test]
+    fn test_page_cursor_encode_decode() {
+        let cursor = PageCursor {
+            value: Bson::String("2024-01-23T13:48:06.761Z".to_string()),
+            id: ObjectId::new(),
+        };
+
+        let decoded = PageCursor::decode(&cursor.encode()).unwrap();
+
+        assert_eq!(decoded, cursor);
+    }
+
+    #[test]
+    fn test_page_cursor_decode_invalid_cursor() {
+        assert!(PageCursor::decode("not a cursor").is_err());
+        assert!(PageCursor::decode("").is_err());
+    }
+
+    #[test]
+    fn test_page_options_clamp_limit() {
+        assert_eq!(
+            PageOptions::new("title", SortOrder::Asc, None, None).limit,
+            20
+        );
+        assert_eq!(
+            PageOptions::new("title", SortOrder::Asc, Some(0), None).limit,
+            1
+        );
+        assert_eq!(
+            PageOptions::new("title", SortOrder::Asc, Some(1000), None).limit,
+            100
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_find_docs_paginated() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let _c = docker.run(mongo_img);
+        populate_test_data(&port);
+        let uri = get_db_connection_uri(&port);
+        let client = Client::with_uri_str(uri).await.unwrap();
+
+        let test_db = client.database("test_db");
+
+        let first_page = find_docs_paginated::<Post>(
+            test_db.clone(),
+            doc! {},
+            PageOptions::new("title", SortOrder::Asc, Some(1), None),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(first_page.items.len(), 1);
+        assert_eq!(first_page.total_count, 2);
+        assert!(first_page.next_cursor.is_some());
+
+        let first_post = to_document(&first_page.items[0]).unwrap();
+        assert_eq!(first_post.get_str("title").unwrap(), "Test Post 1");
+
+        let cursor = PageCursor::decode(&first_page.next_cursor.unwrap()).unwrap();
+        let second_page = find_docs_paginated::<Post>(
+            test_db,
+            doc! {},
+            PageOptions::new("title", SortOrder::Asc, Some(1), Some(cursor)),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(second_page.items.len(), 1);
+        assert_eq!(second_page.total_count, 2);
+        assert!(second_page.next_cursor.is_none());
+
+        let second_post = to_document(&second_page.items[0]).unwrap();
+        assert_eq!(second_post.get_str("title").unwrap(), "Test Post 2");
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_insert_one_doc() {
         let docker = clients::Cli::default();
@@ -208,7 +572,7 @@ mod tests {
 
         let insert_result = insert_one_doc::<Post>(
             test_db.clone(),
-            Post::new_for_sync("asdf", "asdf", "asdf", "asdf"),
+            Post::new_for_sync("8365446", "asdf", "asdf", "asdf", "asdf"),
         )
         .await;
 
@@ -251,7 +615,7 @@ mod tests {
 
         let insert_result = insert_one_doc::<Post>(
             test_db.clone(),
-            Post::new_for_sync("asdf", "asdf", "asdf", "asdf"),
+            Post::new_for_sync("8365446", "asdf", "asdf", "asdf", "asdf"),
         )
         .await;
 
@@ -9,6 +9,6 @@ impl std::fmt::Display for SetupError {
 
 impl From<anyhow::Error> for SetupError {
     fn from(_: anyhow::Error) -> Self {
-        Self { 0: "".to_string() }
+        Self("".to_string())
     }
 }
@@ -0,0 +1,869 @@
+use crate::background::{reconnect_policy, BackgroundTask};
+use crate::redis_pubsub::message::SyncTrigger;
+use crate::sync_post::{sync_post, SyncMode};
+use crate::AppState;
+use anyhow::Result;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use chrono::{SecondsFormat, Utc};
+use redis::aio::MultiplexedConnection;
+use redis::streams::{
+    StreamClaimReply, StreamId, StreamInfoGroupsReply, StreamMaxlen, StreamPendingCountReply,
+    StreamRangeReply, StreamReadOptions, StreamReadReply,
+};
+use redis::{AsyncCommands, Commands, RedisResult, Script};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{debug, error, info};
+use uuid::Uuid;
+
+pub const JOB_STREAM: &str = "jobs";
+pub const JOB_DEAD_LETTER_STREAM: &str = "jobs:dead";
+const JOB_GROUP: &str = "workers";
+const JOB_FIELD: &str = "job";
+const JOB_ENQUEUED_KEY_PREFIX: &str = "jobs:enqueued";
+/// The error of the last failed attempt of each pending entry, by stream id.
+const JOB_ERRORS_KEY: &str = "jobs:errors";
+/// Acknowledged entries stay in the stream until trimmed, this keeps it bounded.
+const JOB_STREAM_MAXLEN: usize = 1000;
+const JOB_DEAD_LETTER_MAXLEN: usize = 1000;
+/// Enqueueing the same job id twice within this window only queues it once.
+const JOB_ENQUEUED_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+pub const MAX_JOB_ATTEMPTS: usize = 5;
+/// A job that failed, was deferred, or whose worker died, is picked up again once left alone
+/// this long.
+const JOB_RETRY_IDLE: Duration = Duration::from_secs(60);
+/// Keeps a running job from looking abandoned, well within `JOB_RETRY_IDLE`.
+const JOB_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+const JOB_READ_BLOCK: Duration = Duration::from_secs(5);
+const JOB_LIST_LIMIT: usize = 100;
+/// Held by the manual sync that is queued or running, so a trigger can't queue a second one.
+const MANUAL_SYNC_KEY: &str = "jobs:sync:manual";
+/// Frees the marker should its job get lost, e.g. trimmed from the stream.
+const MANUAL_SYNC_TTL: Duration = Duration::from_secs(60 * 60);
+
+// only the job holding the marker clears it
+const RELEASE_MANUAL_SYNC_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum JobKind {
+    Sync {
+        mode: SyncMode,
+        #[serde(default)]
+        trigger: SyncTrigger,
+    },
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Job {
+    pub id: String,
+    #[serde(flatten)]
+    pub kind: JobKind,
+    pub enqueued_at: String,
+}
+
+impl Job {
+    pub fn new(kind: JobKind) -> Job {
+        Job::with_id(&Uuid::new_v4().simple().to_string(), kind)
+    }
+
+    pub fn with_id(id: &str, kind: JobKind) -> Job {
+        Job {
+            id: id.to_string(),
+            kind,
+            enqueued_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct QueuedJob {
+    pub stream_id: String,
+    pub job: Option<Job>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct PendingJob {
+    pub stream_id: String,
+    pub job: Option<Job>,
+    pub consumer: String,
+    pub idle_ms: u64,
+    pub delivery_count: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct FailedJob {
+    pub stream_id: String,
+    /// Where the job sat in the job stream before it was dead-lettered.
+    pub job_stream_id: String,
+    pub job: Option<Job>,
+    pub attempts: u64,
+    pub error: String,
+    pub failed_at: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct JobQueueStatus {
+    /// Not handed to a worker yet.
+    pub queued: Vec<QueuedJob>,
+    /// Running, or waiting to be retried.
+    pub pending: Vec<PendingJob>,
+    /// Gave up after `MAX_JOB_ATTEMPTS`, newest first.
+    pub failed: Vec<FailedJob>,
+}
+
+/// Returned by a job that can't run yet, e.g. a sync while another holds the lock. It's retried
+/// like a failed job, but without using up an attempt.
+#[derive(Debug)]
+pub struct JobDeferred(pub String);
+impl std::error::Error for JobDeferred {}
+impl std::fmt::Display for JobDeferred {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+fn parse_job(entry: &StreamId) -> Option<Job> {
+    entry
+        .get::<String>(JOB_FIELD)
+        .and_then(|json| serde_json::from_str(&json).ok())
+}
+
+/// Queues `job` for a worker, returning `false` when a job with its id was already queued.
+pub fn enqueue_job(redis: redis::Client, job: &Job) -> Result<bool> {
+    let mut con = redis.get_connection()?;
+
+    let claimed: Option<String> = redis::cmd("SET")
+        .arg(format!("{}:{}", JOB_ENQUEUED_KEY_PREFIX, job.id))
+        .arg(1)
+        .arg("NX")
+        .arg("PX")
+        .arg(JOB_ENQUEUED_TTL.as_millis() as u64)
+        .query(&mut con)?;
+    if claimed.is_none() {
+        debug!("job {} is already queued", job.id);
+        return Ok(false);
+    }
+
+    let json = serde_json::to_string(job)?;
+    let stream_id: String = con.xadd_maxlen(
+        JOB_STREAM,
+        StreamMaxlen::Approx(JOB_STREAM_MAXLEN),
+        "*",
+        &[(JOB_FIELD, json)],
+    )?;
+    debug!("job {} queued as {}", job.id, stream_id);
+
+    Ok(true)
+}
+
+/// Queues `job` as the manual sync, returning `false` when one is already queued or running.
+/// The marker is claimed in a single command, so of two triggers at once only one gets through.
+pub fn enqueue_manual_sync(redis: redis::Client, job: &Job) -> Result<bool> {
+    let mut con = redis.get_connection()?;
+
+    let claimed: Option<String> = redis::cmd("SET")
+        .arg(MANUAL_SYNC_KEY)
+        .arg(&job.id)
+        .arg("NX")
+        .arg("PX")
+        .arg(MANUAL_SYNC_TTL.as_millis() as u64)
+        .query(&mut con)?;
+    if claimed.is_none() {
+        debug!("a manual sync is already queued");
+        return Ok(false);
+    }
+
+    match enqueue_job(redis, job) {
+        Ok(is_queued) => Ok(is_queued),
+        Err(err) => {
+            let _: RedisResult<i64> = Script::new(RELEASE_MANUAL_SYNC_SCRIPT)
+                .key(MANUAL_SYNC_KEY)
+                .arg(&job.id)
+                .invoke(&mut con);
+            Err(err)
+        }
+    }
+}
+
+/// Lets the next manual sync be queued once `job` is done with, whichever way it ended.
+async fn release_manual_sync(con: &mut MultiplexedConnection, job: &Job) -> RedisResult<()> {
+    let _: i64 = Script::new(RELEASE_MANUAL_SYNC_SCRIPT)
+        .key(MANUAL_SYNC_KEY)
+        .arg(&job.id)
+        .invoke_async(con)
+        .await?;
+
+    Ok(())
+}
+
+async fn create_job_group(con: &mut MultiplexedConnection) -> RedisResult<()> {
+    // starting from the beginning picks up jobs queued before the group existed
+    let result: RedisResult<()> = con.xgroup_create_mkstream(JOB_STREAM, JOB_GROUP, "0").await;
+
+    match result {
+        Err(err) if err.code() == Some("BUSYGROUP") => Ok(()),
+        result => result,
+    }
+}
+
+/// Runs queued jobs until shut down. Every instance runs one, the consumer group
+/// hands each job to a single worker.
+pub fn spawn_job_worker(state: AppState) -> BackgroundTask {
+    let consumer = format!("worker-{}", Uuid::new_v4().simple());
+
+    BackgroundTask::spawn("job worker", |shutdown| {
+        run_job_worker(state, consumer, shutdown)
+    })
+}
+
+async fn run_job_worker(state: AppState, consumer: String, mut shutdown: watch::Receiver<bool>) {
+    let policy = reconnect_policy();
+    let mut attempt = 0;
+
+    while !*shutdown.borrow() {
+        match connect_worker(&state.redis).await {
+            Ok(mut con) => {
+                attempt = 0;
+                info!("job worker {} reading stream {}", consumer, JOB_STREAM);
+
+                if let Err(err) = work_jobs(&state, &mut con, &consumer, &mut shutdown).await {
+                    error!("job worker {} lost connection {}", consumer, err);
+                }
+            }
+            Err(err) => error!("fail to start job worker {}", err),
+        }
+
+        if *shutdown.borrow() {
+            break;
+        }
+
+        let delay = policy.backoff(attempt);
+        attempt = attempt.saturating_add(1);
+        info!("restarting job worker in {:?}", delay);
+
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            _ = tokio::time::sleep(delay) => {}
+        }
+    }
+
+    info!("job worker {} stopped", consumer);
+}
+
+async fn connect_worker(redis: &redis::Client) -> RedisResult<MultiplexedConnection> {
+    let mut con = redis.get_multiplexed_async_connection().await?;
+    create_job_group(&mut con).await?;
+
+    Ok(con)
+}
+
+/// Returns once shut down, or with the error that broke the connection.
+async fn work_jobs(
+    state: &AppState,
+    con: &mut MultiplexedConnection,
+    consumer: &str,
+    shutdown: &mut watch::Receiver<bool>,
+) -> RedisResult<()> {
+    loop {
+        if *shutdown.borrow() {
+            return Ok(());
+        }
+
+        // retries go first, so a failing job can't be starved by new ones
+        if let Some((entry, delivery_count)) = claim_idle_job(con, consumer).await? {
+            process_job(state, con, consumer, entry, delivery_count).await?;
+            continue;
+        }
+
+        let reply = tokio::select! {
+            _ = shutdown.changed() => return Ok(()),
+            reply = read_new_job(con, consumer) => reply?,
+        };
+
+        for entry in reply.keys.into_iter().flat_map(|key| key.ids) {
+            process_job(state, con, consumer, entry, 1).await?;
+        }
+    }
+}
+
+async fn read_new_job(
+    con: &mut MultiplexedConnection,
+    consumer: &str,
+) -> RedisResult<StreamReadReply> {
+    let options = StreamReadOptions::default()
+        .group(JOB_GROUP, consumer)
+        .count(1)
+        .block(JOB_READ_BLOCK.as_millis() as usize);
+
+    let reply: Option<StreamReadReply> = con.xread_options(&[JOB_STREAM], &[">"], &options).await?;
+
+    Ok(reply.unwrap_or_default())
+}
+
+/// Takes over the oldest job nobody worked on for `JOB_RETRY_IDLE`, with its delivery count.
+async fn claim_idle_job(
+    con: &mut MultiplexedConnection,
+    consumer: &str,
+) -> RedisResult<Option<(StreamId, usize)>> {
+    let min_idle = JOB_RETRY_IDLE.as_millis() as u64;
+
+    let pending: StreamPendingCountReply = redis::cmd("XPENDING")
+        .arg(JOB_STREAM)
+        .arg(JOB_GROUP)
+        .arg("IDLE")
+        .arg(min_idle)
+        .arg("-")
+        .arg("+")
+        .arg(1)
+        .query_async(con)
+        .await?;
+
+    let pending = match pending.ids.into_iter().next() {
+        Some(pending) => pending,
+        None => return Ok(None),
+    };
+
+    let claimed: StreamClaimReply = con
+        .xclaim(JOB_STREAM, JOB_GROUP, consumer, min_idle, &[&pending.id])
+        .await?;
+
+    match claimed.ids.into_iter().next() {
+        Some(entry) => Ok(Some((entry, pending.times_delivered + 1))),
+        None => {
+            // another worker was faster, or the entry was trimmed away
+            let _: RedisResult<()> = con.xack(JOB_STREAM, JOB_GROUP, &[&pending.id]).await;
+            Ok(None)
+        }
+    }
+}
+
+async fn process_job(
+    state: &AppState,
+    con: &mut MultiplexedConnection,
+    consumer: &str,
+    entry: StreamId,
+    delivery_count: usize,
+) -> RedisResult<()> {
+    let job = match parse_job(&entry) {
+        Some(job) => job,
+        None => {
+            error!("malformed job {} in stream {}", entry.id, JOB_STREAM);
+            return dead_letter(con, &entry, delivery_count, "malformed job").await;
+        }
+    };
+
+    if delivery_count > MAX_JOB_ATTEMPTS {
+        let last_error: Option<String> = con.hget(JOB_ERRORS_KEY, &entry.id).await?;
+        let error_message = last_error.unwrap_or_else(|| "worker stopped".to_string());
+        error!(
+            "job {} failed {} times, giving up: {}",
+            job.id, MAX_JOB_ATTEMPTS, error_message
+        );
+        dead_letter(con, &entry, delivery_count - 1, &error_message).await?;
+        return release_manual_sync(con, &job).await;
+    }
+
+    info!(
+        "job {} started, attempt {} of {}",
+        job.id, delivery_count, MAX_JOB_ATTEMPTS
+    );
+    let heartbeat = spawn_job_heartbeat(con.clone(), consumer.to_string(), entry.id.clone());
+    let result = run_job(state.clone(), &job).await;
+    heartbeat.abort();
+
+    match result {
+        Ok(()) => {
+            let _: () = redis::pipe()
+                .xack(JOB_STREAM, JOB_GROUP, &[&entry.id])
+                .hdel(JOB_ERRORS_KEY, &entry.id)
+                .query_async(con)
+                .await?;
+            release_manual_sync(con, &job).await?;
+            info!("job {} done", job.id);
+        }
+        Err(err) if err.is::<JobDeferred>() => {
+            // claimed again once idle, with the delivery count it had before this attempt
+            info!("job {} deferred, {}", job.id, err);
+            let _: Vec<String> = redis::cmd("XCLAIM")
+                .arg(JOB_STREAM)
+                .arg(JOB_GROUP)
+                .arg(consumer)
+                .arg(0)
+                .arg(&entry.id)
+                .arg("RETRYCOUNT")
+                .arg(delivery_count - 1)
+                .arg("JUSTID")
+                .query_async(con)
+                .await?;
+        }
+        Err(err) => {
+            // left unacknowledged, so it's claimed again once idle
+            error!("job {} failed {}", job.id, err);
+            let _: () = con.hset(JOB_ERRORS_KEY, &entry.id, err.to_string()).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Resets the idle time of a running job, so no other worker claims it.
+fn spawn_job_heartbeat(
+    mut con: MultiplexedConnection,
+    consumer: String,
+    stream_id: String,
+) -> tokio::task::JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(JOB_HEARTBEAT_INTERVAL);
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            let result: RedisResult<Vec<String>> = redis::cmd("XCLAIM")
+                .arg(JOB_STREAM)
+                .arg(JOB_GROUP)
+                .arg(&consumer)
+                .arg(0)
+                .arg(&stream_id)
+                .arg("JUSTID")
+                .query_async(&mut con)
+                .await;
+            if let Err(err) = result {
+                error!("fail to extend job {} {}", stream_id, err);
+            }
+        }
+    })
+}
+
+async fn dead_letter(
+    con: &mut MultiplexedConnection,
+    entry: &StreamId,
+    attempts: usize,
+    error_message: &str,
+) -> RedisResult<()> {
+    let job = entry.get::<String>(JOB_FIELD).unwrap_or_default();
+    let failed_at = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+
+    let _: () = redis::pipe()
+        .atomic()
+        .xadd_maxlen(
+            JOB_DEAD_LETTER_STREAM,
+            StreamMaxlen::Approx(JOB_DEAD_LETTER_MAXLEN),
+            "*",
+            &[
+                (JOB_FIELD, job),
+                ("job_stream_id", entry.id.clone()),
+                ("attempts", attempts.to_string()),
+                ("error", error_message.to_string()),
+                ("failed_at", failed_at),
+            ],
+        )
+        .ignore()
+        .xack(JOB_STREAM, JOB_GROUP, &[&entry.id])
+        .ignore()
+        .hdel(JOB_ERRORS_KEY, &entry.id)
+        .ignore()
+        .query_async(con)
+        .await?;
+
+    Ok(())
+}
+
+async fn run_job(state: AppState, job: &Job) -> Result<()> {
+    match &job.kind {
+        JobKind::Sync { mode, trigger } => {
+            info!("{:?} sync requested by {:?}", mode, trigger);
+            // the job id doubles as the sync job id, so progress can be followed by it
+            sync_post(state, *mode, &job.id).await
+        }
+    }
+}
+
+fn get_queued_jobs(con: &mut redis::Connection) -> Result<Vec<QueuedJob>> {
+    let groups: StreamInfoGroupsReply = con.xinfo_groups(JOB_STREAM)?;
+    let start = match groups.groups.iter().find(|group| group.name == JOB_GROUP) {
+        // exclusive range, everything after what the group was handed
+        Some(group) => format!("({}", group.last_delivered_id),
+        None => "-".to_string(),
+    };
+
+    let entries: StreamRangeReply = con.xrange_count(JOB_STREAM, start, "+", JOB_LIST_LIMIT)?;
+
+    Ok(entries
+        .ids
+        .iter()
+        .map(|entry| QueuedJob {
+            stream_id: entry.id.clone(),
+            job: parse_job(entry),
+        })
+        .collect())
+}
+
+fn get_pending_jobs(con: &mut redis::Connection) -> Result<Vec<PendingJob>> {
+    let has_group = {
+        let groups: StreamInfoGroupsReply = con.xinfo_groups(JOB_STREAM)?;
+        groups.groups.iter().any(|group| group.name == JOB_GROUP)
+    };
+    if !has_group {
+        return Ok(vec![]);
+    }
+
+    let pending: StreamPendingCountReply =
+        con.xpending_count(JOB_STREAM, JOB_GROUP, "-", "+", JOB_LIST_LIMIT)?;
+
+    let mut pending_jobs = vec![];
+    for pending in pending.ids {
+        let entries: StreamRangeReply = con.xrange(JOB_STREAM, &pending.id, &pending.id)?;
+        let last_error: Option<String> = con.hget(JOB_ERRORS_KEY, &pending.id)?;
+
+        pending_jobs.push(PendingJob {
+            job: entries.ids.first().and_then(parse_job),
+            stream_id: pending.id,
+            consumer: pending.consumer,
+            idle_ms: pending.last_delivered_ms as u64,
+            delivery_count: pending.times_delivered as u64,
+            last_error,
+        });
+    }
+
+    Ok(pending_jobs)
+}
+
+fn get_failed_jobs(con: &mut redis::Connection) -> Result<Vec<FailedJob>> {
+    let entries: StreamRangeReply =
+        con.xrevrange_count(JOB_DEAD_LETTER_STREAM, "+", "-", JOB_LIST_LIMIT)?;
+
+    Ok(entries
+        .ids
+        .iter()
+        .map(|entry| FailedJob {
+            stream_id: entry.id.clone(),
+            job_stream_id: entry.get("job_stream_id").unwrap_or_default(),
+            job: parse_job(entry),
+            attempts: entry.get("attempts").unwrap_or_default(),
+            error: entry.get("error").unwrap_or_default(),
+            failed_at: entry.get("failed_at").unwrap_or_default(),
+        })
+        .collect())
+}
+
+pub fn get_job_queue_status(redis: redis::Client) -> Result<JobQueueStatus> {
+    let mut con = redis.get_connection()?;
+
+    // XINFO fails on a stream nothing was ever queued on
+    let has_jobs: bool = con.exists(JOB_STREAM)?;
+    let (queued, pending) = if has_jobs {
+        (get_queued_jobs(&mut con)?, get_pending_jobs(&mut con)?)
+    } else {
+        (vec![], vec![])
+    };
+
+    Ok(JobQueueStatus {
+        queued,
+        pending,
+        failed: get_failed_jobs(&mut con)?,
+    })
+}
+
+/// Whether a sync job is waiting for a worker, running, or waiting to be retried.
+pub fn has_unfinished_sync_job(redis: redis::Client) -> Result<bool> {
+    let mut con = redis.get_connection()?;
+
+    let has_jobs: bool = con.exists(JOB_STREAM)?;
+    if !has_jobs {
+        return Ok(false);
+    }
+
+    let queued_jobs = get_queued_jobs(&mut con)?
+        .into_iter()
+        .map(|queued| queued.job);
+    let pending_jobs = get_pending_jobs(&mut con)?
+        .into_iter()
+        .map(|pending| pending.job);
+
+    Ok(queued_jobs
+        .chain(pending_jobs)
+        .flatten()
+        .any(|job| matches!(job.kind, JobKind::Sync { .. })))
+}
+
+pub async fn get_jobs(
+    State(state): State<AppState>,
+) -> Result<Json<JobQueueStatus>, impl IntoResponse> {
+    match get_job_queue_status(state.redis) {
+        Ok(status) => Ok(Json(status)),
+        Err(err) => {
+            let error_message = format!("fail to read job queue {}", err);
+            error!("{}", error_message.clone());
+            Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync_job::create_sync_job;
+    use crate::sync_post::SyncResult;
+    use crate::test_util::test_util::{
+        create_test_state, generate_port_number, get_db_connection_uri, get_mongo_image,
+        get_redis_connection_uri, get_redis_image, FakePatreonApi,
+    };
+    use mongodb::Client;
+    use serde_json::json;
+    use std::sync::Arc;
+    use testcontainers_modules::redis::REDIS_PORT;
+    use testcontainers_modules::testcontainers::clients;
+
+    fn sync_job() -> Job {
+        Job::new(JobKind::Sync {
+            mode: SyncMode::Full,
+            trigger: SyncTrigger::Manual,
+        })
+    }
+
+    #[test]
+    fn test_job_json() {
+        let job = Job {
+            id: "asdf".to_string(),
+            kind: JobKind::Sync {
+                mode: SyncMode::Incremental,
+                trigger: SyncTrigger::Schedule,
+            },
+            enqueued_at: "2024-03-14T06:00:00Z".to_string(),
+        };
+
+        let value = serde_json::to_value(&job).unwrap();
+
+        assert_eq!(
+            value,
+            json!({
+                "id": "asdf",
+                "kind": "sync",
+                "mode": "incremental",
+                "trigger": "schedule",
+                "enqueued_at": "2024-03-14T06:00:00Z",
+            })
+        );
+        assert_eq!(serde_json::from_value::<Job>(value).unwrap(), job);
+    }
+
+    #[tokio::test]
+    async fn test_enqueue_job_once() {
+        let docker = clients::Cli::default();
+        let redis_img = get_redis_image();
+        let redis_node = docker.run(redis_img);
+
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let status = get_job_queue_status(redis_client.clone()).unwrap();
+        assert!(status.queued.is_empty());
+        assert!(!has_unfinished_sync_job(redis_client.clone()).unwrap());
+
+        let job = sync_job();
+        assert!(enqueue_job(redis_client.clone(), &job).unwrap());
+        // e.g. every instance receiving the same sync request
+        assert!(!enqueue_job(redis_client.clone(), &job).unwrap());
+        assert!(has_unfinished_sync_job(redis_client.clone()).unwrap());
+
+        let status = get_job_queue_status(redis_client).unwrap();
+        assert_eq!(status.queued.len(), 1);
+        assert_eq!(status.queued[0].job, Some(job));
+        assert!(status.pending.is_empty());
+        assert!(status.failed.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_enqueue_manual_sync_once() {
+        let docker = clients::Cli::default();
+        let redis_img = get_redis_image();
+        let redis_node = docker.run(redis_img);
+
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        // triggers arriving together, each with a job id of its own
+        let triggers: Vec<_> = (0..8)
+            .map(|_| {
+                let redis_client = redis_client.clone();
+                tokio::task::spawn_blocking(move || {
+                    enqueue_manual_sync(redis_client, &sync_job()).unwrap()
+                })
+            })
+            .collect();
+        let mut queued_count = 0;
+        for trigger in triggers {
+            if trigger.await.unwrap() {
+                queued_count += 1;
+            }
+        }
+        assert_eq!(queued_count, 1);
+
+        let queued_job = get_job_queue_status(redis_client.clone()).unwrap().queued[0]
+            .job
+            .clone()
+            .unwrap();
+        let mut con = redis_client
+            .get_multiplexed_async_connection()
+            .await
+            .unwrap();
+        // only the queued job frees the marker
+        release_manual_sync(&mut con, &sync_job()).await.unwrap();
+        assert!(!enqueue_manual_sync(redis_client.clone(), &sync_job()).unwrap());
+        release_manual_sync(&mut con, &queued_job).await.unwrap();
+        assert!(enqueue_manual_sync(redis_client, &sync_job()).unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_job_worker_runs_queued_job() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+        let state = AppState {
+            patreon_api: Arc::new(FakePatreonApi::from_fixtures()),
+            ..create_test_state(test_db.clone(), redis_client.clone())
+        };
+
+        // queued while no worker runs, e.g. during a deploy
+        enqueue_job(redis_client.clone(), &sync_job()).unwrap();
+
+        let worker = spawn_job_worker(state);
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                let status = get_job_queue_status(redis_client.clone()).unwrap();
+                if status.queued.is_empty() && status.pending.is_empty() {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+        worker.shutdown().await;
+
+        let sync_result_count = test_db
+            .collection::<SyncResult>("SyncResult")
+            .count_documents(None, None)
+            .await
+            .unwrap();
+        assert_eq!(sync_result_count, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_deferred_job_keeps_its_attempts() {
+        let docker = clients::Cli::default();
+        let redis_img = get_redis_image();
+        let redis_node = docker.run(redis_img);
+
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+        let client = Client::with_uri_str("mongodb://127.0.0.1:1").await.unwrap();
+        let state = create_test_state(client.database("test_db"), redis_client.clone());
+
+        // e.g. a scheduled sync running longer than all the retries of a queued one
+        create_sync_job(redis_client.clone(), "running_job").unwrap();
+        enqueue_job(redis_client.clone(), &sync_job()).unwrap();
+
+        let mut con = connect_worker(&redis_client).await.unwrap();
+        let entry = read_new_job(&mut con, "test_worker")
+            .await
+            .unwrap()
+            .keys
+            .remove(0)
+            .ids
+            .remove(0);
+
+        process_job(&state, &mut con, "test_worker", entry.clone(), 1)
+            .await
+            .unwrap();
+        process_job(
+            &state,
+            &mut con,
+            "test_worker",
+            entry.clone(),
+            MAX_JOB_ATTEMPTS,
+        )
+        .await
+        .unwrap();
+
+        let status = get_job_queue_status(redis_client).unwrap();
+        assert!(status.failed.is_empty());
+        assert_eq!(status.pending.len(), 1);
+        let pending_job = &status.pending[0];
+        assert_eq!(pending_job.stream_id, entry.id);
+        assert_eq!(pending_job.delivery_count, MAX_JOB_ATTEMPTS as u64 - 1);
+        assert_eq!(pending_job.last_error, None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_dead_letter_after_max_attempts() {
+        let docker = clients::Cli::default();
+        let redis_img = get_redis_image();
+        let redis_node = docker.run(redis_img);
+
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+        let client = Client::with_uri_str("mongodb://127.0.0.1:1").await.unwrap();
+        let state = create_test_state(client.database("test_db"), redis_client.clone());
+
+        let job = sync_job();
+        enqueue_job(redis_client.clone(), &job).unwrap();
+
+        let mut con = connect_worker(&redis_client).await.unwrap();
+        let entry = read_new_job(&mut con, "test_worker")
+            .await
+            .unwrap()
+            .keys
+            .remove(0)
+            .ids
+            .remove(0);
+        let _: () = con
+            .hset(JOB_ERRORS_KEY, &entry.id, "patreon is down")
+            .await
+            .unwrap();
+
+        // the job is not run again
+        process_job(
+            &state,
+            &mut con,
+            "test_worker",
+            entry.clone(),
+            MAX_JOB_ATTEMPTS + 1,
+        )
+        .await
+        .unwrap();
+
+        let status = get_job_queue_status(redis_client).unwrap();
+        assert!(status.queued.is_empty());
+        assert!(status.pending.is_empty());
+        assert_eq!(status.failed.len(), 1);
+        let failed_job = &status.failed[0];
+        assert_eq!(failed_job.job, Some(job));
+        assert_eq!(failed_job.job_stream_id, entry.id);
+        assert_eq!(failed_job.attempts, MAX_JOB_ATTEMPTS as u64);
+        assert_eq!(failed_job.error, "patreon is down");
+    }
+}
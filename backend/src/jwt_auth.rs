@@ -1,46 +1,138 @@
 use axum::{
     extract::{Request, State},
-    http::{header, StatusCode},
+    http::{header, HeaderMap, StatusCode},
     middleware::Next,
     response::IntoResponse,
 };
-use jsonwebtoken::{decode, DecodingKey, Validation};
+use chrono::Utc;
+use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use redis::AsyncCommands;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
 use tracing::error;
+use uuid::Uuid;
 
+use crate::api_key::{authenticate_api_key, API_KEY_HEADER};
+use crate::permission::PATRON_ROLE;
 use crate::AppState;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TokenClaims {
     pub name: String,
     pub role: String,
     pub iat: usize,
     pub exp: usize,
+    /// Unique per token, so a single token can be denied before it expires.
+    pub jti: String,
+    /// The refresh token family the token was issued from, revoked together on logout.
+    pub family_id: String,
+    /// Patreon tiers a patron is entitled to, empty for everyone else.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tier_ids: Vec<String>,
 }
 
-pub async fn auth_jwt(
-    State(state): State<AppState>,
-    req: Request,
-    next: Next,
-) -> Result<impl IntoResponse, StatusCode> {
-    let token = req
-        .headers()
+const DENIED_ACCESS_TOKEN_KEY_PREFIX: &str = "auth:denied";
+
+fn denied_access_token_key(jti: &str) -> String {
+    format!("{}:{}", DENIED_ACCESS_TOKEN_KEY_PREFIX, jti)
+}
+
+/// Access tokens are short-lived, a refresh token gets the next one, see `auth::refresh`.
+pub const ACCESS_TOKEN_TTL: Duration = Duration::from_secs(15 * 60);
+/// Patrons get no refresh token, they log in through Patreon again. A pledge that lapses keeps
+/// its access until then.
+pub const PATRON_ACCESS_TOKEN_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn sign_access_token(jwt_key: &str, claims: &TokenClaims) -> jsonwebtoken::errors::Result<String> {
+    encode(
+        &Header::default(),
+        claims,
+        &EncodingKey::from_secret(jwt_key.as_bytes()),
+    )
+}
+
+/// Signs an access token for `name` with the shared `JWT_SECRET`.
+pub fn issue_access_token(
+    jwt_key: &str,
+    name: &str,
+    role: &str,
+    family_id: &str,
+) -> jsonwebtoken::errors::Result<(String, TokenClaims)> {
+    let iat = Utc::now().timestamp() as usize;
+    let claims = TokenClaims {
+        name: name.to_string(),
+        role: role.to_string(),
+        iat,
+        exp: iat + ACCESS_TOKEN_TTL.as_secs() as usize,
+        jti: Uuid::new_v4().to_string(),
+        family_id: family_id.to_string(),
+        tier_ids: vec![],
+    };
+    let token = sign_access_token(jwt_key, &claims)?;
+
+    Ok((token, claims))
+}
+
+/// Signs the access token of a patron session, which carries the patron's tiers. Its family
+/// has no refresh token, it's only there so logging out works the same as for admins.
+pub fn issue_patron_access_token(
+    jwt_key: &str,
+    name: &str,
+    tier_ids: Vec<String>,
+) -> jsonwebtoken::errors::Result<(String, TokenClaims)> {
+    let iat = Utc::now().timestamp() as usize;
+    let claims = TokenClaims {
+        name: name.to_string(),
+        role: PATRON_ROLE.to_string(),
+        iat,
+        exp: iat + PATRON_ACCESS_TOKEN_TTL.as_secs() as usize,
+        jti: Uuid::new_v4().to_string(),
+        family_id: Uuid::new_v4().to_string(),
+        tier_ids,
+    };
+    let token = sign_access_token(jwt_key, &claims)?;
+
+    Ok((token, claims))
+}
+
+/// Turns down the access tokens with these ids from now on. No access token outlives
+/// `PATRON_ACCESS_TOKEN_TTL`, so neither does its entry.
+pub async fn deny_access_tokens(redis: &redis::Client, jtis: &[String]) -> redis::RedisResult<()> {
+    if jtis.is_empty() {
+        return Ok(());
+    }
+
+    let mut con = redis.get_multiplexed_async_connection().await?;
+    let mut pipe = redis::pipe();
+
+    for jti in jtis {
+        pipe.set_ex(
+            denied_access_token_key(jti),
+            1,
+            PATRON_ACCESS_TOKEN_TTL.as_secs(),
+        )
+        .ignore();
+    }
+
+    pipe.query_async(&mut con).await
+}
+
+async fn is_access_token_denied(redis: &redis::Client, jti: &str) -> redis::RedisResult<bool> {
+    let mut con = redis.get_multiplexed_async_connection().await?;
+
+    con.exists(denied_access_token_key(jti)).await
+}
+
+/// The claims of the bearer token in `headers`, which must be valid and not denied.
+async fn authenticate(state: &AppState, headers: &HeaderMap) -> Result<TokenClaims, StatusCode> {
+    let token = headers
         .get(header::AUTHORIZATION)
         .and_then(|auth_header| auth_header.to_str().ok())
-        .and_then(|auth_value| {
-            if auth_value.starts_with("Bearer ") {
-                Some(auth_value[7..].to_owned())
-            } else {
-                None
-            }
-        });
-
-    let token = token.ok_or_else(|| {
-        return StatusCode::UNAUTHORIZED;
-    })?;
+        .and_then(|auth_value| auth_value.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
 
     let claims = decode::<TokenClaims>(
-        &token,
+        token,
         &DecodingKey::from_secret(state.jwt_key.as_bytes()),
         &Validation::default(),
     )
@@ -48,13 +140,53 @@ pub async fn auth_jwt(
         let error_message = err.to_string();
         error!("{}", error_message);
 
-        return StatusCode::UNAUTHORIZED;
+        StatusCode::UNAUTHORIZED
     })?
     .claims;
 
-    if claims.role != "admin" {
-        return Err(StatusCode::UNAUTHORIZED);
+    match is_access_token_denied(&state.redis, &claims.jti).await {
+        Ok(false) => Ok(claims),
+        Ok(true) => Err(StatusCode::UNAUTHORIZED),
+        Err(err) => {
+            error!("fail to check denied access tokens {}", err);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
     }
+}
 
+/// Lets the request through with a valid token, whatever its role, which handlers can read
+/// back as an `Extension<TokenClaims>`. What the role may do is up to `require_permission`.
+/// Machine clients send an `X-API-Key` instead, which ends up as an `Extension<ApiKeyClaims>`.
+pub async fn auth_jwt(
+    State(state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Result<impl IntoResponse, StatusCode> {
+    if req.headers().contains_key(API_KEY_HEADER) {
+        let api_key = authenticate_api_key(&state, req.headers()).await?;
+
+        req.extensions_mut().insert(api_key);
+        return Ok(next.run(req).await);
+    }
+
+    let claims = authenticate(&state, req.headers()).await?;
+
+    req.extensions_mut().insert(claims);
     Ok(next.run(req).await)
 }
+
+/// For the public routes, which show more to whoever is logged in. Any token that doesn't
+/// pass `auth_jwt`, expired or logged out, is only ignored, so the public pages keep working.
+pub async fn optional_auth_jwt(
+    State(state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> impl IntoResponse {
+    if req.headers().contains_key(header::AUTHORIZATION) {
+        if let Ok(claims) = authenticate(&state, req.headers()).await {
+            req.extensions_mut().insert(claims);
+        }
+    }
+
+    next.run(req).await
+}
@@ -1,23 +1,51 @@
 use axum::Router;
-use mongodb;
 
+mod api_key;
+mod auth;
+mod background;
+mod cache;
+mod conditional;
 mod dao;
 mod errors;
+mod job_queue;
 mod jwt_auth;
+mod patreon_api;
+mod patreon_content;
+mod patreon_oauth;
+mod patron;
+mod permission;
 mod posts;
 mod redis_pubsub;
+mod refresh_token;
 mod router;
+mod search;
+mod slug;
+mod sync_history;
 mod sync_job;
 mod sync_post;
+mod sync_progress;
+mod sync_schedule;
+mod tags;
 mod test_util;
 mod util;
+mod visibility;
+mod webhooks;
 
+use crate::background::BackgroundTask;
+use crate::cache::ResponseCache;
 use crate::errors::SetupError;
+use crate::patreon_api::{PatreonApi, ReqwestPatreonApi};
+use crate::patreon_oauth::PatreonOAuthClient;
+use crate::redis_pubsub::pubsub::{MessageStats, SubscriberHealth};
+use crate::sync_schedule::SyncSchedule;
 use anyhow::Error;
 use mongodb::{options::ClientOptions, Client, Database};
 use router::create_api_router;
-use shuttle_runtime::SecretStore;
-use tracing::{debug, error};
+use shuttle_runtime::{CustomError, SecretStore};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tracing::{debug, error, info};
 
 #[derive(Clone)]
 pub struct AppState {
@@ -26,46 +54,174 @@ pub struct AppState {
     pub jwt_key: String,
     pub server_domain: String,
     pub client_domain: String,
-    pub patreon_access_token: String,
+    pub patreon_campaign_ids: Vec<String>,
+    pub patreon_api: Arc<dyn PatreonApi>,
+    pub sync_schedule: Option<SyncSchedule>,
+    pub patreon_webhook_secret: String,
+    pub message_stats: Arc<MessageStats>,
+    pub subscriber_health: Arc<SubscriberHealth>,
+    pub cache: Arc<ResponseCache>,
+    pub patreon_oauth: PatreonOAuthClient,
+    /// Proxies in front of the server whose `X-Forwarded-For` entries are trusted.
+    pub trusted_proxy_count: usize,
 }
 
 #[shuttle_runtime::main]
 async fn main(
     #[shuttle_shared_db::MongoDb] _mongo: Database,
     #[shuttle_runtime::Secrets] secret_store: SecretStore,
-) -> shuttle_axum::ShuttleAxum {
-    let (
-        jwt_key,
-        server_domain,
-        client_domain,
-        mongo_id,
-        mongo_password,
-        patreon_access_token,
-        redis_connection_string,
-        db_name,
-    ) = grab_secrets(secret_store);
+) -> Result<GalleryService, shuttle_runtime::Error> {
+    let secrets = grab_secrets(secret_store);
 
-    let db = connect_mongo(mongo_id, mongo_password, db_name).await?;
-    let redis = connect_redis(redis_connection_string)?;
+    let db = connect_mongo(secrets.mongo_id, secrets.mongo_password, secrets.db_name).await?;
+    if let Err(error) = posts::create_post_indexes(db.clone()).await {
+        error!("failed to create post indexes {}", error);
+    }
+    if let Err(error) = posts::backfill_post_slugs(db.clone()).await {
+        error!("failed to backfill post slugs {}", error);
+    }
+    if let Err(error) = posts::normalize_post_dates(db.clone()).await {
+        error!("failed to normalize post dates {}", error);
+    }
+    if let Err(error) = sync_post::normalize_sync_result_dates(db.clone()).await {
+        error!("failed to normalize sync result dates {}", error);
+    }
+    if let Err(error) = tags::create_tag_indexes(db.clone()).await {
+        error!("failed to create tag indexes {}", error);
+    }
+    if let Err(error) = auth::create_admin_user_indexes(db.clone()).await {
+        error!("failed to create admin user indexes {}", error);
+    }
+    if let Err(error) = api_key::create_api_key_indexes(db.clone()).await {
+        error!("failed to create api key indexes {}", error);
+    }
+    if let Err(error) =
+        auth::bootstrap_admin(db.clone(), &secrets.admin_name, &secrets.admin_password).await
+    {
+        error!("failed to bootstrap admin {}", error);
+    }
+    let redis = connect_redis(secrets.redis_connection_string)?;
+    let sync_schedule = SyncSchedule::parse(&secrets.sync_schedule).unwrap_or_else(|error| {
+        error!("{}, scheduled sync is disabled", error);
+        None
+    });
+    let trusted_proxy_count = match secrets.trusted_proxy_count.trim() {
+        "" => 0,
+        count => count.parse().unwrap_or_else(|error| {
+            error!("invalid trusted proxy count {}, {}", count, error);
+            0
+        }),
+    };
 
     // redis_pubsub::pubsub::publish_message(redis.clone(), redis_pubsub::message::Message::new());
 
     let state = AppState {
         mongo: db,
         redis,
-        jwt_key,
-        server_domain,
-        client_domain,
-        patreon_access_token,
+        jwt_key: secrets.jwt_key,
+        server_domain: secrets.server_domain,
+        client_domain: secrets.client_domain,
+        patreon_campaign_ids: sync_post::parse_campaign_ids(&secrets.patreon_campaign_ids),
+        patreon_api: Arc::new(ReqwestPatreonApi::new(
+            &secrets.patreon_api_base_url,
+            &secrets.patreon_access_token,
+        )),
+        patreon_oauth: PatreonOAuthClient::new(
+            &secrets.patreon_api_base_url,
+            &secrets.patreon_client_id,
+            &secrets.patreon_client_secret,
+            &secrets.patreon_redirect_uri,
+        ),
+        sync_schedule,
+        patreon_webhook_secret: secrets.patreon_webhook_secret,
+        message_stats: Arc::new(MessageStats::default()),
+        subscriber_health: Arc::new(SubscriberHealth::default()),
+        cache: Arc::new(ResponseCache::default()),
+        trusted_proxy_count,
     };
 
-    if let Err(error) = redis_pubsub::pubsub::subscribe(state.clone()) {
-        error!("failed to subscribe channel {}", error);
-    } else {
-        debug!("subscribe channel");
+    let subscriber =
+        redis_pubsub::pubsub::subscribe(state.clone(), redis_pubsub::pubsub::default_dispatcher());
+    debug!("subscribe channel");
+
+    let worker = job_queue::spawn_job_worker(state.clone());
+    debug!("start job worker");
+
+    let scheduler = state.sync_schedule.clone().map(|schedule| {
+        debug!("schedule sync at {}", schedule.expression());
+        sync_schedule::spawn_sync_scheduler(state.redis.clone(), schedule)
+    });
+
+    Ok(GalleryService {
+        router: app(state),
+        subscriber,
+        worker,
+        scheduler,
+    })
+}
+
+/// Serves the API, and stops the background tasks once the server stops.
+pub struct GalleryService {
+    router: Router,
+    subscriber: BackgroundTask,
+    worker: BackgroundTask,
+    scheduler: Option<BackgroundTask>,
+}
+
+#[shuttle_runtime::async_trait]
+impl shuttle_runtime::Service for GalleryService {
+    async fn bind(self, addr: SocketAddr) -> Result<(), shuttle_runtime::Error> {
+        let listener = TcpListener::bind(addr).await.map_err(CustomError::new)?;
+
+        // the login rate limit goes by the peer address unless a proxy is trusted
+        let result = axum::serve(
+            listener,
+            self.router
+                .into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .with_graceful_shutdown(shutdown_signal())
+        .await;
+        info!("server stopped, shutting down background tasks");
+
+        if let Some(scheduler) = self.scheduler {
+            scheduler.shutdown().await;
+        }
+        self.subscriber.shutdown().await;
+        // lets a running job finish, an unfinished one is retried by another instance
+        self.worker.shutdown().await;
+
+        result.map_err(CustomError::new)?;
+        Ok(())
     }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(error) = tokio::signal::ctrl_c().await {
+            error!("failed to listen for ctrl-c {}", error);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(error) => {
+                error!("failed to listen for SIGTERM {}", error);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
 
-    Ok(app(state).into())
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
 }
 
 fn app(state: AppState) -> Router {
@@ -75,18 +231,29 @@ fn app(state: AppState) -> Router {
     Router::new().nest("/api", api_router)
 }
 
-fn grab_secrets(
-    secrets: SecretStore,
-) -> (
-    String,
-    String,
-    String,
-    String,
-    String,
-    String,
-    String,
-    String,
-) {
+/// Every secret the service reads, with its default filled in when unset.
+struct Secrets {
+    jwt_key: String,
+    server_domain: String,
+    client_domain: String,
+    mongo_id: String,
+    mongo_password: String,
+    patreon_access_token: String,
+    redis_connection_string: String,
+    db_name: String,
+    patreon_campaign_ids: String,
+    patreon_api_base_url: String,
+    sync_schedule: String,
+    patreon_webhook_secret: String,
+    admin_name: String,
+    admin_password: String,
+    patreon_client_id: String,
+    patreon_client_secret: String,
+    patreon_redirect_uri: String,
+    trusted_proxy_count: String,
+}
+
+fn grab_secrets(secrets: SecretStore) -> Secrets {
     let jwt_key = secrets
         .get("JWT_SECRET")
         .unwrap_or_else(|| "None".to_string());
@@ -117,7 +284,33 @@ fn grab_secrets(
 
     let db_name = secrets.get("DB_NAME").unwrap_or_else(|| "None".to_string());
 
-    (
+    let patreon_campaign_ids = secrets
+        .get("PATREON_CAMPAIGN_IDS")
+        .unwrap_or_else(|| sync_post::DEFAULT_CAMPAIGN_ID.to_string());
+
+    let patreon_api_base_url = secrets
+        .get("PATREON_API_BASE_URL")
+        .unwrap_or_else(|| patreon_api::DEFAULT_PATREON_API_BASE_URL.to_string());
+
+    let sync_schedule = secrets.get("SYNC_SCHEDULE").unwrap_or_default();
+
+    let patreon_webhook_secret = secrets
+        .get("PATREON_WEBHOOK_SECRET")
+        .unwrap_or_else(|| "None".to_string());
+
+    // only read while no admin exists, see `auth::bootstrap_admin`
+    let admin_name = secrets.get("ADMIN_NAME").unwrap_or_default();
+    let admin_password = secrets.get("ADMIN_PASSWORD").unwrap_or_default();
+
+    // Patreon login is off while any of them is missing
+    let patreon_client_id = secrets.get("PATREON_CLIENT_ID").unwrap_or_default();
+    let patreon_client_secret = secrets.get("PATREON_CLIENT_SECRET").unwrap_or_default();
+    let patreon_redirect_uri = secrets.get("PATREON_REDIRECT_URI").unwrap_or_default();
+
+    // how many proxies add to `X-Forwarded-For` in front of the server, none by default
+    let trusted_proxy_count = secrets.get("TRUSTED_PROXY_COUNT").unwrap_or_default();
+
+    Secrets {
         jwt_key,
         server_domain,
         client_domain,
@@ -126,7 +319,17 @@ fn grab_secrets(
         patreon_access_token,
         redis_connection_string,
         db_name,
-    )
+        patreon_campaign_ids,
+        patreon_api_base_url,
+        sync_schedule,
+        patreon_webhook_secret,
+        admin_name,
+        admin_password,
+        patreon_client_id,
+        patreon_client_secret,
+        patreon_redirect_uri,
+        trusted_proxy_count,
+    }
 }
 
 async fn connect_mongo(
@@ -162,18 +365,32 @@ fn connect_redis(connection_string: String) -> anyhow::Result<redis::Client> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::api_key::{ApiKeyResponse, CreatedApiKeyResponse, API_KEY_HEADER};
+    use crate::auth::LoginResponse;
+    use crate::cache::CacheStatsResponse;
+    use crate::dao::Page;
+    use crate::patron::PatronLoginResponse;
+    use crate::permission::{Permission, MODERATOR_ROLE};
     use crate::posts::Post;
+    use crate::router::Readiness;
+    use crate::search::PostSearchResult;
+    use crate::sync_job::{SyncJobLock, SyncJobStarted};
     use crate::test_util::test_util::{
         count_all_posts, create_test_state, find_post_by_id, generate_port_number,
-        generate_test_jwt_token, get_db_connection_uri, get_mongo_image, get_redis_connection_uri,
-        get_redis_image, insert_test_post, populate_test_data,
+        generate_test_jwt_token, generate_test_jwt_token_for_role, get_db_connection_uri,
+        get_mongo_image, get_redis_connection_uri, get_redis_image, insert_test_post,
+        populate_test_data, PATREON_IDENTITY,
     };
+    use crate::visibility::PostVisibility;
     use ::axum_test::TestServer;
+    use axum::http::StatusCode;
     use axum::http::{HeaderName, HeaderValue};
     use mongodb::bson::to_document;
     use mongodb::{bson::Bson, Client};
     use serde_json::json;
     use testcontainers_modules::{redis::REDIS_PORT, testcontainers::clients};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
 
     #[tokio::test]
     async fn test_hello_world() {
@@ -225,22 +442,24 @@ mod tests {
         let response = server.get("/api/posts").await;
 
         response.assert_status_ok();
-        let response_posts = response.json::<Vec<posts::Post>>();
-        assert_eq!(response_posts.len(), 2);
+        let response_page = response.json::<Page<posts::Post>>();
+        assert_eq!(response_page.items.len(), 2);
+        assert_eq!(response_page.total_count, 2);
+        assert!(response_page.next_cursor.is_none());
         // let k = response.as_bytes();
         // let kkk: Value = serde_json::from_slice(k).unwrap();
         // assert_eq!(kkk, [json!({})]);
     }
 
     #[tokio::test]
-    async fn test_get_post_by_id_invalid_id() {
+    async fn test_get_all_posts_paginated() {
         let docker = clients::Cli::default();
         let port = generate_port_number();
         let mongo_img = get_mongo_image(&port);
         let redis_img = get_redis_image();
         let _c = docker.run(mongo_img);
         let redis_node = docker.run(redis_img);
-
+        populate_test_data(&port);
         let uri = get_db_connection_uri(&port);
         let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
         let client = Client::with_uri_str(uri).await.unwrap();
@@ -248,29 +467,50 @@ mod tests {
 
         let test_db = client.database("test_db");
 
-        let state = create_test_state(test_db.clone(), redis_client);
+        let state = create_test_state(test_db, redis_client);
         let app = app(state);
 
-        let invalid_id = "invalid id";
-
         let server = TestServer::new(app).unwrap();
 
         let response = server
-            .post(format!("/api/posts/{}", invalid_id).as_str())
+            .get("/api/posts")
+            .add_query_param("limit", 1)
+            .add_query_param("sort", "title")
+            .add_query_param("order", "desc")
             .await;
 
-        response.assert_status_bad_request();
+        response.assert_status_ok();
+        let first_page = response.json::<Page<Post>>();
+        assert_eq!(first_page.items.len(), 1);
+        assert_eq!(first_page.total_count, 2);
+        let first_post = to_document(&first_page.items[0]).unwrap();
+        assert_eq!(first_post.get_str("title").unwrap(), "Test Post 2");
+
+        let response = server
+            .get("/api/posts")
+            .add_query_param("limit", 1)
+            .add_query_param("sort", "title")
+            .add_query_param("order", "desc")
+            .add_query_param("cursor", first_page.next_cursor.unwrap())
+            .await;
+
+        response.assert_status_ok();
+        let second_page = response.json::<Page<Post>>();
+        assert_eq!(second_page.items.len(), 1);
+        assert!(second_page.next_cursor.is_none());
+        let second_post = to_document(&second_page.items[0]).unwrap();
+        assert_eq!(second_post.get_str("title").unwrap(), "Test Post 1");
     }
 
     #[tokio::test]
-    async fn test_get_post_by_id_not_found() {
+    async fn test_get_all_posts_cached() {
         let docker = clients::Cli::default();
         let port = generate_port_number();
         let mongo_img = get_mongo_image(&port);
         let redis_img = get_redis_image();
         let _c = docker.run(mongo_img);
         let redis_node = docker.run(redis_img);
-
+        populate_test_data(&port);
         let uri = get_db_connection_uri(&port);
         let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
         let client = Client::with_uri_str(uri).await.unwrap();
@@ -278,29 +518,62 @@ mod tests {
 
         let test_db = client.database("test_db");
 
-        let state = create_test_state(test_db.clone(), redis_client);
+        let state = create_test_state(test_db, redis_client);
         let app = app(state);
 
-        let invalid_id = "659e79f831f22dc0395699b2";
-
         let server = TestServer::new(app).unwrap();
 
+        let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
+        let header_value =
+            HeaderValue::from_str(&format!("Bearer {}", generate_test_jwt_token())).unwrap();
+
+        for _ in 0..2 {
+            let response = server.get("/api/posts").await;
+
+            response.assert_status_ok();
+            assert_eq!(response.json::<Page<Post>>().total_count, 2);
+        }
+
         let response = server
-            .post(format!("/api/posts/{}", invalid_id).as_str())
+            .get("/api/cache/stats")
+            .add_header(header_name.clone(), header_value.clone())
             .await;
 
-        response.assert_status_not_found()
+        response.assert_status_ok();
+        let stats = response.json::<CacheStatsResponse>();
+        assert_eq!(stats.hit_count, 1);
+        assert_eq!(stats.miss_count, 1);
+
+        let insert_result = server
+            .post("/api/posts/create")
+            .json(&json!({
+                "title": "aa",
+                "content": "content",
+                "imagesUrl": [],
+                "fileUrl": "aa",
+                "modType": "preset",
+            }))
+            .add_header(header_name, header_value)
+            .await;
+
+        insert_result.assert_status_ok();
+
+        // the new post is listed right away
+        let response = server.get("/api/posts").await;
+
+        response.assert_status_ok();
+        assert_eq!(response.json::<Page<Post>>().total_count, 3);
     }
 
     #[tokio::test]
-    async fn test_get_post_by_id() {
+    async fn test_get_all_posts_filter_by_mod_type() {
         let docker = clients::Cli::default();
         let port = generate_port_number();
         let mongo_img = get_mongo_image(&port);
         let redis_img = get_redis_image();
         let _c = docker.run(mongo_img);
         let redis_node = docker.run(redis_img);
-
+        populate_test_data(&port);
         let uri = get_db_connection_uri(&port);
         let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
         let client = Client::with_uri_str(uri).await.unwrap();
@@ -308,199 +581,129 @@ mod tests {
 
         let test_db = client.database("test_db");
 
-        let state = create_test_state(test_db.clone(), redis_client);
+        let state = create_test_state(test_db, redis_client);
         let app = app(state);
 
-        let data = r#"
-            {
-                "_id": "659e79f831f22dc0395699b2",
-                "patreon_post_id": "123123",
-                "title": "test title",
-                "content": "qweqwe",
-                "images_url": [],
-                "file_url": "test url",
-                "mod_type": "qweqwe",
-                "created_at": "2024-01-23T13:48:06.761Z",
-                "updated_at": "2024-01-23T13:48:06.761Z",
-                "synced_at": "2024-01-23T13:48:06.761Z"
-            }
-        "#;
-        let new_post: Post = serde_json::from_str(data).unwrap();
-        let inserted_post_object_id = insert_test_post(test_db.clone(), new_post).await;
-
         let server = TestServer::new(app).unwrap();
 
         let response = server
-            .post(format!("/api/posts/{}", inserted_post_object_id.to_hex()).as_str())
+            .get("/api/posts")
+            .add_query_param("mod_type", "outfit")
             .await;
 
         response.assert_status_ok();
+        let response_page = response.json::<Page<Post>>();
+        assert_eq!(response_page.items.len(), 0);
+        assert_eq!(response_page.total_count, 0);
 
-        let response_post = response.json::<Post>();
-        let post_doc = to_document(&response_post).unwrap();
-        assert_eq!(post_doc.get_str("patreon_post_id").unwrap(), "123123");
-        assert_eq!(post_doc.get_str("title").unwrap(), "test title");
-    }
+        let response = server
+            .get("/api/posts")
+            .add_query_param("sort", "popularity")
+            .await;
 
-    #[tokio::test]
-    async fn test_edit_post_unauthorized() {}
+        response.assert_status_bad_request();
+    }
 
     #[tokio::test]
-    async fn test_edit_post_invalid_id() {
+    async fn test_get_all_posts_hides_orphaned_posts() {
         let docker = clients::Cli::default();
         let port = generate_port_number();
         let mongo_img = get_mongo_image(&port);
         let redis_img = get_redis_image();
         let _c = docker.run(mongo_img);
         let redis_node = docker.run(redis_img);
-
+        populate_test_data(&port);
         let uri = get_db_connection_uri(&port);
         let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
         let client = Client::with_uri_str(uri).await.unwrap();
         let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
 
         let test_db = client.database("test_db");
+        let orphaned_post = Post::new_for_sync(
+            "8365446",
+            "123",
+            "Removed Post",
+            "",
+            "2024-03-15T00:00:00.000+00:00",
+        )
+        .orphaned();
+        insert_test_post(test_db.clone(), orphaned_post).await;
 
-        let state = create_test_state(test_db.clone(), redis_client);
+        let state = create_test_state(test_db, redis_client);
         let app = app(state);
 
-        let invalid_id = "invalid id";
-        let updated_title = "updated test post".to_string();
-        let updated_image_url = vec!["one two three".to_string()];
-        let updated_file_url = "updated file url".to_string();
-
         let server = TestServer::new(app).unwrap();
 
+        let response = server.get("/api/posts").await;
+
+        response.assert_status_ok();
+        let response_page = response.json::<Page<Post>>();
+        assert_eq!(response_page.total_count, 2);
+        assert!(response_page.items.iter().all(|post| !post.is_orphaned()));
+
         let response = server
-            .put(format!("/api/posts/{}", invalid_id).as_str())
-            .content_type(&"application/json")
-            .json(&json!({
-                "title": updated_title.clone(),
-                "imagesUrl": updated_image_url.clone(),
-                "fileUrl": updated_file_url.clone(),
-            }))
+            .get("/api/posts/admin")
+            .add_query_param("orphaned", "true")
             .await;
 
         response.assert_status_unauthorized();
-    }
-
-    #[tokio::test]
-    async fn test_eidt_post_not_found() {
-        let docker = clients::Cli::default();
-        let port = generate_port_number();
-        let mongo_img = get_mongo_image(&port);
-        let redis_img = get_redis_image();
-        let _c = docker.run(mongo_img);
-        let redis_node = docker.run(redis_img);
-
-        let uri = get_db_connection_uri(&port);
-        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
-        let client = Client::with_uri_str(uri).await.unwrap();
-        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
-
-        let test_db = client.database("test_db");
-
-        let state = create_test_state(test_db.clone(), redis_client);
-        let app = app(state);
-
-        let invalid_id = "659e79f831f22dc0395699b2";
-        let updated_title = "updated test post".to_string();
-        let updated_content = "content".to_string();
-        let updated_image_url = vec!["one two three".to_string()];
-        let updated_file_url = "updated file url".to_string();
-        let updated_mod_type = "mod".to_string();
-
-        let server = TestServer::new(app).unwrap();
 
         let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
         let header_value =
             HeaderValue::from_str(&format!("Bearer {}", generate_test_jwt_token())).unwrap();
         let response = server
-            .put(format!("/api/posts/{}", invalid_id).as_str())
-            .content_type(&"application/json")
-            .json(&json!({
-                "title": updated_title.clone(),
-                "content": updated_content.clone(),
-                "imagesUrl": updated_image_url.clone(),
-                "fileUrl": updated_file_url.clone(),
-                "modType": updated_mod_type.clone(),
-            }))
+            .get("/api/posts/admin")
+            .add_query_param("orphaned", "true")
             .add_header(header_name, header_value)
             .await;
 
-        response.assert_status_not_found();
+        response.assert_status_ok();
+        let response_page = response.json::<Page<Post>>();
+        assert_eq!(response_page.total_count, 1);
+        assert!(response_page.items[0].is_orphaned());
     }
 
     #[tokio::test]
-    async fn test_edit_post() {
+    async fn test_search_posts() {
         let docker = clients::Cli::default();
         let port = generate_port_number();
         let mongo_img = get_mongo_image(&port);
         let redis_img = get_redis_image();
         let _c = docker.run(mongo_img);
         let redis_node = docker.run(redis_img);
-
+        populate_test_data(&port);
         let uri = get_db_connection_uri(&port);
         let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
         let client = Client::with_uri_str(uri).await.unwrap();
         let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
 
         let test_db = client.database("test_db");
+        posts::create_post_indexes(test_db.clone()).await.unwrap();
 
-        let state = create_test_state(test_db.clone(), redis_client);
+        let state = create_test_state(test_db, redis_client);
         let app = app(state);
 
-        let new_post_title = "aa".to_string();
-        let new_post_content = "content".to_string();
-        let new_post_images_url: Vec<String> = vec![];
-        let new_post_file_url = "aa".to_string();
-        let new_post_mod_type = "preset".to_string();
-
         let server = TestServer::new(app).unwrap();
 
-        let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
-        let header_value =
-            HeaderValue::from_str(&format!("Bearer {}", generate_test_jwt_token())).unwrap();
-        let insert_result = server
-            .post("/api/posts/create")
-            .content_type(&"application/json")
-            .json(&json!({
-                "title": new_post_title.clone(),
-                "content": new_post_content.clone(),
-                "imagesUrl": new_post_images_url.clone(),
-                "fileUrl": new_post_file_url.clone(),
-                "modType": new_post_mod_type.clone(),
-            }))
-            .add_header(header_name.clone(), header_value.clone())
-            .await;
-
-        let inserted_post_id = insert_result.json::<Bson>();
-        let object_id = inserted_post_id.as_object_id().unwrap();
-
-        let updated_title = "updated test post".to_string();
-        let updated_content = "content".to_string();
-        let updated_image_url = vec!["one two three".to_string()];
-        let updated_file_url = "updated file url".to_string();
-        let updated_mod_type = "mod".to_string();
-
         let response = server
-            .put(format!("/api/posts/{}", object_id.to_hex()).as_str())
-            .content_type(&"application/json")
-            .json(&json!({
-                "title": updated_title.clone(),
-                "content": updated_content.clone(),
-                "imagesUrl": updated_image_url.clone(),
-                "fileUrl": updated_file_url.clone(),
-                "modType": updated_mod_type.clone(),
-            }))
-            .add_header(header_name.clone(), header_value.clone())
+            .get("/api/posts/search")
+            .add_query_param("q", "hohoho")
             .await;
 
         response.assert_status_ok();
+        let response_page = response.json::<Page<PostSearchResult>>();
+        assert_eq!(response_page.total_count, 1);
+        assert_eq!(response_page.items.len(), 1);
+
+        let result = &response_page.items[0];
+        let post_doc = to_document(&result.post).unwrap();
+        assert_eq!(post_doc.get_str("title").unwrap(), "Test Post 1");
+        assert!(result.score > 0.0);
+        assert_eq!(result.content_snippet, "<mark>hohoho</mark>");
     }
 
     #[tokio::test]
-    async fn test_delete_post_unauthorized() {
+    async fn test_search_posts_without_query() {
         let docker = clients::Cli::default();
         let port = generate_port_number();
         let mongo_img = get_mongo_image(&port);
@@ -515,20 +718,18 @@ mod tests {
 
         let test_db = client.database("test_db");
 
-        let state = create_test_state(test_db.clone(), redis_client);
+        let state = create_test_state(test_db, redis_client);
         let app = app(state);
 
         let server = TestServer::new(app).unwrap();
 
-        let response = server
-            .delete(format!("/api/posts/{}", "invalid_id").as_str())
-            .await;
+        let response = server.get("/api/posts/search").await;
 
-        response.assert_status_unauthorized();
+        response.assert_status_bad_request();
     }
 
     #[tokio::test]
-    async fn test_delete_post_invalid_id() {
+    async fn test_get_post_by_id_invalid_id() {
         let docker = clients::Cli::default();
         let port = generate_port_number();
         let mongo_img = get_mongo_image(&port);
@@ -550,19 +751,15 @@ mod tests {
 
         let server = TestServer::new(app).unwrap();
 
-        let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
-        let header_value =
-            HeaderValue::from_str(&format!("Bearer {}", generate_test_jwt_token())).unwrap();
         let response = server
-            .delete(format!("/api/posts/{}", invalid_id).as_str())
-            .add_header(header_name, header_value)
+            .get(format!("/api/posts/{}", invalid_id).as_str())
             .await;
 
         response.assert_status_bad_request();
     }
 
     #[tokio::test]
-    async fn test_delete_post_not_found() {
+    async fn test_get_post_by_id_not_found() {
         let docker = clients::Cli::default();
         let port = generate_port_number();
         let mongo_img = get_mongo_image(&port);
@@ -584,19 +781,15 @@ mod tests {
 
         let server = TestServer::new(app).unwrap();
 
-        let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
-        let header_value =
-            HeaderValue::from_str(&format!("Bearer {}", generate_test_jwt_token())).unwrap();
         let response = server
-            .delete(format!("/api/posts/{}", invalid_id).as_str())
-            .add_header(header_name, header_value)
+            .get(format!("/api/posts/{}", invalid_id).as_str())
             .await;
 
-        response.assert_status_not_found();
+        response.assert_status_not_found()
     }
 
     #[tokio::test]
-    async fn test_delete_post() {
+    async fn test_get_post_by_id() {
         let docker = clients::Cli::default();
         let port = generate_port_number();
         let mongo_img = get_mongo_image(&port);
@@ -614,47 +807,131 @@ mod tests {
         let state = create_test_state(test_db.clone(), redis_client);
         let app = app(state);
 
-        let new_post_title = "aa".to_string();
-        let new_post_content = "content".to_string();
-        let new_post_images_url: Vec<String> = vec![];
-        let new_post_file_url = "aa".to_string();
-        let new_post_mod_type = "preset".to_string();
+        let data = r#"
+            {
+                "_id": "659e79f831f22dc0395699b2",
+                "patreon_post_id": "123123",
+                "title": "test title",
+                "content": "qweqwe",
+                "images_url": [],
+                "file_url": "test url",
+                "mod_type": "qweqwe",
+                "created_at": "2024-01-23T13:48:06.761Z",
+                "updated_at": "2024-01-23T13:48:06.761Z",
+                "synced_at": "2024-01-23T13:48:06.761Z"
+            }
+        "#;
+        let new_post: Post = serde_json::from_str(data).unwrap();
+        let inserted_post_object_id = insert_test_post(test_db.clone(), new_post).await;
+
+        let server = TestServer::new(app).unwrap();
+
+        let response = server
+            .get(format!("/api/posts/{}", inserted_post_object_id.to_hex()).as_str())
+            .await;
+
+        response.assert_status_ok();
+
+        let response_post = response.json::<Post>();
+        let post_doc = to_document(&response_post).unwrap();
+        assert_eq!(post_doc.get_str("patreon_post_id").unwrap(), "123123");
+        assert_eq!(post_doc.get_str("title").unwrap(), "test title");
+    }
+
+    #[tokio::test]
+    async fn test_get_post_by_slug() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+        posts::create_post_indexes(test_db.clone()).await.unwrap();
+
+        let state = create_test_state(test_db.clone(), redis_client);
+        let app = app(state);
 
         let server = TestServer::new(app).unwrap();
 
         let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
         let header_value =
             HeaderValue::from_str(&format!("Bearer {}", generate_test_jwt_token())).unwrap();
-        let insert_result = server
-            .post("/api/posts/create")
-            .content_type(&"application/json")
+
+        let mut post_ids = vec![];
+        for _ in 0..2 {
+            let insert_result = server
+                .post("/api/posts/create")
+                .json(&json!({
+                    "title": "Summer Preset",
+                    "content": "content",
+                    "imagesUrl": [],
+                    "fileUrl": "aa",
+                    "modType": "preset",
+                }))
+                .add_header(header_name.clone(), header_value.clone())
+                .await;
+
+            insert_result.assert_status_ok();
+            post_ids.push(insert_result.json::<Bson>());
+        }
+
+        let response = server.get("/api/posts/by-slug/summer-preset-2").await;
+
+        response.assert_status_ok();
+        let post_doc = to_document(&response.json::<Post>()).unwrap();
+        assert_eq!(
+            post_doc.get_object_id("_id").unwrap(),
+            post_ids[1].as_object_id().unwrap()
+        );
+
+        // renaming the post moves it to a new slug, the old one redirects there
+        let response = server
+            .put(
+                format!(
+                    "/api/posts/{}",
+                    post_ids[1].as_object_id().unwrap().to_hex()
+                )
+                .as_str(),
+            )
             .json(&json!({
-                "title": new_post_title.clone(),
-                "content": new_post_content.clone(),
-                "imagesUrl": new_post_images_url.clone(),
-                "fileUrl": new_post_file_url.clone(),
-                "modType": new_post_mod_type.clone(),
+                "title": "Winter Preset",
+                "content": "content",
+                "imagesUrl": [],
+                "fileUrl": "aa",
+                "modType": "preset",
             }))
-            .add_header(header_name.clone(), header_value.clone())
+            .add_header(header_name, header_value)
             .await;
 
-        let inserted_post_id = insert_result.json::<Bson>();
-        let object_id = inserted_post_id.as_object_id().unwrap();
+        response.assert_status_ok();
+        let post_doc = to_document(&response.json::<Post>()).unwrap();
+        assert_eq!(post_doc.get_str("slug").unwrap(), "winter-preset");
 
-        let delete_result = server
-            .delete(format!("/api/posts/{}", object_id.clone().to_hex()).as_str())
-            .add_header(header_name, header_value)
-            .await;
+        let response = server.get("/api/posts/by-slug/summer-preset-2").await;
 
-        delete_result.assert_status_ok();
+        response.assert_status(StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            response.header("location"),
+            "/api/posts/by-slug/winter-preset"
+        );
 
-        let find_result = find_post_by_id(test_db, object_id).await;
+        let response = server.get("/api/posts/by-slug/autumn-preset").await;
 
-        assert!(find_result.is_none());
+        response.assert_status_not_found();
     }
 
     #[tokio::test]
-    async fn test_create_new_post_unauthorized() {
+    async fn test_edit_post_unauthorized() {}
+
+    #[tokio::test]
+    async fn test_edit_post_invalid_id() {
         let docker = clients::Cli::default();
         let port = generate_port_number();
         let mongo_img = get_mongo_image(&port);
@@ -672,23 +949,75 @@ mod tests {
         let state = create_test_state(test_db.clone(), redis_client);
         let app = app(state);
 
+        let invalid_id = "invalid id";
+        let updated_title = "updated test post".to_string();
+        let updated_image_url = vec!["one two three".to_string()];
+        let updated_file_url = "updated file url".to_string();
+
         let server = TestServer::new(app).unwrap();
 
-        let insert_result = server
-            .post("/api/posts/create")
-            .content_type(&"application/json")
+        let response = server
+            .put(format!("/api/posts/{}", invalid_id).as_str())
+            .content_type("application/json")
             .json(&json!({
-                "title": "new_post_title",
-                "imagesUrl": [],
-                "fileUrl": "new_post_file_url",
+                "title": updated_title.clone(),
+                "imagesUrl": updated_image_url.clone(),
+                "fileUrl": updated_file_url.clone(),
             }))
             .await;
 
-        insert_result.assert_status_unauthorized();
+        response.assert_status_unauthorized();
     }
 
     #[tokio::test]
-    async fn test_create_new_post() {
+    async fn test_eidt_post_not_found() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+
+        let state = create_test_state(test_db.clone(), redis_client);
+        let app = app(state);
+
+        let invalid_id = "659e79f831f22dc0395699b2";
+        let updated_title = "updated test post".to_string();
+        let updated_content = "content".to_string();
+        let updated_image_url = vec!["one two three".to_string()];
+        let updated_file_url = "updated file url".to_string();
+        let updated_mod_type = "mod".to_string();
+
+        let server = TestServer::new(app).unwrap();
+
+        let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
+        let header_value =
+            HeaderValue::from_str(&format!("Bearer {}", generate_test_jwt_token())).unwrap();
+        let response = server
+            .put(format!("/api/posts/{}", invalid_id).as_str())
+            .content_type("application/json")
+            .json(&json!({
+                "title": updated_title.clone(),
+                "content": updated_content.clone(),
+                "imagesUrl": updated_image_url.clone(),
+                "fileUrl": updated_file_url.clone(),
+                "modType": updated_mod_type.clone(),
+            }))
+            .add_header(header_name, header_value)
+            .await;
+
+        response.assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn test_edit_post() {
         let docker = clients::Cli::default();
         let port = generate_port_number();
         let mongo_img = get_mongo_image(&port);
@@ -719,7 +1048,7 @@ mod tests {
             HeaderValue::from_str(&format!("Bearer {}", generate_test_jwt_token())).unwrap();
         let insert_result = server
             .post("/api/posts/create")
-            .content_type(&"application/json")
+            .content_type("application/json")
             .json(&json!({
                 "title": new_post_title.clone(),
                 "content": new_post_content.clone(),
@@ -727,28 +1056,43 @@ mod tests {
                 "fileUrl": new_post_file_url.clone(),
                 "modType": new_post_mod_type.clone(),
             }))
-            .add_header(header_name, header_value)
+            .add_header(header_name.clone(), header_value.clone())
             .await;
 
-        insert_result.assert_status_ok();
-
         let inserted_post_id = insert_result.json::<Bson>();
         let object_id = inserted_post_id.as_object_id().unwrap();
 
-        let find_result = find_post_by_id(test_db, object_id).await;
+        let updated_title = "updated test post".to_string();
+        let updated_content = "content".to_string();
+        let updated_image_url = vec!["one two three".to_string()];
+        let updated_file_url = "updated file url".to_string();
+        let updated_mod_type = "mod".to_string();
 
-        assert!(find_result.is_some());
+        let response = server
+            .put(format!("/api/posts/{}", object_id.to_hex()).as_str())
+            .content_type("application/json")
+            .json(&json!({
+                "title": updated_title.clone(),
+                "content": updated_content.clone(),
+                "imagesUrl": updated_image_url.clone(),
+                "fileUrl": updated_file_url.clone(),
+                "modType": updated_mod_type.clone(),
+            }))
+            .add_header(header_name.clone(), header_value.clone())
+            .await;
+
+        response.assert_status_ok();
     }
 
     #[tokio::test]
-    async fn test_delete_all_posts_unauthorized() {
+    async fn test_post_conditional_requests() {
         let docker = clients::Cli::default();
         let port = generate_port_number();
         let mongo_img = get_mongo_image(&port);
         let redis_img = get_redis_image();
         let _c = docker.run(mongo_img);
         let redis_node = docker.run(redis_img);
-        populate_test_data(&port);
+
         let uri = get_db_connection_uri(&port);
         let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
         let client = Client::with_uri_str(uri).await.unwrap();
@@ -756,18 +1100,108 @@ mod tests {
 
         let test_db = client.database("test_db");
 
-        let state = create_test_state(test_db, redis_client);
+        let state = create_test_state(test_db.clone(), redis_client);
         let app = app(state);
 
         let server = TestServer::new(app).unwrap();
 
-        let response = server.delete("/api/posts").await;
+        let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
+        let header_value =
+            HeaderValue::from_str(&format!("Bearer {}", generate_test_jwt_token())).unwrap();
+        let insert_result = server
+            .post("/api/posts/create")
+            .json(&json!({
+                "title": "aa",
+                "content": "content",
+                "imagesUrl": [],
+                "fileUrl": "aa",
+                "modType": "preset",
+            }))
+            .add_header(header_name.clone(), header_value.clone())
+            .await;
+
+        let inserted_post_id = insert_result.json::<Bson>();
+        let post_path = format!(
+            "/api/posts/{}",
+            inserted_post_id.as_object_id().unwrap().to_hex()
+        );
+
+        let response = server.get("/api/posts").await;
+        response.assert_status_ok();
+        let list_etag = response.header("etag");
+
+        let response = server
+            .get("/api/posts")
+            .add_header(HeaderName::from_static("if-none-match"), list_etag.clone())
+            .await;
+        response.assert_status(StatusCode::NOT_MODIFIED);
+
+        let response = server.get(post_path.as_str()).await;
+        response.assert_status_ok();
+        let post_etag = response.header("etag");
+
+        let edit = json!({
+            "title": "updated test post",
+            "content": "content",
+            "imagesUrl": [],
+            "fileUrl": "aa",
+            "modType": "preset",
+        });
+
+        let response = server
+            .put(post_path.as_str())
+            .json(&edit)
+            .add_header(header_name.clone(), header_value.clone())
+            .add_header(HeaderName::from_static("if-match"), post_etag.clone())
+            .await;
+        response.assert_status_ok();
+
+        // the post changed since its etag was read
+        let response = server
+            .put(post_path.as_str())
+            .json(&edit)
+            .add_header(header_name, header_value)
+            .add_header(HeaderName::from_static("if-match"), post_etag)
+            .await;
+        response.assert_status(StatusCode::PRECONDITION_FAILED);
+
+        let response = server
+            .get("/api/posts")
+            .add_header(HeaderName::from_static("if-none-match"), list_etag)
+            .await;
+        response.assert_status_ok();
+    }
+
+    #[tokio::test]
+    async fn test_delete_post_unauthorized() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+
+        let state = create_test_state(test_db.clone(), redis_client);
+        let app = app(state);
+
+        let server = TestServer::new(app).unwrap();
+
+        let response = server
+            .delete(format!("/api/posts/{}", "invalid_id").as_str())
+            .await;
 
         response.assert_status_unauthorized();
     }
 
     #[tokio::test]
-    async fn test_delete_all_posts() {
+    async fn test_delete_post_invalid_id() {
         let docker = clients::Cli::default();
         let port = generate_port_number();
         let mongo_img = get_mongo_image(&port);
@@ -785,20 +1219,1090 @@ mod tests {
         let state = create_test_state(test_db.clone(), redis_client);
         let app = app(state);
 
+        let invalid_id = "invalid id";
+
         let server = TestServer::new(app).unwrap();
 
         let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
         let header_value =
             HeaderValue::from_str(&format!("Bearer {}", generate_test_jwt_token())).unwrap();
         let response = server
-            .delete("/api/posts")
+            .delete(format!("/api/posts/{}", invalid_id).as_str())
             .add_header(header_name, header_value)
             .await;
 
-        response.assert_status_ok();
+        response.assert_status_bad_request();
+    }
 
-        let count = count_all_posts(test_db).await;
+    #[tokio::test]
+    async fn test_delete_post_not_found() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
 
-        assert_eq!(count, 0);
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+
+        let state = create_test_state(test_db.clone(), redis_client);
+        let app = app(state);
+
+        let invalid_id = "659e79f831f22dc0395699b2";
+
+        let server = TestServer::new(app).unwrap();
+
+        let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
+        let header_value =
+            HeaderValue::from_str(&format!("Bearer {}", generate_test_jwt_token())).unwrap();
+        let response = server
+            .delete(format!("/api/posts/{}", invalid_id).as_str())
+            .add_header(header_name, header_value)
+            .await;
+
+        response.assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn test_delete_post() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+
+        let state = create_test_state(test_db.clone(), redis_client);
+        let app = app(state);
+
+        let new_post_title = "aa".to_string();
+        let new_post_content = "content".to_string();
+        let new_post_images_url: Vec<String> = vec![];
+        let new_post_file_url = "aa".to_string();
+        let new_post_mod_type = "preset".to_string();
+
+        let server = TestServer::new(app).unwrap();
+
+        let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
+        let header_value =
+            HeaderValue::from_str(&format!("Bearer {}", generate_test_jwt_token())).unwrap();
+        let insert_result = server
+            .post("/api/posts/create")
+            .content_type("application/json")
+            .json(&json!({
+                "title": new_post_title.clone(),
+                "content": new_post_content.clone(),
+                "imagesUrl": new_post_images_url.clone(),
+                "fileUrl": new_post_file_url.clone(),
+                "modType": new_post_mod_type.clone(),
+            }))
+            .add_header(header_name.clone(), header_value.clone())
+            .await;
+
+        let inserted_post_id = insert_result.json::<Bson>();
+        let object_id = inserted_post_id.as_object_id().unwrap();
+
+        let delete_result = server
+            .delete(format!("/api/posts/{}", object_id.to_hex()).as_str())
+            .add_header(header_name, header_value)
+            .await;
+
+        delete_result.assert_status_ok();
+
+        let find_result = find_post_by_id(test_db, object_id).await;
+
+        assert!(find_result.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_create_new_post_unauthorized() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+
+        let state = create_test_state(test_db.clone(), redis_client);
+        let app = app(state);
+
+        let server = TestServer::new(app).unwrap();
+
+        let insert_result = server
+            .post("/api/posts/create")
+            .content_type("application/json")
+            .json(&json!({
+                "title": "new_post_title",
+                "imagesUrl": [],
+                "fileUrl": "new_post_file_url",
+            }))
+            .await;
+
+        insert_result.assert_status_unauthorized();
+    }
+
+    #[tokio::test]
+    async fn test_create_new_post() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+
+        let state = create_test_state(test_db.clone(), redis_client);
+        let app = app(state);
+
+        let new_post_title = "aa".to_string();
+        let new_post_content = "content".to_string();
+        let new_post_images_url: Vec<String> = vec![];
+        let new_post_file_url = "aa".to_string();
+        let new_post_mod_type = "preset".to_string();
+
+        let server = TestServer::new(app).unwrap();
+
+        let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
+        let header_value =
+            HeaderValue::from_str(&format!("Bearer {}", generate_test_jwt_token())).unwrap();
+        let insert_result = server
+            .post("/api/posts/create")
+            .content_type("application/json")
+            .json(&json!({
+                "title": new_post_title.clone(),
+                "content": new_post_content.clone(),
+                "imagesUrl": new_post_images_url.clone(),
+                "fileUrl": new_post_file_url.clone(),
+                "modType": new_post_mod_type.clone(),
+            }))
+            .add_header(header_name, header_value)
+            .await;
+
+        insert_result.assert_status_ok();
+
+        let inserted_post_id = insert_result.json::<Bson>();
+        let object_id = inserted_post_id.as_object_id().unwrap();
+
+        let find_result = find_post_by_id(test_db, object_id).await;
+
+        assert!(find_result.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_create_new_post_with_unknown_tag() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+
+        let state = create_test_state(test_db.clone(), redis_client);
+        let app = app(state);
+
+        let server = TestServer::new(app).unwrap();
+
+        let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
+        let header_value =
+            HeaderValue::from_str(&format!("Bearer {}", generate_test_jwt_token())).unwrap();
+        let insert_result = server
+            .post("/api/posts/create")
+            .content_type("application/json")
+            .json(&json!({
+                "title": "aa",
+                "content": "content",
+                "imagesUrl": [],
+                "fileUrl": "aa",
+                "modType": "preset",
+                "tagIds": ["659e79f831f22dc0395699b2"],
+            }))
+            .add_header(header_name, header_value)
+            .await;
+
+        insert_result.assert_status_bad_request();
+
+        let count = count_all_posts(test_db).await;
+
+        assert_eq!(count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_tag_crud_and_filter() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+
+        let state = create_test_state(test_db.clone(), redis_client);
+        let app = app(state);
+
+        let server = TestServer::new(app).unwrap();
+
+        let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
+        let header_value =
+            HeaderValue::from_str(&format!("Bearer {}", generate_test_jwt_token())).unwrap();
+
+        let unauthorized_result = server
+            .post("/api/tags")
+            .json(&json!({ "name": "Skyrim", "group": "game" }))
+            .await;
+
+        unauthorized_result.assert_status_unauthorized();
+
+        let tag_result = server
+            .post("/api/tags")
+            .json(&json!({ "name": "Skyrim", "group": "game" }))
+            .add_header(header_name.clone(), header_value.clone())
+            .await;
+
+        tag_result.assert_status_ok();
+        let tag_id = tag_result.json::<Bson>().as_object_id().unwrap().to_hex();
+
+        let insert_result = server
+            .post("/api/posts/create")
+            .json(&json!({
+                "title": "aa",
+                "content": "content",
+                "imagesUrl": [],
+                "fileUrl": "aa",
+                "modType": "preset",
+                "tagIds": [tag_id.clone()],
+            }))
+            .add_header(header_name.clone(), header_value.clone())
+            .await;
+
+        insert_result.assert_status_ok();
+        let post_id = insert_result
+            .json::<Bson>()
+            .as_object_id()
+            .unwrap()
+            .to_hex();
+
+        // an edit that leaves the tags out keeps them
+        let edit_result = server
+            .put(format!("/api/posts/{}", post_id).as_str())
+            .json(&json!({
+                "title": "aa",
+                "content": "edited content",
+                "imagesUrl": [],
+                "fileUrl": "aa",
+                "modType": "preset",
+            }))
+            .add_header(header_name.clone(), header_value.clone())
+            .await;
+
+        edit_result.assert_status_ok();
+        assert_eq!(
+            to_document(&edit_result.json::<Post>())
+                .unwrap()
+                .get_array("tags")
+                .unwrap(),
+            &vec![Bson::String(tag_id.clone())]
+        );
+
+        let response = server
+            .get("/api/posts")
+            .add_query_param("tags", tag_id.clone())
+            .await;
+
+        response.assert_status_ok();
+        assert_eq!(response.json::<Page<Post>>().total_count, 1);
+
+        let response = server.get("/api/tags").await;
+
+        response.assert_status_ok();
+        let tags = response.json::<serde_json::Value>();
+        assert_eq!(tags[0]["tag"]["name"], "Skyrim");
+        assert_eq!(tags[0]["post_count"], 1);
+
+        let delete_result = server
+            .delete(format!("/api/tags/{}", tag_id).as_str())
+            .add_header(header_name, header_value)
+            .await;
+
+        delete_result.assert_status_ok();
+
+        let response = server
+            .get("/api/posts")
+            .add_query_param("tags", tag_id)
+            .await;
+
+        assert_eq!(response.json::<Page<Post>>().total_count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_delete_all_posts_unauthorized() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+        populate_test_data(&port);
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+
+        let state = create_test_state(test_db, redis_client);
+        let app = app(state);
+
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.delete("/api/posts").await;
+
+        response.assert_status_unauthorized();
+    }
+
+    #[tokio::test]
+    async fn test_delete_all_posts() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+
+        let state = create_test_state(test_db.clone(), redis_client);
+        let app = app(state);
+
+        let server = TestServer::new(app).unwrap();
+
+        let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
+        let header_value =
+            HeaderValue::from_str(&format!("Bearer {}", generate_test_jwt_token())).unwrap();
+        let response = server
+            .delete("/api/posts")
+            .add_header(header_name, header_value)
+            .await;
+
+        response.assert_status_ok();
+
+        let count = count_all_posts(test_db).await;
+
+        assert_eq!(count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_login() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+        auth::create_admin_user_indexes(test_db.clone())
+            .await
+            .unwrap();
+        assert!(auth::bootstrap_admin(test_db.clone(), "Admin", "hunter2")
+            .await
+            .unwrap());
+        // only the first admin is bootstrapped
+        assert!(!auth::bootstrap_admin(test_db.clone(), "other", "hunter2")
+            .await
+            .unwrap());
+
+        let state = create_test_state(test_db, redis_client);
+        let app = app(state);
+
+        let server = TestServer::new(app).unwrap();
+
+        let response = server
+            .post("/api/auth/login")
+            .json(&json!({ "name": "admin", "password": "hunter2" }))
+            .await;
+
+        response.assert_status_ok();
+        let login_response = response.json::<LoginResponse>();
+        assert_eq!(login_response.token_type, "Bearer");
+
+        let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
+        let header_value =
+            HeaderValue::from_str(&format!("Bearer {}", login_response.access_token)).unwrap();
+        let response = server
+            .get("/api/posts/admin")
+            .add_header(header_name, header_value)
+            .await;
+
+        response.assert_status_ok();
+
+        // a burst in parallel gets no more tries than one after another
+        let responses = futures::future::join_all((0..8).map(|_| async {
+            server
+                .post("/api/auth/login")
+                .json(&json!({ "name": "admin", "password": "wrong" }))
+                .await
+        }))
+        .await;
+        let unauthorized_count = responses
+            .iter()
+            .filter(|response| response.status_code() == StatusCode::UNAUTHORIZED)
+            .count();
+        let limited_count = responses
+            .iter()
+            .filter(|response| response.status_code() == StatusCode::TOO_MANY_REQUESTS)
+            .count();
+        assert_eq!((unauthorized_count, limited_count), (5, 3));
+
+        // even the right password is turned down until the window passes
+        let response = server
+            .post("/api/auth/login")
+            .json(&json!({ "name": "admin", "password": "hunter2" }))
+            .await;
+
+        response.assert_status(StatusCode::TOO_MANY_REQUESTS);
+        assert!(response.maybe_header("retry-after").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_and_logout() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+        auth::bootstrap_admin(test_db.clone(), "admin", "hunter2")
+            .await
+            .unwrap();
+
+        let state = create_test_state(test_db, redis_client);
+        let app = app(state);
+
+        let server = TestServer::new(app).unwrap();
+
+        let login = |password: &'static str| {
+            server
+                .post("/api/auth/login")
+                .json(&json!({ "name": "admin", "password": password }))
+        };
+        let refresh = |refresh_token: &str| {
+            server
+                .post("/api/auth/refresh")
+                .json(&json!({ "refresh_token": refresh_token }))
+        };
+        let bearer = |access_token: &str| {
+            HeaderValue::from_str(&format!("Bearer {}", access_token)).unwrap()
+        };
+        let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
+
+        let first = login("hunter2").await.json::<LoginResponse>();
+
+        let response = refresh(&first.refresh_token).await;
+        response.assert_status_ok();
+        let second = response.json::<LoginResponse>();
+        assert_ne!(second.refresh_token, first.refresh_token);
+
+        // the spent token shows up again, so the whole family is revoked
+        refresh(&first.refresh_token)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        refresh(&second.refresh_token)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        server
+            .get("/api/posts/admin")
+            .add_header(header_name.clone(), bearer(&second.access_token))
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+
+        let third = login("hunter2").await.json::<LoginResponse>();
+        server
+            .get("/api/posts/admin")
+            .add_header(header_name.clone(), bearer(&third.access_token))
+            .await
+            .assert_status_ok();
+
+        server
+            .post("/api/auth/logout")
+            .add_header(header_name.clone(), bearer(&third.access_token))
+            .await
+            .assert_status_ok();
+
+        server
+            .get("/api/posts/admin")
+            .add_header(header_name, bearer(&third.access_token))
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+        refresh(&third.refresh_token)
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn test_readiness() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+
+        let state = create_test_state(test_db, redis_client);
+        let server = TestServer::new(app(state.clone())).unwrap();
+
+        let response = server.get("/api/ready").await;
+
+        response.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!response.json::<Readiness>().subscriber_connected);
+
+        let subscriber = redis_pubsub::pubsub::subscribe(
+            state.clone(),
+            redis_pubsub::pubsub::default_dispatcher(),
+        );
+        while !state.subscriber_health.is_connected() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+
+        let response = server.get("/api/ready").await;
+
+        response.assert_status_ok();
+        assert!(response.json::<Readiness>().is_ready);
+
+        subscriber.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn test_sync_lock() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+
+        let state = create_test_state(test_db, redis_client.clone());
+        let app = app(state);
+
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/api/sync/lock").await;
+
+        response.assert_status_unauthorized();
+
+        let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
+        let header_value =
+            HeaderValue::from_str(&format!("Bearer {}", generate_test_jwt_token())).unwrap();
+
+        let response = server
+            .get("/api/sync/lock")
+            .add_header(header_name.clone(), header_value.clone())
+            .await;
+
+        response.assert_status_ok();
+        assert_eq!(response.json::<Option<SyncJobLock>>(), None);
+
+        sync_job::create_sync_job(redis_client, "123123123").unwrap();
+
+        let response = server
+            .get("/api/sync/lock")
+            .add_header(header_name.clone(), header_value.clone())
+            .await;
+
+        response.assert_status_ok();
+        assert_eq!(
+            response.json::<Option<SyncJobLock>>().unwrap().job_id,
+            "123123123"
+        );
+
+        let response = server
+            .delete("/api/sync/lock")
+            .add_header(header_name.clone(), header_value.clone())
+            .await;
+
+        response.assert_status_ok();
+
+        let response = server
+            .delete("/api/sync/lock")
+            .add_header(header_name, header_value)
+            .await;
+
+        response.assert_status_not_found();
+    }
+
+    #[tokio::test]
+    async fn test_sync_posts() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+
+        let state = create_test_state(test_db, redis_client.clone());
+        let app = app(state);
+
+        let server = TestServer::new(app).unwrap();
+
+        let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
+        let header_value =
+            HeaderValue::from_str(&format!("Bearer {}", generate_test_jwt_token())).unwrap();
+
+        let response = server.get("/api/posts/sync").await;
+
+        response.assert_status(StatusCode::UNAUTHORIZED);
+
+        let response = server
+            .get("/api/posts/sync")
+            .add_query_param("mode", "full")
+            .add_header(header_name.clone(), header_value.clone())
+            .await;
+
+        response.assert_status(StatusCode::ACCEPTED);
+        assert!(!response.json::<SyncJobStarted>().job_id.is_empty());
+
+        // no worker runs here, so the first job is still queued
+        let response = server
+            .get("/api/posts/sync")
+            .add_header(header_name.clone(), header_value.clone())
+            .await;
+
+        response.assert_status(StatusCode::CONFLICT);
+
+        sync_job::create_sync_job(redis_client, "123123123").unwrap();
+
+        let response = server
+            .get("/api/posts/sync")
+            .add_header(header_name, header_value)
+            .await;
+
+        response.assert_status(StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_moderator_permissions() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+        populate_test_data(&port);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+
+        let state = create_test_state(test_db.clone(), redis_client);
+        let app = app(state);
+
+        let server = TestServer::new(app).unwrap();
+
+        let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
+        let header_value = HeaderValue::from_str(&format!(
+            "Bearer {}",
+            generate_test_jwt_token_for_role(MODERATOR_ROLE)
+        ))
+        .unwrap();
+
+        let response = server
+            .get("/api/posts/admin")
+            .add_header(header_name.clone(), header_value.clone())
+            .await;
+
+        response.assert_status_ok();
+
+        let response = server
+            .delete("/api/posts")
+            .add_header(header_name.clone(), header_value.clone())
+            .await;
+
+        response.assert_status(StatusCode::FORBIDDEN);
+        assert_eq!(count_all_posts(test_db).await, 2);
+
+        let response = server
+            .get("/api/posts/sync")
+            .add_header(header_name, header_value)
+            .await;
+
+        response.assert_status(StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_api_keys() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+        populate_test_data(&port);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+        api_key::create_api_key_indexes(test_db.clone())
+            .await
+            .unwrap();
+
+        let state = create_test_state(test_db.clone(), redis_client);
+        let app = app(state);
+
+        let server = TestServer::new(app).unwrap();
+
+        let auth_header_name = HeaderName::from_lowercase(b"authorization").unwrap();
+        let auth_header_value =
+            HeaderValue::from_str(&format!("Bearer {}", generate_test_jwt_token())).unwrap();
+
+        // a key can't be given the right to make more keys
+        let response = server
+            .post("/api/api-keys")
+            .add_header(auth_header_name.clone(), auth_header_value.clone())
+            .json(&json!({ "name": "Discord bot", "scopes": ["api_keys:manage"] }))
+            .await;
+
+        response.assert_status(StatusCode::BAD_REQUEST);
+
+        let response = server
+            .post("/api/api-keys")
+            .add_header(auth_header_name.clone(), auth_header_value.clone())
+            .json(&json!({ "name": "Discord bot", "scopes": ["posts:read_hidden"] }))
+            .await;
+
+        response.assert_status_ok();
+        let created = response.json::<CreatedApiKeyResponse>();
+        assert!(created.api_key.starts_with(&created.details.prefix));
+        assert_eq!(created.details.scopes, vec![Permission::PostsReadHidden]);
+
+        let key_header_name = HeaderName::from_static(API_KEY_HEADER);
+        let key_header_value = HeaderValue::from_str(&created.api_key).unwrap();
+
+        let response = server
+            .get("/api/posts/admin")
+            .add_header(key_header_name.clone(), key_header_value.clone())
+            .await;
+
+        response.assert_status_ok();
+
+        let response = server
+            .delete("/api/posts")
+            .add_header(key_header_name.clone(), key_header_value.clone())
+            .await;
+
+        response.assert_status(StatusCode::FORBIDDEN);
+        assert_eq!(count_all_posts(test_db).await, 2);
+
+        let response = server
+            .get("/api/api-keys")
+            .add_header(key_header_name.clone(), key_header_value.clone())
+            .await;
+
+        response.assert_status(StatusCode::FORBIDDEN);
+
+        // there's no session behind a key to log out of
+        let response = server
+            .post("/api/auth/logout")
+            .add_header(key_header_name.clone(), key_header_value.clone())
+            .await;
+
+        response.assert_status(StatusCode::BAD_REQUEST);
+
+        let response = server
+            .get("/api/api-keys")
+            .add_header(auth_header_name.clone(), auth_header_value.clone())
+            .await;
+
+        response.assert_status_ok();
+        let api_keys = response.json::<Vec<ApiKeyResponse>>();
+        assert_eq!(api_keys.len(), 1);
+        assert_eq!(api_keys[0].id, created.details.id);
+
+        let response = server
+            .delete(&format!("/api/api-keys/{}", created.details.id))
+            .add_header(auth_header_name.clone(), auth_header_value.clone())
+            .await;
+
+        response.assert_status_ok();
+        assert!(response.json::<ApiKeyResponse>().revoked_at.is_some());
+
+        let response = server
+            .get("/api/posts/admin")
+            .add_header(key_header_name, key_header_value)
+            .await;
+
+        response.assert_status(StatusCode::UNAUTHORIZED);
+
+        let response = server
+            .delete(&format!("/api/api-keys/{}", created.details.id))
+            .add_header(auth_header_name, auth_header_value)
+            .await;
+
+        response.assert_status(StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_patron_entitlement() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+        let content = "<p><a href=\"https://mega.nz/file/QwErTy#abc987\">Download</a></p>";
+        let locked_id = insert_test_post(
+            test_db.clone(),
+            Post::new_for_sync("8365446", "1", "Locked", content, "").with_visibility(
+                PostVisibility::MinimumTier {
+                    tier_ids: vec!["5678902".to_string()],
+                },
+            ),
+        )
+        .await;
+
+        let state = create_test_state(test_db, redis_client);
+        let app = app(state);
+
+        let server = TestServer::new(app).unwrap();
+        let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
+        let bearer = |token: &str| HeaderValue::from_str(&format!("Bearer {}", token)).unwrap();
+        let post_path = format!("/api/posts/{}", locked_id.to_hex());
+
+        let response = server.get(&post_path).await;
+
+        response.assert_status_ok();
+        let post_doc = to_document(&response.json::<Post>()).unwrap();
+        assert_eq!(post_doc.get_str("file_url").unwrap(), "");
+        assert_eq!(post_doc.get_str("content").unwrap(), "");
+        let locked_etag = response.header("etag");
+
+        // an expired or forged token is only ignored on the public routes
+        let response = server
+            .get(&post_path)
+            .add_header(header_name.clone(), bearer("garbage"))
+            .await;
+
+        response.assert_status_ok();
+
+        for (tier_id, file_url) in [
+            ("5678901", ""),
+            ("5678902", "https://mega.nz/file/QwErTy#abc987"),
+        ] {
+            let (patron_token, _) = jwt_auth::issue_patron_access_token(
+                "test_jwt_key",
+                "patreon:1234567",
+                vec![tier_id.to_string()],
+            )
+            .unwrap();
+
+            let response = server
+                .get("/api/posts")
+                .add_header(header_name.clone(), bearer(&patron_token))
+                .await;
+
+            response.assert_status_ok();
+            let page = response.json::<Page<Post>>();
+            let post_doc = to_document(&page.items[0]).unwrap();
+            assert_eq!(post_doc.get_str("file_url").unwrap(), file_url);
+        }
+
+        let response = server
+            .get(&post_path)
+            .add_header(header_name.clone(), bearer(&generate_test_jwt_token()))
+            .add_header(
+                HeaderName::from_lowercase(b"if-none-match").unwrap(),
+                locked_etag,
+            )
+            .await;
+
+        // the redacted copy isn't the one an admin gets
+        response.assert_status_ok();
+        let post_doc = to_document(&response.json::<Post>()).unwrap();
+        assert_eq!(
+            post_doc.get_str("file_url").unwrap(),
+            "https://mega.nz/file/QwErTy#abc987"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_patreon_login() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let patreon_server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/oauth2/token"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({ "access_token": "patron_token" })),
+            )
+            .mount(&patreon_server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/oauth2/v2/identity"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(PATREON_IDENTITY))
+            .mount(&patreon_server)
+            .await;
+
+        let test_db = client.database("test_db");
+        let mut state = create_test_state(test_db, redis_client);
+        state.patreon_oauth = PatreonOAuthClient::new(
+            &patreon_server.uri(),
+            "test_client_id",
+            "test_client_secret",
+            "http://localhost:3000/patreon/callback",
+        );
+        let app = app(state);
+
+        let server = TestServer::new(app).unwrap();
+
+        let response = server.get("/api/auth/patreon").await;
+
+        response.assert_status(StatusCode::SEE_OTHER);
+        let location = url::Url::parse(response.header("location").to_str().unwrap()).unwrap();
+        assert_eq!(location.path(), "/oauth2/authorize");
+        let oauth_state = location
+            .query_pairs()
+            .find(|(name, _)| name == "state")
+            .map(|(_, value)| value.to_string())
+            .unwrap();
+        let set_cookie = response.header("set-cookie").to_str().unwrap().to_string();
+        assert!(set_cookie.contains("HttpOnly"));
+        let cookie_name = HeaderName::from_lowercase(b"cookie").unwrap();
+        let cookie_value = HeaderValue::from_str(set_cookie.split(';').next().unwrap()).unwrap();
+
+        // a state started by another browser doesn't log this one in
+        let response = server
+            .post("/api/auth/patreon/callback")
+            .json(&json!({ "code": "good_code", "state": oauth_state }))
+            .await;
+
+        response.assert_status(StatusCode::BAD_REQUEST);
+
+        let response = server
+            .post("/api/auth/patreon/callback")
+            .add_header(cookie_name.clone(), cookie_value.clone())
+            .json(&json!({ "code": "good_code", "state": oauth_state }))
+            .await;
+
+        response.assert_status_ok();
+        let login_response = response.json::<PatronLoginResponse>();
+        assert_eq!(login_response.full_name, "Jane Doe");
+        assert_eq!(login_response.tier_ids, vec!["5678902".to_string()]);
+
+        // patrons manage nothing
+        let header_name = HeaderName::from_lowercase(b"authorization").unwrap();
+        let header_value =
+            HeaderValue::from_str(&format!("Bearer {}", login_response.access_token)).unwrap();
+        let response = server
+            .get("/api/posts/admin")
+            .add_header(header_name, header_value)
+            .await;
+
+        response.assert_status(StatusCode::FORBIDDEN);
+
+        // the state only works once
+        let response = server
+            .post("/api/auth/patreon/callback")
+            .add_header(cookie_name, cookie_value)
+            .json(&json!({ "code": "good_code", "state": oauth_state }))
+            .await;
+
+        response.assert_status(StatusCode::BAD_REQUEST);
     }
 }
@@ -0,0 +1,713 @@
+use crate::visibility::PostVisibility;
+use async_trait::async_trait;
+use rand::Rng;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::info;
+use url::form_urlencoded;
+
+pub const DEFAULT_PATREON_API_BASE_URL: &str = "https://www.patreon.com";
+const PATREON_POST_FIELDS: &str = "content,title,published_at,is_public,tiers";
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonPostsApiResult {
+    data: Vec<PatreonPostsApiPostResult>,
+    links: Option<PatreonPostsApiLinksResult>,
+    meta: PatreonPostsApiMetaResult,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonPostsApiLinksResult {
+    next: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonPostsApiMetaResult {
+    pagination: PatreonPostsApiPaginationResult,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonPostsApiPaginationResult {
+    cursors: Option<PatreonPostsApiCursorsResult>,
+    total: usize,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonPostsApiCursorsResult {
+    next: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonPostsApiPostResult {
+    id: String,
+    // type: String,
+    attributes: PatreonPostsApiPostAttributesResult,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonPostsApiPostAttributesResult {
+    content: String,
+    title: String,
+    published_at: String,
+    #[serde(default)]
+    is_public: bool,
+    /// Ids of the tiers with access to the post.
+    #[serde(default, deserialize_with = "deserialize_tier_ids")]
+    tiers: Vec<String>,
+}
+
+/// Tier ids are numbers in post attributes but strings everywhere else in the API.
+fn deserialize_tier_ids<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let tier_ids = Option::<Vec<serde_json::Value>>::deserialize(deserializer)?;
+
+    Ok(tier_ids
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|tier_id| match tier_id {
+            serde_json::Value::String(tier_id) => Some(tier_id),
+            serde_json::Value::Number(tier_id) => Some(tier_id.to_string()),
+            _ => None,
+        })
+        .collect())
+}
+
+impl PatreonPostsApiResult {
+    pub fn into_page(self, campaign_id: &str) -> PatreonPostsPage {
+        PatreonPostsPage {
+            posts: self
+                .data
+                .into_iter()
+                .map(|post| PatreonPost {
+                    id: post.id,
+                    campaign_id: campaign_id.to_string(),
+                    content: post.attributes.content,
+                    title: post.attributes.title,
+                    published_at: post.attributes.published_at,
+                    visibility: PostVisibility::from_patreon(
+                        post.attributes.is_public,
+                        post.attributes.tiers,
+                    ),
+                })
+                .collect(),
+            next_cursor: self
+                .meta
+                .pagination
+                .cursors
+                .and_then(|cursors| cursors.next),
+        }
+    }
+}
+
+/// Body of a `posts:*` webhook: a single post, with its campaign among the relationships.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonWebhookResult {
+    data: PatreonWebhookPostResult,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonWebhookPostResult {
+    id: String,
+    attributes: PatreonWebhookPostAttributesResult,
+    relationships: Option<PatreonWebhookRelationshipsResult>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonWebhookPostAttributesResult {
+    #[serde(default)]
+    content: Option<String>,
+    #[serde(default)]
+    title: Option<String>,
+    /// `null` once the post is unpublished.
+    #[serde(default)]
+    published_at: Option<String>,
+    #[serde(default)]
+    is_public: bool,
+    #[serde(default, deserialize_with = "deserialize_tier_ids")]
+    tiers: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonWebhookRelationshipsResult {
+    campaign: Option<PatreonWebhookRelationshipResult>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonWebhookRelationshipResult {
+    data: Option<PatreonWebhookRelatedResult>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonWebhookRelatedResult {
+    id: String,
+}
+
+impl PatreonWebhookResult {
+    pub fn is_published(&self) -> bool {
+        self.data.attributes.published_at.is_some()
+    }
+
+    pub fn into_post(self) -> PatreonPost {
+        let campaign_id = self
+            .data
+            .relationships
+            .and_then(|relationships| relationships.campaign)
+            .and_then(|campaign| campaign.data)
+            .map(|campaign| campaign.id)
+            .unwrap_or_default();
+        let attributes = self.data.attributes;
+
+        PatreonPost {
+            id: self.data.id,
+            campaign_id,
+            content: attributes.content.unwrap_or_default(),
+            title: attributes.title.unwrap_or_default(),
+            published_at: attributes.published_at.unwrap_or_default(),
+            visibility: PostVisibility::from_patreon(attributes.is_public, attributes.tiers),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PatreonPost {
+    pub id: String,
+    pub campaign_id: String,
+    pub content: String,
+    pub title: String,
+    pub published_at: String,
+    /// Without the access fields the post is taken for a patrons-only one, never a public one.
+    pub visibility: PostVisibility,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PatreonPostsPage {
+    pub posts: Vec<PatreonPost>,
+    /// Cursor of the following page, `None` on the last one.
+    pub next_cursor: Option<String>,
+}
+
+#[async_trait]
+pub trait PatreonApi: Send + Sync {
+    /// One page of a campaign's posts, newest first. Without a cursor the first page is returned.
+    async fn list_campaign_posts(
+        &self,
+        campaign_id: &str,
+        cursor: Option<&str>,
+        stats: &mut FetchStats,
+    ) -> Result<PatreonPostsPage, FetchError>;
+}
+
+pub struct ReqwestPatreonApi {
+    client: Client,
+    base_url: String,
+    access_token: String,
+    retry_policy: RetryPolicy,
+}
+
+impl ReqwestPatreonApi {
+    pub fn new(base_url: &str, access_token: &str) -> Self {
+        ReqwestPatreonApi {
+            client: Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            access_token: access_token.to_string(),
+            retry_policy: RetryPolicy::default(),
+        }
+    }
+
+    fn campaign_posts_url(&self, campaign_id: &str, cursor: Option<&str>) -> String {
+        let mut url = format!(
+            "{}/api/oauth2/v2/campaigns/{}/posts?fields%5Bpost%5D={}&sort=-published_at",
+            self.base_url, campaign_id, PATREON_POST_FIELDS
+        );
+        if let Some(cursor) = cursor {
+            url.push_str("&page%5Bcursor%5D=");
+            url.extend(form_urlencoded::byte_serialize(cursor.as_bytes()));
+        }
+
+        url
+    }
+}
+
+#[async_trait]
+impl PatreonApi for ReqwestPatreonApi {
+    async fn list_campaign_posts(
+        &self,
+        campaign_id: &str,
+        cursor: Option<&str>,
+        stats: &mut FetchStats,
+    ) -> Result<PatreonPostsPage, FetchError> {
+        let result = fetch_json::<PatreonPostsApiResult>(
+            &self.client,
+            &self.campaign_posts_url(campaign_id, cursor),
+            &self.access_token,
+            &self.retry_policy,
+            stats,
+        )
+        .await?;
+
+        Ok(result.into_page(campaign_id))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff with jitter, so concurrent retries don't hit Patreon in lockstep.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay);
+
+        let millis = delay.as_millis() as u64;
+        if millis == 0 {
+            return delay;
+        }
+
+        Duration::from_millis(rand::thread_rng().gen_range(millis / 2..=millis))
+    }
+}
+
+/// What happened over all attempts of the requests made for one sync.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FetchStats {
+    pub retry_count: u32,
+    pub last_http_status: Option<u16>,
+}
+
+#[derive(Debug)]
+pub enum FetchError {
+    /// The access token was rejected, retrying won't help.
+    Unauthorized(StatusCode),
+    /// Any other response that retrying won't fix, like a 404 for an unknown campaign.
+    Rejected(StatusCode),
+    /// Still failing after every retry was used up.
+    RetriesExhausted(String),
+}
+
+impl std::error::Error for FetchError {}
+impl std::fmt::Display for FetchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FetchError::Unauthorized(status) => {
+                write!(f, "Patreon rejected the access token: {}", status)
+            }
+            FetchError::Rejected(status) => write!(f, "Patreon rejected the request: {}", status),
+            FetchError::RetriesExhausted(message) => {
+                write!(f, "Patreon request failed after retries: {}", message)
+            }
+        }
+    }
+}
+
+enum Attempt<T> {
+    Done(T),
+    Retry {
+        message: String,
+        retry_after: Option<Duration>,
+    },
+}
+
+/// GETs `url` and decodes the JSON body, retrying network errors, 429s, 5xx and bad bodies.
+pub async fn fetch_json<T>(
+    client: &Client,
+    url: &str,
+    access_token: &str,
+    policy: &RetryPolicy,
+    stats: &mut FetchStats,
+) -> Result<T, FetchError>
+where
+    T: DeserializeOwned,
+{
+    let mut attempt = 0;
+
+    loop {
+        let response = client.get(url).bearer_auth(access_token).send().await;
+
+        let (message, retry_after) = match check_response(response, stats).await? {
+            Attempt::Done(response) => match response.json::<T>().await {
+                Ok(data) => return Ok(data),
+                Err(err) => (err.to_string(), None),
+            },
+            Attempt::Retry {
+                message,
+                retry_after,
+            } => (message, retry_after),
+        };
+
+        if attempt >= policy.max_retries {
+            return Err(FetchError::RetriesExhausted(message));
+        }
+
+        let delay = retry_after
+            .map(|retry_after| retry_after.min(policy.max_delay))
+            .unwrap_or_else(|| policy.backoff(attempt));
+        info!(
+            "Patreon request failed ({}), retrying in {:?}",
+            message, delay
+        );
+        tokio::time::sleep(delay).await;
+
+        attempt += 1;
+        stats.retry_count += 1;
+    }
+}
+
+async fn check_response(
+    response: reqwest::Result<Response>,
+    stats: &mut FetchStats,
+) -> Result<Attempt<Response>, FetchError> {
+    let response = match response {
+        Ok(response) => response,
+        Err(err) => {
+            return Ok(Attempt::Retry {
+                message: err.to_string(),
+                retry_after: None,
+            })
+        }
+    };
+
+    let status = response.status();
+    stats.last_http_status = Some(status.as_u16());
+
+    if status.is_success() {
+        return Ok(Attempt::Done(response));
+    }
+
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => Err(FetchError::Unauthorized(status)),
+        StatusCode::TOO_MANY_REQUESTS => Ok(Attempt::Retry {
+            message: status.to_string(),
+            retry_after: retry_after(&response),
+        }),
+        status if status.is_server_error() => Ok(Attempt::Retry {
+            message: status.to_string(),
+            retry_after: None,
+        }),
+        status => Err(FetchError::Rejected(status)),
+    }
+}
+
+/// Only the delay-seconds form of `Retry-After`; an HTTP date falls back to the backoff.
+fn retry_after(response: &Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_util::{
+        PATREON_POSTS_PAGE_1, PATREON_POSTS_PAGE_2, PATREON_WEBHOOK_POST_DELETE,
+        PATREON_WEBHOOK_POST_PUBLISH,
+    };
+    use wiremock::matchers::{header, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct TestBody {
+        value: String,
+    }
+
+    fn test_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 2,
+            base_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(10),
+        }
+    }
+
+    async fn fetch_test_body(server: &MockServer) -> (Result<TestBody, FetchError>, FetchStats) {
+        let mut stats = FetchStats::default();
+        let result = fetch_json::<TestBody>(
+            &Client::new(),
+            &format!("{}/posts", server.uri()),
+            "test_token",
+            &test_policy(),
+            &mut stats,
+        )
+        .await;
+
+        (result, stats)
+    }
+
+    fn ok_response() -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(serde_json::json!({ "value": "asdf" }))
+    }
+
+    #[test]
+    fn test_campaign_posts_url() {
+        let patreon_api = ReqwestPatreonApi::new("https://www.patreon.com/", "test_token");
+
+        assert_eq!(
+            patreon_api.campaign_posts_url("1234567", None),
+            "https://www.patreon.com/api/oauth2/v2/campaigns/1234567/posts?fields%5Bpost%5D=content,title,published_at,is_public,tiers&sort=-published_at"
+        );
+        assert_eq!(
+            patreon_api.campaign_posts_url("1234567", Some("a+b/c=")),
+            "https://www.patreon.com/api/oauth2/v2/campaigns/1234567/posts?fields%5Bpost%5D=content,title,published_at,is_public,tiers&sort=-published_at&page%5Bcursor%5D=a%2Bb%2Fc%3D"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_campaign_posts() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/api/oauth2/v2/campaigns/8365446/posts"))
+            .and(query_param("page[cursor]", "page_2_cursor"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(PATREON_POSTS_PAGE_2))
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/oauth2/v2/campaigns/8365446/posts"))
+            .and(query_param("sort", "-published_at"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(PATREON_POSTS_PAGE_1))
+            .mount(&server)
+            .await;
+        let patreon_api = ReqwestPatreonApi::new(&server.uri(), "test_token");
+        let mut stats = FetchStats::default();
+
+        let first_page = patreon_api
+            .list_campaign_posts("8365446", None, &mut stats)
+            .await
+            .unwrap();
+
+        assert_eq!(first_page.posts.len(), 2);
+        assert_eq!(first_page.posts[0].id, "98765432");
+        assert_eq!(first_page.posts[0].campaign_id, "8365446");
+        assert_eq!(first_page.posts[0].title, "Dragonbone Knight Armor");
+        assert_eq!(
+            first_page.posts[0].visibility,
+            PostVisibility::MinimumTier {
+                tier_ids: vec!["5678901".to_string(), "5678902".to_string()]
+            }
+        );
+        assert_eq!(first_page.posts[1].visibility, PostVisibility::Public);
+        assert_eq!(first_page.next_cursor.as_deref(), Some("page_2_cursor"));
+
+        let second_page = patreon_api
+            .list_campaign_posts("8365446", first_page.next_cursor.as_deref(), &mut stats)
+            .await
+            .unwrap();
+
+        assert_eq!(second_page.posts.len(), 1);
+        assert_eq!(second_page.posts[0].id, "11223344");
+        assert_eq!(second_page.posts[0].visibility, PostVisibility::Patrons);
+        assert!(second_page.next_cursor.is_none());
+        assert_eq!(stats.retry_count, 0);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_retries: 5,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+        };
+
+        let first = policy.backoff(0);
+        assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+
+        let third = policy.backoff(2);
+        assert!(third >= Duration::from_millis(200) && third <= Duration::from_millis(400));
+
+        let capped = policy.backoff(30);
+        assert!(capped >= Duration::from_millis(500) && capped <= Duration::from_millis(1000));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_json() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/posts"))
+            .and(header("authorization", "Bearer test_token"))
+            .respond_with(ok_response())
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let (result, stats) = fetch_test_body(&server).await;
+
+        assert_eq!(
+            result.unwrap(),
+            TestBody {
+                value: "asdf".to_string()
+            }
+        );
+        assert_eq!(
+            stats,
+            FetchStats {
+                retry_count: 0,
+                last_http_status: Some(200),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_json_retries_server_error() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ok_response())
+            .mount(&server)
+            .await;
+
+        let (result, stats) = fetch_test_body(&server).await;
+
+        assert!(result.is_ok());
+        assert_eq!(stats.retry_count, 1);
+        assert_eq!(stats.last_http_status, Some(200));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_json_honors_retry_after() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ok_response())
+            .mount(&server)
+            .await;
+
+        let (result, stats) = fetch_test_body(&server).await;
+
+        assert!(result.is_ok());
+        assert_eq!(stats.retry_count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_json_retries_invalid_body() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string("{\"val"))
+            .up_to_n_times(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ok_response())
+            .mount(&server)
+            .await;
+
+        let (result, stats) = fetch_test_body(&server).await;
+
+        assert!(result.is_ok());
+        assert_eq!(stats.retry_count, 1);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_json_does_not_retry_unauthorized() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let (result, stats) = fetch_test_body(&server).await;
+
+        assert!(matches!(
+            result,
+            Err(FetchError::Unauthorized(StatusCode::UNAUTHORIZED))
+        ));
+        assert_eq!(
+            stats,
+            FetchStats {
+                retry_count: 0,
+                last_http_status: Some(401),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_json_does_not_retry_not_found() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(404))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let (result, _) = fetch_test_body(&server).await;
+
+        assert!(matches!(
+            result,
+            Err(FetchError::Rejected(StatusCode::NOT_FOUND))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_json_gives_up_after_max_retries() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(500))
+            .expect(3)
+            .mount(&server)
+            .await;
+
+        let (result, stats) = fetch_test_body(&server).await;
+
+        assert!(matches!(result, Err(FetchError::RetriesExhausted(_))));
+        assert_eq!(stats.retry_count, 2);
+        assert_eq!(stats.last_http_status, Some(500));
+    }
+
+    #[test]
+    fn test_webhook_into_post() {
+        let published =
+            serde_json::from_str::<PatreonWebhookResult>(PATREON_WEBHOOK_POST_PUBLISH).unwrap();
+        assert!(published.is_published());
+
+        let post = published.into_post();
+        assert_eq!(post.id, "77889900");
+        assert_eq!(post.campaign_id, "8365446");
+        assert_eq!(post.title, "Daedric Armor Replacer");
+        assert_eq!(post.published_at, "2024-03-20T09:30:00.000+00:00");
+        assert_eq!(post.visibility, PostVisibility::Public);
+
+        let deleted =
+            serde_json::from_str::<PatreonWebhookResult>(PATREON_WEBHOOK_POST_DELETE).unwrap();
+        assert!(!deleted.is_published());
+
+        let post = deleted.into_post();
+        assert_eq!(post.id, "98765432");
+        assert_eq!(post.content, "");
+    }
+}
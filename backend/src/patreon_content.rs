@@ -0,0 +1,157 @@
+use scraper::{Html, Selector};
+use url::Url;
+
+/// Hosts whose links are treated as mod downloads.
+const DOWNLOAD_HOSTS: [&str; 5] = [
+    "drive.google.com",
+    "docs.google.com",
+    "mega.nz",
+    "mega.co.nz",
+    "mediafire.com",
+];
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ExtractedContent {
+    pub images_url: Vec<String>,
+    pub download_urls: Vec<String>,
+}
+
+impl ExtractedContent {
+    /// The post only has a single `file_url`, so the first download link wins.
+    pub fn file_url(&self) -> String {
+        self.download_urls.first().cloned().unwrap_or_default()
+    }
+}
+
+pub fn extract_content(html: &str) -> ExtractedContent {
+    let fragment = Html::parse_fragment(html);
+
+    ExtractedContent {
+        images_url: select_attr(&fragment, "img[src]", "src")
+            .into_iter()
+            .filter(|src| src.starts_with("http"))
+            .collect(),
+        download_urls: select_attr(&fragment, "a[href]", "href")
+            .into_iter()
+            .filter(|href| is_download_url(href))
+            .collect(),
+    }
+}
+
+pub fn is_download_url(href: &str) -> bool {
+    let url = match Url::parse(href) {
+        Ok(url) => url,
+        Err(_) => return false,
+    };
+    let host = match url.host_str() {
+        Some(host) => host.trim_start_matches("www."),
+        None => return false,
+    };
+
+    // attachments uploaded to the post itself
+    if host == "patreon.com" {
+        return url.path() == "/file";
+    }
+
+    DOWNLOAD_HOSTS.iter().any(|download_host| {
+        host == *download_host || host.ends_with(&format!(".{}", download_host))
+    })
+}
+
+/// Values of `attr` on the elements matching `selector`, in document order and without duplicates.
+fn select_attr(fragment: &Html, selector: &str, attr: &str) -> Vec<String> {
+    let selector = Selector::parse(selector).unwrap();
+
+    let mut values: Vec<String> = vec![];
+    for element in fragment.select(&selector) {
+        if let Some(value) = element.value().attr(attr) {
+            let value = value.trim().to_string();
+            if !value.is_empty() && !values.contains(&value) {
+                values.push(value);
+            }
+        }
+    }
+
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OUTFIT_RELEASE: &str = include_str!("test_data/patreon_html/outfit_release.html");
+    const ATTACHMENT_RELEASE: &str = include_str!("test_data/patreon_html/attachment_release.html");
+    const ANNOUNCEMENT: &str = include_str!("test_data/patreon_html/announcement.html");
+
+    #[test]
+    fn test_extract_content_outfit_release() {
+        let extracted = extract_content(OUTFIT_RELEASE);
+
+        assert_eq!(
+            extracted.images_url,
+            vec![
+                "https://c10.patreonusercontent.com/4/patreon-media/p/post/98765432/abc123def456/eyJ3IjoxMjAwfQ%3D%3D/1.png?token-time=1710460800&token-hash=aaaa".to_string(),
+                "https://c10.patreonusercontent.com/4/patreon-media/p/post/98765432/0f1e2d3c4b5a/eyJ3IjoxMjAwfQ%3D%3D/1.jpg?token-time=1710460800&token-hash=bbbb".to_string(),
+            ]
+        );
+        assert_eq!(
+            extracted.download_urls,
+            vec![
+                "https://drive.google.com/file/d/1a2B3c4D5e6F7g8H9i0J/view?usp=sharing".to_string(),
+                "https://mega.nz/file/AbCdEfGh#xyz123".to_string(),
+            ]
+        );
+        assert_eq!(
+            extracted.file_url(),
+            "https://drive.google.com/file/d/1a2B3c4D5e6F7g8H9i0J/view?usp=sharing"
+        );
+    }
+
+    #[test]
+    fn test_extract_content_attachment_release() {
+        let extracted = extract_content(ATTACHMENT_RELEASE);
+
+        assert_eq!(
+            extracted.images_url,
+            vec!["https://c10.patreonusercontent.com/4/patreon-media/p/post/11223344/99aa88bb77cc/eyJ3Ijo2MjB9/1.png?token-time=1710460800&token-hash=cccc".to_string()]
+        );
+        assert_eq!(
+            extracted.download_urls,
+            vec![
+                "https://www.patreon.com/file?h=11223344&i=55667788".to_string(),
+                "https://www.mediafire.com/file/q1w2e3r4t5y6/RaceMenuPreset.7z/file".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_extract_content_without_release() {
+        let extracted = extract_content(ANNOUNCEMENT);
+
+        assert_eq!(extracted, ExtractedContent::default());
+        assert_eq!(extracted.file_url(), "");
+    }
+
+    #[test]
+    fn test_extract_content_plain_text() {
+        assert_eq!(extract_content("asdf"), ExtractedContent::default());
+        assert_eq!(extract_content(""), ExtractedContent::default());
+    }
+
+    #[test]
+    fn test_is_download_url() {
+        assert!(is_download_url("https://drive.google.com/file/d/abc/view"));
+        assert!(is_download_url(
+            "https://docs.google.com/uc?export=download&id=abc"
+        ));
+        assert!(is_download_url("https://mega.co.nz/#!abc"));
+        assert!(is_download_url("http://mediafire.com/file/abc"));
+        assert!(is_download_url("https://patreon.com/file?h=1&i=2"));
+        assert!(!is_download_url("https://www.patreon.com/posts/faq-12345"));
+        assert!(!is_download_url("https://notmega.nz/file/abc"));
+        assert!(!is_download_url(
+            "https://www.nexusmods.com/skyrimspecialedition/mods/198"
+        ));
+        assert!(!is_download_url("/relative/link"));
+    }
+}
@@ -0,0 +1,389 @@
+use crate::patreon_api::{fetch_json, FetchStats, RetryPolicy};
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use url::form_urlencoded;
+
+/// The patron's profile, and their memberships to tell what the pledge unlocks.
+pub const PATREON_OAUTH_SCOPES: &str = "identity identity.memberships";
+const PATREON_IDENTITY_INCLUDE: &str =
+    "memberships,memberships.currently_entitled_tiers,memberships.campaign";
+const ACTIVE_PATRON_STATUS: &str = "active_patron";
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonTokenResult {
+    access_token: String,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonIdentityResult {
+    data: PatreonIdentityUserResult,
+    /// The memberships, along with their tiers and campaigns.
+    #[serde(default)]
+    included: Vec<PatreonIdentityIncludedResult>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonIdentityUserResult {
+    id: String,
+    #[serde(default)]
+    attributes: PatreonIdentityUserAttributesResult,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct PatreonIdentityUserAttributesResult {
+    #[serde(default)]
+    full_name: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonIdentityIncludedResult {
+    id: String,
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    attributes: PatreonMemberAttributesResult,
+    #[serde(default)]
+    relationships: Option<PatreonMemberRelationshipsResult>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct PatreonMemberAttributesResult {
+    /// `null` for someone who never pledged, only follows.
+    #[serde(default)]
+    patron_status: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonMemberRelationshipsResult {
+    campaign: Option<PatreonRelationshipResult>,
+    currently_entitled_tiers: Option<PatreonRelationshipsResult>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonRelationshipResult {
+    data: Option<PatreonRelatedResult>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonRelationshipsResult {
+    #[serde(default)]
+    data: Vec<PatreonRelatedResult>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonRelatedResult {
+    id: String,
+}
+
+/// A Patreon user, with what they're entitled to in each campaign they're a member of.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatreonIdentity {
+    pub user_id: String,
+    pub full_name: String,
+    pub memberships: Vec<PatreonMembership>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatreonMembership {
+    pub campaign_id: String,
+    pub is_active_patron: bool,
+    pub tier_ids: Vec<String>,
+}
+
+impl PatreonIdentityResult {
+    pub fn into_identity(self) -> PatreonIdentity {
+        let memberships = self
+            .included
+            .into_iter()
+            .filter(|included| included.kind == "member")
+            .map(|member| {
+                let relationships = member.relationships;
+                let campaign_id = relationships
+                    .as_ref()
+                    .and_then(|relationships| relationships.campaign.as_ref())
+                    .and_then(|campaign| campaign.data.as_ref())
+                    .map(|campaign| campaign.id.clone())
+                    .unwrap_or_default();
+                let tier_ids = relationships
+                    .and_then(|relationships| relationships.currently_entitled_tiers)
+                    .map(|tiers| tiers.data.into_iter().map(|tier| tier.id).collect())
+                    .unwrap_or_default();
+
+                PatreonMembership {
+                    campaign_id,
+                    is_active_patron: member.attributes.patron_status.as_deref()
+                        == Some(ACTIVE_PATRON_STATUS),
+                    tier_ids,
+                }
+            })
+            .collect();
+
+        PatreonIdentity {
+            user_id: self.data.id,
+            full_name: self.data.attributes.full_name.unwrap_or_default(),
+            memberships,
+        }
+    }
+}
+
+impl PatreonIdentity {
+    /// The tiers of the user's active pledges to `campaign_ids`, or `None` when they have none.
+    /// A pledge of a custom amount is entitled to no tier, but is still a patron's.
+    pub fn entitled_tier_ids(&self, campaign_ids: &[String]) -> Option<Vec<String>> {
+        let mut memberships = self
+            .memberships
+            .iter()
+            .filter(|membership| {
+                membership.is_active_patron && campaign_ids.contains(&membership.campaign_id)
+            })
+            .peekable();
+        memberships.peek()?;
+
+        Some(
+            memberships
+                .flat_map(|membership| membership.tier_ids.iter().cloned())
+                .collect(),
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum PatreonOAuthError {
+    /// The authorization code was used already, expired, or is for another client.
+    InvalidGrant(StatusCode),
+    /// Patreon couldn't be reached, or answered with something unexpected.
+    Failed(String),
+}
+
+impl std::error::Error for PatreonOAuthError {}
+impl std::fmt::Display for PatreonOAuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PatreonOAuthError::InvalidGrant(status) => {
+                write!(f, "Patreon rejected the authorization code: {}", status)
+            }
+            PatreonOAuthError::Failed(message) => {
+                write!(f, "Patreon login failed: {}", message)
+            }
+        }
+    }
+}
+
+/// The client of the app patrons log in to, registered on Patreon apart from the creator's
+/// access token used to sync.
+#[derive(Clone)]
+pub struct PatreonOAuthClient {
+    client: Client,
+    base_url: String,
+    client_id: String,
+    client_secret: String,
+    /// The page of the gallery Patreon sends the patron back to, it must be registered too.
+    redirect_uri: String,
+}
+
+impl PatreonOAuthClient {
+    pub fn new(base_url: &str, client_id: &str, client_secret: &str, redirect_uri: &str) -> Self {
+        PatreonOAuthClient {
+            client: Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            redirect_uri: redirect_uri.to_string(),
+        }
+    }
+
+    /// Patreon login stays off until the client secrets are set.
+    pub fn is_configured(&self) -> bool {
+        !self.client_id.is_empty()
+            && !self.client_secret.is_empty()
+            && !self.redirect_uri.is_empty()
+    }
+
+    /// Where the patron is sent to approve the login. `state` comes back with the code.
+    pub fn authorize_url(&self, state: &str) -> String {
+        let query = form_urlencoded::Serializer::new(String::new())
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &self.client_id)
+            .append_pair("redirect_uri", &self.redirect_uri)
+            .append_pair("scope", PATREON_OAUTH_SCOPES)
+            .append_pair("state", state)
+            .finish();
+
+        format!("{}/oauth2/authorize?{}", self.base_url, query)
+    }
+
+    fn identity_url(&self) -> String {
+        format!(
+            "{}/api/oauth2/v2/identity?include={}&fields%5Buser%5D=full_name&fields%5Bmember%5D=patron_status",
+            self.base_url, PATREON_IDENTITY_INCLUDE
+        )
+    }
+
+    /// Trades the authorization code for the patron's access token. Never retried, a code
+    /// only works once.
+    pub async fn exchange_code(&self, code: &str) -> Result<String, PatreonOAuthError> {
+        let response = self
+            .client
+            .post(format!("{}/api/oauth2/token", self.base_url))
+            .form(&[
+                ("code", code),
+                ("grant_type", "authorization_code"),
+                ("client_id", &self.client_id),
+                ("client_secret", &self.client_secret),
+                ("redirect_uri", &self.redirect_uri),
+            ])
+            .send()
+            .await
+            .map_err(|err| PatreonOAuthError::Failed(err.to_string()))?;
+
+        let status = response.status();
+        if status == StatusCode::BAD_REQUEST || status == StatusCode::UNAUTHORIZED {
+            return Err(PatreonOAuthError::InvalidGrant(status));
+        }
+        if !status.is_success() {
+            return Err(PatreonOAuthError::Failed(status.to_string()));
+        }
+
+        response
+            .json::<PatreonTokenResult>()
+            .await
+            .map(|token| token.access_token)
+            .map_err(|err| PatreonOAuthError::Failed(err.to_string()))
+    }
+
+    /// Who logged in, with the patron's own access token. The patron is waiting on it, so it's
+    /// retried less than a sync.
+    pub async fn fetch_identity(
+        &self,
+        access_token: &str,
+    ) -> Result<PatreonIdentity, PatreonOAuthError> {
+        let policy = RetryPolicy {
+            max_retries: 2,
+            max_delay: Duration::from_secs(2),
+            ..RetryPolicy::default()
+        };
+
+        fetch_json::<PatreonIdentityResult>(
+            &self.client,
+            &self.identity_url(),
+            access_token,
+            &policy,
+            &mut FetchStats::default(),
+        )
+        .await
+        .map(PatreonIdentityResult::into_identity)
+        .map_err(|err| PatreonOAuthError::Failed(err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_util::PATREON_IDENTITY;
+    use wiremock::matchers::{body_string_contains, header, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    fn test_client(base_url: &str) -> PatreonOAuthClient {
+        PatreonOAuthClient::new(
+            base_url,
+            "test_client_id",
+            "test_client_secret",
+            "http://localhost:3000/patreon/callback",
+        )
+    }
+
+    #[test]
+    fn test_authorize_url() {
+        let oauth_client = test_client("https://www.patreon.com/");
+
+        assert_eq!(
+            oauth_client.authorize_url("test_state"),
+            "https://www.patreon.com/oauth2/authorize?response_type=code&client_id=test_client_id&redirect_uri=http%3A%2F%2Flocalhost%3A3000%2Fpatreon%2Fcallback&scope=identity+identity.memberships&state=test_state"
+        );
+        assert!(oauth_client.is_configured());
+        assert!(!PatreonOAuthClient::new("https://www.patreon.com", "", "", "").is_configured());
+    }
+
+    #[test]
+    fn test_into_identity() {
+        let identity = serde_json::from_str::<PatreonIdentityResult>(PATREON_IDENTITY)
+            .unwrap()
+            .into_identity();
+
+        assert_eq!(identity.user_id, "1234567");
+        assert_eq!(identity.full_name, "Jane Doe");
+        assert_eq!(
+            identity.memberships,
+            vec![
+                PatreonMembership {
+                    campaign_id: "8365446".to_string(),
+                    is_active_patron: true,
+                    tier_ids: vec!["5678902".to_string()],
+                },
+                PatreonMembership {
+                    campaign_id: "999".to_string(),
+                    is_active_patron: false,
+                    tier_ids: vec![],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_entitled_tier_ids() {
+        let identity = serde_json::from_str::<PatreonIdentityResult>(PATREON_IDENTITY)
+            .unwrap()
+            .into_identity();
+
+        assert_eq!(
+            identity.entitled_tier_ids(&["8365446".to_string()]),
+            Some(vec!["5678902".to_string()])
+        );
+        // a former patron is no patron
+        assert_eq!(identity.entitled_tier_ids(&["999".to_string()]), None);
+        assert_eq!(identity.entitled_tier_ids(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn test_exchange_code_and_fetch_identity() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/api/oauth2/token"))
+            .and(body_string_contains("code=good_code"))
+            .and(body_string_contains("grant_type=authorization_code"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({
+                "access_token": "patron_token",
+                "refresh_token": "patron_refresh_token",
+                "expires_in": 2678400,
+                "scope": PATREON_OAUTH_SCOPES,
+                "token_type": "Bearer",
+            })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/oauth2/token"))
+            .respond_with(ResponseTemplate::new(400))
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/oauth2/v2/identity"))
+            .and(query_param("fields[member]", "patron_status"))
+            .and(header("authorization", "Bearer patron_token"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(PATREON_IDENTITY))
+            .mount(&server)
+            .await;
+        let oauth_client = test_client(&server.uri());
+
+        let access_token = oauth_client.exchange_code("good_code").await.unwrap();
+        assert_eq!(access_token, "patron_token");
+
+        let identity = oauth_client.fetch_identity(&access_token).await.unwrap();
+        assert_eq!(identity.user_id, "1234567");
+
+        assert!(matches!(
+            oauth_client.exchange_code("spent_code").await,
+            Err(PatreonOAuthError::InvalidGrant(StatusCode::BAD_REQUEST))
+        ));
+    }
+}
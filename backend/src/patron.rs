@@ -0,0 +1,271 @@
+use crate::jwt_auth::{issue_patron_access_token, PATRON_ACCESS_TOKEN_TTL};
+use crate::patreon_oauth::{PatreonIdentity, PatreonOAuthError};
+use crate::refresh_token::generate_token;
+use crate::AppState;
+use axum::extract::State;
+use axum::http::header::{COOKIE, SET_COOKIE};
+use axum::http::{HeaderMap, HeaderName, StatusCode};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum::Json;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{error, info};
+
+const OAUTH_STATE_KEY_PREFIX: &str = "patreon:oauth:state";
+/// Carries the state back from the browser that started the login, so a state minted by
+/// someone else can't be used to log that browser in.
+const OAUTH_STATE_COOKIE: &str = "patreon_oauth_state";
+/// How long the visitor has to approve the login on Patreon.
+const OAUTH_STATE_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Someone with an active pledge to one of the synced campaigns.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Patron {
+    pub patreon_user_id: String,
+    pub full_name: String,
+    pub tier_ids: Vec<String>,
+}
+
+impl Patron {
+    /// `None` when the Patreon user has no active pledge to `campaign_ids`.
+    pub fn from_identity(identity: PatreonIdentity, campaign_ids: &[String]) -> Option<Self> {
+        let tier_ids = identity.entitled_tier_ids(campaign_ids)?;
+
+        Some(Patron {
+            patreon_user_id: identity.user_id,
+            full_name: identity.full_name,
+            tier_ids,
+        })
+    }
+
+    /// The name in the patron's token, set apart from admin names.
+    pub fn name(&self) -> String {
+        format!("patreon:{}", self.patreon_user_id)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct PatreonCallbackRequest {
+    pub code: String,
+    pub state: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct PatronLoginResponse {
+    pub access_token: String,
+    pub token_type: String,
+    /// Seconds until the access token expires, then the patron logs in through Patreon again.
+    pub expires_in: u64,
+    pub full_name: String,
+    pub tier_ids: Vec<String>,
+}
+
+fn oauth_state_key(oauth_state: &str) -> String {
+    format!("{}:{}", OAUTH_STATE_KEY_PREFIX, oauth_state)
+}
+
+async fn store_oauth_state(redis: &redis::Client, oauth_state: &str) -> redis::RedisResult<()> {
+    let mut con = redis.get_multiplexed_async_connection().await?;
+
+    con.set_ex(oauth_state_key(oauth_state), 1, OAUTH_STATE_TTL.as_secs())
+        .await
+}
+
+/// The callback is posted from the gallery's own domain, so the cookie has to be sent
+/// cross-site. It only ever goes to the Patreon login routes.
+fn oauth_state_cookie(oauth_state: &str, max_age: Duration) -> String {
+    format!(
+        "{}={}; Path=/api/auth/patreon; Max-Age={}; HttpOnly; Secure; SameSite=None",
+        OAUTH_STATE_COOKIE,
+        oauth_state,
+        max_age.as_secs()
+    )
+}
+
+fn oauth_state_from_cookie(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == OAUTH_STATE_COOKIE)
+        .map(|(_, oauth_state)| oauth_state)
+}
+
+/// Whether `oauth_state` was issued by `patreon_login` and not used yet. Each works once.
+async fn take_oauth_state(redis: &redis::Client, oauth_state: &str) -> redis::RedisResult<bool> {
+    let mut con = redis.get_multiplexed_async_connection().await?;
+    let deleted_count: u64 = con.del(oauth_state_key(oauth_state)).await?;
+
+    Ok(deleted_count > 0)
+}
+
+/// Sends the visitor to Patreon to approve the login. Patreon sends them back to the gallery's
+/// redirect page, which posts the code it got to `patreon_callback`.
+pub async fn patreon_login(
+    State(state): State<AppState>,
+) -> Result<([(HeaderName, String); 1], Redirect), impl IntoResponse> {
+    if !state.patreon_oauth.is_configured() {
+        let error_message = "Patreon login is not configured!".to_string();
+        error!(error_message);
+        return Err((StatusCode::SERVICE_UNAVAILABLE, error_message).into_response());
+    }
+
+    let oauth_state = generate_token();
+    if let Err(err) = store_oauth_state(&state.redis, &oauth_state).await {
+        let error_message = format!("fail to store oauth state {}", err);
+        error!(error_message);
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response());
+    }
+
+    Ok((
+        [(
+            SET_COOKIE,
+            oauth_state_cookie(&oauth_state, OAUTH_STATE_TTL),
+        )],
+        Redirect::to(&state.patreon_oauth.authorize_url(&oauth_state)),
+    ))
+}
+
+fn patreon_oauth_error(err: PatreonOAuthError) -> Response {
+    let error_message = err.to_string();
+    error!(error_message);
+
+    let status = match err {
+        PatreonOAuthError::InvalidGrant(_) => StatusCode::UNAUTHORIZED,
+        PatreonOAuthError::Failed(_) => StatusCode::BAD_GATEWAY,
+    };
+    (status, error_message).into_response()
+}
+
+/// Finishes the login `patreon_login` started, issuing a token with the patron's tiers.
+pub async fn patreon_callback(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<PatreonCallbackRequest>,
+) -> Result<([(HeaderName, String); 1], Json<PatronLoginResponse>), impl IntoResponse> {
+    if oauth_state_from_cookie(&headers) != Some(req.state.as_str()) {
+        info!("Patreon login state doesn't match the browser's");
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Invalid or expired login state!".to_string(),
+        )
+            .into_response());
+    }
+
+    match take_oauth_state(&state.redis, &req.state).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Invalid or expired login state!".to_string(),
+            )
+                .into_response());
+        }
+        Err(err) => {
+            let error_message = format!("fail to check oauth state {}", err);
+            error!(error_message);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response());
+        }
+    }
+
+    let patreon_access_token = match state.patreon_oauth.exchange_code(&req.code).await {
+        Ok(patreon_access_token) => patreon_access_token,
+        Err(err) => return Err(patreon_oauth_error(err)),
+    };
+    let identity = match state
+        .patreon_oauth
+        .fetch_identity(&patreon_access_token)
+        .await
+    {
+        Ok(identity) => identity,
+        Err(err) => return Err(patreon_oauth_error(err)),
+    };
+
+    let user_id = identity.user_id.clone();
+    let patron = match Patron::from_identity(identity, &state.patreon_campaign_ids) {
+        Some(patron) => patron,
+        None => {
+            info!("Patreon user {} is no active patron", user_id);
+            return Err((
+                StatusCode::FORBIDDEN,
+                "Only active patrons can log in with Patreon!".to_string(),
+            )
+                .into_response());
+        }
+    };
+
+    let access_token =
+        match issue_patron_access_token(&state.jwt_key, &patron.name(), patron.tier_ids.clone()) {
+            Ok((access_token, _)) => access_token,
+            Err(err) => {
+                let error_message = format!("fail to issue access token {}", err);
+                error!(error_message);
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response());
+            }
+        };
+    info!(
+        "{} logged in with tiers {:?}",
+        patron.name(),
+        patron.tier_ids
+    );
+
+    Ok((
+        // the state is spent, so is the cookie
+        [(SET_COOKIE, oauth_state_cookie("", Duration::ZERO))],
+        Json(PatronLoginResponse {
+            access_token,
+            token_type: "Bearer".to_string(),
+            expires_in: PATRON_ACCESS_TOKEN_TTL.as_secs(),
+            full_name: patron.full_name,
+            tier_ids: patron.tier_ids,
+        }),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::patreon_oauth::PatreonMembership;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_patron_from_identity() {
+        let identity = PatreonIdentity {
+            user_id: "1234567".to_string(),
+            full_name: "Jane Doe".to_string(),
+            memberships: vec![PatreonMembership {
+                campaign_id: "8365446".to_string(),
+                is_active_patron: true,
+                tier_ids: vec!["5678902".to_string()],
+            }],
+        };
+
+        let patron = Patron::from_identity(identity.clone(), &["8365446".to_string()]).unwrap();
+        assert_eq!(patron.name(), "patreon:1234567");
+        assert_eq!(patron.tier_ids, vec!["5678902".to_string()]);
+
+        assert!(Patron::from_identity(identity, &["999".to_string()]).is_none());
+        assert_eq!(oauth_state_key("abc"), "patreon:oauth:state:abc");
+    }
+
+    #[test]
+    fn test_oauth_state_cookie() {
+        let cookie = oauth_state_cookie("abc", OAUTH_STATE_TTL);
+        assert_eq!(
+            cookie,
+            "patreon_oauth_state=abc; Path=/api/auth/patreon; Max-Age=600; HttpOnly; Secure; SameSite=None"
+        );
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(oauth_state_from_cookie(&headers), None);
+
+        headers.insert(
+            COOKIE,
+            HeaderValue::from_static("theme=dark; patreon_oauth_state=abc"),
+        );
+        assert_eq!(oauth_state_from_cookie(&headers), Some("abc"));
+    }
+}
@@ -0,0 +1,189 @@
+use crate::api_key::ApiKeyClaims;
+use crate::jwt_auth::TokenClaims;
+use axum::extract::{Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+pub const ADMIN_ROLE: &str = "admin";
+/// Edits posts and watches syncs, but can't wipe every post or start a sync.
+pub const MODERATOR_ROLE: &str = "moderator";
+/// Logged in with Patreon. Manages nothing, only sees what the pledge unlocks, see `visibility`.
+pub const PATRON_ROLE: &str = "patron";
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Orphaned posts, only listed through the admin API.
+    #[serde(rename = "posts:read_hidden")]
+    PostsReadHidden,
+    /// Creating and editing posts and their tags.
+    #[serde(rename = "posts:write")]
+    PostsWrite,
+    #[serde(rename = "posts:delete")]
+    PostsDelete,
+    #[serde(rename = "posts:delete_all")]
+    PostsDeleteAll,
+    /// Starting a sync, releasing its lock and replaying webhooks.
+    #[serde(rename = "sync:trigger")]
+    SyncTrigger,
+    /// Sync results, jobs, webhook deliveries and the other operational state.
+    #[serde(rename = "sync:read")]
+    SyncRead,
+    /// Creating, listing and revoking API keys. No API key can have it.
+    #[serde(rename = "api_keys:manage")]
+    ApiKeysManage,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 7] = [
+        Permission::PostsReadHidden,
+        Permission::PostsWrite,
+        Permission::PostsDelete,
+        Permission::PostsDeleteAll,
+        Permission::SyncTrigger,
+        Permission::SyncRead,
+        Permission::ApiKeysManage,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Permission::PostsReadHidden => "posts:read_hidden",
+            Permission::PostsWrite => "posts:write",
+            Permission::PostsDelete => "posts:delete",
+            Permission::PostsDeleteAll => "posts:delete_all",
+            Permission::SyncTrigger => "sync:trigger",
+            Permission::SyncRead => "sync:read",
+            Permission::ApiKeysManage => "api_keys:manage",
+        }
+    }
+}
+
+/// What a role may do. An unknown role may do nothing.
+pub fn role_permissions(role: &str) -> &'static [Permission] {
+    match role {
+        ADMIN_ROLE => &Permission::ALL,
+        MODERATOR_ROLE => &[
+            Permission::PostsReadHidden,
+            Permission::PostsWrite,
+            Permission::PostsDelete,
+            Permission::SyncRead,
+        ],
+        _ => &[],
+    }
+}
+
+pub fn has_permission(role: &str, permission: Permission) -> bool {
+    role_permissions(role).contains(&permission)
+}
+
+/// Lets the request through when the caller's role, or API key scopes, grant `permission`.
+/// Goes inside `auth_jwt`, which turns down a missing or invalid token or key with 401 first,
+/// so this only ever answers 403.
+pub async fn require_permission(
+    State(permission): State<Permission>,
+    req: Request,
+    next: Next,
+) -> Result<impl IntoResponse, StatusCode> {
+    let extensions = req.extensions();
+
+    if let Some(claims) = extensions.get::<TokenClaims>() {
+        if !has_permission(&claims.role, permission) {
+            info!(
+                "{} with role {} lacks {}",
+                claims.name,
+                claims.role,
+                permission.as_str()
+            );
+            return Err(StatusCode::FORBIDDEN);
+        }
+    } else if let Some(api_key) = extensions.get::<ApiKeyClaims>() {
+        if !api_key.scopes.contains(&permission) {
+            info!(
+                "API key {} {} lacks {}",
+                api_key.prefix,
+                api_key.name,
+                permission.as_str()
+            );
+            return Err(StatusCode::FORBIDDEN);
+        }
+    } else {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(next.run(req).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_util::test_token_claims;
+    use ::axum_test::TestServer;
+    use axum::routing::get;
+    use axum::{middleware, Extension, Router};
+
+    #[test]
+    fn test_role_permissions() {
+        assert_eq!(role_permissions(ADMIN_ROLE), &Permission::ALL);
+        assert!(has_permission(MODERATOR_ROLE, Permission::PostsWrite));
+        assert!(!has_permission(MODERATOR_ROLE, Permission::PostsDeleteAll));
+        assert!(!has_permission(MODERATOR_ROLE, Permission::SyncTrigger));
+        assert!(!has_permission(MODERATOR_ROLE, Permission::ApiKeysManage));
+        assert!(role_permissions(PATRON_ROLE).is_empty());
+    }
+
+    #[test]
+    fn test_permission_json() {
+        for permission in Permission::ALL {
+            assert_eq!(
+                serde_json::to_string(&permission).unwrap(),
+                format!("\"{}\"", permission.as_str())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_require_permission() {
+        let router = |role: Option<&str>| {
+            let router = Router::new().route(
+                "/",
+                get(|| async { "ok" }).route_layer(middleware::from_fn_with_state(
+                    Permission::PostsDeleteAll,
+                    require_permission,
+                )),
+            );
+
+            match role {
+                Some(role) => router.layer(Extension(test_token_claims(role))),
+                None => router,
+            }
+        };
+
+        let server = TestServer::new(router(Some(ADMIN_ROLE))).unwrap();
+        server.get("/").await.assert_status_ok();
+
+        let server = TestServer::new(router(Some(MODERATOR_ROLE))).unwrap();
+        server.get("/").await.assert_status(StatusCode::FORBIDDEN);
+
+        let server = TestServer::new(router(None)).unwrap();
+        server
+            .get("/")
+            .await
+            .assert_status(StatusCode::UNAUTHORIZED);
+
+        for (scopes, status) in [
+            (vec![Permission::PostsDeleteAll], StatusCode::OK),
+            (vec![Permission::PostsDelete], StatusCode::FORBIDDEN),
+        ] {
+            let server = TestServer::new(router(None).layer(Extension(ApiKeyClaims {
+                id: "test_id".to_string(),
+                name: "Discord bot".to_string(),
+                prefix: "mg_1a2b3c4d".to_string(),
+                scopes,
+            })))
+            .unwrap();
+            server.get("/").await.assert_status(status);
+        }
+    }
+}
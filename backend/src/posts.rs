@@ -3,15 +3,19 @@ use std::vec;
 
 // use crate::sync_post::sync_post;
 use crate::AppState;
-use axum::response::IntoResponse;
+use axum::http::header::IF_MATCH;
+use axum::http::HeaderMap;
+use axum::response::{IntoResponse, Redirect, Response};
 use axum::{
-    extract::{Path, State},
+    extract::{Path, Query, State},
     http::StatusCode,
-    Json,
+    Extension, Json,
 };
 use mongodb::bson::oid::ObjectId;
-use mongodb::bson::serde_helpers::{bson_datetime_as_rfc3339_string, hex_string_as_object_id};
-use mongodb::bson::{doc, Bson, DateTime};
+use mongodb::bson::serde_helpers::hex_string_as_object_id;
+use mongodb::bson::{doc, Bson, DateTime, Document};
+use mongodb::options::IndexOptions;
+use mongodb::{Database, IndexModel};
 use serde::{Deserialize, Serialize};
 use tracing::{error, info};
 
@@ -20,41 +24,127 @@ pub struct Post {
     #[serde(with = "hex_string_as_object_id")]
     _id: String,
     title: String,
+    /// Unique, made from the title. Older posts may not have one until the startup backfill.
+    #[serde(default)]
+    slug: String,
+    /// Slugs the post had before its title changed, they redirect to the current one.
+    #[serde(default)]
+    slug_history: Vec<String>,
     patreon_post_id: String,
+    #[serde(default)]
+    campaign_id: String,
     content: String,
     images_url: Vec<String>,
     file_url: String,
     mod_type: String,
-    #[serde(with = "bson_datetime_as_rfc3339_string")]
+    #[serde(with = "bson_datetime_as_rfc3339_millis")]
     created_at: DateTime,
-    #[serde(with = "bson_datetime_as_rfc3339_string")]
+    #[serde(with = "bson_datetime_as_rfc3339_millis")]
     updated_at: DateTime,
-    #[serde(with = "bson_datetime_as_rfc3339_string")]
+    #[serde(with = "bson_datetime_as_rfc3339_millis")]
     synced_at: DateTime,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    overridden_fields: Vec<String>,
+    #[serde(default)]
+    is_orphaned: bool,
+    #[serde(default)]
+    visibility: PostVisibility,
 }
 
 impl Post {
     pub fn new_for_sync(
+        campaign_id: &str,
         patreon_post_id: &str,
         title: &str,
         content: &str,
         date_string: &str,
     ) -> Self {
+        let extracted = extract_content(content);
+
         Post {
             _id: ObjectId::new().to_hex(),
             patreon_post_id: patreon_post_id.to_string(),
+            campaign_id: campaign_id.to_string(),
             title: title.to_string(),
+            // picked when the post is inserted, see `assign_slugs`
+            slug: "".to_string(),
+            slug_history: vec![],
             content: content.to_string(),
-            images_url: vec![],
-            file_url: "".to_string(),
+            images_url: extracted.images_url.clone(),
+            file_url: extracted.file_url(),
             mod_type: "".to_string(),
             created_at: DateTime::now(),
             updated_at: DateTime::from_chrono(get_chrono_dt_from_string(date_string.to_string())),
             synced_at: DateTime::now(),
+            tags: vec![],
+            overridden_fields: vec![],
+            is_orphaned: false,
+            visibility: PostVisibility::Public,
+        }
+    }
+
+    pub fn with_visibility(mut self, visibility: PostVisibility) -> Self {
+        self.visibility = visibility;
+        self
+    }
+
+    /// Set when a full sync no longer finds the post on Patreon.
+    pub fn is_orphaned(&self) -> bool {
+        self.is_orphaned
+    }
+
+    fn version(&self) -> ResourceVersion<'_> {
+        ResourceVersion {
+            id: &self._id,
+            updated_at: self.updated_at,
+            synced_at: self.synced_at,
+        }
+    }
+
+    pub fn validators(&self) -> Validators {
+        Validators::new([self.version()], "")
+    }
+
+    /// Blanks the download when `entitlement` doesn't cover the post, and the content too,
+    /// which links the same files. Returns whether it did.
+    pub fn redact_for(&mut self, entitlement: &Entitlement) -> bool {
+        if entitlement.can_view(&self.visibility) {
+            return false;
         }
+
+        self.file_url = "".to_string();
+        self.content = "".to_string();
+        true
     }
 }
 
+/// Redacts what `entitlement` doesn't cover, returning the ids of the redacted posts. They go
+/// into the validators, so a copy cached before logging in isn't taken as current after.
+fn redact_posts<'a>(
+    posts: impl IntoIterator<Item = &'a mut Post>,
+    entitlement: &Entitlement,
+) -> String {
+    posts
+        .into_iter()
+        .filter_map(|post| post.redact_for(entitlement).then(|| post._id.clone()))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn page_validators(page: &Page<Post>, redacted_ids: &str) -> Validators {
+    Validators::new(
+        page.items.iter().map(Post::version),
+        &format!(
+            "{}:{}:{}",
+            page.total_count,
+            page.next_cursor.as_deref().unwrap_or_default(),
+            redacted_ids
+        ),
+    )
+}
+
 #[derive(Serialize, Deserialize)]
 #[allow(non_snake_case)]
 pub struct NewPostRequest {
@@ -63,6 +153,8 @@ pub struct NewPostRequest {
     imagesUrl: Vec<String>,
     fileUrl: String,
     modType: String,
+    #[serde(default)]
+    tagIds: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -73,13 +165,266 @@ pub struct EditPostRequest {
     imagesUrl: Vec<String>,
     fileUrl: String,
     modType: String,
+    /// The post keeps its tags when this is left out.
+    tagIds: Option<Vec<String>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PostSortField {
+    CreatedAt,
+    UpdatedAt,
+    SyncedAt,
+    Title,
+}
+
+impl PostSortField {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PostSortField::CreatedAt => "created_at",
+            PostSortField::UpdatedAt => "updated_at",
+            PostSortField::SyncedAt => "synced_at",
+            PostSortField::Title => "title",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+pub struct PostListQuery {
+    q: Option<String>,
+    limit: Option<i64>,
+    cursor: Option<String>,
+    sort: Option<PostSortField>,
+    order: Option<SortOrder>,
+    mod_type: Option<String>,
+    campaign_id: Option<String>,
+    tags: Option<String>,
+    created_from: Option<String>,
+    created_to: Option<String>,
+    updated_from: Option<String>,
+    updated_to: Option<String>,
+    orphaned: Option<bool>,
+}
+
+impl PostListQuery {
+    /// Orphaned posts are only listed through the admin API.
+    pub fn for_public(self) -> Self {
+        PostListQuery {
+            orphaned: Some(false),
+            ..self
+        }
+    }
+
+    pub fn search_text(&self) -> Option<&str> {
+        self.q
+            .as_deref()
+            .map(str::trim)
+            .filter(|search_text| !search_text.is_empty())
+    }
+
+    /// Tag ids from the comma separated `tags` parameter; posts must carry all of them.
+    pub fn tag_ids(&self) -> Vec<String> {
+        self.tags
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|tag_id| !tag_id.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+
+    pub fn parse(&self) -> Result<(Document, PageOptions), String> {
+        Ok((self.filter()?, self.page_options()?))
+    }
+
+    pub fn page_options(&self) -> Result<PageOptions, String> {
+        let cursor = match &self.cursor {
+            Some(cursor) => {
+                Some(PageCursor::decode(cursor).map_err(|err| format!("invalid cursor: {}", err))?)
+            }
+            None => None,
+        };
+
+        Ok(PageOptions::new(
+            self.sort.unwrap_or(PostSortField::CreatedAt).as_str(),
+            self.order.unwrap_or(SortOrder::Desc),
+            self.limit,
+            cursor,
+        ))
+    }
+
+    pub fn filter(&self) -> Result<Document, String> {
+        let mut filter = doc! {};
+
+        if let Some(search_text) = self.search_text() {
+            filter.insert("$text", doc! { "$search": search_text });
+        }
+
+        if let Some(mod_type) = &self.mod_type {
+            filter.insert("mod_type", mod_type);
+        }
+
+        if let Some(campaign_id) = &self.campaign_id {
+            filter.insert("campaign_id", campaign_id);
+        }
+
+        let tag_ids = self.tag_ids();
+        if !tag_ids.is_empty() {
+            filter.insert("tags", doc! { "$all": tag_ids });
+        }
+
+        if let Some(range) = date_range_filter(&self.created_from, &self.created_to)? {
+            filter.insert("created_at", range);
+        }
+
+        if let Some(range) = date_range_filter(&self.updated_from, &self.updated_to)? {
+            filter.insert("updated_at", range);
+        }
+
+        match self.orphaned {
+            Some(true) => filter.insert("is_orphaned", true),
+            // posts synced before orphans were tracked have no flag at all
+            Some(false) => filter.insert("is_orphaned", doc! { "$ne": true }),
+            None => None,
+        };
+
+        Ok(filter)
+    }
+}
+
+pub async fn create_post_indexes(mongo: Database) -> anyhow::Result<()> {
+    let text_index = IndexModel::builder()
+        .keys(doc! { "title": "text", "content": "text" })
+        .options(
+            IndexOptions::builder()
+                .name("post_text_search".to_string())
+                .weights(doc! { "title": 10, "content": 1 })
+                .build(),
+        )
+        .build();
+
+    let unique_slug_index = IndexModel::builder()
+        .keys(doc! { "slug": 1 })
+        .options(
+            IndexOptions::builder()
+                .name("post_unique_slug".to_string())
+                .unique(true)
+                // posts waiting for the backfill have no slug yet
+                .partial_filter_expression(doc! { "slug": { "$gt": "" } })
+                .build(),
+        )
+        .build();
+    let slug_history_index = IndexModel::builder()
+        .keys(doc! { "slug_history": 1 })
+        .options(
+            IndexOptions::builder()
+                .name("post_slug_history".to_string())
+                .build(),
+        )
+        .build();
+
+    let index_names = create_indexes::<Post>(
+        mongo,
+        vec![text_index, unique_slug_index, slug_history_index],
+    )
+    .await?;
+    info!("Post indexes ready {:?}", index_names);
+
+    Ok(())
+}
+
+/// Gives each post a slug that no other post, or another post of the batch, uses.
+pub async fn assign_slugs(mongo: Database, posts: &mut [Post]) -> anyhow::Result<()> {
+    let titles: Vec<&str> = posts.iter().map(|post| post.title.as_str()).collect();
+    let slugs = unique_slugs(mongo, &titles, None).await?;
+
+    for (post, slug) in posts.iter_mut().zip(slugs) {
+        post.slug = slug;
+    }
+
+    Ok(())
+}
+
+/// Gives a slug to the posts stored before posts had one, returning how many were updated.
+pub async fn backfill_post_slugs(mongo: Database) -> anyhow::Result<usize> {
+    let mut posts = find_docs::<Post>(
+        mongo.clone(),
+        doc! { "$or": [{ "slug": { "$exists": false } }, { "slug": "" }] },
+    )
+    .await?;
+    if posts.is_empty() {
+        return Ok(0);
+    }
+
+    assign_slugs(mongo.clone(), &mut posts).await?;
+
+    for post in &posts {
+        update_many_docs::<Post>(
+            mongo.clone(),
+            doc! { "_id": ObjectId::from_str(&post._id)? },
+            doc! {
+                "$set": {
+                    "slug": &post.slug,
+                    "updated_at": to_rfc3339_millis(DateTime::now()),
+                }
+            },
+        )
+        .await?;
+    }
+    info!("{} posts got a slug", posts.len());
+
+    Ok(posts.len())
+}
+
+/// Rewrites the dates of posts stored before dates had a fixed precision.
+pub async fn normalize_post_dates(mongo: Database) -> anyhow::Result<u64> {
+    normalize_date_fields::<Post>(mongo, &["created_at", "updated_at", "synced_at"]).await
 }
 
+/// Cached as stored, each caller gets their own redaction.
 pub async fn get_all_posts(
     State(state): State<AppState>,
-) -> Result<Json<Vec<Post>>, impl IntoResponse> {
-    match get_all_docs::<Post>(state.mongo).await {
-        Ok(posts) => Ok(Json(posts)),
+    claims: Option<Extension<TokenClaims>>,
+    headers: HeaderMap,
+    Query(query): Query<PostListQuery>,
+) -> Result<ConditionalJson<Page<Post>>, impl IntoResponse> {
+    let entitlement = Entitlement::of(claims.as_deref());
+    let query = query.for_public();
+    // the query struct serializes its fields in a fixed order, unlike the raw query string
+    let cache_key = serde_json::to_string(&query).unwrap_or_default();
+
+    cached(&state, CacheScope::Posts, "list", &cache_key, || async {
+        list_posts(state.mongo.clone(), query)
+            .await
+            .map(|page| page.0)
+    })
+    .await
+    .map(|mut page| {
+        let redacted_ids = redact_posts(&mut page.items, &entitlement);
+        let validators = page_validators(&page, &redacted_ids);
+        ConditionalJson::new(&headers, page, validators)
+    })
+}
+
+pub async fn get_all_posts_for_admin(
+    State(state): State<AppState>,
+    Query(query): Query<PostListQuery>,
+) -> Result<Json<Page<Post>>, impl IntoResponse> {
+    list_posts(state.mongo, query).await
+}
+
+async fn list_posts(mongo: Database, query: PostListQuery) -> Result<Json<Page<Post>>, Response> {
+    let (filter, page_options) = match query.parse() {
+        Ok(result) => result,
+        Err(error_message) => {
+            error!(error_message);
+            return Err((StatusCode::BAD_REQUEST, error_message).into_response());
+        }
+    };
+
+    match find_docs_paginated::<Post>(mongo, filter, page_options).await {
+        Ok(page) => Ok(Json(page)),
         Err(err) => {
             let error_message = err.to_string();
             error!("{}", error_message.clone());
@@ -90,12 +435,136 @@ pub async fn get_all_posts(
 
 pub async fn get_post_by_id(
     State(state): State<AppState>,
+    claims: Option<Extension<TokenClaims>>,
     Path(id): Path<String>,
-) -> Result<Json<Post>, impl IntoResponse> {
-    let target_post_object_id_result = ObjectId::from_str(&id);
+    headers: HeaderMap,
+) -> Result<ConditionalJson<Post>, impl IntoResponse> {
+    let entitlement = Entitlement::of(claims.as_deref());
+
+    cached(&state, CacheScope::Posts, "post", &id, || {
+        find_post(state.mongo.clone(), &id)
+    })
+    .await
+    .map(|post| redacted_post_response(&headers, post, &entitlement))
+}
+
+fn redacted_post_response(
+    headers: &HeaderMap,
+    mut post: Post,
+    entitlement: &Entitlement,
+) -> ConditionalJson<Post> {
+    let redacted_ids = redact_posts([&mut post], entitlement);
+    let validators = Validators::new([post.version()], &redacted_ids);
+
+    ConditionalJson::new(headers, post, validators)
+}
+
+/// Serves the post by its slug, or redirects when `slug` is one the post had before.
+pub async fn get_post_by_slug(
+    State(state): State<AppState>,
+    claims: Option<Extension<TokenClaims>>,
+    Path(slug): Path<String>,
+    headers: HeaderMap,
+) -> Result<Response, Response> {
+    let post = cached(&state, CacheScope::Posts, "slug", &slug, || {
+        find_post_by_slug(state.mongo.clone(), &slug)
+    })
+    .await?;
+
+    if post.slug != slug {
+        return Ok(Redirect::permanent(&slug_path(&post.slug)).into_response());
+    }
+
+    let entitlement = Entitlement::of(claims.as_deref());
+    Ok(redacted_post_response(&headers, post, &entitlement).into_response())
+}
+
+async fn find_post_by_slug(mongo: Database, slug: &str) -> Result<Post, Response> {
+    let filter = doc! {
+        "$or": [{ "slug": slug }, { "slug_history": slug }]
+    };
+
+    match find_one_doc::<Post>(mongo, filter).await {
+        Ok(Some(post)) => Ok(post),
+        Ok(None) => {
+            error!("The post with slug: {} not found!", slug);
+            Err((
+                StatusCode::NOT_FOUND,
+                format!("The post with slug: {} not found!", slug),
+            )
+                .into_response())
+        }
+        Err(err) => {
+            let error_message = err.to_string();
+            error!("{}", error_message.clone());
+            Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response())
+        }
+    }
+}
+
+/// Turns down a write whose `If-Match` names another version than `post`,
+/// so an admin can't overwrite an edit they never saw.
+fn check_post_precondition(post: &Post, headers: &HeaderMap) -> Result<(), (StatusCode, String)> {
+    if post.validators().is_precondition_met(headers) {
+        return Ok(());
+    }
+
+    Err(post_changed_error(&post._id))
+}
+
+fn post_changed_error(id: &str) -> (StatusCode, String) {
+    let error_message = format!("The post with id: {} was changed since it was read!", id);
+    error!(error_message);
+
+    (StatusCode::PRECONDITION_FAILED, error_message)
+}
+
+/// The filter of a write to `post`. With `If-Match` it only matches the version the
+/// precondition was checked against, so a write that raced in between isn't overwritten.
+fn post_write_filter(post: &Post, headers: &HeaderMap) -> Result<Document, (StatusCode, String)> {
+    let filter = match ObjectId::from_str(&post._id) {
+        Ok(object_id) => doc! { "_id": object_id },
+        Err(err) => {
+            let error_message = err.to_string();
+            error!(error_message);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, error_message));
+        }
+    };
+    if !headers.contains_key(IF_MATCH) {
+        return Ok(filter);
+    }
+
+    let mut filter = filter;
+    filter.insert("updated_at", to_rfc3339_millis(post.updated_at));
+    filter.insert("synced_at", to_rfc3339_millis(post.synced_at));
+    Ok(filter)
+}
 
-    if target_post_object_id_result.is_err() {
-        let error_message = target_post_object_id_result.unwrap_err().to_string();
+/// Tells apart why a write matched nothing: the post changed since it was read, or it's gone.
+async fn unmatched_write_response(mongo: Database, object_id: ObjectId, id: &str) -> Response {
+    match find_one_doc::<Post>(mongo, doc! { "_id": object_id }).await {
+        Ok(Some(_)) => post_changed_error(id).into_response(),
+        Ok(None) => {
+            error!("The post with id: {} not found!", id);
+            (
+                StatusCode::NOT_FOUND,
+                format!("The post with id: {} not found!", id),
+            )
+                .into_response()
+        }
+        Err(err) => {
+            let error_message = err.to_string();
+            error!("{}", error_message.clone());
+            (StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response()
+        }
+    }
+}
+
+async fn find_post(mongo: Database, id: &str) -> Result<Post, Response> {
+    let target_post_object_id_result = ObjectId::from_str(id);
+
+    if let Err(err) = &target_post_object_id_result {
+        let error_message = err.to_string();
         error!(error_message);
 
         return Err((StatusCode::BAD_REQUEST, error_message).into_response());
@@ -105,9 +574,9 @@ pub async fn get_post_by_id(
         "_id": target_post_object_id_result.unwrap()
     };
 
-    match find_one_doc::<Post>(state.mongo, filter).await {
+    match find_one_doc::<Post>(mongo, filter).await {
         Ok(result) => match result {
-            Some(post) => Ok(Json(post)),
+            Some(post) => Ok(post),
             None => {
                 error!("The post with id: {} not found!", id);
                 Err((
@@ -129,10 +598,30 @@ pub async fn create_new_post(
     State(state): State<AppState>,
     Json(req): Json<NewPostRequest>,
 ) -> Result<Json<Bson>, impl IntoResponse> {
+    let tags = match validate_tag_ids(state.mongo.clone(), &req.tagIds).await {
+        Ok(tags) => tags,
+        Err((status_code, error_message)) => {
+            error!(error_message);
+            return Err((status_code, error_message).into_response());
+        }
+    };
+
+    let slug = match unique_slug(state.mongo.clone(), &req.title, None).await {
+        Ok(slug) => slug,
+        Err(err) => {
+            let error_message = format!("fail to pick a slug {}", err);
+            error!(error_message);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response());
+        }
+    };
+
     let new_post = Post {
         _id: ObjectId::new().to_hex(),
         patreon_post_id: "".to_string(),
+        campaign_id: "".to_string(),
         title: req.title,
+        slug,
+        slug_history: vec![],
         content: req.content,
         images_url: req.imagesUrl,
         file_url: req.fileUrl,
@@ -140,14 +629,35 @@ pub async fn create_new_post(
         created_at: DateTime::now(),
         updated_at: DateTime::now(),
         synced_at: DateTime::now(),
+        tags,
+        overridden_fields: vec![],
+        is_orphaned: false,
+        visibility: PostVisibility::Public,
     };
 
-    match insert_one_doc::<Post>(state.mongo, new_post).await {
+    match insert_one_doc::<Post>(state.mongo.clone(), new_post).await {
         Ok(inserted_id) => {
             let object_id = inserted_id.as_object_id().unwrap();
             info!("New Post Created {}", object_id.to_hex());
+            expire_cache(&state, CacheScope::Posts);
+            notify(
+                state.redis,
+                MessageKind::PostChanged {
+                    post_id: object_id.to_hex(),
+                    change: PostChange::Created,
+                },
+            );
             Ok(Json(inserted_id))
         }
+        // another post took the slug between picking and inserting it
+        Err(err) if is_duplicate_key_error(&err) => {
+            error!("{}", err.to_string());
+            Err((
+                StatusCode::CONFLICT,
+                "A post with the same slug was just created!".to_string(),
+            )
+                .into_response())
+        }
         Err(err) => {
             let error_message = err.to_string();
             error!("{}", error_message.clone());
@@ -159,44 +669,114 @@ pub async fn create_new_post(
 pub async fn edit_post(
     State(state): State<AppState>,
     Path(id): Path<String>,
+    headers: HeaderMap,
     Json(req): Json<EditPostRequest>,
 ) -> Result<Json<Post>, impl IntoResponse> {
     let target_post_object_id_result = ObjectId::from_str(&id);
 
-    if target_post_object_id_result.is_err() {
-        let error_message = target_post_object_id_result.unwrap_err().to_string();
+    if let Err(err) = &target_post_object_id_result {
+        let error_message = err.to_string();
         error!(error_message);
 
         return Err((StatusCode::BAD_REQUEST, error_message).into_response());
     }
 
-    let filter = doc! {
-        "_id": target_post_object_id_result.unwrap()
+    let current_post = find_post(state.mongo.clone(), &id).await?;
+    check_post_precondition(&current_post, &headers).map_err(IntoResponse::into_response)?;
+
+    let tags = match &req.tagIds {
+        Some(tag_ids) => match validate_tag_ids(state.mongo.clone(), tag_ids).await {
+            Ok(tags) => Some(tags),
+            Err((status_code, error_message)) => {
+                error!(error_message);
+                return Err((status_code, error_message).into_response());
+            }
+        },
+        None => None,
+    };
+
+    // the slug follows the title, the old one keeps redirecting
+    let slug = if is_slug_of(&current_post.slug, &slugify(&req.title)) {
+        current_post.slug.clone()
+    } else {
+        match unique_slug(
+            state.mongo.clone(),
+            &req.title,
+            target_post_object_id_result.as_ref().ok().copied(),
+        )
+        .await
+        {
+            Ok(slug) => slug,
+            Err(err) => {
+                let error_message = format!("fail to pick a slug {}", err);
+                error!(error_message);
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response());
+            }
+        }
     };
-    let update = doc! {
-        "$set": doc! {
-            "title": req.title,
-            "images_url": req.imagesUrl,
-            "file_url": req.fileUrl,
-            "updated_at": DateTime::now().try_to_rfc3339_string().unwrap()
+    let old_slugs: Vec<&str> = [current_post.slug.as_str()]
+        .into_iter()
+        .filter(|old_slug| !old_slug.is_empty() && *old_slug != slug)
+        .collect();
+
+    let filter = post_write_filter(&current_post, &headers).map_err(IntoResponse::into_response)?;
+    // an admin change to a synced field pins it, so the next sync keeps the admin's value
+    let images_url = Bson::from(req.imagesUrl);
+    let file_url = Bson::from(req.fileUrl);
+    let mut changes = doc! {
+        "overridden_fields": {
+            "$setUnion": [
+                { "$ifNull": ["$overridden_fields", []] },
+                { "$cond": [{ "$eq": ["$images_url", { "$literal": &images_url }] }, [], ["images_url"]] },
+                { "$cond": [{ "$eq": ["$file_url", { "$literal": &file_url }] }, [], ["file_url"]] },
+            ]
+        },
+        "title": { "$literal": req.title },
+        "slug": &slug,
+        "slug_history": {
+            "$setDifference": [
+                { "$setUnion": [{ "$ifNull": ["$slug_history", []] }, old_slugs] },
+                [&slug],
+            ]
         },
+        "images_url": { "$literal": images_url },
+        "file_url": { "$literal": file_url },
+        "updated_at": to_rfc3339_millis(DateTime::now())
     };
+    if let Some(tags) = tags {
+        changes.insert("tags", doc! { "$literal": tags });
+    }
+    let update = vec![doc! { "$set": changes }];
 
-    match edit_one_doc::<Post>(state.mongo, filter, update).await {
+    match edit_one_doc::<Post>(state.mongo.clone(), filter, update).await {
         Ok(result) => match result {
             Some(post) => {
                 info!("Post {} edited", post._id);
+                expire_cache(&state, CacheScope::Posts);
+                notify(
+                    state.redis,
+                    MessageKind::PostChanged {
+                        post_id: post._id.clone(),
+                        change: PostChange::Updated,
+                    },
+                );
                 Ok(Json(post))
             }
-            None => {
-                error!("The post with id: {} not found!", id);
-                Err((
-                    StatusCode::NOT_FOUND,
-                    format!("The post with id: {} not found!", id),
-                )
-                    .into_response())
-            }
+            None => Err(unmatched_write_response(
+                state.mongo.clone(),
+                target_post_object_id_result.unwrap(),
+                &id,
+            )
+            .await),
         },
+        Err(err) if is_duplicate_key_error(&err) => {
+            error!("{}", err.to_string());
+            Err((
+                StatusCode::CONFLICT,
+                "A post with the same slug was just created!".to_string(),
+            )
+                .into_response())
+        }
         Err(err) => {
             let error_message = err.to_string();
             error!("{}", error_message.clone());
@@ -208,31 +788,42 @@ pub async fn edit_post(
 pub async fn delete_post(
     State(state): State<AppState>,
     Path(id): Path<String>,
+    headers: HeaderMap,
 ) -> Result<StatusCode, impl IntoResponse> {
     let target_post_object_id_result = ObjectId::from_str(&id);
 
-    if target_post_object_id_result.is_err() {
-        let error_message = target_post_object_id_result.unwrap_err().to_string();
+    if let Err(err) = &target_post_object_id_result {
+        let error_message = err.to_string();
         error!(error_message);
 
         return Err((StatusCode::BAD_REQUEST, error_message).into_response());
     }
 
-    let filter = doc! {
-        "_id": target_post_object_id_result.unwrap()
+    let object_id = target_post_object_id_result.unwrap();
+
+    // the etag is a digest, so the version it names is only known by reading the post
+    let filter = if headers.contains_key(IF_MATCH) {
+        let current_post = find_post(state.mongo.clone(), &id).await?;
+        check_post_precondition(&current_post, &headers).map_err(IntoResponse::into_response)?;
+        post_write_filter(&current_post, &headers).map_err(IntoResponse::into_response)?
+    } else {
+        doc! { "_id": object_id }
     };
 
-    match delete_one_doc::<Post>(state.mongo, filter).await {
+    match delete_one_doc::<Post>(state.mongo.clone(), filter).await {
         Ok(result) => match result {
-            Some(_) => Ok(StatusCode::OK),
-            None => {
-                error!("The post with id: {} not found!", id);
-                Err((
-                    StatusCode::NOT_FOUND,
-                    format!("The post with id: {} not found!", id),
-                )
-                    .into_response())
+            Some(_) => {
+                expire_cache(&state, CacheScope::Posts);
+                notify(
+                    state.redis,
+                    MessageKind::PostChanged {
+                        post_id: id,
+                        change: PostChange::Deleted,
+                    },
+                );
+                Ok(StatusCode::OK)
             }
+            None => Err(unmatched_write_response(state.mongo.clone(), object_id, &id).await),
         },
         Err(err) => {
             let error_message = err.to_string();
@@ -245,9 +836,10 @@ pub async fn delete_post(
 pub async fn delete_all_posts(
     State(state): State<AppState>,
 ) -> Result<StatusCode, impl IntoResponse> {
-    match delete_all_docs::<Post>(state.mongo).await {
+    match delete_all_docs::<Post>(state.mongo.clone()).await {
         Ok(deleted_count) => {
             info!("{} posts deleted", deleted_count);
+            invalidate_cache(&state, CacheScope::Posts);
             Ok(StatusCode::OK)
         }
         Err(err) => {
@@ -258,42 +850,93 @@ pub async fn delete_all_posts(
     }
 }
 
-pub async fn sync_posts(State(state): State<AppState>) -> StatusCode {
+#[derive(Deserialize, Debug, Default)]
+pub struct SyncPostsQuery {
+    mode: Option<SyncMode>,
+}
+
+pub async fn sync_posts(
+    State(state): State<AppState>,
+    Query(query): Query<SyncPostsQuery>,
+) -> Result<(StatusCode, Json<SyncJobStarted>), impl IntoResponse> {
+    let mode = query.mode.unwrap_or(SyncMode::Incremental);
     let redis = state.redis.clone();
 
-    let is_running_job_exist_result = check_sync_job_exists(redis.clone());
-    if is_running_job_exist_result.is_err() {
-        error!("fail to check sync job");
-        return StatusCode::OK;
+    match check_sync_job_exists(redis.clone()) {
+        Ok(false) => {}
+        Ok(true) => {
+            let error_message = "there is already running job!".to_string();
+            info!(error_message);
+            return Err((StatusCode::CONFLICT, error_message).into_response());
+        }
+        Err(err) => {
+            let error_message = format!("fail to check sync job {}", err);
+            error!(error_message);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response());
+        }
     }
 
-    let is_running_job_exist = is_running_job_exist_result.unwrap();
-    if is_running_job_exist {
-        info!("there is already running job!");
-        return StatusCode::OK;
+    // a queued sync covers this request just as well
+    match has_unfinished_sync_job(redis.clone()) {
+        Ok(false) => {}
+        Ok(true) => {
+            let error_message = "there is already queued job!".to_string();
+            info!(error_message);
+            return Err((StatusCode::CONFLICT, error_message).into_response());
+        }
+        Err(err) => {
+            let error_message = format!("fail to check job queue {}", err);
+            error!(error_message);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response());
+        }
     }
 
-    // tokio::spawn(async move {
-    //     sync_post(x, state.patreon_access_token).await;
-    // });
-    let publish_result = publish_message(redis, Message::new(String::from("Sync")));
-
-    if publish_result.is_err() {
-        error!("Failed to publish message: {}", publish_result.unwrap_err());
+    // queued rather than published, so the request survives no worker listening right now
+    let job = Job::new(JobKind::Sync {
+        mode,
+        trigger: SyncTrigger::Manual,
+    });
+    let job_id = job.id.clone();
+
+    match enqueue_manual_sync(redis, &job) {
+        Ok(true) => Ok((StatusCode::ACCEPTED, Json(SyncJobStarted { job_id }))),
+        // another trigger got through since the checks above
+        Ok(false) => {
+            let error_message = "there is already queued job!".to_string();
+            info!(error_message);
+            Err((StatusCode::CONFLICT, error_message).into_response())
+        }
+        Err(err) => {
+            let error_message = format!("fail to queue sync job {}", err);
+            error!(error_message);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response())
+        }
     }
-
-    StatusCode::OK
 }
 
+use crate::cache::{cached, expire_cache, invalidate_cache};
+use crate::conditional::{ConditionalJson, ResourceVersion, Validators};
 use crate::dao::{
-    delete_all_docs, delete_one_doc, edit_one_doc, find_one_doc, get_all_docs, insert_one_doc,
+    create_indexes, delete_all_docs, delete_one_doc, edit_one_doc, find_docs, find_docs_paginated,
+    find_one_doc, insert_one_doc, is_duplicate_key_error, update_many_docs, Page, PageCursor,
+    PageOptions, SortOrder,
+};
+use crate::job_queue::{enqueue_manual_sync, has_unfinished_sync_job, Job, JobKind};
+use crate::jwt_auth::TokenClaims;
+use crate::patreon_content::extract_content;
+use crate::redis_pubsub::message::{CacheScope, MessageKind, PostChange, SyncTrigger};
+use crate::redis_pubsub::pubsub::notify;
+use crate::slug::{is_slug_of, slug_path, slugify, unique_slug, unique_slugs};
+use crate::sync_job::{check_sync_job_exists, SyncJobStarted};
+use crate::sync_post::SyncMode;
+use crate::tags::validate_tag_ids;
+use crate::util::{
+    bson_datetime_as_rfc3339_millis, date_range_filter, get_chrono_dt_from_string,
+    normalize_date_fields, to_rfc3339_millis,
 };
-use crate::redis_pubsub::message::Message;
-use crate::redis_pubsub::pubsub::publish_message;
-use crate::util::get_chrono_dt_from_string;
+use crate::visibility::{Entitlement, PostVisibility};
 #[cfg(test)]
 use test_env_helpers::*;
-use crate::sync_job::check_sync_job_exists;
 
 #[before_all]
 #[cfg(test)]
@@ -307,6 +950,18 @@ mod tests {
     use mongodb::Client;
     use testcontainers_modules::{redis::REDIS_PORT, testcontainers::clients};
 
+    impl Post {
+        pub fn with_tags(mut self, tags: Vec<String>) -> Self {
+            self.tags = tags;
+            self
+        }
+
+        pub fn orphaned(mut self) -> Self {
+            self.is_orphaned = true;
+            self
+        }
+    }
+
     async fn before_all() {
         // let docker = clients::Cli::default();
         // let port = generate_port_number();
@@ -332,7 +987,13 @@ mod tests {
 
         let state = create_test_state(test_db, redis_client);
 
-        let result = get_all_posts(State(state)).await;
+        let result = get_all_posts(
+            State(state),
+            None,
+            HeaderMap::new(),
+            Query(PostListQuery::default()),
+        )
+        .await;
 
         assert!(result.is_ok());
 
@@ -342,6 +1003,151 @@ mod tests {
         // assert_eq!(result.unwrap()., Json(vec![]));
     }
 
+    #[test]
+    fn test_post_list_query_filter() {
+        let query = PostListQuery {
+            mod_type: Some("preset".to_string()),
+            created_from: Some("2024-01-01T00:00:00Z".to_string()),
+            created_to: Some("2024-02-01T09:00:00+09:00".to_string()),
+            ..Default::default()
+        };
+
+        let filter = query.filter().unwrap();
+
+        assert_eq!(
+            filter,
+            doc! {
+                "mod_type": "preset",
+                "created_at": doc! {
+                    "$gte": "2024-01-01T00:00:00.000Z",
+                    "$lte": "2024-02-01T00:00:00.000Z",
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn test_post_list_query_tag_filter() {
+        let query = PostListQuery {
+            tags: Some("aaa, bbb,,".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            query.filter().unwrap(),
+            doc! { "tags": doc! { "$all": ["aaa", "bbb"] } }
+        );
+    }
+
+    #[test]
+    fn test_post_list_query_campaign_filter() {
+        let query = PostListQuery {
+            campaign_id: Some("1234567".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(query.filter().unwrap(), doc! { "campaign_id": "1234567" });
+    }
+
+    #[test]
+    fn test_post_list_query_orphaned_filter() {
+        let query = PostListQuery {
+            orphaned: Some(true),
+            ..Default::default()
+        };
+
+        assert_eq!(query.filter().unwrap(), doc! { "is_orphaned": true });
+        assert_eq!(
+            query.for_public().filter().unwrap(),
+            doc! { "is_orphaned": doc! { "$ne": true } }
+        );
+        assert_eq!(PostListQuery::default().filter().unwrap(), doc! {});
+    }
+
+    #[test]
+    fn test_post_list_query_filter_invalid_date() {
+        let query = PostListQuery {
+            updated_from: Some("yesterday".to_string()),
+            ..Default::default()
+        };
+
+        assert!(query.filter().is_err());
+    }
+
+    #[test]
+    fn test_post_list_query_page_options() {
+        let page_options = PostListQuery::default().page_options().unwrap();
+
+        assert_eq!(page_options.sort_field, "created_at");
+        assert_eq!(page_options.order, SortOrder::Desc);
+        assert!(page_options.cursor.is_none());
+
+        let query = PostListQuery {
+            cursor: Some("garbage".to_string()),
+            ..Default::default()
+        };
+
+        assert!(query.page_options().is_err());
+    }
+
+    #[test]
+    fn test_redact_posts() {
+        let content = "<p><a href=\"https://mega.nz/file/QwErTy#abc987\">Download</a></p>";
+        let new_post = |visibility: PostVisibility| {
+            Post::new_for_sync("8365446", "1", "Preset", content, "").with_visibility(visibility)
+        };
+        let mut posts = vec![
+            new_post(PostVisibility::Public),
+            new_post(PostVisibility::Patrons),
+            new_post(PostVisibility::MinimumTier {
+                tier_ids: vec!["5678902".to_string()],
+            }),
+        ];
+        let locked_id = posts[2]._id.clone();
+
+        let redacted_ids = redact_posts(
+            &mut posts,
+            &Entitlement::Patron {
+                tier_ids: vec!["5678901".to_string()],
+            },
+        );
+
+        assert_eq!(redacted_ids, locked_id);
+        assert_eq!(posts[1].file_url, "https://mega.nz/file/QwErTy#abc987");
+        assert_eq!(posts[1].content, content);
+        assert_eq!(posts[2].file_url, "");
+        assert_eq!(posts[2].content, "");
+
+        assert_eq!(redact_posts(&mut posts, &Entitlement::Everything), "");
+        assert!(new_post(PostVisibility::Patrons).redact_for(&Entitlement::Public));
+    }
+
+    #[test]
+    fn test_post_write_filter() {
+        let post = Post::new_for_sync("8365446", "1", "Preset", "", "2024-01-23T13:48:06.761Z");
+        let object_id = ObjectId::from_str(&post._id).unwrap();
+
+        assert_eq!(
+            post_write_filter(&post, &HeaderMap::new()).unwrap(),
+            doc! { "_id": object_id }
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_MATCH, axum::http::HeaderValue::from_static("\"asdf\""));
+        assert_eq!(
+            post_write_filter(&post, &headers).unwrap(),
+            doc! {
+                "_id": object_id,
+                "updated_at": "2024-01-23T13:48:06.761Z",
+                "synced_at": to_rfc3339_millis(post.synced_at),
+            }
+        );
+        assert_eq!(
+            check_post_precondition(&post, &headers).unwrap_err().0,
+            StatusCode::PRECONDITION_FAILED
+        );
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_get_post_by_id_invalid_id() {
         let docker = clients::Cli::default();
@@ -360,7 +1166,13 @@ mod tests {
 
         let state = create_test_state(test_db, redis_client);
 
-        let result = get_post_by_id(State(state), Path("aaaa".to_string())).await;
+        let result = get_post_by_id(
+            State(state),
+            None,
+            Path("aaaa".to_string()),
+            HeaderMap::new(),
+        )
+        .await;
 
         assert!(result.is_err());
 
@@ -396,7 +1208,10 @@ mod tests {
         let new_post = Post {
             _id: ObjectId::new().to_hex(),
             patreon_post_id: "123123".to_string(),
+            campaign_id: "8365446".to_string(),
             title: new_post_title.clone(),
+            slug: "".to_string(),
+            slug_history: vec![],
             content: new_post_content.clone(),
             images_url: new_post_images_url.clone(),
             file_url: new_post_file_url.clone(),
@@ -404,16 +1219,21 @@ mod tests {
             created_at: DateTime::now(),
             updated_at: DateTime::now(),
             synced_at: DateTime::now(),
+            tags: vec![],
+            overridden_fields: vec![],
+            is_orphaned: false,
+            visibility: PostVisibility::Public,
         };
 
         let inserted_post_object_id = insert_test_post(test_db, new_post).await;
         let object_id_string = inserted_post_object_id.to_hex();
 
-        let result = get_post_by_id(State(state), Path(object_id_string)).await;
+        let result =
+            get_post_by_id(State(state), None, Path(object_id_string), HeaderMap::new()).await;
 
         assert!(result.is_ok());
 
-        let found_post = result.ok().unwrap().0;
+        let found_post = result.ok().unwrap().body;
 
         assert_eq!(found_post.title, new_post_title);
         assert_eq!(found_post.images_url, new_post_images_url);
@@ -450,6 +1270,7 @@ mod tests {
             imagesUrl: new_post_images_url.clone(),
             fileUrl: new_post_file_url.clone(),
             modType: new_post_mod_type.clone(),
+            tagIds: vec![],
         };
 
         let result = create_new_post(State(state), Json(new_post_request)).await;
@@ -484,7 +1305,10 @@ mod tests {
         let new_post = Post {
             _id: ObjectId::new().to_hex(),
             patreon_post_id: "123123".to_string(),
+            campaign_id: "8365446".to_string(),
             title: "test post".to_string(),
+            slug: "test-post".to_string(),
+            slug_history: vec![],
             content: "test content".to_string(),
             images_url: vec![],
             file_url: "test file url".to_string(),
@@ -492,6 +1316,10 @@ mod tests {
             created_at: DateTime::now(),
             updated_at: DateTime::now(),
             synced_at: DateTime::now(),
+            tags: vec![],
+            overridden_fields: vec![],
+            is_orphaned: false,
+            visibility: PostVisibility::Public,
         };
 
         let updated_title = "updated test post".to_string();
@@ -506,6 +1334,7 @@ mod tests {
             imagesUrl: updated_image_url.clone(),
             fileUrl: updated_file_url.clone(),
             modType: updated_mod_type.clone(),
+            tagIds: None,
         };
 
         let inserted_post_object_id = insert_test_post(test_db.clone(), new_post).await;
@@ -513,6 +1342,7 @@ mod tests {
         let result = edit_post(
             State(state),
             Path(object_id_string),
+            HeaderMap::new(),
             Json(edit_post_request),
         )
         .await;
@@ -526,6 +1356,12 @@ mod tests {
         assert_eq!(updated_post.title, updated_title);
         assert_eq!(updated_post.images_url, updated_image_url);
         assert_eq!(updated_post.file_url, updated_file_url);
+        assert!(updated_post
+            .overridden_fields
+            .contains(&"images_url".to_string()));
+        assert!(updated_post
+            .overridden_fields
+            .contains(&"file_url".to_string()));
     }
 
     #[tokio::test(flavor = "multi_thread")]
@@ -549,7 +1385,10 @@ mod tests {
         let new_post = Post {
             _id: ObjectId::new().to_hex(),
             patreon_post_id: "123123".to_string(),
+            campaign_id: "8365446".to_string(),
             title: "test post".to_string(),
+            slug: "test-post".to_string(),
+            slug_history: vec![],
             content: "test content".to_string(),
             images_url: vec![],
             file_url: "test file url".to_string(),
@@ -557,11 +1396,15 @@ mod tests {
             created_at: DateTime::now(),
             updated_at: DateTime::now(),
             synced_at: DateTime::now(),
+            tags: vec![],
+            overridden_fields: vec![],
+            is_orphaned: false,
+            visibility: PostVisibility::Public,
         };
 
         let inserted_post_object_id = insert_test_post(test_db.clone(), new_post).await;
         let object_id_string = inserted_post_object_id.to_hex();
-        let result = delete_post(State(state), Path(object_id_string)).await;
+        let result = delete_post(State(state), Path(object_id_string), HeaderMap::new()).await;
 
         assert!(result.is_ok());
 
@@ -596,4 +1439,50 @@ mod tests {
 
         assert_eq!(count_posts, 0);
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_normalize_post_dates() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let _c = docker.run(mongo_img);
+
+        let uri = get_db_connection_uri(&port);
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let test_db = client.database("test_db");
+        let typed_collection = test_db.collection::<Document>("Post");
+        typed_collection
+            .insert_one(
+                doc! {
+                    "title": "Preset",
+                    "created_at": "2024-03-15T10:00:49Z",
+                    "updated_at": "2024-03-15T10:00:49.5Z",
+                    "synced_at": "2024-03-15T10:00:49.761Z",
+                },
+                None,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(normalize_post_dates(test_db.clone()).await.unwrap(), 2);
+        assert_eq!(normalize_post_dates(test_db.clone()).await.unwrap(), 0);
+
+        let post = typed_collection
+            .find_one(doc! { "title": "Preset" }, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            post.get_str("created_at").unwrap(),
+            "2024-03-15T10:00:49.000Z"
+        );
+        assert_eq!(
+            post.get_str("updated_at").unwrap(),
+            "2024-03-15T10:00:49.500Z"
+        );
+        assert_eq!(
+            post.get_str("synced_at").unwrap(),
+            "2024-03-15T10:00:49.761Z"
+        );
+    }
 }
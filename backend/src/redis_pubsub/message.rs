@@ -1,17 +1,145 @@
+use crate::sync_post::SyncMode;
+use crate::sync_progress::SyncProgress;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
 use uuid::Uuid;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Bumped whenever a change to `MessageKind` can't be read by instances still on the old one.
+pub const MESSAGE_VERSION: u64 = 1;
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncTrigger {
+    #[default]
+    Manual,
+    Schedule,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PostChange {
+    Created,
+    Updated,
+    Deleted,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheScope {
+    Posts,
+    Tags,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum MessageKind {
+    SyncRequested {
+        mode: SyncMode,
+        #[serde(default)]
+        trigger: SyncTrigger,
+    },
+    SyncProgress(SyncProgress),
+    PostChanged {
+        post_id: String,
+        change: PostChange,
+    },
+    CacheInvalidate {
+        scope: CacheScope,
+    },
+    Ping,
+}
+
+/// What a handler subscribes to, one per `MessageKind` variant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageType {
+    SyncRequested,
+    SyncProgress,
+    PostChanged,
+    CacheInvalidate,
+    Ping,
+}
+
+impl MessageKind {
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            MessageKind::SyncRequested { .. } => MessageType::SyncRequested,
+            MessageKind::SyncProgress(_) => MessageType::SyncProgress,
+            MessageKind::PostChanged { .. } => MessageType::PostChanged,
+            MessageKind::CacheInvalidate { .. } => MessageType::CacheInvalidate,
+            MessageKind::Ping => MessageType::Ping,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Message {
+    pub version: u64,
     pub id: String,
-    pub payload: String,
+    #[serde(flatten)]
+    pub kind: MessageKind,
+}
+
+/// Messages published before they were versioned, e.g. `{ "id": "...", "payload": "FullSync" }`.
+#[derive(Deserialize, Debug)]
+struct LegacyMessage {
+    id: String,
+    payload: String,
+}
+
+#[derive(Debug)]
+pub enum MessageError {
+    Json(serde_json::Error),
+    UnsupportedVersion(u64),
+    UnknownPayload(String),
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::Json(err) => write!(f, "malformed message: {}", err),
+            MessageError::UnsupportedVersion(version) => {
+                write!(f, "unsupported message version {}", version)
+            }
+            MessageError::UnknownPayload(payload) => {
+                write!(f, "unknown legacy message payload {}", payload)
+            }
+        }
+    }
 }
 
+impl std::error::Error for MessageError {}
+
 impl Message {
-    pub fn new(payload: String) -> Message {
+    pub fn new(kind: MessageKind) -> Message {
         Message {
+            version: MESSAGE_VERSION,
             id: Message::generate_id(),
-            payload,
+            kind,
+        }
+    }
+
+    pub fn decode(received: &str) -> Result<Message, MessageError> {
+        let value = serde_json::from_str::<Value>(received).map_err(MessageError::Json)?;
+
+        match value.get("version").and_then(Value::as_u64) {
+            Some(MESSAGE_VERSION) => serde_json::from_value(value).map_err(MessageError::Json),
+            Some(version) => Err(MessageError::UnsupportedVersion(version)),
+            None => {
+                let legacy =
+                    serde_json::from_value::<LegacyMessage>(value).map_err(MessageError::Json)?;
+                match SyncMode::from_payload(&legacy.payload) {
+                    Some(mode) => Ok(Message {
+                        version: MESSAGE_VERSION,
+                        id: legacy.id,
+                        kind: MessageKind::SyncRequested {
+                            mode,
+                            trigger: SyncTrigger::Manual,
+                        },
+                    }),
+                    None => Err(MessageError::UnknownPayload(legacy.payload)),
+                }
+            }
         }
     }
 
@@ -20,11 +148,98 @@ impl Message {
     }
 }
 
-impl Default for Message {
-    fn default() -> Self {
-        Self {
-            id: String::from(""),
-            payload: String::from(""),
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync_progress::SyncProgressEvent;
+    use serde_json::json;
+
+    #[test]
+    fn test_message_json() {
+        let message = Message {
+            version: MESSAGE_VERSION,
+            id: "asdf".to_string(),
+            kind: MessageKind::SyncRequested {
+                mode: SyncMode::Incremental,
+                trigger: SyncTrigger::Schedule,
+            },
+        };
+
+        let value = serde_json::to_value(&message).unwrap();
+
+        assert_eq!(
+            value,
+            json!({
+                "version": 1,
+                "id": "asdf",
+                "kind": "sync_requested",
+                "mode": "incremental",
+                "trigger": "schedule",
+            })
+        );
+        assert_eq!(Message::decode(&value.to_string()).unwrap(), message);
+    }
+
+    #[test]
+    fn test_decode_every_kind() {
+        let kinds = vec![
+            MessageKind::SyncRequested {
+                mode: SyncMode::Full,
+                trigger: SyncTrigger::Manual,
+            },
+            MessageKind::SyncProgress(SyncProgress {
+                job_id: "asdf".to_string(),
+                event: SyncProgressEvent::Finished { sync_count: 3 },
+            }),
+            MessageKind::PostChanged {
+                post_id: "65f2a0c8e4b0a1b2c3d4e5f6".to_string(),
+                change: PostChange::Updated,
+            },
+            MessageKind::CacheInvalidate {
+                scope: CacheScope::Posts,
+            },
+            MessageKind::Ping,
+        ];
+
+        for kind in kinds {
+            let message = Message::new(kind);
+            let received = serde_json::to_string(&message).unwrap();
+
+            assert_eq!(Message::decode(&received).unwrap(), message);
         }
     }
+
+    #[test]
+    fn test_decode_legacy_message() {
+        let message = Message::decode(r#"{"id":"asdf","payload":"FullSync"}"#).unwrap();
+
+        assert_eq!(message.id, "asdf");
+        assert_eq!(
+            message.kind,
+            MessageKind::SyncRequested {
+                mode: SyncMode::Full,
+                trigger: SyncTrigger::Manual,
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_malformed_message() {
+        assert!(matches!(
+            Message::decode("Sync"),
+            Err(MessageError::Json(_))
+        ));
+        assert!(matches!(
+            Message::decode(r#"{"version":1,"id":"asdf","kind":"unknown"}"#),
+            Err(MessageError::Json(_))
+        ));
+        assert!(matches!(
+            Message::decode(r#"{"version":2,"id":"asdf","kind":"ping"}"#),
+            Err(MessageError::UnsupportedVersion(2))
+        ));
+        assert!(matches!(
+            Message::decode(r#"{"id":"asdf","payload":"test message"}"#),
+            Err(MessageError::UnknownPayload(_))
+        ));
+    }
 }
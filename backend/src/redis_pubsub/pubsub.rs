@@ -1,9 +1,21 @@
-use crate::redis_pubsub::message::Message;
+use crate::background::{reconnect_policy, BackgroundTask};
+use crate::cache::CacheInvalidateHandler;
+use crate::job_queue::{enqueue_job, Job, JobKind};
+use crate::redis_pubsub::message::{Message, MessageKind, MessageType};
 use crate::redis_pubsub::CHANNEL;
-use crate::sync_post::sync_post;
 use crate::AppState;
+use async_trait::async_trait;
+use axum::extract::State;
+use axum::Json;
+use futures::StreamExt;
+use redis::aio::PubSub;
 use redis::Commands;
-use tracing::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::watch;
+use tracing::{debug, error, info};
 
 pub fn publish_message(redis: redis::Client, message: Message) -> anyhow::Result<i64> {
     let mut con = redis.get_connection()?;
@@ -16,54 +28,349 @@ pub fn publish_message(redis: redis::Client, message: Message) -> anyhow::Result
     Ok(num)
 }
 
-pub fn subscribe(state: AppState) -> anyhow::Result<()> {
-    // let _ = tokio::spawn(async move {
-    //     let mut con = redis.get_connection().unwrap();
-    //
-    //     let _: () = con.subscribe(CHANNEL, |message| {
-    //         let received: String = message.get_payload().unwrap();
-    //         let message_obj = serde_json::from_str::<Message>(&received).unwrap();
-    //
-    //         println!("{:?}", message_obj);
-    //
-    //         return ControlFlow::Continue;
-    //     }).unwrap();
-    // });
-    let redis = state.redis.clone();
-
-    // TODO: propagate errors
-    tokio::spawn(async move {
-        let mut con = redis.get_connection().unwrap();
-        let mut pubsub = con.as_pubsub();
-        pubsub.subscribe(CHANNEL).unwrap();
+/// Publishes a message nobody waits on, so a failure is only logged.
+pub fn notify(redis: redis::Client, kind: MessageKind) {
+    if let Err(err) = publish_message(redis, Message::new(kind)) {
+        error!("Failed to publish message: {}", err);
+    }
+}
+
+#[async_trait]
+pub trait MessageHandler: Send + Sync {
+    async fn handle(&self, state: AppState, message: Message);
+}
+
+/// Counts what this instance received, for spotting publishers speaking another protocol.
+#[derive(Debug, Default)]
+pub struct MessageStats {
+    received_count: AtomicU64,
+    malformed_count: AtomicU64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct MessageStatsResponse {
+    pub received_count: u64,
+    pub malformed_count: u64,
+}
+
+impl MessageStats {
+    pub fn snapshot(&self) -> MessageStatsResponse {
+        MessageStatsResponse {
+            received_count: self.received_count.load(Ordering::Relaxed),
+            malformed_count: self.malformed_count.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Routes each received message to the handlers registered for its kind.
+#[derive(Default, Clone)]
+pub struct Dispatcher {
+    handlers: HashMap<MessageType, Vec<Arc<dyn MessageHandler>>>,
+}
+
+impl Dispatcher {
+    pub fn new() -> Self {
+        Dispatcher::default()
+    }
+
+    pub fn on(mut self, message_type: MessageType, handler: impl MessageHandler + 'static) -> Self {
+        self.handlers
+            .entry(message_type)
+            .or_default()
+            .push(Arc::new(handler));
+        self
+    }
+
+    /// Spawns the handlers of the received message, returning how many there were.
+    pub fn dispatch(&self, state: &AppState, received: &str) -> usize {
+        state
+            .message_stats
+            .received_count
+            .fetch_add(1, Ordering::Relaxed);
+
+        let message = match Message::decode(received) {
+            Ok(message) => message,
+            Err(err) => {
+                let malformed_count = state
+                    .message_stats
+                    .malformed_count
+                    .fetch_add(1, Ordering::Relaxed)
+                    + 1;
+                error!(
+                    "{} ({} malformed so far): {}",
+                    err, malformed_count, received
+                );
+                return 0;
+            }
+        };
+        debug!("Message received: {:?}", message);
+
+        let handlers = match self.handlers.get(&message.kind.message_type()) {
+            Some(handlers) => handlers,
+            None => return 0,
+        };
+
+        for handler in handlers {
+            let handler = Arc::clone(handler);
+            let state = state.clone();
+            let message = message.clone();
+            tokio::spawn(async move { handler.handle(state, message).await });
+        }
+
+        handlers.len()
+    }
+}
 
-        loop {
-            let msg = pubsub.get_message().unwrap();
-            let received: String = msg.get_payload().unwrap();
-            let message_obj = serde_json::from_str::<Message>(&received).unwrap_or_default();
+pub struct SyncRequestedHandler;
 
-            debug!("Message received: {:?}", message_obj);
+#[async_trait]
+impl MessageHandler for SyncRequestedHandler {
+    async fn handle(&self, state: AppState, message: Message) {
+        if let MessageKind::SyncRequested { mode, trigger } = message.kind {
+            info!("{:?} sync requested by {:?}", mode, trigger);
+            // every instance receives the message, the message id makes sure it's queued once
+            let job = Job::with_id(&message.id, JobKind::Sync { mode, trigger });
+            if let Err(err) = enqueue_job(state.redis, &job) {
+                error!("fail to queue sync job {} {}", job.id, err);
+            }
+        }
+    }
+}
+
+pub fn default_dispatcher() -> Dispatcher {
+    Dispatcher::new()
+        .on(MessageType::SyncRequested, SyncRequestedHandler)
+        .on(MessageType::CacheInvalidate, CacheInvalidateHandler)
+        .on(MessageType::PostChanged, CacheInvalidateHandler)
+}
+
+/// Whether this instance currently listens to the channel, for the readiness endpoint.
+#[derive(Debug, Default)]
+pub struct SubscriberHealth {
+    is_connected: AtomicBool,
+    reconnect_count: AtomicU64,
+}
+
+impl SubscriberHealth {
+    pub fn is_connected(&self) -> bool {
+        self.is_connected.load(Ordering::Relaxed)
+    }
+
+    pub fn reconnect_count(&self) -> u64 {
+        self.reconnect_count.load(Ordering::Relaxed)
+    }
+
+    fn set_connected(&self, is_connected: bool) {
+        self.is_connected.store(is_connected, Ordering::Relaxed);
+    }
+}
 
-            if received.eq("Sync") {
-                sync_post(state.clone()).await;
+pub fn subscribe(state: AppState, dispatcher: Dispatcher) -> BackgroundTask {
+    BackgroundTask::spawn("subscriber", |shutdown| {
+        run_subscriber(state, dispatcher, shutdown)
+    })
+}
+
+async fn run_subscriber(
+    state: AppState,
+    dispatcher: Dispatcher,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let health = Arc::clone(&state.subscriber_health);
+    let policy = reconnect_policy();
+    let mut attempt = 0;
+
+    while !*shutdown.borrow() {
+        match subscribe_channel(&state.redis).await {
+            Ok(pubsub) => {
+                attempt = 0;
+                health.set_connected(true);
+                info!("subscribed to channel {}", CHANNEL);
+
+                let mut messages = pubsub.into_on_message();
+                loop {
+                    tokio::select! {
+                        _ = shutdown.changed() => break,
+                        msg = messages.next() => match msg {
+                            Some(msg) => match msg.get_payload::<String>() {
+                                Ok(received) => {
+                                    dispatcher.dispatch(&state, &received);
+                                }
+                                Err(err) => error!("fail to read message payload {}", err),
+                            },
+                            None => {
+                                error!("lost connection to channel {}", CHANNEL);
+                                break;
+                            }
+                        },
+                    }
+                }
+
+                health.set_connected(false);
             }
+            Err(err) => error!("fail to subscribe channel {} {}", CHANNEL, err),
+        }
+
+        if *shutdown.borrow() {
+            break;
         }
-    });
 
-    Ok(())
+        let delay = policy.backoff(attempt);
+        attempt = attempt.saturating_add(1);
+        health.reconnect_count.fetch_add(1, Ordering::Relaxed);
+        info!("resubscribing to channel {} in {:?}", CHANNEL, delay);
+
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            _ = tokio::time::sleep(delay) => {}
+        }
+    }
+
+    info!("subscriber to channel {} stopped", CHANNEL);
+}
+
+async fn subscribe_channel(redis: &redis::Client) -> redis::RedisResult<PubSub> {
+    let mut pubsub = redis.get_async_pubsub().await?;
+    pubsub.subscribe(CHANNEL).await?;
+
+    Ok(pubsub)
+}
+
+pub async fn get_message_stats(State(state): State<AppState>) -> Json<MessageStatsResponse> {
+    Json(state.message_stats.snapshot())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::redis_pubsub::message::Message;
+    use crate::redis_pubsub::message::{CacheScope, PostChange, SyncTrigger};
+    use crate::sync_post::SyncMode;
+    use crate::sync_progress::{SyncProgress, SyncProgressEvent};
     use crate::test_util::test_util::{
         create_test_state, generate_port_number, get_db_connection_uri, get_mongo_image,
         get_redis_connection_uri, get_redis_image,
     };
     use mongodb::Client;
+    use std::time::Duration;
     use testcontainers_modules::redis::REDIS_PORT;
     use testcontainers_modules::testcontainers::clients;
+    use tokio::sync::mpsc;
+
+    /// Forwards every message it handles, so tests can wait for it.
+    struct RecordingHandler(mpsc::UnboundedSender<Message>);
+
+    #[async_trait]
+    impl MessageHandler for RecordingHandler {
+        async fn handle(&self, _state: AppState, message: Message) {
+            self.0.send(message).unwrap();
+        }
+    }
+
+    fn every_kind() -> Vec<MessageKind> {
+        vec![
+            MessageKind::SyncRequested {
+                mode: SyncMode::Full,
+                trigger: SyncTrigger::Manual,
+            },
+            MessageKind::SyncProgress(SyncProgress {
+                job_id: "asdf".to_string(),
+                event: SyncProgressEvent::PostsInserted { count: 2 },
+            }),
+            MessageKind::PostChanged {
+                post_id: "65f2a0c8e4b0a1b2c3d4e5f6".to_string(),
+                change: PostChange::Deleted,
+            },
+            MessageKind::CacheInvalidate {
+                scope: CacheScope::Tags,
+            },
+            MessageKind::Ping,
+        ]
+    }
+
+    fn recording_dispatcher() -> (Dispatcher, mpsc::UnboundedReceiver<Message>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let dispatcher = every_kind()
+            .iter()
+            .fold(Dispatcher::new(), |dispatcher, kind| {
+                dispatcher.on(kind.message_type(), RecordingHandler(sender.clone()))
+            });
+
+        (dispatcher, receiver)
+    }
+
+    /// Neither client connects until used, so dispatching needs no containers.
+    async fn create_offline_state() -> AppState {
+        let client = Client::with_uri_str("mongodb://127.0.0.1:1").await.unwrap();
+        let redis_client = redis::Client::open("redis://127.0.0.1:1").unwrap();
+
+        create_test_state(client.database("test_db"), redis_client)
+    }
+
+    async fn next_message(receiver: &mut mpsc::UnboundedReceiver<Message>) -> Message {
+        tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn wait_until_connected(state: &AppState) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !state.subscriber_health.is_connected() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_every_kind() {
+        let state = create_offline_state().await;
+        let (dispatcher, mut receiver) = recording_dispatcher();
+
+        for kind in every_kind() {
+            let message = Message::new(kind);
+
+            let handler_count =
+                dispatcher.dispatch(&state, &serde_json::to_string(&message).unwrap());
+
+            assert_eq!(handler_count, 1);
+            assert_eq!(next_message(&mut receiver).await, message);
+        }
+        assert_eq!(state.message_stats.snapshot().malformed_count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_without_handler() {
+        let state = create_offline_state().await;
+        let message = Message::new(MessageKind::Ping);
+
+        let handler_count =
+            Dispatcher::new().dispatch(&state, &serde_json::to_string(&message).unwrap());
+
+        assert_eq!(handler_count, 0);
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_counts_malformed_messages() {
+        let state = create_offline_state().await;
+        let (dispatcher, mut receiver) = recording_dispatcher();
+
+        assert_eq!(dispatcher.dispatch(&state, "Sync"), 0);
+        assert_eq!(
+            dispatcher.dispatch(&state, r#"{"version":1,"id":"asdf","kind":"unknown"}"#),
+            0
+        );
+
+        assert_eq!(
+            state.message_stats.snapshot(),
+            MessageStatsResponse {
+                received_count: 2,
+                malformed_count: 2,
+            }
+        );
+        assert!(receiver.try_recv().is_err());
+    }
 
     #[tokio::test]
     async fn test_subscribe() {
@@ -80,10 +387,54 @@ mod tests {
         let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
 
         let test_db = client.database("test_db");
+        let state = create_test_state(test_db, redis_client);
 
-        let result = subscribe(create_test_state(test_db, redis_client));
+        let subscriber = subscribe(state.clone(), default_dispatcher());
+        wait_until_connected(&state).await;
 
-        assert!(result.is_ok());
+        subscriber.shutdown().await;
+
+        assert!(!state.subscriber_health.is_connected());
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_keeps_retrying_until_shutdown() {
+        let state = create_offline_state().await;
+
+        let subscriber = subscribe(state.clone(), default_dispatcher());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        assert!(!state.subscriber_health.is_connected());
+        assert!(state.subscriber_health.reconnect_count() >= 1);
+
+        // the backoff sleep is cut short
+        tokio::time::timeout(Duration::from_secs(1), subscriber.shutdown())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_subscribe_dispatches_published_messages() {
+        let docker = clients::Cli::default();
+        let redis_img = get_redis_image();
+        let redis_node = docker.run(redis_img);
+
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+        let client = Client::with_uri_str("mongodb://127.0.0.1:1").await.unwrap();
+        let state = create_test_state(client.database("test_db"), redis_client.clone());
+
+        let (dispatcher, mut receiver) = recording_dispatcher();
+        let _subscriber = subscribe(state.clone(), dispatcher);
+        wait_until_connected(&state).await;
+
+        for kind in every_kind() {
+            let message = Message::new(kind);
+
+            publish_message(redis_client.clone(), message.clone()).unwrap();
+
+            assert_eq!(next_message(&mut receiver).await, message);
+        }
     }
 
     #[tokio::test]
@@ -99,7 +450,7 @@ mod tests {
         let mut pubsub = con.as_pubsub();
         pubsub.subscribe(CHANNEL).unwrap();
 
-        let result = publish_message(redis_client, Message::new(String::from("test message")));
+        let result = publish_message(redis_client, Message::new(MessageKind::Ping));
 
         assert!(result.is_ok());
         assert_eq!(result.unwrap(), 1);
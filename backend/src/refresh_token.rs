@@ -0,0 +1,197 @@
+use crate::jwt_auth::deny_access_tokens;
+use rand::RngCore;
+use redis::{AsyncCommands, Script};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::info;
+
+const REFRESH_TOKEN_KEY_PREFIX: &str = "auth:refresh";
+const TOKEN_FAMILY_KEY_PREFIX: &str = "auth:family";
+/// How long an admin stays logged in without using the refresh token. Every rotation
+/// extends the whole family by as much.
+pub const REFRESH_TOKEN_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// a rotation must not bring back a family revoked since its token was checked
+const STORE_REFRESH_TOKEN_SCRIPT: &str = r#"
+if ARGV[6] == "0" and redis.call("EXISTS", KEYS[2]) == 0 then
+    return 0
+end
+redis.call("HSET", KEYS[1], "family_id", ARGV[1], "name", ARGV[2], "role", ARGV[3])
+redis.call("EXPIRE", KEYS[1], ARGV[5])
+redis.call("SET", KEYS[2], ARGV[2], "EX", ARGV[5])
+redis.call("SADD", KEYS[3], ARGV[4])
+redis.call("EXPIRE", KEYS[3], ARGV[5])
+return 1
+"#;
+// returns the ids of the family's access tokens, none of which can be added after
+const REVOKE_TOKEN_FAMILY_SCRIPT: &str = r#"
+local jtis = redis.call("SMEMBERS", KEYS[2])
+redis.call("DEL", KEYS[1], KEYS[2])
+return jtis
+"#;
+
+/// Who a refresh token was issued to, and the family each rotation of it stays in.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RefreshTokenGrant {
+    pub family_id: String,
+    pub name: String,
+    pub role: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RefreshOutcome {
+    Rotated(RefreshTokenGrant),
+    /// Unknown, expired, or of a revoked family.
+    Invalid,
+    /// Used before, so it was copied. The whole family is revoked.
+    Reused,
+}
+
+/// Only the hash is stored, a redis dump alone doesn't let anyone log in.
+fn hash_token(refresh_token: &str) -> String {
+    hex::encode(Sha256::digest(refresh_token.as_bytes()))
+}
+
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+
+    hex::encode(bytes)
+}
+
+fn refresh_token_key(refresh_token: &str) -> String {
+    format!("{}:{}", REFRESH_TOKEN_KEY_PREFIX, hash_token(refresh_token))
+}
+
+fn token_family_key(family_id: &str) -> String {
+    format!("{}:{}", TOKEN_FAMILY_KEY_PREFIX, family_id)
+}
+
+/// Ids of the access tokens issued in a family, denied when the family is revoked.
+fn token_family_access_key(family_id: &str) -> String {
+    format!("{}:{}:access", TOKEN_FAMILY_KEY_PREFIX, family_id)
+}
+
+async fn store_refresh_token(
+    redis: &redis::Client,
+    grant: &RefreshTokenGrant,
+    jti: &str,
+    is_new_family: bool,
+) -> redis::RedisResult<Option<String>> {
+    let refresh_token = generate_token();
+    let mut con = redis.get_multiplexed_async_connection().await?;
+
+    let is_stored: bool = Script::new(STORE_REFRESH_TOKEN_SCRIPT)
+        .key(refresh_token_key(&refresh_token))
+        .key(token_family_key(&grant.family_id))
+        .key(token_family_access_key(&grant.family_id))
+        .arg(&grant.family_id)
+        .arg(&grant.name)
+        .arg(&grant.role)
+        .arg(jti)
+        .arg(REFRESH_TOKEN_TTL.as_secs())
+        .arg(if is_new_family { "1" } else { "0" })
+        .invoke_async(&mut con)
+        .await?;
+
+    Ok(is_stored.then_some(refresh_token))
+}
+
+/// Starts a family with its first refresh token, issued along with the access token `jti`.
+pub async fn issue_refresh_token(
+    redis: &redis::Client,
+    grant: &RefreshTokenGrant,
+    jti: &str,
+) -> redis::RedisResult<String> {
+    let refresh_token = store_refresh_token(redis, grant, jti, true).await?;
+
+    Ok(refresh_token.unwrap_or_default())
+}
+
+/// Issues the successor of a spent refresh token, or `None` when the family was revoked
+/// in the meantime.
+pub async fn rotate_refresh_token(
+    redis: &redis::Client,
+    grant: &RefreshTokenGrant,
+    jti: &str,
+) -> redis::RedisResult<Option<String>> {
+    store_refresh_token(redis, grant, jti, false).await
+}
+
+/// Spends `refresh_token`. Each one may be used once; the caller issues its successor.
+pub async fn use_refresh_token(
+    redis: &redis::Client,
+    refresh_token: &str,
+) -> redis::RedisResult<RefreshOutcome> {
+    let token_key = refresh_token_key(refresh_token);
+    let mut con = redis.get_multiplexed_async_connection().await?;
+
+    let fields: HashMap<String, String> = con.hgetall(&token_key).await?;
+    let grant = match (
+        fields.get("family_id"),
+        fields.get("name"),
+        fields.get("role"),
+    ) {
+        (Some(family_id), Some(name), Some(role)) => RefreshTokenGrant {
+            family_id: family_id.clone(),
+            name: name.clone(),
+            role: role.clone(),
+        },
+        _ => return Ok(RefreshOutcome::Invalid),
+    };
+
+    let is_family_active: bool = con.exists(token_family_key(&grant.family_id)).await?;
+    if !is_family_active {
+        return Ok(RefreshOutcome::Invalid);
+    }
+
+    // set only by the first use, so of two racing uses one is caught as a reuse
+    let is_first_use: bool = con.hset_nx(&token_key, "used", 1).await?;
+    if !is_first_use {
+        info!(
+            "refresh token of {} reused, revoking family {}",
+            grant.name, grant.family_id
+        );
+        revoke_token_family(redis, &grant.family_id).await?;
+        return Ok(RefreshOutcome::Reused);
+    }
+
+    Ok(RefreshOutcome::Rotated(grant))
+}
+
+/// Logs the family out: its refresh tokens stop working, and so do its access tokens.
+pub async fn revoke_token_family(redis: &redis::Client, family_id: &str) -> redis::RedisResult<()> {
+    let mut con = redis.get_multiplexed_async_connection().await?;
+
+    let jtis: Vec<String> = Script::new(REVOKE_TOKEN_FAMILY_SCRIPT)
+        .key(token_family_key(family_id))
+        .key(token_family_access_key(family_id))
+        .invoke_async(&mut con)
+        .await?;
+
+    deny_access_tokens(redis, &jtis).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_token() {
+        let refresh_token = generate_token();
+
+        assert_eq!(refresh_token.len(), 64);
+        assert_ne!(refresh_token, generate_token());
+    }
+
+    #[test]
+    fn test_refresh_token_key() {
+        let refresh_token = generate_token();
+        let token_key = refresh_token_key(&refresh_token);
+
+        assert_eq!(token_key, refresh_token_key(&refresh_token));
+        assert!(token_key.starts_with("auth:refresh:"));
+        assert!(!token_key.contains(&refresh_token));
+    }
+}
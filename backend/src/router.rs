@@ -1,20 +1,36 @@
-use crate::jwt_auth::auth_jwt;
+use crate::api_key::{create_api_key, get_api_keys, revoke_api_key};
+use crate::auth::{login, logout, refresh};
+use crate::cache::get_cache_stats;
+use crate::job_queue::get_jobs;
+use crate::jwt_auth::{auth_jwt, optional_auth_jwt};
+use crate::patron::{patreon_callback, patreon_login};
+use crate::permission::{require_permission, Permission};
 use crate::posts::{
-    create_new_post, delete_all_posts, delete_post, edit_post, get_all_posts, get_post_by_id,
-    sync_posts,
+    create_new_post, delete_all_posts, delete_post, edit_post, get_all_posts,
+    get_all_posts_for_admin, get_post_by_id, get_post_by_slug, sync_posts,
 };
+use crate::redis_pubsub::pubsub::get_message_stats;
+use crate::search::search_posts;
+use crate::sync_history::{get_sync_result_by_id, get_sync_results, get_sync_stats};
+use crate::sync_job::{get_sync_lock, release_sync_lock};
+use crate::sync_progress::stream_sync_progress;
+use crate::sync_schedule::get_sync_schedule;
+use crate::tags::{create_tag, delete_tag, edit_tag, get_all_tags};
+use crate::webhooks::{get_webhook_deliveries, receive_patreon_webhook, replay_webhook_delivery};
 use crate::{redis_pubsub, AppState};
 use axum::extract::State;
 use axum::http::StatusCode;
 use axum::routing::delete;
+use axum::Json;
 use axum::{
     http::{self},
     middleware::{self},
     routing::{get, post, put},
     Router,
 };
-use http::header::{ACCEPT, AUTHORIZATION, ORIGIN};
+use http::header::{ACCEPT, AUTHORIZATION, ETAG, IF_MATCH, LAST_MODIFIED, ORIGIN};
 use http::Method;
+use serde::{Deserialize, Serialize};
 use tower_http::cors::CorsLayer;
 use tracing::error;
 
@@ -27,25 +43,145 @@ pub fn create_api_router(state: AppState) -> Router {
     let cors = CorsLayer::new()
         .allow_credentials(true)
         .allow_methods(vec![Method::GET, Method::POST, Method::PUT, Method::DELETE])
-        .allow_headers(vec![ORIGIN, AUTHORIZATION, ACCEPT])
+        .allow_headers(vec![ORIGIN, AUTHORIZATION, ACCEPT, IF_MATCH])
+        // the admin page sends the etag back with its edits
+        .expose_headers(vec![ETAG, LAST_MODIFIED])
         .allow_origin(origins);
 
+    // every route behind `auth_jwt` names the permission it needs
+    let require =
+        |permission: Permission| middleware::from_fn_with_state(permission, require_permission);
+
+    // open to anyone, only patrons and admins see the downloads of the posts for patrons
+    let public_posts_router = Router::new()
+        .route("/", get(get_all_posts))
+        .route("/search", get(search_posts))
+        .route("/:id", get(get_post_by_id))
+        .route("/by-slug/:slug", get(get_post_by_slug))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            optional_auth_jwt,
+        ));
+
     let posts_router = Router::new()
-        .route("/:id", put(edit_post).delete(delete_post))
-        .route("/create", post(create_new_post))
-        .route("/", delete(delete_all_posts))
+        .route(
+            "/:id",
+            put(edit_post).route_layer(require(Permission::PostsWrite)),
+        )
+        .route(
+            "/:id",
+            delete(delete_post).route_layer(require(Permission::PostsDelete)),
+        )
+        .route(
+            "/create",
+            post(create_new_post).route_layer(require(Permission::PostsWrite)),
+        )
+        .route(
+            "/admin",
+            get(get_all_posts_for_admin).route_layer(require(Permission::PostsReadHidden)),
+        )
+        .route(
+            "/",
+            delete(delete_all_posts).route_layer(require(Permission::PostsDeleteAll)),
+        )
+        .route(
+            "/sync",
+            get(sync_posts).route_layer(require(Permission::SyncTrigger)),
+        )
         .layer(middleware::from_fn_with_state(state.clone(), auth_jwt))
-        .route("/", get(get_all_posts))
-        .route("/:id", post(get_post_by_id))
-        .route("/sync", get(sync_posts));
+        .merge(public_posts_router);
+
+    let tags_router = Router::new()
+        .route(
+            "/",
+            post(create_tag).route_layer(require(Permission::PostsWrite)),
+        )
+        .route(
+            "/:id",
+            put(edit_tag).route_layer(require(Permission::PostsWrite)),
+        )
+        .route(
+            "/:id",
+            delete(delete_tag).route_layer(require(Permission::PostsDelete)),
+        )
+        .layer(middleware::from_fn_with_state(state.clone(), auth_jwt))
+        .route("/", get(get_all_tags));
+
+    let sync_router = Router::new()
+        .route(
+            "/lock",
+            get(get_sync_lock).route_layer(require(Permission::SyncRead)),
+        )
+        .route(
+            "/lock",
+            delete(release_sync_lock).route_layer(require(Permission::SyncTrigger)),
+        )
+        .route("/progress", get(stream_sync_progress))
+        .route("/schedule", get(get_sync_schedule))
+        .route("/results", get(get_sync_results))
+        .route("/results/stats", get(get_sync_stats))
+        .route("/results/:id", get(get_sync_result_by_id))
+        .route_layer(require(Permission::SyncRead))
+        .layer(middleware::from_fn_with_state(state.clone(), auth_jwt));
+
+    let jobs_router = Router::new()
+        .route("/", get(get_jobs))
+        .route_layer(require(Permission::SyncRead))
+        .layer(middleware::from_fn_with_state(state.clone(), auth_jwt));
+
+    let webhooks_router = Router::new()
+        .route(
+            "/patreon/deliveries",
+            get(get_webhook_deliveries).route_layer(require(Permission::SyncRead)),
+        )
+        .route(
+            "/patreon/deliveries/:id/replay",
+            post(replay_webhook_delivery).route_layer(require(Permission::SyncTrigger)),
+        )
+        .layer(middleware::from_fn_with_state(state.clone(), auth_jwt))
+        .route("/patreon", post(receive_patreon_webhook));
+
+    let api_keys_router = Router::new()
+        .route("/", get(get_api_keys).post(create_api_key))
+        .route("/:id", delete(revoke_api_key))
+        .route_layer(require(Permission::ApiKeysManage))
+        .layer(middleware::from_fn_with_state(state.clone(), auth_jwt));
+
+    // logging out only needs a valid token, whatever the role
+    let auth_router = Router::new()
+        .route("/logout", post(logout))
+        .layer(middleware::from_fn_with_state(state.clone(), auth_jwt))
+        .route("/login", post(login))
+        .route("/refresh", post(refresh))
+        .route("/patreon", get(patreon_login))
+        .route("/patreon/callback", post(patreon_callback));
 
     Router::new()
+        .nest("/auth", auth_router)
         .nest("/posts", posts_router)
+        .nest("/tags", tags_router)
+        .nest("/sync", sync_router)
+        .nest("/jobs", jobs_router)
+        .nest("/webhooks", webhooks_router)
+        .nest("/api-keys", api_keys_router)
         // .layer(middleware::from_extractor_with_state(
         //     state.clone()
         // ))
         .route("/health_check", get(hello_world))
+        .route("/ready", get(readiness))
         .route("/pubsub_test", get(pubsub_test))
+        .route(
+            "/pubsub/stats",
+            get(get_message_stats)
+                .route_layer(require(Permission::SyncRead))
+                .layer(middleware::from_fn_with_state(state.clone(), auth_jwt)),
+        )
+        .route(
+            "/cache/stats",
+            get(get_cache_stats)
+                .route_layer(require(Permission::SyncRead))
+                .layer(middleware::from_fn_with_state(state.clone(), auth_jwt)),
+        )
         .with_state(state)
         .layer(cors)
 }
@@ -54,16 +190,39 @@ pub async fn hello_world() -> &'static str {
     "Hello, world!"
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Readiness {
+    pub is_ready: bool,
+    pub subscriber_connected: bool,
+    pub subscriber_reconnect_count: u64,
+}
+
+/// Unlike `/health_check`, fails while sync triggers can't be received.
+pub async fn readiness(State(state): State<AppState>) -> (StatusCode, Json<Readiness>) {
+    let health = &state.subscriber_health;
+    let readiness = Readiness {
+        is_ready: health.is_connected(),
+        subscriber_connected: health.is_connected(),
+        subscriber_reconnect_count: health.reconnect_count(),
+    };
+
+    if readiness.is_ready {
+        (StatusCode::OK, Json(readiness))
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, Json(readiness))
+    }
+}
+
 pub async fn pubsub_test(State(state): State<AppState>) -> StatusCode {
     let redis = state.redis.clone();
 
     let result = redis_pubsub::pubsub::publish_message(
         redis.clone(),
-        redis_pubsub::message::Message::new(String::from("test message")),
+        redis_pubsub::message::Message::new(redis_pubsub::message::MessageKind::Ping),
     );
 
-    if result.is_err() {
-        error!("Failed to publish message: {}", result.unwrap_err());
+    if let Err(err) = result {
+        error!("Failed to publish message: {}", err);
         return StatusCode::INTERNAL_SERVER_ERROR;
     }
 
@@ -0,0 +1,303 @@
+use crate::dao::{aggregate_docs_paginated, Page, PageOptions, SortOrder};
+use crate::jwt_auth::TokenClaims;
+use crate::posts::{Post, PostListQuery};
+use crate::visibility::Entitlement;
+use crate::AppState;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use mongodb::bson::{doc, from_document, Document};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+const SCORE_FIELD: &str = "score";
+const SNIPPET_RADIUS: usize = 80;
+const HIGHLIGHT_OPEN: &str = "<mark>";
+const HIGHLIGHT_CLOSE: &str = "</mark>";
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct PostSearchResult {
+    pub post: Post,
+    pub score: f64,
+    pub title_snippet: String,
+    pub content_snippet: String,
+}
+
+pub async fn search_posts(
+    State(state): State<AppState>,
+    claims: Option<Extension<TokenClaims>>,
+    Query(query): Query<PostListQuery>,
+) -> Result<Json<Page<PostSearchResult>>, impl IntoResponse> {
+    let entitlement = Entitlement::of(claims.as_deref());
+    let search_text = match query.search_text() {
+        Some(search_text) => search_text.to_string(),
+        None => {
+            let error_message = "search text `q` is required".to_string();
+            error!(error_message);
+            return Err((StatusCode::BAD_REQUEST, error_message).into_response());
+        }
+    };
+
+    let (filter, page_options) = match query.for_public().parse() {
+        Ok(result) => result,
+        Err(error_message) => {
+            error!(error_message);
+            return Err((StatusCode::BAD_REQUEST, error_message).into_response());
+        }
+    };
+
+    // results are always ranked by relevance, whatever sort was requested
+    let page_options = PageOptions {
+        sort_field: SCORE_FIELD.to_string(),
+        order: SortOrder::Desc,
+        ..page_options
+    };
+    let stages = vec![doc! {
+        "$addFields": { SCORE_FIELD: { "$meta": "textScore" } }
+    }];
+
+    match aggregate_docs_paginated::<Post>(state.mongo, filter, stages, page_options).await {
+        Ok(page) => {
+            let terms = search_terms(&search_text);
+            let items = page
+                .items
+                .into_iter()
+                .map(|doc| to_search_result(doc, &terms, &entitlement))
+                .collect::<anyhow::Result<Vec<_>>>();
+
+            match items {
+                Ok(items) => Ok(Json(Page {
+                    items,
+                    next_cursor: page.next_cursor,
+                    total_count: page.total_count,
+                })),
+                Err(err) => {
+                    let error_message = err.to_string();
+                    error!("{}", error_message.clone());
+                    Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response())
+                }
+            }
+        }
+        Err(err) => {
+            let error_message = err.to_string();
+            error!("{}", error_message.clone());
+            Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response())
+        }
+    }
+}
+
+fn to_search_result(
+    mut doc: Document,
+    terms: &[String],
+    entitlement: &Entitlement,
+) -> anyhow::Result<PostSearchResult> {
+    let score = doc.get_f64(SCORE_FIELD).unwrap_or_default();
+    doc.remove(SCORE_FIELD);
+
+    let title_snippet = highlight(doc.get_str("title").unwrap_or_default(), terms);
+    let mut content_snippet = build_snippet(doc.get_str("content").unwrap_or_default(), terms);
+    let mut post = from_document::<Post>(doc)?;
+    // the snippet would give away what the redacted content says
+    if post.redact_for(entitlement) {
+        content_snippet = "".to_string();
+    }
+
+    Ok(PostSearchResult {
+        post,
+        score,
+        title_snippet,
+        content_snippet,
+    })
+}
+
+/// Words of a `$text` query that should be highlighted; negated words and operators are skipped.
+pub fn search_terms(search_text: &str) -> Vec<String> {
+    search_text
+        .split_whitespace()
+        .filter(|word| !word.starts_with('-'))
+        .map(|word| word.trim_matches('"').to_lowercase())
+        .filter(|word| !word.is_empty())
+        .collect()
+}
+
+/// Escapes `text` and wraps every occurrence of a term in `<mark>`.
+pub fn highlight(text: &str, terms: &[String]) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    render_highlighted(&chars, &find_matches(&chars, terms))
+}
+
+/// Picks a window of plain text around the first match in `html` and highlights it.
+pub fn build_snippet(html: &str, terms: &[String]) -> String {
+    let text = strip_html(html);
+    let chars: Vec<char> = text.chars().collect();
+    let matches = find_matches(&chars, terms);
+
+    let center = matches.first().map(|(start, _)| *start).unwrap_or(0);
+    let start = center.saturating_sub(SNIPPET_RADIUS);
+    let end = (center + SNIPPET_RADIUS).min(chars.len());
+
+    let window_matches: Vec<(usize, usize)> = matches
+        .into_iter()
+        .filter(|(match_start, match_end)| *match_start >= start && *match_end <= end)
+        .map(|(match_start, match_end)| (match_start - start, match_end - start))
+        .collect();
+
+    let mut snippet = render_highlighted(&chars[start..end], &window_matches);
+    if start > 0 {
+        snippet.insert(0, '…');
+    }
+    if end < chars.len() {
+        snippet.push('…');
+    }
+
+    snippet
+}
+
+/// Non-overlapping, case-insensitive occurrences of the terms as char index ranges.
+fn find_matches(chars: &[char], terms: &[String]) -> Vec<(usize, usize)> {
+    let lowered: Vec<char> = chars
+        .iter()
+        .map(|c| c.to_lowercase().next().unwrap_or(*c))
+        .collect();
+    let terms: Vec<Vec<char>> = terms.iter().map(|term| term.chars().collect()).collect();
+
+    let mut matches = vec![];
+    let mut index = 0;
+    while index < lowered.len() {
+        let longest_match = terms
+            .iter()
+            .filter(|term| lowered[index..].starts_with(term))
+            .map(|term| term.len())
+            .max();
+
+        match longest_match {
+            Some(length) => {
+                matches.push((index, index + length));
+                index += length;
+            }
+            None => index += 1,
+        }
+    }
+
+    matches
+}
+
+fn render_highlighted(chars: &[char], matches: &[(usize, usize)]) -> String {
+    let mut rendered = String::new();
+    let mut position = 0;
+
+    for (start, end) in matches {
+        rendered.push_str(&escape_html(&chars[position..*start]));
+        rendered.push_str(HIGHLIGHT_OPEN);
+        rendered.push_str(&escape_html(&chars[*start..*end]));
+        rendered.push_str(HIGHLIGHT_CLOSE);
+        position = *end;
+    }
+    rendered.push_str(&escape_html(&chars[position..]));
+
+    rendered
+}
+
+fn escape_html(chars: &[char]) -> String {
+    let mut escaped = String::new();
+    for c in chars {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(*c),
+        }
+    }
+    escaped
+}
+
+/// Drops tags and collapses whitespace so Patreon HTML can be shown as a plain text snippet.
+fn strip_html(html: &str) -> String {
+    let mut text = String::new();
+    let mut in_tag = false;
+
+    for c in html.chars() {
+        match c {
+            '<' => {
+                in_tag = true;
+                text.push(' ');
+            }
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_terms() {
+        assert_eq!(
+            search_terms(r#"Bikini  "Armor" -preset"#),
+            vec!["bikini".to_string(), "armor".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_highlight() {
+        let terms = search_terms("armor");
+
+        assert_eq!(
+            highlight("Dragon Armor & ARMOR <v2>", &terms),
+            "Dragon <mark>Armor</mark> &amp; <mark>ARMOR</mark> &lt;v2&gt;"
+        );
+    }
+
+    #[test]
+    fn test_highlight_without_match() {
+        assert_eq!(
+            highlight("Dragon Armor", &search_terms("boots")),
+            "Dragon Armor"
+        );
+    }
+
+    #[test]
+    fn test_build_snippet_strips_html() {
+        let snippet = build_snippet(
+            "<p>New <strong>outfit</strong> for&nbsp;CBBE</p><p>Download below</p>",
+            &search_terms("outfit"),
+        );
+
+        assert_eq!(snippet, "New <mark>outfit</mark> for CBBE Download below");
+    }
+
+    #[test]
+    fn test_build_snippet_window() {
+        let content = format!("{} needle {}", "a".repeat(200), "b".repeat(200));
+
+        let snippet = build_snippet(&content, &search_terms("needle"));
+
+        assert!(snippet.starts_with('…'));
+        assert!(snippet.ends_with('…'));
+        assert!(snippet.contains("<mark>needle</mark>"));
+        assert!(snippet.chars().count() < content.len());
+    }
+
+    #[test]
+    fn test_build_snippet_non_ascii() {
+        let snippet = build_snippet("<p>새로운 의상 모드</p>", &search_terms("의상"));
+
+        assert_eq!(snippet, "새로운 <mark>의상</mark> 모드");
+    }
+}
@@ -0,0 +1,211 @@
+use crate::dao::aggregate_docs;
+use crate::posts::Post;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, Bson, Document};
+use mongodb::Database;
+use std::collections::HashSet;
+
+const MAX_SLUG_LENGTH: usize = 80;
+/// Used when a title has nothing to make a slug from, e.g. only punctuation.
+const FALLBACK_SLUG: &str = "post";
+
+/// Lowercases `title` and joins its words with dashes, e.g. "Summer Preset #2" is
+/// "summer-preset-2". Letters outside ASCII are kept, browsers percent-encode them.
+pub fn slugify(title: &str) -> String {
+    let mut slug = String::new();
+
+    for word in title
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+    {
+        let word = word.to_lowercase();
+        let separator_length = if slug.is_empty() { 0 } else { 1 };
+        if slug.chars().count() + separator_length + word.chars().count() > MAX_SLUG_LENGTH {
+            break;
+        }
+
+        if !slug.is_empty() {
+            slug.push('-');
+        }
+        slug.push_str(&word);
+    }
+
+    if slug.is_empty() {
+        FALLBACK_SLUG.to_string()
+    } else {
+        slug
+    }
+}
+
+/// Where `slug` is served, percent-encoded so it fits in a `Location` header.
+pub fn slug_path(slug: &str) -> String {
+    let base = url::Url::parse("http://localhost/api/posts/by-slug/").unwrap();
+
+    match base.join(slug) {
+        Ok(url) => url.path().to_string(),
+        Err(_) => base.path().to_string(),
+    }
+}
+
+/// Whether `slug` was made from `base`, either as is or with a collision suffix.
+pub fn is_slug_of(slug: &str, base: &str) -> bool {
+    match slug.strip_prefix(base) {
+        Some("") => true,
+        Some(suffix) => suffix
+            .strip_prefix('-')
+            .is_some_and(|number| !number.is_empty() && number.chars().all(|c| c.is_ascii_digit())),
+        None => false,
+    }
+}
+
+/// `base`, or `base-2`, `base-3`, ... when it's taken.
+fn next_free_slug(base: &str, taken: &HashSet<String>) -> String {
+    if !taken.contains(base) {
+        return base.to_string();
+    }
+
+    (2..)
+        .map(|number| format!("{}-{}", base, number))
+        .find(|slug| !taken.contains(slug))
+        .unwrap()
+}
+
+/// Slugs made from `base` that are in use, now or as an old slug that still redirects.
+async fn taken_slugs(
+    mongo: Database,
+    base: &str,
+    exclude_id: Option<ObjectId>,
+) -> anyhow::Result<HashSet<String>> {
+    // a slug has no regex metacharacters, only letters, digits and dashes
+    let pattern = format!("^{}(-[0-9]+)?$", base);
+    let mut filter = doc! {
+        "$or": [
+            { "slug": { "$regex": &pattern } },
+            { "slug_history": { "$regex": &pattern } },
+        ]
+    };
+    if let Some(exclude_id) = exclude_id {
+        filter.insert("_id", doc! { "$ne": exclude_id });
+    }
+
+    let docs = aggregate_docs::<Post>(
+        mongo,
+        vec![
+            doc! { "$match": filter },
+            doc! { "$project": { "slug": 1, "slug_history": 1 } },
+        ],
+    )
+    .await?;
+
+    Ok(docs
+        .iter()
+        .flat_map(slugs_of)
+        .filter(|slug| is_slug_of(slug, base))
+        .collect())
+}
+
+fn slugs_of(doc: &Document) -> Vec<String> {
+    let history = match doc.get("slug_history") {
+        Some(Bson::Array(history)) => history.clone(),
+        _ => vec![],
+    };
+
+    doc.get("slug")
+        .into_iter()
+        .chain(history.iter())
+        .filter_map(Bson::as_str)
+        .map(str::to_string)
+        .collect()
+}
+
+/// Picks a slug for each title that no other post uses, including the titles in the same batch.
+/// `exclude_id` is the post being renamed, whose own old slugs may be taken back.
+pub async fn unique_slugs(
+    mongo: Database,
+    titles: &[&str],
+    exclude_id: Option<ObjectId>,
+) -> anyhow::Result<Vec<String>> {
+    let mut taken_by_base: Vec<(String, HashSet<String>)> = vec![];
+    let mut slugs = vec![];
+
+    for title in titles {
+        let base = slugify(title);
+
+        let index = match taken_by_base.iter().position(|(b, _)| *b == base) {
+            Some(index) => index,
+            None => {
+                let taken = taken_slugs(mongo.clone(), &base, exclude_id).await?;
+                taken_by_base.push((base.clone(), taken));
+                taken_by_base.len() - 1
+            }
+        };
+
+        let taken = &mut taken_by_base[index].1;
+        let slug = next_free_slug(&base, taken);
+        taken.insert(slug.clone());
+        slugs.push(slug);
+    }
+
+    Ok(slugs)
+}
+
+pub async fn unique_slug(
+    mongo: Database,
+    title: &str,
+    exclude_id: Option<ObjectId>,
+) -> anyhow::Result<String> {
+    let mut slugs = unique_slugs(mongo, &[title], exclude_id).await?;
+
+    Ok(slugs.remove(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slugify() {
+        assert_eq!(slugify("Summer Preset #2"), "summer-preset-2");
+        assert_eq!(slugify("  --Hello,   World!-- "), "hello-world");
+        assert_eq!(slugify("Café Crème"), "café-crème");
+        assert_eq!(slugify("!!!"), FALLBACK_SLUG);
+        assert_eq!(slugify(""), FALLBACK_SLUG);
+
+        // cut at a word boundary
+        let slug = slugify(&"word ".repeat(30));
+        assert!(slug.len() <= MAX_SLUG_LENGTH);
+        assert!(slug.ends_with("word"));
+    }
+
+    #[test]
+    fn test_slug_path() {
+        assert_eq!(
+            slug_path("summer-preset"),
+            "/api/posts/by-slug/summer-preset"
+        );
+        assert_eq!(slug_path("café"), "/api/posts/by-slug/caf%C3%A9");
+    }
+
+    #[test]
+    fn test_is_slug_of() {
+        assert!(is_slug_of("summer-preset", "summer-preset"));
+        assert!(is_slug_of("summer-preset-12", "summer-preset"));
+        assert!(!is_slug_of("summer-preset-", "summer-preset"));
+        assert!(!is_slug_of("summer-preset-v2", "summer-preset"));
+        assert!(!is_slug_of("summer-presets", "summer-preset"));
+        assert!(!is_slug_of("summer", "summer-preset"));
+    }
+
+    #[test]
+    fn test_next_free_slug() {
+        let mut taken = HashSet::new();
+        assert_eq!(next_free_slug("preset", &taken), "preset");
+
+        taken.insert("preset".to_string());
+        taken.insert("preset-3".to_string());
+        assert_eq!(next_free_slug("preset", &taken), "preset-2");
+
+        taken.insert("preset-2".to_string());
+        assert_eq!(next_free_slug("preset", &taken), "preset-4");
+    }
+}
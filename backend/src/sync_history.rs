@@ -0,0 +1,408 @@
+use crate::dao::{
+    aggregate_docs, find_docs_paginated, find_one_doc, Page, PageCursor, PageOptions, SortOrder,
+};
+use crate::sync_post::{SyncMode, SyncResult, SyncResultResponse};
+use crate::util::date_range_filter;
+use crate::AppState;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::{doc, Bson, Document};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use tracing::error;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct SyncResultListQuery {
+    limit: Option<i64>,
+    cursor: Option<String>,
+    success: Option<bool>,
+    mode: Option<SyncMode>,
+    from: Option<String>,
+    to: Option<String>,
+}
+
+impl SyncResultListQuery {
+    pub fn parse(&self) -> Result<(Document, PageOptions), String> {
+        Ok((self.filter()?, self.page_options()?))
+    }
+
+    pub fn filter(&self) -> Result<Document, String> {
+        let mut filter = doc! {};
+
+        if let Some(success) = self.success {
+            filter.insert("is_success", success);
+        }
+
+        if let Some(mode) = self.mode {
+            filter.insert(
+                "mode",
+                mongodb::bson::to_bson(&mode).map_err(|err| err.to_string())?,
+            );
+        }
+
+        if let Some(range) = date_range_filter(&self.from, &self.to)? {
+            filter.insert("synced_at", range);
+        }
+
+        Ok(filter)
+    }
+
+    pub fn page_options(&self) -> Result<PageOptions, String> {
+        let cursor = match &self.cursor {
+            Some(cursor) => {
+                Some(PageCursor::decode(cursor).map_err(|err| format!("invalid cursor: {}", err))?)
+            }
+            None => None,
+        };
+
+        Ok(PageOptions::new(
+            "synced_at",
+            SortOrder::Desc,
+            self.limit,
+            cursor,
+        ))
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct SyncStatsQuery {
+    from: Option<String>,
+    to: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct SyncDayStats {
+    /// `YYYY-MM-DD` in UTC.
+    pub date: String,
+    pub run_count: u64,
+    pub sync_count: u64,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct SyncStatsResponse {
+    pub total_count: u64,
+    pub success_count: u64,
+    pub success_rate: f64,
+    pub average_elapsed_time: f64,
+    pub per_day: Vec<SyncDayStats>,
+}
+
+pub async fn get_sync_results(
+    State(state): State<AppState>,
+    Query(query): Query<SyncResultListQuery>,
+) -> Result<Json<Page<SyncResultResponse>>, impl IntoResponse> {
+    let (filter, page_options) = match query.parse() {
+        Ok(result) => result,
+        Err(error_message) => {
+            error!(error_message);
+            return Err((StatusCode::BAD_REQUEST, error_message).into_response());
+        }
+    };
+
+    match find_docs_paginated::<SyncResult>(state.mongo, filter, page_options).await {
+        Ok(page) => Ok(Json(Page {
+            items: page
+                .items
+                .into_iter()
+                .map(SyncResultResponse::from)
+                .collect(),
+            next_cursor: page.next_cursor,
+            total_count: page.total_count,
+        })),
+        Err(err) => {
+            let error_message = err.to_string();
+            error!("{}", error_message.clone());
+            Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response())
+        }
+    }
+}
+
+pub async fn get_sync_result_by_id(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<SyncResultResponse>, impl IntoResponse> {
+    let target_object_id_result = ObjectId::from_str(&id);
+
+    if let Err(err) = &target_object_id_result {
+        let error_message = err.to_string();
+        error!(error_message);
+
+        return Err((StatusCode::BAD_REQUEST, error_message).into_response());
+    }
+
+    let filter = doc! {
+        "_id": target_object_id_result.unwrap()
+    };
+
+    match find_one_doc::<SyncResult>(state.mongo, filter).await {
+        Ok(result) => match result {
+            Some(sync_result) => Ok(Json(SyncResultResponse::from(sync_result))),
+            None => {
+                error!("The sync result with id: {} not found!", id);
+                Err((
+                    StatusCode::NOT_FOUND,
+                    format!("The sync result with id: {} not found!", id),
+                )
+                    .into_response())
+            }
+        },
+        Err(err) => {
+            let error_message = err.to_string();
+            error!("{}", error_message.clone());
+            Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response())
+        }
+    }
+}
+
+pub async fn get_sync_stats(
+    State(state): State<AppState>,
+    Query(query): Query<SyncStatsQuery>,
+) -> Result<Json<SyncStatsResponse>, impl IntoResponse> {
+    let mut filter = doc! {};
+    match date_range_filter(&query.from, &query.to) {
+        Ok(Some(range)) => {
+            filter.insert("synced_at", range);
+        }
+        Ok(None) => {}
+        Err(error_message) => {
+            error!(error_message);
+            return Err((StatusCode::BAD_REQUEST, error_message).into_response());
+        }
+    }
+
+    match aggregate_docs::<SyncResult>(state.mongo, sync_stats_pipeline(filter)).await {
+        Ok(docs) => Ok(Json(docs.first().map(parse_sync_stats).unwrap_or_default())),
+        Err(err) => {
+            let error_message = err.to_string();
+            error!("{}", error_message.clone());
+            Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response())
+        }
+    }
+}
+
+fn sync_stats_pipeline(filter: Document) -> Vec<Document> {
+    vec![
+        doc! { "$match": filter },
+        doc! {
+            "$facet": {
+                "totals": [
+                    {
+                        "$group": {
+                            "_id": Bson::Null,
+                            "total_count": { "$sum": 1 },
+                            "success_count": { "$sum": { "$cond": ["$is_success", 1, 0] } },
+                            "average_elapsed_time": { "$avg": "$elapsed_time" },
+                        }
+                    }
+                ],
+                "per_day": [
+                    {
+                        // synced_at is stored as an RFC 3339 string, its first 10 chars are the day
+                        "$group": {
+                            "_id": { "$substrBytes": ["$synced_at", 0, 10] },
+                            "run_count": { "$sum": 1 },
+                            "sync_count": { "$sum": "$sync_count" },
+                        }
+                    },
+                    { "$sort": { "_id": 1 } }
+                ],
+            }
+        },
+    ]
+}
+
+/// Numbers coming out of `$sum`/`$avg` can be any numeric BSON type.
+fn number_of(doc: &Document, key: &str) -> f64 {
+    match doc.get(key) {
+        Some(Bson::Int32(value)) => *value as f64,
+        Some(Bson::Int64(value)) => *value as f64,
+        Some(Bson::Double(value)) => *value,
+        _ => 0.0,
+    }
+}
+
+fn parse_sync_stats(facets: &Document) -> SyncStatsResponse {
+    let totals = facets
+        .get_array("totals")
+        .ok()
+        .and_then(|totals| totals.first())
+        .and_then(Bson::as_document)
+        .cloned()
+        .unwrap_or_default();
+
+    let total_count = number_of(&totals, "total_count") as u64;
+    let success_count = number_of(&totals, "success_count") as u64;
+    let success_rate = if total_count == 0 {
+        0.0
+    } else {
+        success_count as f64 / total_count as f64
+    };
+
+    let per_day = facets
+        .get_array("per_day")
+        .map(|days| {
+            days.iter()
+                .filter_map(Bson::as_document)
+                .map(|day| SyncDayStats {
+                    date: day.get_str("_id").unwrap_or_default().to_string(),
+                    run_count: number_of(day, "run_count") as u64,
+                    sync_count: number_of(day, "sync_count") as u64,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    SyncStatsResponse {
+        total_count,
+        success_count,
+        success_rate,
+        average_elapsed_time: number_of(&totals, "average_elapsed_time"),
+        per_day,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_util::{
+        create_test_state, generate_port_number, get_db_connection_uri, get_mongo_image,
+        get_redis_connection_uri, get_redis_image,
+    };
+    use mongodb::Client;
+    use testcontainers_modules::redis::REDIS_PORT;
+    use testcontainers_modules::testcontainers::clients;
+
+    #[test]
+    fn test_sync_result_list_query_filter() {
+        let query = SyncResultListQuery {
+            success: Some(false),
+            mode: Some(SyncMode::Full),
+            from: Some("2024-03-01T00:00:00Z".to_string()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            query.filter().unwrap(),
+            doc! {
+                "is_success": false,
+                "mode": "full",
+                "synced_at": doc! { "$gte": "2024-03-01T00:00:00.000Z" },
+            }
+        );
+        assert_eq!(query.page_options().unwrap().sort_field, "synced_at");
+    }
+
+    #[test]
+    fn test_parse_sync_stats() {
+        let facets = doc! {
+            "totals": [
+                {
+                    "_id": Bson::Null,
+                    "total_count": 4,
+                    "success_count": 3,
+                    "average_elapsed_time": 1250.5,
+                }
+            ],
+            "per_day": [
+                { "_id": "2024-03-01", "run_count": 1, "sync_count": 10_i64 },
+                { "_id": "2024-03-02", "run_count": 3, "sync_count": 5 },
+            ],
+        };
+
+        assert_eq!(
+            parse_sync_stats(&facets),
+            SyncStatsResponse {
+                total_count: 4,
+                success_count: 3,
+                success_rate: 0.75,
+                average_elapsed_time: 1250.5,
+                per_day: vec![
+                    SyncDayStats {
+                        date: "2024-03-01".to_string(),
+                        run_count: 1,
+                        sync_count: 10,
+                    },
+                    SyncDayStats {
+                        date: "2024-03-02".to_string(),
+                        run_count: 3,
+                        sync_count: 5,
+                    },
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_sync_stats_without_results() {
+        let facets = doc! { "totals": [], "per_day": [] };
+
+        assert_eq!(parse_sync_stats(&facets), SyncStatsResponse::default());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_get_sync_stats() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+        test_db
+            .collection::<SyncResult>("SyncResult")
+            .insert_many(
+                vec![
+                    SyncResult::with("2024-03-01T03:00:00Z", true, 10, 1000),
+                    SyncResult::with("2024-03-02T03:00:00Z", true, 2, 2000),
+                    SyncResult::with("2024-03-02T15:00:00Z", false, 1, 3000),
+                ],
+                None,
+            )
+            .await
+            .unwrap();
+
+        let state = create_test_state(test_db, redis_client);
+
+        let result = get_sync_stats(State(state.clone()), Query(SyncStatsQuery::default())).await;
+
+        let stats = result.ok().unwrap().0;
+        assert_eq!(stats.total_count, 3);
+        assert_eq!(stats.success_count, 2);
+        assert_eq!(stats.average_elapsed_time, 2000.0);
+        assert_eq!(
+            stats.per_day,
+            vec![
+                SyncDayStats {
+                    date: "2024-03-01".to_string(),
+                    run_count: 1,
+                    sync_count: 10,
+                },
+                SyncDayStats {
+                    date: "2024-03-02".to_string(),
+                    run_count: 2,
+                    sync_count: 3,
+                },
+            ]
+        );
+
+        let query = SyncResultListQuery {
+            success: Some(false),
+            ..Default::default()
+        };
+        let result = get_sync_results(State(state), Query(query)).await;
+
+        let page = result.ok().unwrap().0;
+        assert_eq!(page.total_count, 1);
+        assert!(!page.items[0].is_success);
+        assert_eq!(page.items[0].synced_at, "2024-03-02T15:00:00Z");
+    }
+}
@@ -1,23 +1,105 @@
+use crate::AppState;
 use anyhow::Result;
-use redis::Commands;
-use uuid::Uuid;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use redis::{Commands, Script};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+use tracing::{error, info};
 
 const JOB_ID_KEY: &str = "job_id";
+/// A crashed sync stops extending the lock, so it frees itself after this long.
+const JOB_TTL: Duration = Duration::from_secs(60);
+const JOB_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+
+// only the owner of the lock may extend or release it
+const EXTEND_JOB_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("PEXPIRE", KEYS[1], ARGV[2])
+end
+return 0
+"#;
+const RELEASE_JOB_SCRIPT: &str = r#"
+if redis.call("GET", KEYS[1]) == ARGV[1] then
+    return redis.call("DEL", KEYS[1])
+end
+return 0
+"#;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct SyncJobStarted {
+    pub job_id: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct SyncJobLock {
+    pub job_id: String,
+    /// Milliseconds until the lock expires unless its owner extends it.
+    pub ttl_ms: i64,
+}
 
-pub fn create_sync_job(redis: redis::Client) -> Result<()> {
+/// Takes the sync lock for `job_id`, returning `false` when another job holds it.
+pub fn create_sync_job(redis: redis::Client, job_id: &str) -> Result<bool> {
     let mut con = redis.get_connection()?;
-    con.set(JOB_ID_KEY, Uuid::new_v4().simple().to_string())?;
 
-    Ok(())
+    let result: Option<String> = redis::cmd("SET")
+        .arg(JOB_ID_KEY)
+        .arg(job_id)
+        .arg("NX")
+        .arg("PX")
+        .arg(JOB_TTL.as_millis() as u64)
+        .query(&mut con)?;
+
+    Ok(result.is_some())
+}
+
+/// Pushes the lock expiry back, returning `false` when `job_id` no longer holds the lock.
+pub async fn extend_sync_job(redis: redis::Client, job_id: &str) -> Result<bool> {
+    let mut con = redis.get_multiplexed_async_connection().await?;
+
+    let extended: i64 = Script::new(EXTEND_JOB_SCRIPT)
+        .key(JOB_ID_KEY)
+        .arg(job_id)
+        .arg(JOB_TTL.as_millis() as u64)
+        .invoke_async(&mut con)
+        .await?;
+
+    Ok(extended == 1)
+}
+
+pub async fn delete_sync_job(redis: redis::Client, job_id: &str) -> Result<i64> {
+    let mut con = redis.get_multiplexed_async_connection().await?;
+
+    let deleted_key_num: i64 = Script::new(RELEASE_JOB_SCRIPT)
+        .key(JOB_ID_KEY)
+        .arg(job_id)
+        .invoke_async(&mut con)
+        .await?;
+
+    Ok(deleted_key_num)
 }
 
-pub fn delete_sync_job(redis: redis::Client) -> Result<i64> {
+/// Drops the lock whoever holds it.
+pub fn force_delete_sync_job(redis: redis::Client) -> Result<i64> {
     let mut con = redis.get_connection()?;
     let deleted_key_num: i64 = con.del(JOB_ID_KEY)?;
 
     Ok(deleted_key_num)
 }
 
+pub fn get_sync_job(redis: redis::Client) -> Result<Option<SyncJobLock>> {
+    let mut con = redis.get_connection()?;
+
+    let job_id: Option<String> = con.get(JOB_ID_KEY)?;
+    let ttl_ms: i64 = con.pttl(JOB_ID_KEY)?;
+
+    Ok(job_id.map(|job_id| SyncJobLock { job_id, ttl_ms }))
+}
+
 pub fn check_sync_job_exists(redis: redis::Client) -> Result<bool> {
     let mut con = redis.get_connection()?;
 
@@ -26,6 +108,129 @@ pub fn check_sync_job_exists(redis: redis::Client) -> Result<bool> {
     Ok(job_id.is_some())
 }
 
+/// Holds the sync lock while alive: keeps extending it, and releases it on `release`, or
+/// when dropped whichever other way the sync ends.
+pub struct SyncJobGuard {
+    redis: redis::Client,
+    job_id: String,
+    /// Ends only when the lock is lost.
+    heartbeat: JoinHandle<()>,
+    is_released: bool,
+}
+
+impl SyncJobGuard {
+    pub fn acquire(redis: redis::Client, job_id: &str) -> Result<Option<Self>> {
+        if !create_sync_job(redis.clone(), job_id)? {
+            return Ok(None);
+        }
+        let job_id = job_id.to_string();
+
+        let heartbeat = tokio::spawn(heartbeat(redis.clone(), job_id.clone()));
+
+        Ok(Some(SyncJobGuard {
+            redis,
+            job_id,
+            heartbeat,
+            is_released: false,
+        }))
+    }
+
+    pub fn job_id(&self) -> &str {
+        &self.job_id
+    }
+
+    /// Completes once the lock can no longer be extended, another instance may hold it by then.
+    /// Only to be awaited until it completes once.
+    pub async fn lock_lost(&mut self) {
+        let _ = (&mut self.heartbeat).await;
+    }
+
+    pub async fn release(mut self) {
+        self.heartbeat.abort();
+        self.is_released = true;
+
+        release_lock(self.redis.clone(), self.job_id.clone()).await;
+    }
+}
+
+impl Drop for SyncJobGuard {
+    fn drop(&mut self) {
+        self.heartbeat.abort();
+        if self.is_released {
+            return;
+        }
+
+        // e.g. the sync panicked, a blocking release here would stall a runtime thread
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                handle.spawn(release_lock(self.redis.clone(), self.job_id.clone()));
+            }
+            Err(_) => error!("job {} left its lock to expire", self.job_id),
+        }
+    }
+}
+
+async fn release_lock(redis: redis::Client, job_id: String) {
+    match delete_sync_job(redis, &job_id).await {
+        Ok(0) => error!("job {} lost its lock before finishing", job_id),
+        Ok(_) => info!("job {} released", job_id),
+        Err(err) => error!("fail to delete job {}", err.to_string()),
+    }
+}
+
+async fn heartbeat(redis: redis::Client, job_id: String) {
+    let mut interval = tokio::time::interval(JOB_HEARTBEAT_INTERVAL);
+    // the first tick completes immediately, right after the lock was taken
+    interval.tick().await;
+    let mut extended_at = Instant::now();
+
+    loop {
+        interval.tick().await;
+
+        match extend_sync_job(redis.clone(), &job_id).await {
+            Ok(true) => extended_at = Instant::now(),
+            Ok(false) => {
+                error!("job {} lost its lock, stopping heartbeat", job_id);
+                return;
+            }
+            Err(err) => {
+                error!("fail to extend job {}", err.to_string());
+                if extended_at.elapsed() >= JOB_TTL {
+                    error!("job {} lock expired, stopping heartbeat", job_id);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+pub async fn get_sync_lock(
+    State(state): State<AppState>,
+) -> Result<Json<Option<SyncJobLock>>, impl IntoResponse> {
+    match get_sync_job(state.redis) {
+        Ok(sync_job) => Ok(Json(sync_job)),
+        Err(err) => {
+            let error_message = err.to_string();
+            error!("{}", error_message.clone());
+            Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response())
+        }
+    }
+}
+
+pub async fn release_sync_lock(State(state): State<AppState>) -> StatusCode {
+    match force_delete_sync_job(state.redis) {
+        Ok(0) => StatusCode::NOT_FOUND,
+        Ok(_) => {
+            info!("sync lock released by admin");
+            StatusCode::OK
+        }
+        Err(err) => {
+            error!("fail to delete job {}", err.to_string());
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -42,9 +247,17 @@ mod tests {
         let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
         let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
 
-        let result = create_sync_job(redis_client);
+        let result = create_sync_job(redis_client.clone(), "123123123");
 
         assert!(result.is_ok());
+        assert!(result.unwrap());
+
+        // the lock is already taken
+        assert!(!create_sync_job(redis_client.clone(), "456456456").unwrap());
+
+        let sync_job = get_sync_job(redis_client).unwrap().unwrap();
+        assert_eq!(sync_job.job_id, "123123123");
+        assert!(sync_job.ttl_ms > 0 && sync_job.ttl_ms <= JOB_TTL.as_millis() as i64);
     }
 
     #[tokio::test(flavor = "multi_thread")]
@@ -56,7 +269,7 @@ mod tests {
         let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
         let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
 
-        let result = delete_sync_job(redis_client);
+        let result = delete_sync_job(redis_client, "123123123").await;
 
         assert!(result.is_ok());
 
@@ -75,13 +288,123 @@ mod tests {
         let mut con = redis_client.clone().get_connection().unwrap();
         let _: () = con.set(JOB_ID_KEY, "123123123").unwrap();
 
-        let result = delete_sync_job(redis_client);
+        // another job's lock is left alone
+        assert_eq!(
+            delete_sync_job(redis_client.clone(), "456456456")
+                .await
+                .unwrap(),
+            0
+        );
+
+        let result = delete_sync_job(redis_client, "123123123").await;
 
         assert!(result.is_ok());
 
         assert_eq!(result.unwrap(), 1);
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_extend_sync_job() {
+        let docker = clients::Cli::default();
+        let redis_img = get_redis_image();
+        let redis_node = docker.run(redis_img);
+
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let mut con = redis_client.clone().get_connection().unwrap();
+        let _: () = con.set_ex(JOB_ID_KEY, "123123123", 1).unwrap();
+
+        assert!(!extend_sync_job(redis_client.clone(), "456456456")
+            .await
+            .unwrap());
+        assert!(extend_sync_job(redis_client.clone(), "123123123")
+            .await
+            .unwrap());
+
+        let ttl_ms: i64 = con.pttl(JOB_ID_KEY).unwrap();
+        assert!(ttl_ms > 1000);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sync_job_guard_releases_lock() {
+        let docker = clients::Cli::default();
+        let redis_img = get_redis_image();
+        let redis_node = docker.run(redis_img);
+
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let guard = SyncJobGuard::acquire(redis_client.clone(), "123123123")
+            .unwrap()
+            .unwrap();
+        assert!(SyncJobGuard::acquire(redis_client.clone(), "456456456")
+            .unwrap()
+            .is_none());
+        assert_eq!(
+            get_sync_job(redis_client.clone()).unwrap().unwrap().job_id,
+            guard.job_id()
+        );
+        guard.release().await;
+
+        assert!(!check_sync_job_exists(redis_client.clone()).unwrap());
+        let guard = SyncJobGuard::acquire(redis_client.clone(), "456456456")
+            .unwrap()
+            .unwrap();
+
+        // dropped without a release, e.g. by a panicking sync
+        drop(guard);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while check_sync_job_exists(redis_client.clone()).unwrap() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sync_job_guard_notices_lost_lock() {
+        let docker = clients::Cli::default();
+        let redis_img = get_redis_image();
+        let redis_node = docker.run(redis_img);
+
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let mut guard = SyncJobGuard::acquire(redis_client.clone(), "123123123")
+            .unwrap()
+            .unwrap();
+        // e.g. released by an admin, then taken by another instance
+        force_delete_sync_job(redis_client.clone()).unwrap();
+        create_sync_job(redis_client.clone(), "456456456").unwrap();
+
+        tokio::time::timeout(JOB_HEARTBEAT_INTERVAL * 2, guard.lock_lost())
+            .await
+            .unwrap();
+        guard.release().await;
+        // the other instance's lock is left alone
+        assert_eq!(
+            get_sync_job(redis_client).unwrap().unwrap().job_id,
+            "456456456"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_force_delete_sync_job() {
+        let docker = clients::Cli::default();
+        let redis_img = get_redis_image();
+        let redis_node = docker.run(redis_img);
+
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        create_sync_job(redis_client.clone(), "123123123").unwrap();
+
+        assert_eq!(force_delete_sync_job(redis_client.clone()).unwrap(), 1);
+        assert_eq!(get_sync_job(redis_client).unwrap(), None);
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_check_sync_job_exist_when_no_job_exist() {
         let docker = clients::Cli::default();
@@ -94,7 +417,7 @@ mod tests {
         let result = check_sync_job_exists(redis_client);
 
         assert!(result.is_ok());
-        assert_eq!(result.unwrap(), false);
+        assert!(!result.unwrap());
     }
 
     #[tokio::test(flavor = "multi_thread")]
@@ -112,6 +435,6 @@ mod tests {
         let result = check_sync_job_exists(redis_client);
 
         assert!(result.is_ok());
-        assert_eq!(result.unwrap(), true);
+        assert!(result.unwrap());
     }
 }
@@ -1,13 +1,23 @@
-use crate::dao::insert_one_doc;
-use crate::posts::Post;
-use crate::sync_job::{create_sync_job, delete_sync_job};
-use crate::util::convert_to_rfc3999_string;
+use crate::cache::invalidate_cache;
+use crate::dao::{find_one_doc_sorted, insert_one_doc, update_many_docs};
+use crate::job_queue::JobDeferred;
+use crate::patreon_api::{FetchStats, PatreonPost};
+use crate::patreon_content::extract_content;
+use crate::posts::{assign_slugs, Post};
+use crate::redis_pubsub::message::CacheScope;
+use crate::sync_job::SyncJobGuard;
+use crate::sync_progress::{SyncProgressEvent, SyncProgressReporter};
+use crate::util::{
+    bson_datetime_as_rfc3339_millis, convert_to_rfc3999_string, get_chrono_dt_from_string,
+    normalize_date_fields, to_rfc3339_millis,
+};
 use crate::AppState;
-use chrono::{NaiveTime, Utc};
+use anyhow::anyhow;
+use chrono::{Duration, Utc};
 use mongodb::bson::oid::ObjectId;
-use mongodb::bson::serde_helpers::{bson_datetime_as_rfc3339_string, hex_string_as_object_id};
-use mongodb::bson::{doc, DateTime};
-use mongodb::Database;
+use mongodb::bson::serde_helpers::hex_string_as_object_id;
+use mongodb::bson::{doc, to_bson, Bson, DateTime};
+use mongodb::{Collection, Database};
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
 use tokio::sync::Mutex;
@@ -21,180 +31,316 @@ pub struct SyncResult {
     message: String,
     sync_count: usize,
     elapsed_time: i64,
-    #[serde(with = "bson_datetime_as_rfc3339_string")]
+    #[serde(with = "bson_datetime_as_rfc3339_millis")]
     synced_at: DateTime,
+    #[serde(default)]
+    mode: SyncMode,
+    #[serde(default)]
+    orphaned_count: u64,
+    #[serde(default)]
+    restored_count: u64,
+    #[serde(default)]
+    retry_count: u32,
+    #[serde(default)]
+    last_http_status: Option<u16>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-pub struct PatreonPostsApiResult {
-    data: Vec<PatreonPostsApiPostResult>,
-    links: Option<PatreonPostsApiLinksResult>,
-    meta: PatreonPostsApiMetaResult,
+/// What the admin API returns for a `SyncResult`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct SyncResultResponse {
+    pub id: String,
+    pub is_success: bool,
+    pub message: String,
+    pub sync_count: usize,
+    pub elapsed_time: i64,
+    pub synced_at: String,
+    pub mode: SyncMode,
+    pub orphaned_count: u64,
+    pub restored_count: u64,
+    pub retry_count: u32,
+    pub last_http_status: Option<u16>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-pub struct PatreonPostsApiLinksResult {
-    next: String,
+impl From<SyncResult> for SyncResultResponse {
+    fn from(sync_result: SyncResult) -> Self {
+        SyncResultResponse {
+            id: sync_result._id,
+            is_success: sync_result.is_success,
+            message: sync_result.message,
+            sync_count: sync_result.sync_count,
+            elapsed_time: sync_result.elapsed_time,
+            synced_at: sync_result
+                .synced_at
+                .try_to_rfc3339_string()
+                .unwrap_or_default(),
+            mode: sync_result.mode,
+            orphaned_count: sync_result.orphaned_count,
+            restored_count: sync_result.restored_count,
+            retry_count: sync_result.retry_count,
+            last_http_status: sync_result.last_http_status,
+        }
+    }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-pub struct PatreonPostsApiMetaResult {
-    pagination: PatreonPostsApiPaginationResult,
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SyncStats {
+    sync_count: usize,
+    orphaned_count: u64,
+    restored_count: u64,
+    retry_count: u32,
+    last_http_status: Option<u16>,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-pub struct PatreonPostsApiPaginationResult {
-    cursors: PatreonPostsApiCursorsResult,
-    total: usize,
+impl SyncStats {
+    fn add_fetch_stats(&mut self, fetch_stats: FetchStats) {
+        self.retry_count += fetch_stats.retry_count;
+        if fetch_stats.last_http_status.is_some() {
+            self.last_http_status = fetch_stats.last_http_status;
+        }
+    }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-pub struct PatreonPostsApiCursorsResult {
-    next: Option<String>,
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncMode {
+    /// Only fetches posts published since the last successful sync started.
+    Incremental,
+    /// Walks every post of the campaign. Results saved before modes existed were always full.
+    #[default]
+    Full,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-pub struct PatreonPostsApiPostResult {
-    id: String,
-    // type: String,
-    attributes: PatreonPostsApiPostAttributesResult,
+impl SyncMode {
+    /// Mode requested by a pub/sub message from before messages were versioned.
+    pub fn from_payload(payload: &str) -> Option<Self> {
+        match payload {
+            "Sync" => Some(SyncMode::Incremental),
+            "FullSync" => Some(SyncMode::Full),
+            _ => None,
+        }
+    }
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-pub struct PatreonPostsApiPostAttributesResult {
-    content: String,
-    title: String,
-    published_at: String,
+pub const DEFAULT_CAMPAIGN_ID: &str = "8365446";
+
+/// Campaign ids from the comma separated `PATREON_CAMPAIGN_IDS` secret.
+pub fn parse_campaign_ids(campaign_ids: &str) -> Vec<String> {
+    let campaign_ids: Vec<String> = campaign_ids
+        .split(',')
+        .map(str::trim)
+        .filter(|campaign_id| !campaign_id.is_empty())
+        .map(str::to_string)
+        .collect();
+
+    if campaign_ids.is_empty() {
+        return vec![DEFAULT_CAMPAIGN_ID.to_string()];
+    }
+
+    campaign_ids
 }
 
-#[derive(Debug, Clone)]
-pub struct PatreonPost {
-    id: String,
-    content: String,
-    title: String,
-    published_at: String,
+/// Fails when the sync did, so the job queue can retry it. A sync that is
+/// already running defers it, the running one may be of another mode and
+/// the job runs once the lock is free.
+pub async fn sync_post(state: AppState, mode: SyncMode, job_id: &str) -> anyhow::Result<()> {
+    let progress = SyncProgressReporter::new(state.redis.clone(), job_id);
+
+    let mut job_guard = match SyncJobGuard::acquire(state.redis.clone(), job_id) {
+        Ok(Some(job_guard)) => job_guard,
+        Ok(None) => {
+            let message = "there is already running job".to_string();
+            info!("{}, job {} is retried later", message, job_id);
+            progress.report(SyncProgressEvent::Waiting {
+                message: message.clone(),
+            });
+            return Err(JobDeferred(message).into());
+        }
+        Err(err) => {
+            error!("fail to create job {}", err.to_string());
+            progress.report(SyncProgressEvent::Failed {
+                message: err.to_string(),
+            });
+            return Err(err);
+        }
+    };
+    info!("job {} started", job_guard.job_id());
+
+    // another instance may take the lock once it's lost, so the sync stops rather than run twice
+    let result = tokio::select! {
+        result = run_sync(state.clone(), mode, &progress) => result,
+        _ = job_guard.lock_lost() => {
+            let message = "the sync lost its lock".to_string();
+            error!("{}, job {} stopped", message, job_id);
+            progress.report(SyncProgressEvent::Failed {
+                message: message.clone(),
+            });
+            Err(anyhow!(message))
+        }
+    };
+    job_guard.release().await;
+
+    // even a failed sync may have changed some posts
+    invalidate_cache(&state, CacheScope::Posts);
+
+    result
 }
 
-pub async fn sync_post(state: AppState) {
+async fn run_sync(
+    state: AppState,
+    mode: SyncMode,
+    progress: &SyncProgressReporter,
+) -> anyhow::Result<()> {
     let mongo = state.mongo;
-    let patreon_access_token = state.patreon_access_token;
-    let redis = state.redis;
-
-    let start_time = Utc::now().time();
+    let patreon_api = state.patreon_api;
+    let campaign_ids = state.patreon_campaign_ids;
 
-    let create_job_result = create_sync_job(redis.clone());
+    let start_time = Utc::now();
 
-    if create_job_result.is_err() {
-        let error = create_job_result.err().unwrap();
-        error!("fail to create job {}", error.to_string());
-        return;
-    }
+    let (mode, synced_since) = match mode {
+        SyncMode::Full => (SyncMode::Full, None),
+        SyncMode::Incremental => match last_successful_sync_start(mongo.clone()).await {
+            Some(synced_since) => (SyncMode::Incremental, Some(synced_since)),
+            None => {
+                info!("no successful sync found, falling back to full sync");
+                (SyncMode::Full, None)
+            }
+        },
+    };
 
-    let sync_count: Arc<Mutex<usize>> = Arc::new(Mutex::new(0));
-    let client = reqwest::Client::new();
-    let mut next_link = Some(PatreonPostsApiLinksResult {
-        next: "https://www.patreon.com/api/oauth2/v2/campaigns/8365446/posts?fields%5Bpost%5D=content,title,published_at".to_string()
+    progress.report(SyncProgressEvent::Started {
+        mode,
+        campaign_count: campaign_ids.len(),
     });
 
-    loop {
-        let response_result = client
-            .get(next_link.unwrap().next)
-            .header(
-                reqwest::header::AUTHORIZATION,
-                format!("Bearer {}", patreon_access_token),
-            )
-            .send()
-            .await;
+    let sync_stats: Arc<Mutex<SyncStats>> = Arc::new(Mutex::new(SyncStats::default()));
+    let mut seen_patreon_post_ids: Vec<String> = vec![];
 
-        if response_result.is_err() {
-            let error = response_result.as_ref().err().unwrap();
-            save_sync_result(
-                mongo,
-                error.to_string(),
-                sync_count.lock().await.to_owned(),
-                start_time,
-            )
-            .await;
-            return;
-        }
+    for campaign_id in &campaign_ids {
+        info!("syncing campaign {}", campaign_id);
+        let mut cursor: Option<String> = None;
 
-        let response = response_result.unwrap();
-        let data_result: reqwest::Result<PatreonPostsApiResult> = response.json().await;
-        if data_result.is_err() {
-            let error = data_result.as_ref().err().unwrap();
-            save_sync_result(
-                mongo,
-                error.to_string(),
-                sync_count.lock().await.to_owned(),
+        loop {
+            let mut fetch_stats = FetchStats::default();
+            let page_result = patreon_api
+                .list_campaign_posts(campaign_id, cursor.as_deref(), &mut fetch_stats)
+                .await;
+            sync_stats.lock().await.add_fetch_stats(fetch_stats);
+
+            let page = match page_result {
+                Ok(page) => page,
+                Err(err) => {
+                    save_sync_result(
+                        mongo,
+                        err.to_string(),
+                        sync_stats.lock().await.to_owned(),
+                        start_time,
+                        mode,
+                        progress,
+                    )
+                    .await;
+                    return Err(err.into());
+                }
+            };
+
+            cursor = page.next_cursor;
+            let patreon_posts = page.posts;
+            progress.report(SyncProgressEvent::PageFetched {
+                campaign_id: campaign_id.clone(),
+                post_count: patreon_posts.len(),
+            });
+            seen_patreon_post_ids.extend(patreon_posts.iter().map(|post| post.id.clone()));
+
+            // posts come newest first, so the first already synced post ends an incremental sync
+            let (patreon_posts, reached_synced_posts) = match synced_since {
+                Some(synced_since) => take_posts_published_since(patreon_posts, synced_since),
+                None => (patreon_posts, false),
+            };
+
+            let upsert_result = upsert_posts(
+                mongo.clone(),
+                patreon_posts,
+                Arc::clone(&sync_stats),
                 start_time,
+                mode,
+                progress,
             )
             .await;
-            return;
-        }
-
-        let data = data_result.unwrap();
 
-        next_link = data.links;
-
-        // update db
-        let patreon_posts: Vec<PatreonPost> = data
-            .data
-            .into_iter()
-            .map(|post| PatreonPost {
-                id: post.id,
-                content: post.attributes.content,
-                title: post.attributes.title,
-                published_at: post.attributes.published_at,
-            })
-            .collect();
-        let upsert_result = upsert_posts(
-            mongo.clone(),
-            patreon_posts,
-            Arc::clone(&sync_count),
-            start_time,
-        )
-        .await;
+            if !upsert_result {
+                return Err(anyhow!("fail to save synced posts"));
+            }
 
-        if !upsert_result {
-            return;
+            if reached_synced_posts || cursor.is_none() {
+                break;
+            }
         }
+    }
 
-        if next_link.is_none() {
-            break;
+    // only a full sync has seen every post, so only it can tell which ones are gone
+    if mode == SyncMode::Full {
+        match orphan_missing_posts(mongo.clone(), &seen_patreon_post_ids).await {
+            Ok(orphaned_count) => {
+                sync_stats.lock().await.orphaned_count = orphaned_count;
+            }
+            Err(err) => {
+                save_sync_result(
+                    mongo,
+                    err.to_string(),
+                    sync_stats.lock().await.to_owned(),
+                    start_time,
+                    mode,
+                    progress,
+                )
+                .await;
+                return Err(err);
+            }
         }
     }
 
     save_sync_result(
         mongo.clone(),
         "".to_string(),
-        sync_count.lock().await.to_owned(),
+        sync_stats.lock().await.to_owned(),
         start_time,
+        mode,
+        progress,
     )
     .await;
 
-    let delete_job_result = delete_sync_job(redis);
-    if delete_job_result.is_err() {
-        let error = delete_job_result.err().unwrap();
-        error!("fail to delete job {}", error.to_string());
-    }
+    Ok(())
 }
 
 async fn save_sync_result(
     mongo: Database,
     message: String,
-    sync_count: usize,
-    start_time: NaiveTime,
+    sync_stats: SyncStats,
+    start_time: chrono::DateTime<Utc>,
+    mode: SyncMode,
+    progress: &SyncProgressReporter,
 ) {
-    let end_time = Utc::now().time();
-    let elapsed_time = (end_time - start_time).num_milliseconds();
-    let is_success = if message.is_empty() { true } else { false };
+    let elapsed_time = (Utc::now() - start_time).num_milliseconds();
+    let is_success = message.is_empty();
+    if is_success {
+        progress.report(SyncProgressEvent::Finished {
+            sync_count: sync_stats.sync_count,
+        });
+    } else {
+        progress.report(SyncProgressEvent::Failed {
+            message: message.clone(),
+        });
+    }
     let new_sync_result = SyncResult {
         _id: ObjectId::new().to_hex(),
         is_success,
         message,
-        sync_count,
+        sync_count: sync_stats.sync_count,
         elapsed_time,
         synced_at: DateTime::now(),
+        mode,
+        orphaned_count: sync_stats.orphaned_count,
+        restored_count: sync_stats.restored_count,
+        retry_count: sync_stats.retry_count,
+        last_http_status: sync_stats.last_http_status,
     };
 
     match insert_one_doc::<SyncResult>(mongo, new_sync_result).await {
@@ -210,53 +356,120 @@ async fn save_sync_result(
     }
 }
 
+/// Rewrites the dates of sync results stored before dates had a fixed precision.
+pub async fn normalize_sync_result_dates(mongo: Database) -> anyhow::Result<u64> {
+    normalize_date_fields::<SyncResult>(mongo, &["synced_at"]).await
+}
+
+/// Start of the last successful sync, so posts published while it ran are fetched again.
+async fn last_successful_sync_start(mongo: Database) -> Option<chrono::DateTime<Utc>> {
+    match find_one_doc_sorted::<SyncResult>(
+        mongo,
+        doc! { "is_success": true },
+        doc! { "synced_at": -1 },
+    )
+    .await
+    {
+        Ok(sync_result) => sync_result.map(|sync_result| {
+            // results saved while elapsed time was measured on the time of day wrap around midnight
+            let elapsed_time = sync_result
+                .elapsed_time
+                .rem_euclid(Duration::days(1).num_milliseconds());
+            sync_result.synced_at.to_chrono() - Duration::milliseconds(elapsed_time)
+        }),
+        Err(err) => {
+            error!("fail to find last sync result {}", err.to_string());
+            None
+        }
+    }
+}
+
+/// Flags synced posts that Patreon no longer returns, instead of deleting them.
+async fn orphan_missing_posts(
+    mongo: Database,
+    seen_patreon_post_ids: &[String],
+) -> anyhow::Result<u64> {
+    // an empty response is far more likely an API hiccup than every post being removed
+    if seen_patreon_post_ids.is_empty() {
+        info!("no posts seen during sync, skipping orphan check");
+        return Ok(0);
+    }
+
+    let orphaned_count = update_many_docs::<Post>(
+        mongo,
+        doc! {
+            "patreon_post_id": { "$nin": seen_patreon_post_ids, "$ne": "" },
+            "is_orphaned": { "$ne": true },
+        },
+        // a new updated_at moves the etag, so clients drop their copies of the post
+        doc! {
+            "$set": {
+                "is_orphaned": true,
+                "updated_at": to_rfc3339_millis(DateTime::now()),
+            }
+        },
+    )
+    .await?;
+    info!("{} Posts orphaned during sync", orphaned_count);
+
+    Ok(orphaned_count)
+}
+
+/// Keeps the posts published at or after `synced_since`, and tells whether an older one was seen.
+fn take_posts_published_since(
+    patreon_posts: Vec<PatreonPost>,
+    synced_since: chrono::DateTime<Utc>,
+) -> (Vec<PatreonPost>, bool) {
+    let (new_posts, old_posts): (Vec<PatreonPost>, Vec<PatreonPost>) =
+        patreon_posts.into_iter().partition(|patreon_post| {
+            get_chrono_dt_from_string(patreon_post.published_at.clone()) >= synced_since
+        });
+
+    (new_posts, !old_posts.is_empty())
+}
+
 async fn upsert_posts(
     mongo: Database,
     mut patreon_posts: Vec<PatreonPost>,
-    sync_count: Arc<Mutex<usize>>,
-    start_time: NaiveTime,
+    sync_stats: Arc<Mutex<SyncStats>>,
+    start_time: chrono::DateTime<Utc>,
+    mode: SyncMode,
+    progress: &SyncProgressReporter,
 ) -> bool {
     let typed_collection = mongo.collection::<Post>("Post");
     let new_posts = Arc::new(Mutex::new(vec![]));
     let is_update_success = Arc::new(Mutex::new(true));
+    let mut upserted_count = 0;
 
     while let Some(patreon_post) = patreon_posts.pop() {
         let typed_collection = typed_collection.clone();
         let new_posts = Arc::clone(&new_posts);
         let is_update_success = Arc::clone(&is_update_success);
-        let sync_count = Arc::clone(&sync_count);
+        let sync_stats = Arc::clone(&sync_stats);
         let mongo = mongo.clone();
 
-        let x = patreon_post.published_at.clone();
-
-        match typed_collection
-            .find_one_and_update(
-                doc! { "patreon_post_id": &patreon_post.id },
-                doc! {
-                    "$set": doc! {
-                        "title": &patreon_post.title,
-                        "content": &patreon_post.content,
-                        "synced_at": convert_to_rfc3999_string(x),
-                    }
-                },
-                None,
-            )
-            .await
-        {
+        match update_synced_post(&typed_collection, &patreon_post).await {
             Ok(result) => match result {
-                Some(_) => {
+                Some(post) => {
                     info!("Post {} synced", &patreon_post.id);
-                    let mut sync_count_lock = sync_count.lock().await;
-                    *sync_count_lock += 1;
+                    let mut sync_stats_lock = sync_stats.lock().await;
+                    sync_stats_lock.sync_count += 1;
+                    upserted_count += 1;
+                    if post.is_orphaned() {
+                        info!("Post {} restored", &patreon_post.id);
+                        sync_stats_lock.restored_count += 1;
+                    }
                 }
                 None => {
                     let mut new_posts = new_posts.lock().await;
                     let new_post = Post::new_for_sync(
+                        &patreon_post.campaign_id,
                         &patreon_post.id,
                         &patreon_post.title,
                         &patreon_post.content,
                         &patreon_post.published_at,
-                    );
+                    )
+                    .with_visibility(patreon_post.visibility.clone());
                     new_posts.push(new_post);
                 }
             },
@@ -264,8 +477,10 @@ async fn upsert_posts(
                 save_sync_result(
                     mongo.clone(),
                     err.to_string(),
-                    sync_count.lock().await.to_owned(),
+                    sync_stats.lock().await.to_owned(),
                     start_time,
+                    mode,
+                    progress,
                 )
                 .await;
                 let mut is_update_success = is_update_success.lock().await;
@@ -280,28 +495,189 @@ async fn upsert_posts(
         return false;
     }
 
+    if upserted_count > 0 {
+        progress.report(SyncProgressEvent::PostsUpserted {
+            count: upserted_count,
+        });
+    }
+
     let new_posts = new_posts.lock().await;
     if !new_posts.is_empty() {
         let x = new_posts.to_vec();
-        return insert_posts(mongo.clone(), x, Arc::clone(&sync_count), start_time).await;
+        return insert_posts(
+            mongo.clone(),
+            x,
+            Arc::clone(&sync_stats),
+            start_time,
+            mode,
+            progress,
+        )
+        .await;
     }
 
     true
 }
 
+/// Refreshes the stored copy of `patreon_post`, returning the post as it was before the update,
+/// or `None` when it was never synced.
+async fn update_synced_post(
+    typed_collection: &Collection<Post>,
+    patreon_post: &PatreonPost,
+) -> mongodb::error::Result<Option<Post>> {
+    let extracted = extract_content(&patreon_post.content);
+    let visibility = to_bson(&patreon_post.visibility)?;
+
+    let mut changes = doc! {
+        "title": { "$literal": &patreon_post.title },
+        "content": { "$literal": &patreon_post.content },
+        "images_url": synced_field_value("images_url", extracted.images_url.clone().into()),
+        "file_url": synced_field_value("file_url", extracted.file_url().into()),
+        "campaign_id": &patreon_post.campaign_id,
+        // access changes on Patreon are never overridden here
+        "visibility": { "$literal": visibility },
+    };
+    // published_at stays the same when a post is edited on Patreon, so updated_at has to move
+    // for the validators to change. A restored post changed like an orphaned one did.
+    let mut is_changed: Vec<Bson> = changes
+        .iter()
+        .map(|(field, value)| doc! { "$ne": [format!("${}", field), value] }.into())
+        .collect();
+    is_changed.push(doc! { "$eq": ["$is_orphaned", true] }.into());
+    changes.insert(
+        "updated_at",
+        doc! {
+            "$cond": [{ "$or": is_changed }, to_rfc3339_millis(DateTime::now()), "$updated_at"]
+        },
+    );
+    changes.insert(
+        "synced_at",
+        convert_to_rfc3999_string(patreon_post.published_at.clone()),
+    );
+    changes.insert("is_orphaned", false);
+
+    typed_collection
+        .find_one_and_update(
+            doc! { "patreon_post_id": &patreon_post.id },
+            vec![doc! { "$set": changes }],
+            None,
+        )
+        .await
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpsertedPost {
+    Created,
+    Updated,
+    Restored,
+}
+
+/// Syncs a single post the same way a sync run does.
+pub async fn upsert_post(
+    mongo: Database,
+    patreon_post: &PatreonPost,
+) -> anyhow::Result<UpsertedPost> {
+    let typed_collection = mongo.collection::<Post>("Post");
+
+    match update_synced_post(&typed_collection, patreon_post).await? {
+        Some(post) if post.is_orphaned() => {
+            info!("Post {} restored", &patreon_post.id);
+            Ok(UpsertedPost::Restored)
+        }
+        Some(_) => {
+            info!("Post {} synced", &patreon_post.id);
+            Ok(UpsertedPost::Updated)
+        }
+        None => {
+            let mut new_post = Post::new_for_sync(
+                &patreon_post.campaign_id,
+                &patreon_post.id,
+                &patreon_post.title,
+                &patreon_post.content,
+                &patreon_post.published_at,
+            )
+            .with_visibility(patreon_post.visibility.clone());
+            assign_slugs(mongo.clone(), std::slice::from_mut(&mut new_post)).await?;
+            insert_one_doc::<Post>(mongo, new_post).await?;
+            info!("Post {} created", &patreon_post.id);
+            Ok(UpsertedPost::Created)
+        }
+    }
+}
+
+/// Flags a single post as gone from Patreon, returning `false` when it was never synced or is
+/// already orphaned.
+pub async fn orphan_post(mongo: Database, patreon_post_id: &str) -> anyhow::Result<bool> {
+    let orphaned_count = update_many_docs::<Post>(
+        mongo,
+        doc! {
+            "patreon_post_id": patreon_post_id,
+            "is_orphaned": { "$ne": true },
+        },
+        doc! {
+            "$set": {
+                "is_orphaned": true,
+                "updated_at": to_rfc3339_millis(DateTime::now()),
+            }
+        },
+    )
+    .await?;
+
+    Ok(orphaned_count > 0)
+}
+
+/// Update expression for a field filled from the Patreon content: the stored value is kept when
+/// an admin overrode it, or when the sync found nothing to replace it with.
+fn synced_field_value(field: &str, value: Bson) -> Bson {
+    let is_empty = match &value {
+        Bson::String(value) => value.is_empty(),
+        Bson::Array(value) => value.is_empty(),
+        _ => false,
+    };
+    if is_empty {
+        return Bson::String(format!("${}", field));
+    }
+
+    Bson::Document(doc! {
+        "$cond": [
+            { "$in": [field, { "$ifNull": ["$overridden_fields", []] }] },
+            format!("${}", field),
+            { "$literal": value },
+        ]
+    })
+}
+
 async fn insert_posts(
     mongo: Database,
-    new_posts: Vec<Post>,
-    sync_count: Arc<Mutex<usize>>,
-    start_time: NaiveTime,
+    mut new_posts: Vec<Post>,
+    sync_stats: Arc<Mutex<SyncStats>>,
+    start_time: chrono::DateTime<Utc>,
+    mode: SyncMode,
+    progress: &SyncProgressReporter,
 ) -> bool {
     let typed_collection = mongo.collection::<Post>("Post");
 
+    if let Err(err) = assign_slugs(mongo.clone(), &mut new_posts).await {
+        save_sync_result(
+            mongo,
+            err.to_string(),
+            sync_stats.lock().await.to_owned(),
+            start_time,
+            mode,
+            progress,
+        )
+        .await;
+        return false;
+    }
+
     match typed_collection.insert_many(new_posts, None).await {
         Ok(result) => {
             let inserted_count = result.inserted_ids.len();
-            let mut sync_count_lock = sync_count.lock().await;
-            *sync_count_lock += inserted_count;
+            let mut sync_stats_lock = sync_stats.lock().await;
+            sync_stats_lock.sync_count += inserted_count;
+            progress.report(SyncProgressEvent::PostsInserted {
+                count: inserted_count,
+            });
             info!("{} Posts Created during sync", result.inserted_ids.len());
             true
         }
@@ -309,8 +685,10 @@ async fn insert_posts(
             save_sync_result(
                 mongo,
                 err.to_string(),
-                sync_count.lock().await.to_owned(),
+                sync_stats.lock().await.to_owned(),
                 start_time,
+                mode,
+                progress,
             )
             .await;
             false
@@ -321,14 +699,21 @@ async fn insert_posts(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::patreon_api::ReqwestPatreonApi;
+    use crate::sync_job::{check_sync_job_exists, create_sync_job, get_sync_job};
     use crate::test_util::test_util::{
         create_test_state, generate_port_number, get_db_connection_uri, get_mongo_image,
         get_redis_connection_uri, get_redis_image, populate_test_data,
     };
+    use crate::test_util::test_util::{FakePatreonApi, PATREON_POSTS_PAGE_1, PATREON_POSTS_PAGE_2};
+    use crate::visibility::PostVisibility;
     use futures::TryStreamExt;
+    use mongodb::bson::to_document;
     use mongodb::Client;
     use testcontainers_modules::redis::REDIS_PORT;
     use testcontainers_modules::testcontainers::clients;
+    use wiremock::matchers::{method, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
 
     impl SyncResult {
         pub fn new() -> Self {
@@ -338,11 +723,161 @@ mod tests {
                 message: "".to_string(),
                 sync_count: 32,
                 elapsed_time: 444,
-                synced_at: DateTime::now()
+                synced_at: DateTime::now(),
+                mode: SyncMode::Full,
+                orphaned_count: 0,
+                restored_count: 0,
+                retry_count: 0,
+                last_http_status: Some(200),
+            }
+        }
+
+        pub fn with(
+            synced_at: &str,
+            is_success: bool,
+            sync_count: usize,
+            elapsed_time: i64,
+        ) -> Self {
+            SyncResult {
+                is_success,
+                sync_count,
+                elapsed_time,
+                synced_at: DateTime::parse_rfc3339_str(synced_at).unwrap(),
+                ..SyncResult::new()
             }
         }
     }
 
+    #[test]
+    fn test_synced_field_value() {
+        assert_eq!(
+            synced_field_value("file_url", Bson::String("".to_string())),
+            Bson::String("$file_url".to_string())
+        );
+        assert_eq!(
+            synced_field_value("images_url", Bson::Array(vec![])),
+            Bson::String("$images_url".to_string())
+        );
+        assert_eq!(
+            synced_field_value(
+                "file_url",
+                Bson::String("https://mega.nz/file/a".to_string())
+            ),
+            Bson::Document(doc! {
+                "$cond": [
+                    { "$in": ["file_url", { "$ifNull": ["$overridden_fields", []] }] },
+                    "$file_url",
+                    { "$literal": "https://mega.nz/file/a" },
+                ]
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_campaign_ids() {
+        assert_eq!(
+            parse_campaign_ids("8365446, 1234567,"),
+            vec!["8365446".to_string(), "1234567".to_string()]
+        );
+        assert_eq!(
+            parse_campaign_ids(" "),
+            vec![DEFAULT_CAMPAIGN_ID.to_string()]
+        );
+    }
+
+    #[test]
+    fn test_sync_mode_payload() {
+        assert_eq!(SyncMode::from_payload("Sync"), Some(SyncMode::Incremental));
+        assert_eq!(SyncMode::from_payload("FullSync"), Some(SyncMode::Full));
+        assert_eq!(SyncMode::from_payload("asdf"), None);
+    }
+
+    #[test]
+    fn test_take_posts_published_since() {
+        let patreon_post = |id: &str, published_at: &str| PatreonPost {
+            id: id.to_string(),
+            campaign_id: DEFAULT_CAMPAIGN_ID.to_string(),
+            content: "".to_string(),
+            title: "".to_string(),
+            published_at: published_at.to_string(),
+            visibility: PostVisibility::Public,
+        };
+        let patreon_posts = vec![
+            patreon_post("3", "2024-03-15T00:00:00.000+00:00"),
+            patreon_post("2", "2024-03-10T00:00:00.000+00:00"),
+            patreon_post("1", "2024-03-01T00:00:00.000+00:00"),
+        ];
+
+        let (new_posts, reached_synced_posts) = take_posts_published_since(
+            patreon_posts,
+            get_chrono_dt_from_string("2024-03-10T00:00:00.000+00:00".to_string()),
+        );
+
+        assert!(reached_synced_posts);
+        assert_eq!(
+            new_posts
+                .iter()
+                .map(|post| post.id.as_str())
+                .collect::<Vec<_>>(),
+            vec!["3", "2"]
+        );
+
+        let (new_posts, reached_synced_posts) = take_posts_published_since(
+            new_posts,
+            get_chrono_dt_from_string("2024-01-01T00:00:00.000+00:00".to_string()),
+        );
+
+        assert!(!reached_synced_posts);
+        assert_eq!(new_posts.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_last_successful_sync_start() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let _c = docker.run(mongo_img);
+        let uri = get_db_connection_uri(&port);
+        let client = Client::with_uri_str(uri).await.unwrap();
+
+        let test_db = client.database("test_db");
+
+        assert_eq!(last_successful_sync_start(test_db.clone()).await, None);
+
+        let synced_at = |date_string: &str| {
+            DateTime::from_chrono(get_chrono_dt_from_string(date_string.to_string()))
+        };
+        let sync_results = vec![
+            SyncResult {
+                synced_at: synced_at("2024-03-01T00:00:00.000+00:00"),
+                ..SyncResult::new()
+            },
+            SyncResult {
+                elapsed_time: 60_000,
+                synced_at: synced_at("2024-03-10T00:00:00.000+00:00"),
+                ..SyncResult::new()
+            },
+            SyncResult {
+                is_success: false,
+                message: "error".to_string(),
+                synced_at: synced_at("2024-03-15T00:00:00.000+00:00"),
+                ..SyncResult::new()
+            },
+        ];
+        test_db
+            .collection::<SyncResult>("SyncResult")
+            .insert_many(sync_results, None)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            last_successful_sync_start(test_db.clone()).await,
+            Some(get_chrono_dt_from_string(
+                "2024-03-09T23:59:00.000+00:00".to_string()
+            ))
+        );
+    }
+
     #[tokio::test]
     async fn test_save_sync_result_success() {
         let docker = clients::Cli::default();
@@ -355,7 +890,21 @@ mod tests {
 
         let test_db = client.database("test_db");
 
-        save_sync_result(test_db.clone(), "".to_string(), 30, Utc::now().time()).await;
+        save_sync_result(
+            test_db.clone(),
+            "".to_string(),
+            SyncStats {
+                sync_count: 30,
+                orphaned_count: 2,
+                restored_count: 1,
+                retry_count: 3,
+                last_http_status: Some(200),
+            },
+            Utc::now(),
+            SyncMode::Incremental,
+            &SyncProgressReporter::noop(),
+        )
+        .await;
 
         let typed_collection = test_db.collection::<SyncResult>("SyncResult");
         let x = typed_collection.find(None, None).await.unwrap();
@@ -363,10 +912,15 @@ mod tests {
         let sync_results: Vec<SyncResult> = x.try_collect().await.unwrap();
 
         assert_eq!(sync_results.len(), 1);
-        let sync_result = sync_results.get(0).unwrap();
+        let sync_result = sync_results.first().unwrap();
 
-        assert_eq!(sync_result.is_success, true);
+        assert!(sync_result.is_success);
         assert_eq!(sync_result.sync_count, 30);
+        assert_eq!(sync_result.mode, SyncMode::Incremental);
+        assert_eq!(sync_result.orphaned_count, 2);
+        assert_eq!(sync_result.restored_count, 1);
+        assert_eq!(sync_result.retry_count, 3);
+        assert_eq!(sync_result.last_http_status, Some(200));
     }
 
     #[tokio::test]
@@ -384,8 +938,13 @@ mod tests {
         save_sync_result(
             test_db.clone(),
             "test message".to_string(),
-            30,
-            Utc::now().time(),
+            SyncStats {
+                sync_count: 30,
+                ..SyncStats::default()
+            },
+            Utc::now(),
+            SyncMode::Full,
+            &SyncProgressReporter::noop(),
         )
         .await;
 
@@ -395,12 +954,238 @@ mod tests {
         let sync_results: Vec<SyncResult> = x.try_collect().await.unwrap();
 
         assert_eq!(sync_results.len(), 1);
-        let sync_result = sync_results.get(0).unwrap();
+        let sync_result = sync_results.first().unwrap();
 
-        assert_eq!(sync_result.is_success, false);
+        assert!(!sync_result.is_success);
         assert_eq!(sync_result.sync_count, 30);
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_upsert_posts_keeps_overridden_fields() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let _c = docker.run(mongo_img);
+        let uri = get_db_connection_uri(&port);
+        let client = Client::with_uri_str(uri).await.unwrap();
+
+        let test_db = client.database("test_db");
+        let typed_collection = test_db.collection::<Post>("Post");
+
+        let content = include_str!("test_data/patreon_html/outfit_release.html");
+        let patreon_post = PatreonPost {
+            id: "98765432".to_string(),
+            campaign_id: DEFAULT_CAMPAIGN_ID.to_string(),
+            content: content.to_string(),
+            title: "Dragonbone Knight Armor".to_string(),
+            published_at: "2024-03-15T00:00:00.000+00:00".to_string(),
+            visibility: PostVisibility::Public,
+        };
+
+        let sync_stats = Arc::new(Mutex::new(SyncStats::default()));
+        let is_success = upsert_posts(
+            test_db.clone(),
+            vec![patreon_post.clone()],
+            Arc::clone(&sync_stats),
+            Utc::now(),
+            SyncMode::Full,
+            &SyncProgressReporter::noop(),
+        )
+        .await;
+        assert!(is_success);
+
+        let synced_post = typed_collection
+            .find_one(doc! { "patreon_post_id": "98765432" }, None)
+            .await
+            .unwrap()
+            .unwrap();
+        let synced_post_doc = to_document(&synced_post).unwrap();
+        assert_eq!(
+            synced_post_doc.get_str("campaign_id").unwrap(),
+            DEFAULT_CAMPAIGN_ID
+        );
+        assert_eq!(synced_post_doc.get_array("images_url").unwrap().len(), 2);
+        assert_eq!(
+            synced_post_doc.get_str("file_url").unwrap(),
+            "https://drive.google.com/file/d/1a2B3c4D5e6F7g8H9i0J/view?usp=sharing"
+        );
+
+        typed_collection
+            .update_one(
+                doc! { "patreon_post_id": "98765432" },
+                doc! { "$set": {
+                    "file_url": "https://example.com/manual.7z",
+                    "overridden_fields": ["file_url"],
+                } },
+                None,
+            )
+            .await
+            .unwrap();
+
+        let is_success = upsert_posts(
+            test_db.clone(),
+            vec![patreon_post],
+            Arc::clone(&sync_stats),
+            Utc::now(),
+            SyncMode::Full,
+            &SyncProgressReporter::noop(),
+        )
+        .await;
+        assert!(is_success);
+
+        let resynced_post = typed_collection
+            .find_one(doc! { "patreon_post_id": "98765432" }, None)
+            .await
+            .unwrap()
+            .unwrap();
+        let resynced_post_doc = to_document(&resynced_post).unwrap();
+        assert_eq!(
+            resynced_post_doc.get_str("file_url").unwrap(),
+            "https://example.com/manual.7z"
+        );
+        assert_eq!(resynced_post_doc.get_array("images_url").unwrap().len(), 2);
+        assert_eq!(sync_stats.lock().await.sync_count, 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_orphan_and_restore_posts() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let _c = docker.run(mongo_img);
+        let uri = get_db_connection_uri(&port);
+        let client = Client::with_uri_str(uri).await.unwrap();
+
+        let test_db = client.database("test_db");
+        let typed_collection = test_db.collection::<Post>("Post");
+        let date_string = "2024-03-15T00:00:00.000+00:00";
+        typed_collection
+            .insert_many(
+                vec![
+                    Post::new_for_sync(DEFAULT_CAMPAIGN_ID, "1", "kept", "", date_string),
+                    Post::new_for_sync(DEFAULT_CAMPAIGN_ID, "2", "removed", "", date_string),
+                    // created by hand, never on Patreon
+                    Post::new_for_sync(DEFAULT_CAMPAIGN_ID, "", "manual", "", date_string),
+                ],
+                None,
+            )
+            .await
+            .unwrap();
+
+        let orphaned_count = orphan_missing_posts(test_db.clone(), &["1".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(orphaned_count, 1);
+        assert_eq!(orphan_missing_posts(test_db.clone(), &[]).await.unwrap(), 0);
+
+        let is_orphaned = |patreon_post_id: &'static str| {
+            let typed_collection = typed_collection.clone();
+            async move {
+                typed_collection
+                    .find_one(doc! { "patreon_post_id": patreon_post_id }, None)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .is_orphaned()
+            }
+        };
+        let updated_at = |patreon_post_id: &'static str| {
+            let typed_collection = typed_collection.clone();
+            async move {
+                let post = typed_collection
+                    .find_one(doc! { "patreon_post_id": patreon_post_id }, None)
+                    .await
+                    .unwrap()
+                    .unwrap();
+                to_document(&post)
+                    .unwrap()
+                    .get_str("updated_at")
+                    .unwrap()
+                    .to_string()
+            }
+        };
+        assert!(!is_orphaned("1").await);
+        assert!(is_orphaned("2").await);
+        assert!(!is_orphaned("").await);
+        // orphaning changes the validators of the post
+        assert_eq!(updated_at("1").await, "2024-03-15T00:00:00.000Z");
+        let orphaned_at = updated_at("2").await;
+        assert_ne!(orphaned_at, "2024-03-15T00:00:00.000Z");
+
+        let sync_stats = Arc::new(Mutex::new(SyncStats::default()));
+        let is_success = upsert_posts(
+            test_db.clone(),
+            vec![PatreonPost {
+                id: "2".to_string(),
+                campaign_id: DEFAULT_CAMPAIGN_ID.to_string(),
+                content: "".to_string(),
+                title: "republished".to_string(),
+                published_at: date_string.to_string(),
+                visibility: PostVisibility::Public,
+            }],
+            Arc::clone(&sync_stats),
+            Utc::now(),
+            SyncMode::Full,
+            &SyncProgressReporter::noop(),
+        )
+        .await;
+        assert!(is_success);
+
+        assert!(!is_orphaned("2").await);
+        assert!(updated_at("2").await >= orphaned_at);
+        assert_eq!(sync_stats.lock().await.restored_count, 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_synced_edit_changes_validators() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let _c = docker.run(mongo_img);
+        let uri = get_db_connection_uri(&port);
+        let client = Client::with_uri_str(uri).await.unwrap();
+
+        let test_db = client.database("test_db");
+        let typed_collection = test_db.collection::<Post>("Post");
+        let validators = || {
+            let typed_collection = typed_collection.clone();
+            async move {
+                typed_collection
+                    .find_one(doc! { "patreon_post_id": "1" }, None)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .validators()
+            }
+        };
+        let mut patreon_post = PatreonPost {
+            id: "1".to_string(),
+            campaign_id: DEFAULT_CAMPAIGN_ID.to_string(),
+            content: "".to_string(),
+            title: "Preset".to_string(),
+            published_at: "2024-03-15T00:00:00.000+00:00".to_string(),
+            visibility: PostVisibility::Public,
+        };
+
+        upsert_post(test_db.clone(), &patreon_post).await.unwrap();
+        let created = validators().await;
+
+        // a sync that finds nothing new leaves the validators alone
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        upsert_post(test_db.clone(), &patreon_post).await.unwrap();
+        assert_eq!(validators().await, created);
+
+        patreon_post.title = "Preset v2".to_string();
+        upsert_post(test_db.clone(), &patreon_post).await.unwrap();
+        let edited = validators().await;
+        assert_ne!(edited, created);
+
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+        patreon_post.visibility = PostVisibility::Patrons;
+        upsert_post(test_db.clone(), &patreon_post).await.unwrap();
+        assert_ne!(validators().await, edited);
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_sync_post_fail_with_invalid_token() {
         let docker = clients::Cli::default();
@@ -417,9 +1202,28 @@ mod tests {
 
         let test_db = client.database("test_db");
 
-        let state = create_test_state(test_db.clone(), redis_client);
+        let patreon_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(1)
+            .mount(&patreon_server)
+            .await;
+
+        let state = AppState {
+            patreon_api: Arc::new(ReqwestPatreonApi::new(
+                &patreon_server.uri(),
+                "asdfasdfasdf",
+            )),
+            ..create_test_state(test_db.clone(), redis_client)
+        };
+
+        let redis_client = state.redis.clone();
+        assert!(sync_post(state, SyncMode::Incremental, "test_job")
+            .await
+            .is_err());
 
-        sync_post(state).await;
+        // failed syncs must not leave the lock behind
+        assert!(!check_sync_job_exists(redis_client).unwrap());
 
         let typed_collection = test_db.collection::<SyncResult>("SyncResult");
         let x = typed_collection.find(None, None).await.unwrap();
@@ -427,8 +1231,185 @@ mod tests {
         let sync_results: Vec<SyncResult> = x.try_collect().await.unwrap();
 
         assert_eq!(sync_results.len(), 1);
-        let sync_result = sync_results.get(0).unwrap();
+        let sync_result = sync_results.first().unwrap();
+
+        assert!(!sync_result.is_success);
+        // nothing was synced before, so the incremental sync fell back to a full one
+        assert_eq!(sync_result.mode, SyncMode::Full);
+        assert_eq!(sync_result.retry_count, 0);
+        assert_eq!(sync_result.last_http_status, Some(401));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sync_post_fails_while_another_job_runs() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+        let state = AppState {
+            patreon_api: Arc::new(FakePatreonApi::from_fixtures()),
+            ..create_test_state(test_db.clone(), redis_client.clone())
+        };
+
+        create_sync_job(redis_client.clone(), "running_job").unwrap();
+
+        // deferring leaves the job queued for a retry instead of dropping it
+        assert!(sync_post(state, SyncMode::Full, "test_job")
+            .await
+            .unwrap_err()
+            .is::<JobDeferred>());
+        assert!(find_sync_results(test_db).await.is_empty());
+        assert_eq!(
+            get_sync_job(redis_client).unwrap().unwrap().job_id,
+            "running_job"
+        );
+    }
+
+    async fn find_sync_results(test_db: Database) -> Vec<SyncResult> {
+        let typed_collection = test_db.collection::<SyncResult>("SyncResult");
+        let x = typed_collection.find(None, None).await.unwrap();
+
+        x.try_collect().await.unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sync_post_inserts_then_updates_posts() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+        let typed_collection = test_db.collection::<Post>("Post");
+
+        let state = AppState {
+            patreon_api: Arc::new(FakePatreonApi::from_fixtures()),
+            ..create_test_state(test_db.clone(), redis_client)
+        };
+
+        // first run only inserts
+        sync_post(state.clone(), SyncMode::Full, "test_job")
+            .await
+            .unwrap();
+
+        let sync_results = find_sync_results(test_db.clone()).await;
+        assert_eq!(sync_results.len(), 1);
+        let sync_result = sync_results.first().unwrap();
+        assert!(sync_result.is_success);
+        assert_eq!(sync_result.sync_count, 3);
+        assert_eq!(sync_result.last_http_status, Some(200));
+        assert_eq!(
+            typed_collection.count_documents(None, None).await.unwrap(),
+            3
+        );
+
+        let synced_post = typed_collection
+            .find_one(doc! { "patreon_post_id": "98765432" }, None)
+            .await
+            .unwrap()
+            .unwrap();
+        let synced_post_doc = to_document(&synced_post).unwrap();
+        assert_eq!(
+            synced_post_doc.get_str("title").unwrap(),
+            "Dragonbone Knight Armor"
+        );
+        assert_eq!(
+            synced_post_doc.get_str("slug").unwrap(),
+            "dragonbone-knight-armor"
+        );
+        assert_eq!(
+            synced_post_doc.get_str("file_url").unwrap(),
+            "https://drive.google.com/file/d/1a2B3c4D5e6F7g8H9i0J/view?usp=sharing"
+        );
+        assert_eq!(
+            synced_post_doc.get_document("visibility").unwrap(),
+            &doc! { "kind": "minimum_tier", "tier_ids": ["5678901", "5678902"] }
+        );
+
+        // second run only updates
+        sync_post(state, SyncMode::Full, "test_job").await.unwrap();
+
+        let sync_results = find_sync_results(test_db.clone()).await;
+        assert_eq!(sync_results.len(), 2);
+        assert!(sync_results
+            .iter()
+            .all(|sync_result| sync_result.is_success));
+        assert_eq!(sync_results[1].sync_count, 3);
+        assert_eq!(
+            typed_collection.count_documents(None, None).await.unwrap(),
+            3
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sync_post_with_stub_server() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+
+        let patreon_server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(query_param("page[cursor]", "page_2_cursor"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(PATREON_POSTS_PAGE_2))
+            .with_priority(1)
+            .expect(1)
+            .mount(&patreon_server)
+            .await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(200).set_body_string(PATREON_POSTS_PAGE_1))
+            .expect(1)
+            .mount(&patreon_server)
+            .await;
+
+        let state = AppState {
+            patreon_api: Arc::new(ReqwestPatreonApi::new(
+                &patreon_server.uri(),
+                "asdfasdfasdf",
+            )),
+            ..create_test_state(test_db.clone(), redis_client)
+        };
 
-        assert_eq!(sync_result.is_success, false);
+        sync_post(state, SyncMode::Full, "test_job").await.unwrap();
+
+        let sync_results = find_sync_results(test_db.clone()).await;
+        assert_eq!(sync_results.len(), 1);
+        let sync_result = sync_results.first().unwrap();
+        assert!(sync_result.is_success);
+        assert_eq!(sync_result.sync_count, 3);
+        assert_eq!(sync_result.retry_count, 0);
+        assert_eq!(
+            test_db
+                .collection::<Post>("Post")
+                .count_documents(doc! { "campaign_id": DEFAULT_CAMPAIGN_ID }, None)
+                .await
+                .unwrap(),
+            3
+        );
     }
 }
@@ -0,0 +1,181 @@
+use crate::redis_pubsub::message::{Message, MessageKind};
+use crate::redis_pubsub::pubsub::notify;
+use crate::redis_pubsub::CHANNEL;
+use crate::sync_post::SyncMode;
+use crate::AppState;
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::IntoResponse;
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use tracing::error;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum SyncProgressEvent {
+    /// Another sync holds the lock, the job is retried once it's released.
+    Waiting {
+        message: String,
+    },
+    Started {
+        mode: SyncMode,
+        campaign_count: usize,
+    },
+    PageFetched {
+        campaign_id: String,
+        post_count: usize,
+    },
+    PostsUpserted {
+        count: usize,
+    },
+    PostsInserted {
+        count: usize,
+    },
+    Finished {
+        sync_count: usize,
+    },
+    Failed {
+        message: String,
+    },
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct SyncProgress {
+    pub job_id: String,
+    #[serde(flatten)]
+    pub event: SyncProgressEvent,
+}
+
+/// Publishes the progress of one sync job, so whichever instance serves the stream can relay it.
+#[derive(Clone)]
+pub struct SyncProgressReporter {
+    redis: Option<redis::Client>,
+    job_id: String,
+}
+
+impl SyncProgressReporter {
+    pub fn new(redis: redis::Client, job_id: &str) -> Self {
+        SyncProgressReporter {
+            redis: Some(redis),
+            job_id: job_id.to_string(),
+        }
+    }
+
+    /// Progress is best effort; a failed publish never fails the sync.
+    pub fn report(&self, event: SyncProgressEvent) {
+        let redis = match &self.redis {
+            Some(redis) => redis.clone(),
+            None => return,
+        };
+        let progress = SyncProgress {
+            job_id: self.job_id.clone(),
+            event,
+        };
+
+        notify(redis, MessageKind::SyncProgress(progress));
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct SyncProgressQuery {
+    job_id: Option<String>,
+}
+
+pub async fn stream_sync_progress(
+    State(state): State<AppState>,
+    Query(query): Query<SyncProgressQuery>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, impl IntoResponse> {
+    let pubsub = match state.redis.get_async_pubsub().await {
+        Ok(mut pubsub) => match pubsub.subscribe(CHANNEL).await {
+            Ok(_) => pubsub,
+            Err(err) => {
+                let error_message = err.to_string();
+                error!("{}", error_message.clone());
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response());
+            }
+        },
+        Err(err) => {
+            let error_message = err.to_string();
+            error!("{}", error_message.clone());
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response());
+        }
+    };
+
+    let stream = pubsub.into_on_message().filter_map(move |msg| {
+        let job_id = query.job_id.clone();
+        async move {
+            let payload: String = msg.get_payload().ok()?;
+            let progress = match Message::decode(&payload).ok()?.kind {
+                MessageKind::SyncProgress(progress) => progress,
+                _ => return None,
+            };
+
+            if job_id.is_some_and(|job_id| job_id != progress.job_id) {
+                return None;
+            }
+
+            to_sse_event(&progress).map(Ok)
+        }
+    });
+
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+fn to_sse_event(progress: &SyncProgress) -> Option<Event> {
+    let event_name = match &progress.event {
+        SyncProgressEvent::Waiting { .. } => "waiting",
+        SyncProgressEvent::Started { .. } => "started",
+        SyncProgressEvent::PageFetched { .. } => "page_fetched",
+        SyncProgressEvent::PostsUpserted { .. } => "posts_upserted",
+        SyncProgressEvent::PostsInserted { .. } => "posts_inserted",
+        SyncProgressEvent::Finished { .. } => "finished",
+        SyncProgressEvent::Failed { .. } => "failed",
+    };
+
+    Event::default().event(event_name).json_data(progress).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    impl SyncProgressReporter {
+        /// Reports nowhere, for tests that don't run redis.
+        pub fn noop() -> Self {
+            SyncProgressReporter {
+                redis: None,
+                job_id: "".to_string(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_sync_progress_json() {
+        let progress = SyncProgress {
+            job_id: "asdf".to_string(),
+            event: SyncProgressEvent::PageFetched {
+                campaign_id: "8365446".to_string(),
+                post_count: 20,
+            },
+        };
+
+        let value = serde_json::to_value(&progress).unwrap();
+
+        assert_eq!(
+            value,
+            json!({
+                "job_id": "asdf",
+                "event": "page_fetched",
+                "campaign_id": "8365446",
+                "post_count": 20,
+            })
+        );
+        assert_eq!(
+            serde_json::from_value::<SyncProgress>(value).unwrap(),
+            progress
+        );
+    }
+}
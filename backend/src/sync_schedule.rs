@@ -0,0 +1,316 @@
+use crate::background::BackgroundTask;
+use crate::job_queue::{enqueue_job, Job, JobKind};
+use crate::redis_pubsub::message::SyncTrigger;
+use crate::sync_job::check_sync_job_exists;
+use crate::sync_post::SyncMode;
+use crate::AppState;
+use anyhow::Result;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::IntoResponse;
+use axum::Json;
+use chrono::{DateTime, SecondsFormat, Utc};
+use cron::Schedule;
+use redis::Commands;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{debug, error, info};
+
+const SCHEDULE_LAST_RUN_KEY: &str = "sync_schedule_last_run";
+const SCHEDULE_TICK_KEY_PREFIX: &str = "sync_schedule_tick";
+/// Long enough that an instance whose clock lags behind still finds the tick claimed.
+const SCHEDULE_TICK_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The cron expression scheduled syncs run on, e.g. `0 0 */6 * * *` (with seconds).
+#[derive(Debug, Clone)]
+pub struct SyncSchedule {
+    expression: String,
+    schedule: Schedule,
+}
+
+impl SyncSchedule {
+    /// An empty expression turns the scheduler off.
+    pub fn parse(expression: &str) -> Result<Option<Self>, String> {
+        let expression = expression.trim();
+        if expression.is_empty() || expression == "None" {
+            return Ok(None);
+        }
+
+        let schedule = Schedule::from_str(expression)
+            .map_err(|err| format!("invalid sync schedule {}: {}", expression, err))?;
+
+        Ok(Some(SyncSchedule {
+            expression: expression.to_string(),
+            schedule,
+        }))
+    }
+
+    pub fn expression(&self) -> &str {
+        &self.expression
+    }
+
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        self.schedule.after(&after).next()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ScheduledRun {
+    pub job_id: String,
+    /// The tick of the schedule this run was fired for.
+    pub scheduled_at: String,
+    pub fired_at: String,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct SyncScheduleStatus {
+    pub enabled: bool,
+    pub expression: Option<String>,
+    pub last_run: Option<ScheduledRun>,
+    pub next_run: Option<String>,
+}
+
+fn to_rfc3339(dt: DateTime<Utc>) -> String {
+    dt.to_rfc3339_opts(SecondsFormat::Secs, true)
+}
+
+/// Every instance runs the scheduler, the first one to claim a tick fires it.
+fn claim_schedule_tick(redis: redis::Client, tick: DateTime<Utc>) -> Result<bool> {
+    let mut con = redis.get_connection()?;
+
+    let result: Option<String> = redis::cmd("SET")
+        .arg(format!("{}:{}", SCHEDULE_TICK_KEY_PREFIX, tick.timestamp()))
+        .arg(1)
+        .arg("NX")
+        .arg("PX")
+        .arg(SCHEDULE_TICK_TTL.as_millis() as u64)
+        .query(&mut con)?;
+
+    Ok(result.is_some())
+}
+
+fn save_last_run(redis: redis::Client, run: &ScheduledRun) -> Result<()> {
+    let mut con = redis.get_connection()?;
+
+    let json = serde_json::to_string(run)?;
+    let _: () = con.set(SCHEDULE_LAST_RUN_KEY, json)?;
+
+    Ok(())
+}
+
+fn get_last_run(redis: redis::Client) -> Result<Option<ScheduledRun>> {
+    let mut con = redis.get_connection()?;
+
+    let json: Option<String> = con.get(SCHEDULE_LAST_RUN_KEY)?;
+
+    match json {
+        Some(json) => Ok(Some(serde_json::from_str(&json)?)),
+        None => Ok(None),
+    }
+}
+
+/// Queues the sync job for `tick`, unless another instance already did
+/// or a sync is still holding the job lock.
+pub fn fire_scheduled_sync(
+    redis: redis::Client,
+    tick: DateTime<Utc>,
+) -> Result<Option<ScheduledRun>> {
+    if !claim_schedule_tick(redis.clone(), tick)? {
+        debug!("sync schedule tick {} fired by another instance", tick);
+        return Ok(None);
+    }
+
+    if check_sync_job_exists(redis.clone())? {
+        info!("skip scheduled sync at {}, a sync job is running", tick);
+        return Ok(None);
+    }
+
+    // incremental falls back to a full sync on its own when nothing was synced yet
+    let job = Job::new(JobKind::Sync {
+        mode: SyncMode::Incremental,
+        trigger: SyncTrigger::Schedule,
+    });
+    let run = ScheduledRun {
+        job_id: job.id.clone(),
+        scheduled_at: to_rfc3339(tick),
+        fired_at: to_rfc3339(Utc::now()),
+    };
+
+    enqueue_job(redis.clone(), &job)?;
+    save_last_run(redis, &run)?;
+
+    Ok(Some(run))
+}
+
+pub fn spawn_sync_scheduler(redis: redis::Client, schedule: SyncSchedule) -> BackgroundTask {
+    BackgroundTask::spawn("sync scheduler", |shutdown| {
+        run_sync_scheduler(redis, schedule, shutdown)
+    })
+}
+
+async fn run_sync_scheduler(
+    redis: redis::Client,
+    schedule: SyncSchedule,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    while !*shutdown.borrow() {
+        let now = Utc::now();
+        let tick = match schedule.next_after(now) {
+            Some(tick) => tick,
+            None => {
+                info!("sync schedule {} has no more runs", schedule.expression());
+                return;
+            }
+        };
+
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            _ = tokio::time::sleep((tick - now).to_std().unwrap_or_default()) => {}
+        }
+
+        match fire_scheduled_sync(redis.clone(), tick) {
+            Ok(Some(run)) => info!("scheduled sync {} fired", run.job_id),
+            Ok(None) => {}
+            Err(err) => error!("fail to fire scheduled sync {}", err.to_string()),
+        }
+    }
+
+    info!("sync scheduler stopped");
+}
+
+pub async fn get_sync_schedule(
+    State(state): State<AppState>,
+) -> Result<Json<SyncScheduleStatus>, impl IntoResponse> {
+    let last_run = match get_last_run(state.redis) {
+        Ok(last_run) => last_run,
+        Err(err) => {
+            let error_message = err.to_string();
+            error!("{}", error_message.clone());
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, error_message).into_response());
+        }
+    };
+
+    let schedule = state.sync_schedule.as_ref();
+
+    Ok(Json(SyncScheduleStatus {
+        enabled: schedule.is_some(),
+        expression: schedule.map(|schedule| schedule.expression().to_string()),
+        last_run,
+        next_run: schedule
+            .and_then(|schedule| schedule.next_after(Utc::now()))
+            .map(to_rfc3339),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync_job::create_sync_job;
+    use crate::test_util::test_util::{
+        create_test_state, generate_port_number, get_db_connection_uri, get_mongo_image,
+        get_redis_connection_uri, get_redis_image,
+    };
+    use mongodb::Client;
+    use testcontainers_modules::redis::REDIS_PORT;
+    use testcontainers_modules::testcontainers::clients;
+
+    #[test]
+    fn test_parse_sync_schedule() {
+        assert!(SyncSchedule::parse("").unwrap().is_none());
+        assert!(SyncSchedule::parse("None").unwrap().is_none());
+        assert!(SyncSchedule::parse("every day").is_err());
+
+        let schedule = SyncSchedule::parse(" 0 0 */6 * * * ").unwrap().unwrap();
+        assert_eq!(schedule.expression(), "0 0 */6 * * *");
+
+        let after = "2024-03-14T05:23:49Z".parse::<DateTime<Utc>>().unwrap();
+        assert_eq!(
+            schedule.next_after(after).map(to_rfc3339),
+            Some("2024-03-14T06:00:00Z".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sync_scheduler_stops_on_shutdown() {
+        let redis_client = redis::Client::open("redis://127.0.0.1:1").unwrap();
+        let schedule = SyncSchedule::parse("0 0 0 1 1 *").unwrap().unwrap();
+
+        let scheduler = spawn_sync_scheduler(redis_client, schedule);
+
+        // the wait for the next tick is cut short
+        tokio::time::timeout(Duration::from_secs(1), scheduler.shutdown())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_fire_scheduled_sync_once_per_tick() {
+        let docker = clients::Cli::default();
+        let redis_img = get_redis_image();
+        let redis_node = docker.run(redis_img);
+
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let tick = "2024-03-14T06:00:00Z".parse::<DateTime<Utc>>().unwrap();
+
+        let run = fire_scheduled_sync(redis_client.clone(), tick)
+            .unwrap()
+            .unwrap();
+        assert_eq!(run.scheduled_at, "2024-03-14T06:00:00Z");
+        assert_eq!(get_last_run(redis_client.clone()).unwrap(), Some(run));
+
+        // another instance reaching the same tick
+        assert!(fire_scheduled_sync(redis_client.clone(), tick)
+            .unwrap()
+            .is_none());
+
+        // the next tick is skipped while a sync holds the lock
+        create_sync_job(redis_client.clone(), "123123123").unwrap();
+        let next_tick = "2024-03-14T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        assert!(fire_scheduled_sync(redis_client, next_tick)
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_get_sync_schedule() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let mut state = create_test_state(client.database("test_db"), redis_client);
+
+        let status = get_sync_schedule(State(state.clone()))
+            .await
+            .ok()
+            .unwrap()
+            .0;
+        assert_eq!(
+            status,
+            SyncScheduleStatus {
+                enabled: false,
+                expression: None,
+                last_run: None,
+                next_run: None,
+            }
+        );
+
+        state.sync_schedule = SyncSchedule::parse("0 0 */6 * * *").unwrap();
+
+        let status = get_sync_schedule(State(state)).await.ok().unwrap().0;
+        assert!(status.enabled);
+        assert_eq!(status.expression, Some("0 0 */6 * * *".to_string()));
+        assert!(status.next_run.is_some());
+    }
+}
@@ -0,0 +1,449 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use crate::cache::invalidate_cache;
+use crate::dao::{
+    aggregate_docs, create_indexes, delete_one_doc, edit_one_doc, find_docs, insert_one_doc,
+    is_duplicate_key_error, update_many_docs,
+};
+use crate::posts::Post;
+use crate::redis_pubsub::message::CacheScope;
+use crate::util::to_rfc3339_millis;
+use crate::AppState;
+use axum::response::IntoResponse;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Json,
+};
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::serde_helpers::{bson_datetime_as_rfc3339_string, hex_string_as_object_id};
+use mongodb::bson::{doc, Bson, DateTime};
+use mongodb::options::IndexOptions;
+use mongodb::{Database, IndexModel};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Tag {
+    #[serde(with = "hex_string_as_object_id")]
+    _id: String,
+    name: String,
+    group: String,
+    #[serde(with = "bson_datetime_as_rfc3339_string")]
+    created_at: DateTime,
+    #[serde(with = "bson_datetime_as_rfc3339_string")]
+    updated_at: DateTime,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct TagWithCount {
+    pub tag: Tag,
+    pub post_count: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NewTagRequest {
+    name: String,
+    group: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EditTagRequest {
+    name: String,
+    group: String,
+}
+
+pub async fn create_tag_indexes(mongo: Database) -> anyhow::Result<()> {
+    let unique_name_index = IndexModel::builder()
+        .keys(doc! { "group": 1, "name": 1 })
+        .options(
+            IndexOptions::builder()
+                .name("tag_unique_name".to_string())
+                .unique(true)
+                .build(),
+        )
+        .build();
+
+    let index_names = create_indexes::<Tag>(mongo, vec![unique_name_index]).await?;
+    info!("Tag indexes ready {:?}", index_names);
+
+    Ok(())
+}
+
+/// Checks that every id refers to an existing tag and returns them deduplicated.
+pub async fn validate_tag_ids(
+    mongo: Database,
+    tag_ids: &[String],
+) -> Result<Vec<String>, (StatusCode, String)> {
+    let mut unique_ids: Vec<String> = vec![];
+    let mut object_ids: Vec<ObjectId> = vec![];
+    for tag_id in tag_ids {
+        let object_id = ObjectId::from_str(tag_id).map_err(|_| {
+            (
+                StatusCode::BAD_REQUEST,
+                format!("invalid tag id: {}", tag_id),
+            )
+        })?;
+        if !object_ids.contains(&object_id) {
+            object_ids.push(object_id);
+            unique_ids.push(object_id.to_hex());
+        }
+    }
+
+    if object_ids.is_empty() {
+        return Ok(unique_ids);
+    }
+
+    let found_tags = find_docs::<Tag>(mongo, doc! { "_id": { "$in": object_ids } })
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let found_ids: HashSet<String> = found_tags.into_iter().map(|tag| tag._id).collect();
+
+    let unknown_ids: Vec<String> = unique_ids
+        .iter()
+        .filter(|tag_id| !found_ids.contains(*tag_id))
+        .cloned()
+        .collect();
+    if !unknown_ids.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("unknown tag ids: {}", unknown_ids.join(", ")),
+        ));
+    }
+
+    Ok(unique_ids)
+}
+
+async fn count_posts_by_tag(mongo: Database) -> anyhow::Result<HashMap<String, i64>> {
+    let pipeline = vec![
+        doc! { "$unwind": "$tags" },
+        doc! { "$group": { "_id": "$tags", "count": { "$sum": 1 } } },
+    ];
+
+    let counts = aggregate_docs::<Post>(mongo, pipeline)
+        .await?
+        .into_iter()
+        .filter_map(|count_doc| {
+            let tag_id = count_doc.get_str("_id").ok()?.to_string();
+            let count = match count_doc.get("count") {
+                Some(Bson::Int32(count)) => *count as i64,
+                Some(Bson::Int64(count)) => *count,
+                _ => 0,
+            };
+            Some((tag_id, count))
+        })
+        .collect();
+
+    Ok(counts)
+}
+
+pub async fn get_all_tags(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<TagWithCount>>, impl IntoResponse> {
+    let tags = match find_docs::<Tag>(state.mongo.clone(), doc! {}).await {
+        Ok(tags) => tags,
+        Err(err) => {
+            let error_message = err.to_string();
+            error!("{}", error_message.clone());
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response());
+        }
+    };
+
+    match count_posts_by_tag(state.mongo).await {
+        Ok(counts) => {
+            let mut tags_with_count: Vec<TagWithCount> = tags
+                .into_iter()
+                .map(|tag| TagWithCount {
+                    post_count: counts.get(&tag._id).copied().unwrap_or(0),
+                    tag,
+                })
+                .collect();
+            tags_with_count
+                .sort_by(|a, b| (&a.tag.group, &a.tag.name).cmp(&(&b.tag.group, &b.tag.name)));
+
+            Ok(Json(tags_with_count))
+        }
+        Err(err) => {
+            let error_message = err.to_string();
+            error!("{}", error_message.clone());
+            Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response())
+        }
+    }
+}
+
+pub async fn create_tag(
+    State(state): State<AppState>,
+    Json(req): Json<NewTagRequest>,
+) -> Result<Json<Bson>, impl IntoResponse> {
+    let new_tag = Tag {
+        _id: ObjectId::new().to_hex(),
+        name: req.name,
+        group: req.group,
+        created_at: DateTime::now(),
+        updated_at: DateTime::now(),
+    };
+
+    match insert_one_doc::<Tag>(state.mongo.clone(), new_tag).await {
+        Ok(inserted_id) => {
+            let object_id = inserted_id.as_object_id().unwrap();
+            info!("New Tag Created {}", object_id.to_hex());
+            invalidate_cache(&state, CacheScope::Tags);
+            Ok(Json(inserted_id))
+        }
+        Err(err) if is_duplicate_key_error(&err) => {
+            error!("{}", err.to_string());
+            Err((StatusCode::CONFLICT, "The tag already exists!".to_string()).into_response())
+        }
+        Err(err) => {
+            let error_message = err.to_string();
+            error!("{}", error_message.clone());
+            Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response())
+        }
+    }
+}
+
+pub async fn edit_tag(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(req): Json<EditTagRequest>,
+) -> Result<Json<Tag>, impl IntoResponse> {
+    let target_tag_object_id_result = ObjectId::from_str(&id);
+
+    if let Err(err) = &target_tag_object_id_result {
+        let error_message = err.to_string();
+        error!(error_message);
+
+        return Err((StatusCode::BAD_REQUEST, error_message).into_response());
+    }
+
+    let filter = doc! {
+        "_id": target_tag_object_id_result.unwrap()
+    };
+    let update = doc! {
+        "$set": doc! {
+            "name": req.name,
+            "group": req.group,
+            "updated_at": DateTime::now().try_to_rfc3339_string().unwrap()
+        },
+    };
+
+    match edit_one_doc::<Tag>(state.mongo.clone(), filter, update).await {
+        Ok(result) => match result {
+            Some(tag) => {
+                info!("Tag {} edited", tag._id);
+                invalidate_cache(&state, CacheScope::Tags);
+                Ok(Json(tag))
+            }
+            None => {
+                error!("The tag with id: {} not found!", id);
+                Err((
+                    StatusCode::NOT_FOUND,
+                    format!("The tag with id: {} not found!", id),
+                )
+                    .into_response())
+            }
+        },
+        Err(err) if is_duplicate_key_error(&err) => {
+            error!("{}", err.to_string());
+            Err((StatusCode::CONFLICT, "The tag already exists!".to_string()).into_response())
+        }
+        Err(err) => {
+            let error_message = err.to_string();
+            error!("{}", error_message.clone());
+            Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response())
+        }
+    }
+}
+
+pub async fn delete_tag(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, impl IntoResponse> {
+    let target_tag_object_id_result = ObjectId::from_str(&id);
+
+    if let Err(err) = &target_tag_object_id_result {
+        let error_message = err.to_string();
+        error!(error_message);
+
+        return Err((StatusCode::BAD_REQUEST, error_message).into_response());
+    }
+
+    let filter = doc! {
+        "_id": target_tag_object_id_result.unwrap()
+    };
+
+    match delete_one_doc::<Tag>(state.mongo.clone(), filter).await {
+        Ok(Some(tag)) => {
+            let untag_result = update_many_docs::<Post>(
+                state.mongo.clone(),
+                doc! { "tags": &tag._id },
+                doc! {
+                    "$pull": { "tags": &tag._id },
+                    // untagging is a change to the post, its etag has to move
+                    "$set": { "updated_at": to_rfc3339_millis(DateTime::now()) },
+                },
+            )
+            .await;
+
+            match untag_result {
+                Ok(untagged_count) => {
+                    info!("Tag {} deleted from {} posts", tag._id, untagged_count);
+                    // the untagged posts changed along with the tags
+                    for scope in [CacheScope::Tags, CacheScope::Posts] {
+                        invalidate_cache(&state, scope);
+                    }
+                    Ok(StatusCode::OK)
+                }
+                Err(err) => {
+                    let error_message = err.to_string();
+                    error!("{}", error_message.clone());
+                    Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response())
+                }
+            }
+        }
+        Ok(None) => {
+            error!("The tag with id: {} not found!", id);
+            Err((
+                StatusCode::NOT_FOUND,
+                format!("The tag with id: {} not found!", id),
+            )
+                .into_response())
+        }
+        Err(err) => {
+            let error_message = err.to_string();
+            error!("{}", error_message.clone());
+            Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::test_util::{
+        create_test_state, find_post_by_id, generate_port_number, get_db_connection_uri,
+        get_mongo_image, get_redis_connection_uri, get_redis_image, insert_test_post,
+    };
+    use mongodb::bson::to_document;
+    use mongodb::Client;
+    use testcontainers_modules::{redis::REDIS_PORT, testcontainers::clients};
+
+    impl Tag {
+        pub fn new(name: &str, group: &str) -> Self {
+            Tag {
+                _id: ObjectId::new().to_hex(),
+                name: name.to_string(),
+                group: group.to_string(),
+                created_at: DateTime::now(),
+                updated_at: DateTime::now(),
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_validate_tag_ids() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let _c = docker.run(mongo_img);
+        let uri = get_db_connection_uri(&port);
+        let client = Client::with_uri_str(uri).await.unwrap();
+
+        let test_db = client.database("test_db");
+
+        let inserted_id = insert_one_doc::<Tag>(test_db.clone(), Tag::new("Skyrim", "game"))
+            .await
+            .unwrap();
+        let tag_id = inserted_id.as_object_id().unwrap().to_hex();
+
+        let result = validate_tag_ids(test_db.clone(), &[tag_id.clone(), tag_id.clone()]).await;
+        assert_eq!(result.unwrap(), vec![tag_id.clone()]);
+
+        let unknown_id = ObjectId::new().to_hex();
+        let result = validate_tag_ids(test_db.clone(), &[tag_id, unknown_id]).await;
+        assert_eq!(result.unwrap_err().0, StatusCode::BAD_REQUEST);
+
+        let result = validate_tag_ids(test_db, &["not an id".to_string()]).await;
+        assert_eq!(result.unwrap_err().0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_get_all_tags_with_post_count() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+
+        let state = create_test_state(test_db.clone(), redis_client);
+
+        let game_tag = Tag::new("Skyrim", "game");
+        let slot_tag = Tag::new("Body", "outfit slot");
+        insert_one_doc::<Tag>(test_db.clone(), game_tag.clone())
+            .await
+            .unwrap();
+        insert_one_doc::<Tag>(test_db.clone(), slot_tag.clone())
+            .await
+            .unwrap();
+
+        let tagged_post = Post::new_for_sync("8365446", "1", "tagged", "content", "")
+            .with_tags(vec![game_tag._id.clone()]);
+        insert_test_post(test_db, tagged_post).await;
+
+        let result = get_all_tags(State(state)).await;
+
+        assert!(result.is_ok());
+
+        let tags = result.ok().unwrap().0;
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags[0].tag.name, "Skyrim");
+        assert_eq!(tags[0].post_count, 1);
+        assert_eq!(tags[1].tag.name, "Body");
+        assert_eq!(tags[1].post_count, 0);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_delete_tag_removes_it_from_posts() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+
+        let state = create_test_state(test_db.clone(), redis_client);
+
+        let tag = Tag::new("Skyrim", "game");
+        insert_one_doc::<Tag>(test_db.clone(), tag.clone())
+            .await
+            .unwrap();
+
+        let tagged_post = Post::new_for_sync("8365446", "1", "tagged", "content", "")
+            .with_tags(vec![tag._id.clone()]);
+        let post_id = insert_test_post(test_db.clone(), tagged_post).await;
+
+        let result = delete_tag(State(state), Path(tag._id.clone())).await;
+
+        assert!(result.is_ok());
+
+        let post = find_post_by_id(test_db, post_id).await.unwrap();
+        let post_doc = to_document(&post).unwrap();
+        assert!(post_doc.get_array("tags").unwrap().is_empty());
+    }
+}
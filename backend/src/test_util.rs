@@ -1,4 +1,5 @@
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 pub mod test_util {
     use jsonwebtoken::{encode, EncodingKey, Header};
     use mongodb::{
@@ -12,7 +13,91 @@ pub mod test_util {
         testcontainers::{GenericImage, RunnableImage},
     };
 
+    use crate::cache::ResponseCache;
+    use crate::patreon_api::{
+        FetchError, FetchStats, PatreonApi, PatreonPostsApiResult, PatreonPostsPage,
+        DEFAULT_PATREON_API_BASE_URL,
+    };
+    use crate::patreon_oauth::PatreonOAuthClient;
+    use crate::permission::ADMIN_ROLE;
+    use crate::redis_pubsub::pubsub::{MessageStats, SubscriberHealth};
     use crate::{jwt_auth::TokenClaims, posts::Post, AppState};
+    use async_trait::async_trait;
+    use reqwest::StatusCode;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    /// Two pages of posts of campaign 8365446, as returned by the Patreon API.
+    pub const PATREON_POSTS_PAGE_1: &str = include_str!("test_data/patreon_api/posts_page_1.json");
+    pub const PATREON_POSTS_PAGE_2: &str = include_str!("test_data/patreon_api/posts_page_2.json");
+    /// Webhook bodies Patreon sends for posts of campaign 8365446.
+    pub const PATREON_WEBHOOK_POST_PUBLISH: &str =
+        include_str!("test_data/patreon_webhook/post_publish.json");
+    pub const PATREON_WEBHOOK_POST_DELETE: &str =
+        include_str!("test_data/patreon_webhook/post_delete.json");
+    /// A patron of tier 5678902 of campaign 8365446, and a former patron of campaign 999.
+    pub const PATREON_IDENTITY: &str = include_str!("test_data/patreon_oauth/identity.json");
+
+    /// Serves canned pages per campaign, chained by their `next_cursor`.
+    #[derive(Default)]
+    pub struct FakePatreonApi {
+        pages: HashMap<String, Vec<PatreonPostsPage>>,
+    }
+
+    impl FakePatreonApi {
+        pub fn new() -> Self {
+            FakePatreonApi::default()
+        }
+
+        pub fn with_pages(mut self, campaign_id: &str, pages: Vec<&str>) -> Self {
+            let pages = pages
+                .into_iter()
+                .map(|page| {
+                    serde_json::from_str::<PatreonPostsApiResult>(page)
+                        .unwrap()
+                        .into_page(campaign_id)
+                })
+                .collect();
+            self.pages.insert(campaign_id.to_string(), pages);
+            self
+        }
+
+        pub fn from_fixtures() -> Self {
+            FakePatreonApi::new()
+                .with_pages("8365446", vec![PATREON_POSTS_PAGE_1, PATREON_POSTS_PAGE_2])
+        }
+    }
+
+    #[async_trait]
+    impl PatreonApi for FakePatreonApi {
+        async fn list_campaign_posts(
+            &self,
+            campaign_id: &str,
+            cursor: Option<&str>,
+            stats: &mut FetchStats,
+        ) -> Result<PatreonPostsPage, FetchError> {
+            let pages = match self.pages.get(campaign_id) {
+                Some(pages) => pages,
+                None => return Err(FetchError::Rejected(StatusCode::NOT_FOUND)),
+            };
+
+            let index = match cursor {
+                None => Some(0),
+                Some(cursor) => pages
+                    .iter()
+                    .position(|page| page.next_cursor.as_deref() == Some(cursor))
+                    .map(|index| index + 1),
+            };
+
+            match index.and_then(|index| pages.get(index)) {
+                Some(page) => {
+                    stats.last_http_status = Some(200);
+                    Ok(page.clone())
+                }
+                None => Err(FetchError::Rejected(StatusCode::BAD_REQUEST)),
+            }
+        }
+    }
 
     pub fn generate_port_number() -> u16 {
         let address = "0.0.0.0:0";
@@ -27,7 +112,7 @@ pub mod test_util {
     }
 
     pub fn get_redis_image() -> RunnableImage<Redis> {
-        RunnableImage::from(Redis::default())
+        RunnableImage::from(Redis)
     }
 
     pub fn populate_test_data(&port: &u16) {
@@ -78,17 +163,41 @@ pub mod test_util {
             jwt_key: "test_jwt_key".to_string(),
             server_domain: "http://localhost:8000".to_string(),
             client_domain: "http://localhost:3000".to_string(),
-            patreon_access_token: "asdfasdfasdf".to_string(),
+            patreon_campaign_ids: vec!["8365446".to_string()],
+            patreon_api: Arc::new(FakePatreonApi::new()),
+            sync_schedule: None,
+            patreon_webhook_secret: "None".to_string(),
+            message_stats: Arc::new(MessageStats::default()),
+            subscriber_health: Arc::new(SubscriberHealth::default()),
+            cache: Arc::new(ResponseCache::default()),
+            patreon_oauth: PatreonOAuthClient::new(
+                DEFAULT_PATREON_API_BASE_URL,
+                "test_client_id",
+                "test_client_secret",
+                "http://localhost:3000/patreon/callback",
+            ),
+            trusted_proxy_count: 0,
         }
     }
 
-    pub fn generate_test_jwt_token() -> String {
-        let my_claims = TokenClaims {
+    pub fn test_token_claims(role: &str) -> TokenClaims {
+        TokenClaims {
             name: "b@b.com".to_owned(),
-            role: "admin".to_owned(),
+            role: role.to_owned(),
             iat: 1516239022,
             exp: 9999999999,
-        };
+            jti: "test_jti".to_owned(),
+            family_id: "test_family".to_owned(),
+            tier_ids: vec![],
+        }
+    }
+
+    pub fn generate_test_jwt_token() -> String {
+        generate_test_jwt_token_for_role(ADMIN_ROLE)
+    }
+
+    pub fn generate_test_jwt_token_for_role(role: &str) -> String {
+        let my_claims = test_token_claims(role);
 
         let token = encode(
             &Header::default(),
@@ -1,5 +1,6 @@
-use chrono::Utc;
-use mongodb::bson::DateTime;
+use crate::dao::update_many_docs;
+use chrono::{SecondsFormat, Utc};
+use mongodb::bson::{doc, DateTime, Document};
 
 pub fn get_chrono_dt_from_string(date_string: String) -> chrono::DateTime<Utc> {
     let chrono_dt: chrono::DateTime<Utc> = date_string
@@ -13,11 +14,94 @@ pub fn convert_to_rfc3999_string(date_string: String) -> String {
     let chrono_dt: chrono::DateTime<Utc> = date_string
         .parse()
         .unwrap_or("1970-01-01T09:00:00+09:00".parse().unwrap());
-    let bson_dt = DateTime::from_chrono(chrono_dt);
 
-    bson_dt
-        .try_to_rfc3339_string()
-        .unwrap_or("1970-01-01T09:00:00+09:00".to_string())
+    to_rfc3339_millis(DateTime::from_chrono(chrono_dt))
+}
+
+/// Dates are stored as RFC 3339 strings, which only sort and compare in time order when they all
+/// have the same precision, so every stored date is written with milliseconds.
+pub fn to_rfc3339_millis(dt: DateTime) -> String {
+    dt.to_chrono().to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// `bson_datetime_as_rfc3339_string` writing `to_rfc3339_millis`, for dates that are filtered
+/// or sorted on.
+pub mod bson_datetime_as_rfc3339_millis {
+    use mongodb::bson::DateTime;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(dt: &DateTime, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&super::to_rfc3339_millis(*dt))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<DateTime, D::Error> {
+        let date_string = String::deserialize(deserializer)?;
+        DateTime::parse_rfc3339_str(&date_string).map_err(de::Error::custom)
+    }
+}
+
+/// Rewrites `fields` of the documents stored before dates had a fixed precision, returning how
+/// many were updated.
+pub async fn normalize_date_fields<T>(
+    mongo: mongodb::Database,
+    fields: &[&str],
+) -> anyhow::Result<u64> {
+    let mut updated_count = 0;
+
+    for field in fields {
+        updated_count += update_many_docs::<T>(
+            mongo.clone(),
+            doc! {
+                *field: {
+                    "$type": "string",
+                    "$not": { "$regex": r"^\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}\.\d{3}Z$" },
+                }
+            },
+            vec![doc! {
+                "$set": {
+                    *field: {
+                        "$dateToString": {
+                            "date": { "$dateFromString": { "dateString": format!("${}", field) } },
+                            "format": "%Y-%m-%dT%H:%M:%S.%LZ",
+                        }
+                    }
+                }
+            }],
+        )
+        .await?;
+    }
+
+    Ok(updated_count)
+}
+
+/// Bounds are normalized like the stored dates they are compared with.
+pub fn date_range_filter(
+    from: &Option<String>,
+    to: &Option<String>,
+) -> Result<Option<Document>, String> {
+    let mut range = doc! {};
+
+    if let Some(from) = from {
+        range.insert("$gte", parse_query_date(from)?);
+    }
+
+    if let Some(to) = to {
+        range.insert("$lte", parse_query_date(to)?);
+    }
+
+    if range.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(range))
+}
+
+fn parse_query_date(date_string: &str) -> Result<String, String> {
+    let chrono_dt: chrono::DateTime<chrono::Utc> = date_string
+        .parse()
+        .map_err(|_| format!("invalid date: {}", date_string))?;
+
+    Ok(to_rfc3339_millis(DateTime::from_chrono(chrono_dt)))
 }
 
 #[cfg(test)]
@@ -42,13 +126,42 @@ mod tests {
     fn test_convert_to_rfc3999_string() {
         let date_string = convert_to_rfc3999_string("2022-03-14T05:23:49.000+00:00".to_string());
 
-        assert_eq!(date_string, "2022-03-14T05:23:49Z");
+        assert_eq!(date_string, "2022-03-14T05:23:49.000Z");
     }
 
     #[test]
     fn test_convert_to_rfc3999_string_with_invalid_string() {
         let date_string = convert_to_rfc3999_string("asdfasdf".to_string());
 
-        assert_eq!(date_string, "1970-01-01T00:00:00Z");
+        assert_eq!(date_string, "1970-01-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn test_to_rfc3339_millis() {
+        let whole_second = DateTime::parse_rfc3339_str("2024-03-15T10:00:49Z").unwrap();
+        let half_second = DateTime::parse_rfc3339_str("2024-03-15T10:00:49.5Z").unwrap();
+
+        assert_eq!(to_rfc3339_millis(whole_second), "2024-03-15T10:00:49.000Z");
+        assert_eq!(to_rfc3339_millis(half_second), "2024-03-15T10:00:49.500Z");
+        // unlike "...49Z" and "...49.5Z"
+        assert!(to_rfc3339_millis(whole_second) < to_rfc3339_millis(half_second));
+    }
+
+    #[test]
+    fn test_date_range_filter() {
+        let range = date_range_filter(
+            &Some("2024-03-15T10:00:49Z".to_string()),
+            &Some("2024-03-15T19:00:49.5+09:00".to_string()),
+        )
+        .unwrap();
+
+        assert_eq!(
+            range,
+            Some(doc! {
+                "$gte": "2024-03-15T10:00:49.000Z",
+                "$lte": "2024-03-15T10:00:49.500Z",
+            })
+        );
+        assert_eq!(date_range_filter(&None, &None).unwrap(), None);
     }
 }
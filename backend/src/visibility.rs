@@ -0,0 +1,159 @@
+use crate::jwt_auth::TokenClaims;
+use crate::permission::{has_permission, Permission, PATRON_ROLE};
+use serde::{Deserialize, Serialize};
+
+/// Who may download a post, taken from the access settings of its Patreon post at every sync.
+/// Posts made in the admin page, and ones synced before access was, are public.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PostVisibility {
+    #[default]
+    Public,
+    /// Any active patron, whatever the tier.
+    Patrons,
+    /// Patrons of the minimum tier or a higher one. Patreon lists every one of those tiers,
+    /// so the tiers between don't have to be worked out from their prices.
+    MinimumTier { tier_ids: Vec<String> },
+}
+
+impl PostVisibility {
+    /// A post that isn't public and names no tiers is for every patron.
+    pub fn from_patreon(is_public: bool, tier_ids: Vec<String>) -> Self {
+        if is_public {
+            PostVisibility::Public
+        } else if tier_ids.is_empty() {
+            PostVisibility::Patrons
+        } else {
+            PostVisibility::MinimumTier { tier_ids }
+        }
+    }
+}
+
+/// What the caller's pledge, or role, unlocks.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Entitlement {
+    /// Anyone who isn't logged in.
+    Public,
+    Patron {
+        tier_ids: Vec<String>,
+    },
+    /// Admins and moderators, who see the hidden posts too.
+    Everything,
+}
+
+impl Entitlement {
+    pub fn of(claims: Option<&TokenClaims>) -> Self {
+        match claims {
+            Some(claims) if has_permission(&claims.role, Permission::PostsReadHidden) => {
+                Entitlement::Everything
+            }
+            Some(claims) if claims.role == PATRON_ROLE => Entitlement::Patron {
+                tier_ids: claims.tier_ids.clone(),
+            },
+            _ => Entitlement::Public,
+        }
+    }
+
+    pub fn can_view(&self, visibility: &PostVisibility) -> bool {
+        match (self, visibility) {
+            (Entitlement::Everything, _) | (_, PostVisibility::Public) => true,
+            (Entitlement::Patron { .. }, PostVisibility::Patrons) => true,
+            (
+                Entitlement::Patron { tier_ids },
+                PostVisibility::MinimumTier { tier_ids: allowed },
+            ) => tier_ids.iter().any(|tier_id| allowed.contains(tier_id)),
+            (Entitlement::Public, _) => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::permission::{ADMIN_ROLE, MODERATOR_ROLE};
+    use crate::test_util::test_util::test_token_claims;
+
+    fn tier_ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn test_from_patreon() {
+        assert_eq!(
+            PostVisibility::from_patreon(true, tier_ids(&["1"])),
+            PostVisibility::Public
+        );
+        assert_eq!(
+            PostVisibility::from_patreon(false, vec![]),
+            PostVisibility::Patrons
+        );
+        assert_eq!(
+            PostVisibility::from_patreon(false, tier_ids(&["1", "2"])),
+            PostVisibility::MinimumTier {
+                tier_ids: tier_ids(&["1", "2"])
+            }
+        );
+    }
+
+    #[test]
+    fn test_entitlement_of() {
+        let mut patron_claims = test_token_claims(PATRON_ROLE);
+        patron_claims.tier_ids = tier_ids(&["2"]);
+
+        assert_eq!(Entitlement::of(None), Entitlement::Public);
+        assert_eq!(
+            Entitlement::of(Some(&test_token_claims(ADMIN_ROLE))),
+            Entitlement::Everything
+        );
+        assert_eq!(
+            Entitlement::of(Some(&test_token_claims(MODERATOR_ROLE))),
+            Entitlement::Everything
+        );
+        assert_eq!(
+            Entitlement::of(Some(&patron_claims)),
+            Entitlement::Patron {
+                tier_ids: tier_ids(&["2"])
+            }
+        );
+        assert_eq!(
+            Entitlement::of(Some(&test_token_claims("unknown"))),
+            Entitlement::Public
+        );
+    }
+
+    #[test]
+    fn test_can_view() {
+        let minimum_tier = PostVisibility::MinimumTier {
+            tier_ids: tier_ids(&["2", "3"]),
+        };
+        let patron = |ids: &[&str]| Entitlement::Patron {
+            tier_ids: tier_ids(ids),
+        };
+
+        assert!(Entitlement::Public.can_view(&PostVisibility::Public));
+        assert!(!Entitlement::Public.can_view(&PostVisibility::Patrons));
+        assert!(!Entitlement::Public.can_view(&minimum_tier));
+
+        assert!(patron(&[]).can_view(&PostVisibility::Patrons));
+        assert!(!patron(&["1"]).can_view(&minimum_tier));
+        assert!(patron(&["1", "3"]).can_view(&minimum_tier));
+
+        assert!(Entitlement::Everything.can_view(&minimum_tier));
+    }
+
+    #[test]
+    fn test_visibility_json() {
+        assert_eq!(
+            serde_json::to_value(PostVisibility::MinimumTier {
+                tier_ids: tier_ids(&["2"])
+            })
+            .unwrap(),
+            serde_json::json!({ "kind": "minimum_tier", "tier_ids": ["2"] })
+        );
+        assert_eq!(
+            serde_json::from_value::<PostVisibility>(serde_json::json!({ "kind": "patrons" }))
+                .unwrap(),
+            PostVisibility::Patrons
+        );
+    }
+}
@@ -0,0 +1,545 @@
+use crate::cache::invalidate_cache;
+use crate::dao::{
+    find_docs_paginated, find_one_doc, insert_one_doc, Page, PageCursor, PageOptions, SortOrder,
+};
+use crate::patreon_api::PatreonWebhookResult;
+use crate::redis_pubsub::message::CacheScope;
+use crate::sync_post::{orphan_post, upsert_post, UpsertedPost};
+use crate::AppState;
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::serde_helpers::{bson_datetime_as_rfc3339_string, hex_string_as_object_id};
+use mongodb::bson::{doc, DateTime, Document};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use tracing::{error, info};
+
+pub const PATREON_EVENT_HEADER: &str = "X-Patreon-Event";
+pub const PATREON_SIGNATURE_HEADER: &str = "X-Patreon-Signature";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WebhookEvent {
+    Publish,
+    Update,
+    Delete,
+}
+
+impl WebhookEvent {
+    pub fn from_header(event: &str) -> Option<Self> {
+        match event {
+            "posts:publish" => Some(WebhookEvent::Publish),
+            "posts:update" => Some(WebhookEvent::Update),
+            "posts:delete" => Some(WebhookEvent::Delete),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookOutcome {
+    Created,
+    Updated,
+    Restored,
+    Orphaned,
+    /// Nothing to do for the delivery, e.g. an event or campaign we don't sync.
+    Ignored,
+    /// The body could not be parsed, redelivering it won't help.
+    Rejected,
+    /// Applying the delivery failed, Patreon retries it.
+    Failed,
+}
+
+impl From<UpsertedPost> for WebhookOutcome {
+    fn from(upserted_post: UpsertedPost) -> Self {
+        match upserted_post {
+            UpsertedPost::Created => WebhookOutcome::Created,
+            UpsertedPost::Updated => WebhookOutcome::Updated,
+            UpsertedPost::Restored => WebhookOutcome::Restored,
+        }
+    }
+}
+
+/// Every verified webhook delivery, with its raw body so it can be replayed.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct WebhookDelivery {
+    #[serde(with = "hex_string_as_object_id")]
+    _id: String,
+    event: String,
+    patreon_post_id: String,
+    outcome: WebhookOutcome,
+    message: String,
+    payload: String,
+    #[serde(with = "bson_datetime_as_rfc3339_string")]
+    received_at: DateTime,
+    /// Id of the delivery this one replays.
+    #[serde(default)]
+    replay_of: Option<String>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct WebhookDeliveryResponse {
+    pub id: String,
+    pub event: String,
+    pub patreon_post_id: String,
+    pub outcome: WebhookOutcome,
+    pub message: String,
+    pub received_at: String,
+    pub replay_of: Option<String>,
+}
+
+impl From<WebhookDelivery> for WebhookDeliveryResponse {
+    fn from(delivery: WebhookDelivery) -> Self {
+        WebhookDeliveryResponse {
+            id: delivery._id,
+            event: delivery.event,
+            patreon_post_id: delivery.patreon_post_id,
+            outcome: delivery.outcome,
+            message: delivery.message,
+            received_at: delivery
+                .received_at
+                .try_to_rfc3339_string()
+                .unwrap_or_default(),
+            replay_of: delivery.replay_of,
+        }
+    }
+}
+
+/// Patreon signs the raw body with HMAC-MD5 using the webhook secret, hex encoded.
+pub fn verify_signature(secret: &str, body: &[u8], signature: &str) -> bool {
+    if secret.is_empty() || secret == "None" {
+        return false;
+    }
+    let signature = match hex::decode(signature.trim()) {
+        Ok(signature) => signature,
+        Err(_) => return false,
+    };
+
+    let mut mac = match Hmac::<Md5>::new_from_slice(secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => return false,
+    };
+    mac.update(body);
+
+    mac.verify_slice(&signature).is_ok()
+}
+
+struct AppliedWebhook {
+    patreon_post_id: String,
+    outcome: WebhookOutcome,
+    message: String,
+}
+
+impl AppliedWebhook {
+    fn new(patreon_post_id: &str, outcome: WebhookOutcome, message: &str) -> Self {
+        AppliedWebhook {
+            patreon_post_id: patreon_post_id.to_string(),
+            outcome,
+            message: message.to_string(),
+        }
+    }
+}
+
+async fn apply_webhook(state: &AppState, event: &str, payload: &str) -> AppliedWebhook {
+    let event = match WebhookEvent::from_header(event) {
+        Some(event) => event,
+        None => return AppliedWebhook::new("", WebhookOutcome::Ignored, "unsupported event"),
+    };
+
+    let webhook = match serde_json::from_str::<PatreonWebhookResult>(payload) {
+        Ok(webhook) => webhook,
+        Err(err) => return AppliedWebhook::new("", WebhookOutcome::Rejected, &err.to_string()),
+    };
+    // an update that unpublishes the post takes it off the gallery like a delete
+    let is_removed = event == WebhookEvent::Delete || !webhook.is_published();
+    let patreon_post = webhook.into_post();
+
+    if !state
+        .patreon_campaign_ids
+        .contains(&patreon_post.campaign_id)
+    {
+        return AppliedWebhook::new(
+            &patreon_post.id,
+            WebhookOutcome::Ignored,
+            &format!("campaign {} is not synced", patreon_post.campaign_id),
+        );
+    }
+
+    if is_removed {
+        return match orphan_post(state.mongo.clone(), &patreon_post.id).await {
+            Ok(true) => AppliedWebhook::new(&patreon_post.id, WebhookOutcome::Orphaned, ""),
+            Ok(false) => AppliedWebhook::new(
+                &patreon_post.id,
+                WebhookOutcome::Ignored,
+                "post is not synced",
+            ),
+            Err(err) => {
+                AppliedWebhook::new(&patreon_post.id, WebhookOutcome::Failed, &err.to_string())
+            }
+        };
+    }
+
+    match upsert_post(state.mongo.clone(), &patreon_post).await {
+        Ok(upserted_post) => AppliedWebhook::new(&patreon_post.id, upserted_post.into(), ""),
+        Err(err) => AppliedWebhook::new(&patreon_post.id, WebhookOutcome::Failed, &err.to_string()),
+    }
+}
+
+/// Applies the delivery and logs it, answering with an error status when it should be redelivered.
+async fn handle_delivery(
+    state: AppState,
+    event: &str,
+    payload: String,
+    replay_of: Option<String>,
+) -> Result<Json<WebhookDeliveryResponse>, Response> {
+    let applied = apply_webhook(&state, event, &payload).await;
+    info!(
+        "Patreon webhook {} for post {}: {:?}",
+        event, applied.patreon_post_id, applied.outcome
+    );
+
+    let delivery = WebhookDelivery {
+        _id: ObjectId::new().to_hex(),
+        event: event.to_string(),
+        patreon_post_id: applied.patreon_post_id,
+        outcome: applied.outcome,
+        message: applied.message,
+        payload,
+        received_at: DateTime::now(),
+        replay_of,
+    };
+
+    if matches!(
+        delivery.outcome,
+        WebhookOutcome::Created
+            | WebhookOutcome::Updated
+            | WebhookOutcome::Restored
+            | WebhookOutcome::Orphaned
+    ) {
+        invalidate_cache(&state, CacheScope::Posts);
+    }
+
+    if let Err(err) = insert_one_doc::<WebhookDelivery>(state.mongo, delivery.clone()).await {
+        error!("fail to log webhook delivery {}", err.to_string());
+    }
+
+    match delivery.outcome {
+        WebhookOutcome::Rejected => {
+            error!("{}", delivery.message.clone());
+            Err((StatusCode::BAD_REQUEST, delivery.message).into_response())
+        }
+        WebhookOutcome::Failed => {
+            error!("{}", delivery.message.clone());
+            Err((StatusCode::INTERNAL_SERVER_ERROR, delivery.message).into_response())
+        }
+        _ => Ok(Json(WebhookDeliveryResponse::from(delivery))),
+    }
+}
+
+pub async fn receive_patreon_webhook(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<WebhookDeliveryResponse>, impl IntoResponse> {
+    let header_value = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string()
+    };
+
+    if !verify_signature(
+        &state.patreon_webhook_secret,
+        &body,
+        &header_value(PATREON_SIGNATURE_HEADER),
+    ) {
+        error!("Patreon webhook with invalid signature");
+        return Err((StatusCode::UNAUTHORIZED, "invalid signature".to_string()).into_response());
+    }
+
+    let payload = String::from_utf8_lossy(&body).to_string();
+
+    handle_delivery(state, &header_value(PATREON_EVENT_HEADER), payload, None).await
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct WebhookDeliveryListQuery {
+    limit: Option<i64>,
+    cursor: Option<String>,
+    outcome: Option<WebhookOutcome>,
+    patreon_post_id: Option<String>,
+}
+
+impl WebhookDeliveryListQuery {
+    pub fn parse(&self) -> Result<(Document, PageOptions), String> {
+        Ok((self.filter()?, self.page_options()?))
+    }
+
+    pub fn filter(&self) -> Result<Document, String> {
+        let mut filter = doc! {};
+
+        if let Some(outcome) = self.outcome {
+            filter.insert(
+                "outcome",
+                mongodb::bson::to_bson(&outcome).map_err(|err| err.to_string())?,
+            );
+        }
+
+        if let Some(patreon_post_id) = &self.patreon_post_id {
+            filter.insert("patreon_post_id", patreon_post_id);
+        }
+
+        Ok(filter)
+    }
+
+    pub fn page_options(&self) -> Result<PageOptions, String> {
+        let cursor = match &self.cursor {
+            Some(cursor) => {
+                Some(PageCursor::decode(cursor).map_err(|err| format!("invalid cursor: {}", err))?)
+            }
+            None => None,
+        };
+
+        Ok(PageOptions::new(
+            "received_at",
+            SortOrder::Desc,
+            self.limit,
+            cursor,
+        ))
+    }
+}
+
+pub async fn get_webhook_deliveries(
+    State(state): State<AppState>,
+    Query(query): Query<WebhookDeliveryListQuery>,
+) -> Result<Json<Page<WebhookDeliveryResponse>>, impl IntoResponse> {
+    let (filter, page_options) = match query.parse() {
+        Ok(result) => result,
+        Err(error_message) => {
+            error!(error_message);
+            return Err((StatusCode::BAD_REQUEST, error_message).into_response());
+        }
+    };
+
+    match find_docs_paginated::<WebhookDelivery>(state.mongo, filter, page_options).await {
+        Ok(page) => Ok(Json(Page {
+            items: page
+                .items
+                .into_iter()
+                .map(WebhookDeliveryResponse::from)
+                .collect(),
+            next_cursor: page.next_cursor,
+            total_count: page.total_count,
+        })),
+        Err(err) => {
+            let error_message = err.to_string();
+            error!("{}", error_message.clone());
+            Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response())
+        }
+    }
+}
+
+/// Applies a logged delivery again, e.g. after fixing what made it fail.
+pub async fn replay_webhook_delivery(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<WebhookDeliveryResponse>, impl IntoResponse> {
+    let target_object_id_result = ObjectId::from_str(&id);
+
+    if let Err(err) = &target_object_id_result {
+        let error_message = err.to_string();
+        error!(error_message);
+
+        return Err((StatusCode::BAD_REQUEST, error_message).into_response());
+    }
+
+    let filter = doc! {
+        "_id": target_object_id_result.unwrap()
+    };
+
+    match find_one_doc::<WebhookDelivery>(state.mongo.clone(), filter).await {
+        Ok(Some(delivery)) => {
+            handle_delivery(state, &delivery.event, delivery.payload, Some(id)).await
+        }
+        Ok(None) => {
+            error!("The webhook delivery with id: {} not found!", id);
+            Err((
+                StatusCode::NOT_FOUND,
+                format!("The webhook delivery with id: {} not found!", id),
+            )
+                .into_response())
+        }
+        Err(err) => {
+            let error_message = err.to_string();
+            error!("{}", error_message.clone());
+            Err((StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::posts::Post;
+    use crate::test_util::test_util::{
+        count_all_posts, create_test_state, generate_port_number, get_db_connection_uri,
+        get_mongo_image, get_redis_connection_uri, get_redis_image, PATREON_WEBHOOK_POST_DELETE,
+        PATREON_WEBHOOK_POST_PUBLISH,
+    };
+    use axum::http::HeaderValue;
+    use mongodb::Client;
+    use testcontainers_modules::redis::REDIS_PORT;
+    use testcontainers_modules::testcontainers::clients;
+
+    const TEST_SECRET: &str = "test_webhook_secret";
+
+    fn sign(body: &str) -> String {
+        let mut mac = Hmac::<Md5>::new_from_slice(TEST_SECRET.as_bytes()).unwrap();
+        mac.update(body.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn webhook_headers(event: &str, signature: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(PATREON_EVENT_HEADER, HeaderValue::from_str(event).unwrap());
+        headers.insert(
+            PATREON_SIGNATURE_HEADER,
+            HeaderValue::from_str(signature).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn test_verify_signature() {
+        let body = PATREON_WEBHOOK_POST_PUBLISH.as_bytes();
+        let signature = sign(PATREON_WEBHOOK_POST_PUBLISH);
+
+        assert!(verify_signature(TEST_SECRET, body, &signature));
+        assert!(verify_signature(
+            TEST_SECRET,
+            body,
+            &signature.to_uppercase()
+        ));
+        assert!(!verify_signature("other_secret", body, &signature));
+        assert!(!verify_signature(TEST_SECRET, b"{}", &signature));
+        assert!(!verify_signature(TEST_SECRET, body, "not hex"));
+        // without a configured secret nothing is trusted
+        assert!(!verify_signature("", body, &signature));
+    }
+
+    #[test]
+    fn test_webhook_event_from_header() {
+        assert_eq!(
+            WebhookEvent::from_header("posts:publish"),
+            Some(WebhookEvent::Publish)
+        );
+        assert_eq!(
+            WebhookEvent::from_header("posts:update"),
+            Some(WebhookEvent::Update)
+        );
+        assert_eq!(
+            WebhookEvent::from_header("posts:delete"),
+            Some(WebhookEvent::Delete)
+        );
+        assert_eq!(WebhookEvent::from_header("members:create"), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_receive_patreon_webhook() {
+        let docker = clients::Cli::default();
+        let port = generate_port_number();
+        let mongo_img = get_mongo_image(&port);
+        let redis_img = get_redis_image();
+        let _c = docker.run(mongo_img);
+        let redis_node = docker.run(redis_img);
+
+        let uri = get_db_connection_uri(&port);
+        let redis_uri = get_redis_connection_uri(&redis_node.get_host_port_ipv4(REDIS_PORT));
+        let client = Client::with_uri_str(uri).await.unwrap();
+        let redis_client = redis::Client::open(redis_uri.as_ref()).unwrap();
+
+        let test_db = client.database("test_db");
+        let mut state = create_test_state(test_db.clone(), redis_client);
+        state.patreon_webhook_secret = TEST_SECRET.to_string();
+
+        let result = receive_patreon_webhook(
+            State(state.clone()),
+            webhook_headers("posts:publish", "00"),
+            Bytes::from(PATREON_WEBHOOK_POST_PUBLISH),
+        )
+        .await;
+        assert_eq!(
+            result.err().unwrap().into_response().status(),
+            StatusCode::UNAUTHORIZED
+        );
+
+        let signature = sign(PATREON_WEBHOOK_POST_PUBLISH);
+        let publish = |event: &str| {
+            receive_patreon_webhook(
+                State(state.clone()),
+                webhook_headers(event, &signature),
+                Bytes::from(PATREON_WEBHOOK_POST_PUBLISH),
+            )
+        };
+
+        let delivery = publish("posts:publish").await.ok().unwrap().0;
+        assert_eq!(delivery.outcome, WebhookOutcome::Created);
+        assert_eq!(delivery.patreon_post_id, "77889900");
+        assert_eq!(count_all_posts(test_db.clone()).await, 1);
+
+        let delivery = publish("posts:update").await.ok().unwrap().0;
+        assert_eq!(delivery.outcome, WebhookOutcome::Updated);
+        assert_eq!(count_all_posts(test_db.clone()).await, 1);
+
+        let delivery = publish("members:create").await.ok().unwrap().0;
+        assert_eq!(delivery.outcome, WebhookOutcome::Ignored);
+
+        let delete_body = PATREON_WEBHOOK_POST_DELETE.replace("98765432", "77889900");
+        let delivery = receive_patreon_webhook(
+            State(state.clone()),
+            webhook_headers("posts:delete", &sign(&delete_body)),
+            Bytes::from(delete_body),
+        )
+        .await
+        .ok()
+        .unwrap()
+        .0;
+        assert_eq!(delivery.outcome, WebhookOutcome::Orphaned);
+
+        let post = test_db
+            .collection::<Post>("Post")
+            .find_one(doc! { "patreon_post_id": "77889900" }, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(post.is_orphaned());
+
+        // replaying the publish brings the post back
+        let deliveries = get_webhook_deliveries(
+            State(state.clone()),
+            Query(WebhookDeliveryListQuery::default()),
+        )
+        .await
+        .ok()
+        .unwrap()
+        .0;
+        assert_eq!(deliveries.total_count, 4);
+        let first_delivery = deliveries.items.last().unwrap();
+        assert_eq!(first_delivery.event, "posts:publish");
+
+        let replayed = replay_webhook_delivery(State(state), Path(first_delivery.id.clone()))
+            .await
+            .ok()
+            .unwrap()
+            .0;
+        assert_eq!(replayed.outcome, WebhookOutcome::Restored);
+        assert_eq!(replayed.replay_of, Some(first_delivery.id.clone()));
+    }
+}